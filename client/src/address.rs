@@ -0,0 +1,289 @@
+/// address.rs - parsing and resolving of server locations
+/// accepts anything a user is likely to type into the server location popup:
+/// - `localhost`, `files.example.com:4000`
+/// - `127.0.0.1:3333`, `[::1]:3333`, `::1`
+/// - `http://host:3333`, `https://host`
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+
+/// Port used when the user does not give one
+pub const DEFAULT_PORT: u16 = 3333;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Scheme {
+    #[default]
+    Http,
+    Https,
+}
+
+impl fmt::Display for Scheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Scheme::Http => write!(f, "http"),
+            Scheme::Https => write!(f, "https"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AddressError {
+    Empty,
+    UnsupportedScheme(String),
+    MissingHost,
+    InvalidHost(String),
+    InvalidPort(String),
+    UnexpectedPath(String),
+    Resolve(String),
+}
+
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddressError::Empty => write!(f, "server location is empty"),
+            AddressError::UnsupportedScheme(s) => {
+                write!(f, "unsupported scheme '{}', use http or https", s)
+            }
+            AddressError::MissingHost => write!(f, "server location has no host"),
+            AddressError::InvalidHost(h) => write!(f, "'{}' is not a valid host name or IP", h),
+            AddressError::InvalidPort(p) => write!(f, "'{}' is not a valid port (1-65535)", p),
+            AddressError::UnexpectedPath(p) => {
+                write!(f, "server location should not contain a path ('{}')", p)
+            }
+            AddressError::Resolve(e) => write!(f, "could not resolve server: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for AddressError {}
+
+/// Where a tuifs server lives - scheme, host (name or IP literal) and port
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerAddress {
+    pub scheme: Scheme,
+    /// host name or IP literal, IPv6 literals are stored without brackets
+    pub host: String,
+    pub port: u16,
+}
+
+impl Default for ServerAddress {
+    fn default() -> Self {
+        Self {
+            scheme: Scheme::Http,
+            host: "127.0.0.1".to_string(),
+            port: DEFAULT_PORT,
+        }
+    }
+}
+
+impl fmt::Display for ServerAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}://{}", self.scheme, self.authority())
+    }
+}
+
+impl FromStr for ServerAddress {
+    type Err = AddressError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let input = input.trim();
+        if input.is_empty() {
+            return Err(AddressError::Empty);
+        }
+
+        let (scheme, rest) = match input.split_once("://") {
+            Some((scheme, rest)) => match scheme.to_ascii_lowercase().as_str() {
+                "http" => (Scheme::Http, rest),
+                "https" => (Scheme::Https, rest),
+                _ => return Err(AddressError::UnsupportedScheme(scheme.to_string())),
+            },
+            None => (Scheme::Http, input),
+        };
+
+        // allow a trailing slash, anything else after the authority is a mistake
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, ""),
+        };
+        if !path.is_empty() && path != "/" {
+            return Err(AddressError::UnexpectedPath(path.to_string()));
+        }
+        if authority.is_empty() {
+            return Err(AddressError::MissingHost);
+        }
+
+        let (host, port) = split_host_port(authority)?;
+        let port = match port {
+            Some(p) => match p.parse::<u16>() {
+                Ok(port) if port != 0 => port,
+                _ => return Err(AddressError::InvalidPort(p.to_string())),
+            },
+            None => DEFAULT_PORT,
+        };
+
+        Ok(Self { scheme, host, port })
+    }
+}
+
+/// Splits `host[:port]`, `[v6][:port]` or a bare IPv6 literal
+fn split_host_port(authority: &str) -> Result<(String, Option<&str>), AddressError> {
+    if let Some(bracketed) = authority.strip_prefix('[') {
+        let (host, after) = bracketed
+            .split_once(']')
+            .ok_or_else(|| AddressError::InvalidHost(authority.to_string()))?;
+        host.parse::<Ipv6Addr>()
+            .map_err(|_| AddressError::InvalidHost(host.to_string()))?;
+        let port = match after {
+            "" => None,
+            _ => Some(
+                after
+                    .strip_prefix(':')
+                    .ok_or_else(|| AddressError::InvalidHost(authority.to_string()))?,
+            ),
+        };
+        return Ok((host.to_string(), port));
+    }
+
+    // more than one colon without brackets can only be a bare IPv6 address
+    if authority.matches(':').count() > 1 {
+        return match authority.parse::<Ipv6Addr>() {
+            Ok(ip) => Ok((ip.to_string(), None)),
+            Err(_) => Err(AddressError::InvalidHost(authority.to_string())),
+        };
+    }
+
+    let (host, port) = match authority.split_once(':') {
+        Some((host, port)) => (host, Some(port)),
+        None => (authority, None),
+    };
+    if host.is_empty() {
+        return Err(AddressError::MissingHost);
+    }
+    if host.parse::<IpAddr>().is_err() && !is_valid_hostname(host) {
+        return Err(AddressError::InvalidHost(host.to_string()));
+    }
+    Ok((host.to_ascii_lowercase(), port))
+}
+
+/// RFC 1123 host name check - labels of letters, digits and hyphens
+fn is_valid_hostname(host: &str) -> bool {
+    let host = host.strip_suffix('.').unwrap_or(host);
+    !host.is_empty()
+        && host.len() <= 253
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+impl ServerAddress {
+    /// `host:port`, with brackets around IPv6 literals - usable in URIs and for DNS lookups
+    pub fn authority(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+
+    /// Full URI for an endpoint on this server, `path` should start with '/'
+    pub fn url(&self, path: &str) -> String {
+        format!("{}://{}{}", self.scheme, self.authority(), path)
+    }
+
+    /// Resolves the host through DNS (IP literals resolve to themselves)
+    pub async fn resolve(&self) -> Result<Vec<SocketAddr>, AddressError> {
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host(self.authority())
+            .await
+            .map_err(|e| AddressError::Resolve(e.to_string()))?
+            .collect();
+        if addrs.is_empty() {
+            return Err(AddressError::Resolve(format!(
+                "no addresses found for {}",
+                self.host
+            )));
+        }
+        Ok(addrs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &str) -> Result<ServerAddress, AddressError> {
+        input.parse()
+    }
+
+    fn address(scheme: Scheme, host: &str, port: u16) -> ServerAddress {
+        ServerAddress {
+            scheme,
+            host: host.to_string(),
+            port,
+        }
+    }
+
+    #[test]
+    fn host_names_and_ports() {
+        assert_eq!(parse("localhost"), Ok(address(Scheme::Http, "localhost", DEFAULT_PORT)));
+        assert_eq!(parse("Files.Example.com:4000"), Ok(address(Scheme::Http, "files.example.com", 4000)));
+        assert_eq!(parse("  example.com.  "), Ok(address(Scheme::Http, "example.com.", DEFAULT_PORT)));
+        assert_eq!(parse("127.0.0.1:3333/"), Ok(address(Scheme::Http, "127.0.0.1", 3333)));
+    }
+
+    #[test]
+    fn schemes_and_default_ports() {
+        assert_eq!(parse("http://host"), Ok(address(Scheme::Http, "host", DEFAULT_PORT)));
+        assert_eq!(parse("HTTPS://host"), Ok(address(Scheme::Https, "host", DEFAULT_PORT)));
+        assert_eq!(parse("https://host:8443"), Ok(address(Scheme::Https, "host", 8443)));
+        assert_eq!(parse("ftp://host"), Err(AddressError::UnsupportedScheme("ftp".to_string())));
+    }
+
+    #[test]
+    fn ipv6_literals() {
+        assert_eq!(parse("[::1]:3333"), Ok(address(Scheme::Http, "::1", 3333)));
+        assert_eq!(parse("[::1]"), Ok(address(Scheme::Http, "::1", DEFAULT_PORT)));
+        assert_eq!(parse("https://[fe80::1]:9000/"), Ok(address(Scheme::Https, "fe80::1", 9000)));
+        // bare ones can't have a port, and are stored in their short form
+        assert_eq!(parse("::1"), Ok(address(Scheme::Http, "::1", DEFAULT_PORT)));
+        assert_eq!(parse("2001:db8:0:0:0:0:0:1"), Ok(address(Scheme::Http, "2001:db8::1", DEFAULT_PORT)));
+        assert_eq!(parse("[::1]:3333").unwrap().authority(), "[::1]:3333");
+        assert_eq!(parse("::1").unwrap().url("/getfiles"), "http://[::1]:3333/getfiles");
+    }
+
+    #[test]
+    fn bad_addresses() {
+        assert_eq!(parse(""), Err(AddressError::Empty));
+        assert_eq!(parse("   "), Err(AddressError::Empty));
+        assert_eq!(parse("http://"), Err(AddressError::MissingHost));
+        assert_eq!(parse(":3333"), Err(AddressError::MissingHost));
+        assert_eq!(parse("host/files"), Err(AddressError::UnexpectedPath("/files".to_string())));
+        assert_eq!(parse("host:0"), Err(AddressError::InvalidPort("0".to_string())));
+        assert_eq!(parse("host:65536"), Err(AddressError::InvalidPort("65536".to_string())));
+        assert_eq!(parse("host:"), Err(AddressError::InvalidPort(String::new())));
+    }
+
+    #[test]
+    fn bad_hosts() {
+        for host in ["-host", "host-", "a..b", "under_score", "sp ace", &"a".repeat(64)] {
+            assert_eq!(parse(host), Err(AddressError::InvalidHost(host.to_string())), "{}", host);
+        }
+        assert_eq!(parse("[::1"), Err(AddressError::InvalidHost("[::1".to_string())));
+        assert_eq!(parse("[1.2.3.4]"), Err(AddressError::InvalidHost("1.2.3.4".to_string())));
+        assert_eq!(parse("[::1]3333"), Err(AddressError::InvalidHost("[::1]3333".to_string())));
+        assert_eq!(parse("::g"), Err(AddressError::InvalidHost("::g".to_string())));
+    }
+
+    #[test]
+    fn split_host_port_parts() {
+        assert_eq!(split_host_port("host:80"), Ok(("host".to_string(), Some("80"))));
+        assert_eq!(split_host_port("host"), Ok(("host".to_string(), None)));
+        assert_eq!(split_host_port("[::1]:80"), Ok(("::1".to_string(), Some("80"))));
+        assert_eq!(split_host_port("::1"), Ok(("::1".to_string(), None)));
+        // the port is checked by the caller
+        assert_eq!(split_host_port("host:port"), Ok(("host".to_string(), Some("port"))));
+    }
+}
@@ -1,24 +1,32 @@
 /// This file contains the main application logic for the TUI file sharing application.
 /// - The App struct contains the main state for the application and related functions
+///
 /// in this file:
 /// - run: main start point
 /// - handle_key_event: handles key events based on current screen state
 /// - upload/download server files
 /// - server configuration backend
 use hyper::header::HeaderValue;
-use crate::httpclient::CustomHTTPClient;
+use crate::address::ServerAddress;
+use crate::httpclient::{self, CustomHTTPClient, TransferStats};
+use crate::preview::{self, Preview, PREVIEW_BYTES};
+use crate::profiles::Profiles;
 use tokio::io::AsyncWriteExt;
 use crate::statefullist::StatefulList;
-use tokio::fs::File;
-use crate::ui::ui;
+use crate::sync::{FolderSync, PlanItem, SyncAction};
+use crate::ui::{format_size, transfer_text, ui};
+use shared::{
+    ArchiveKind, ChangeEvent, FileEntry, FileVersion, GetFilesResponse, QuotaUsage, SearchMode, SearchRequest, SearchResult, ShareResponse,
+    TextMatch, TrashItem,
+};
 use color_eyre::Result;
 use crossterm::event::{self, Event, KeyCode, KeyEvent};
 use futures::executor::block_on;
-///
 use http_body_util::BodyExt;
-use http_body_util::{combinators::BoxBody, Empty, Full, StreamBody};
-use hyper::body::{Body, Bytes, Frame};
-use hyper::Request;
+use http_body_util::combinators::BoxBody;
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::{Request, StatusCode};
 use ratatui::{
     // layout::Rect,
     // style::Stylize,
@@ -32,21 +40,52 @@ use std::fs;
 use std::io;
 use std::path::PathBuf;
 use std::{borrow::BorrowMut, fmt};
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::time::Duration;
 
 pub enum CurrentScreen {
     Start, // Main screen - Menu and stuff
     ServerFiles,
     Uploading,   // screen while a file is uploading - should show success.
+    // downloads block until they are saved and report in the help box, nothing switches here yet
+    #[allow(dead_code)]
     Downloading, // screen while a file is downloading - should show success.
     Configuring, // screen for configuring the server location, download location, upload location
+    Sharing,     // shows a freshly created share link
+    Versions,    // previous versions of the selected file, can restore one
+    Trash,       // deleted files and folders, can restore or purge them
+    Search,      // search results below the current folder, can download or open them
+    TextSearch,  // files below the current folder with the searched words in them
+    Sync,        // what syncing the current folder with a local one would do, waits for approval
+    FolderDownload, // asks how to download the selected folder or the marked entries
+}
+
+/// How folders (and marked entries) are downloaded
+#[derive(Debug, Clone, Copy)]
+pub enum FolderDownload {
+    Zip,
+    TarGz,
+    /// every file on its own, recreating the folders locally
+    Tree,
 }
 
 pub enum CurrentlyConfiguring {
     DownloadLocation,
     ServerLocation,
     UploadLocation,
+    Username,
+    Password,
+    FolderName,
+    ShareExpiry,
+    SharePassword,
+    Search,
+    TextSearch,
+    Tags,
+    Note,
+    SyncFolder,
 }
 
+#[allow(clippy::derivable_impls)]
 impl Default for CurrentScreen {
     fn default() -> Self {
         CurrentScreen::Start
@@ -55,10 +94,24 @@ impl Default for CurrentScreen {
 
 impl fmt::Debug for CurrentScreen {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self)
+        f.write_str(match self {
+            CurrentScreen::Start => "Start",
+            CurrentScreen::ServerFiles => "ServerFiles",
+            CurrentScreen::Uploading => "Uploading",
+            CurrentScreen::Downloading => "Downloading",
+            CurrentScreen::Configuring => "Configuring",
+            CurrentScreen::Sharing => "Sharing",
+            CurrentScreen::Versions => "Versions",
+            CurrentScreen::Trash => "Trash",
+            CurrentScreen::Search => "Search",
+            CurrentScreen::TextSearch => "TextSearch",
+            CurrentScreen::Sync => "Sync",
+            CurrentScreen::FolderDownload => "FolderDownload",
+        })
     }
 }
 
+#[allow(clippy::derivable_impls)]
 impl Default for CurrentlyConfiguring {
     fn default() -> Self {
         CurrentlyConfiguring::ServerLocation
@@ -67,36 +120,129 @@ impl Default for CurrentlyConfiguring {
 
 impl fmt::Debug for CurrentlyConfiguring {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self)
+        f.write_str(match self {
+            CurrentlyConfiguring::DownloadLocation => "DownloadLocation",
+            CurrentlyConfiguring::ServerLocation => "ServerLocation",
+            CurrentlyConfiguring::UploadLocation => "UploadLocation",
+            CurrentlyConfiguring::Username => "Username",
+            CurrentlyConfiguring::Password => "Password",
+            CurrentlyConfiguring::FolderName => "FolderName",
+            CurrentlyConfiguring::ShareExpiry => "ShareExpiry",
+            CurrentlyConfiguring::SharePassword => "SharePassword",
+            CurrentlyConfiguring::Search => "Search",
+            CurrentlyConfiguring::TextSearch => "TextSearch",
+            CurrentlyConfiguring::Tags => "Tags",
+            CurrentlyConfiguring::Note => "Note",
+            CurrentlyConfiguring::SyncFolder => "SyncFolder",
+        })
     }
 }
+
 #[derive(Debug, Default)]
 pub struct App<'a> {
     pub title: &'a str,
     pub input: String,
-    pub server_files: StatefulList<String>,
+    pub server_files: StatefulList<FileEntry>,
+    /// folder being browsed on the server, "" is the root
+    pub current_dir: String,
+    /// whether the user may upload into / create folders in `current_dir`
+    pub dir_can_write: bool,
+    /// result of the last action, shown in the help box
+    pub status_message: Option<String>,
+    /// how the last upload or download went over the network, for the transfer screens
+    pub last_transfer: Option<TransferStats>,
     pub client: Option<CustomHTTPClient>,
     pub exit: bool,
     pub current_screen: CurrentScreen,
     pub currently_configuring: Option<CurrentlyConfiguring>,
+    /// error shown in the configuring popup, cleared on the next key press
+    pub config_error: Option<String>,
+    /// saved per server settings (pinned certificates, login tokens)
+    pub profiles: Profiles,
     download_location: String,
+    /// file on the server the download location is asked for
+    download_path: String,
+    /// folders and files the download location is asked for, and how they are downloaded
+    pub download_paths: Vec<String>,
+    folder_download: Option<FolderDownload>,
+    /// names in the current folder marked with space, 'D' downloads them together
+    pub marked: Vec<String>,
+    /// username typed in the login popup, kept while the password is entered
+    login_username: String,
+    /// expiry typed in the share popup, kept while the password is entered
+    share_expiry_secs: u64,
+    /// last share link created, shown on the Sharing screen
+    pub share_link: Option<ShareResponse>,
+    /// versions of `versions_path`, shown on the Versions screen
+    pub versions: StatefulList<FileVersion>,
+    pub versions_path: String,
+    /// whether the user may restore `versions_path`
+    pub versions_can_write: bool,
+    /// what the Trash screen shows
+    pub trash: StatefulList<TrashItem>,
+    /// fullest quota covering `current_dir`, shown as a gauge
+    pub usage: Option<QuotaUsage>,
+    /// what the Search screen shows
+    pub search_results: StatefulList<SearchResult>,
+    /// what the TextSearch screen shows
+    pub text_results: StatefulList<TextMatch>,
+    /// query as typed, for the Search and TextSearch screen titles
+    pub search_query: String,
+    /// the server had more results than it sent
+    pub search_truncated: bool,
+    /// changes the server tells us about, the listing is reloaded when they touch it
+    changes: Option<Receiver<ChangeEvent>>,
+    /// sync being prepared, its plan is what the Sync screen shows
+    pub sync: Option<FolderSync>,
+    pub sync_plan: StatefulList<PlanItem>,
+    /// what the preview pane shows, of the selected entry once it has loaded
+    pub preview: Option<Preview>,
+    pub show_preview: bool,
 }
 
 impl<'a> App<'a> {
-    pub fn new(client: Option<CustomHTTPClient>) -> Self {
+    pub fn new(client: Option<CustomHTTPClient>, profiles: Profiles) -> Self {
         Self {
             title: "tuifs",
             input: String::new(),
-            server_files: StatefulList::default(),
+            server_files: StatefulList::new(),
+            current_dir: String::new(),
+            dir_can_write: true,
+            status_message: None,
+            last_transfer: None,
             client,
             exit: false,
             current_screen: CurrentScreen::Start,
             currently_configuring: None,
+            config_error: None,
+            profiles,
             download_location: String::new(),
+            download_path: String::new(),
+            download_paths: Vec::new(),
+            folder_download: None,
+            marked: Vec::new(),
+            login_username: String::new(),
+            share_expiry_secs: 0,
+            share_link: None,
+            versions: StatefulList::new(),
+            versions_path: String::new(),
+            versions_can_write: false,
+            trash: StatefulList::new(),
+            usage: None,
+            search_results: StatefulList::new(),
+            text_results: StatefulList::new(),
+            search_query: String::new(),
+            search_truncated: false,
+            changes: None,
+            sync: None,
+            sync_plan: StatefulList::new(),
+            preview: None,
+            show_preview: true,
         }
     }
 
     pub fn run(&mut self, terminal: &mut DefaultTerminal) -> io::Result<()> {
+        std::thread::spawn(preview::load_highlighting);
         if self.client.is_some() {
             self.get_server_files();
         } else {
@@ -105,8 +251,16 @@ impl<'a> App<'a> {
         }
         while !self.exit {
             terminal.draw(|frame| ui(frame, self.borrow_mut()))?;
-            if let Event::Key(key_event) = event::read()? {
-                self.handle_key_event(key_event).unwrap();
+            // wake up now and then to show changes made by others
+            if event::poll(Duration::from_millis(200))? {
+                if let Event::Key(key_event) = event::read()? {
+                    self.handle_key_event(key_event).unwrap();
+                }
+            }
+            self.apply_changes();
+            // the preview follows the selection once the keys stop coming
+            if !event::poll(Duration::ZERO)? {
+                self.update_preview();
             }
         }
         Ok(())
@@ -118,60 +272,655 @@ impl<'a> App<'a> {
         if file_data.is_dir() {
             // eventually add code to upload directories
             println!("Cannot upload directories");
-            return Err(
-                io::Error::new(io::ErrorKind::Other, "Cannot upload directories (yet)").into(),
-            );
+            return Err(io::Error::other("Cannot upload directories (yet)").into());
         }
 
-        let res = block_on(self.client.as_mut().unwrap().send_file(file_path)).unwrap();
-
-        Ok(())
+        self.last_transfer = None;
+        // the server may already have this content under another name
+        let client = self.client.as_mut().unwrap();
+        if block_on(client.link_file(&file_path, &self.current_dir)).unwrap_or(false) {
+            self.status_message = Some(format!(
+                "{} was already on the server, nothing had to be uploaded",
+                file_path.file_name().unwrap_or_default().to_string_lossy()
+            ));
+            return Ok(());
+        }
+        // or an older version of it
+        if let Ok(Some((sent, total))) = block_on(client.send_file_delta(&file_path, &self.current_dir)) {
+            self.status_message = Some(format!(
+                "Only the changes to {} were uploaded ({} of {})",
+                file_path.file_name().unwrap_or_default().to_string_lossy(),
+                format_size(sent),
+                format_size(total)
+            ));
+            return Ok(());
+        }
+        let (res, stats) =
+            block_on(client.send_file(file_path, &self.current_dir)).map_err(|e| io::Error::other(e.to_string()))?;
+        self.last_transfer = Some(stats);
+        match res.status() {
+            StatusCode::UNAUTHORIZED => Err(io::Error::other("Login required").into()),
+            StatusCode::FORBIDDEN => {
+                Err(io::Error::other("You aren't allowed to upload to this folder").into())
+            }
+            StatusCode::INSUFFICIENT_STORAGE => Err(io::Error::other("Not enough quota left for this file").into()),
+            StatusCode::PAYLOAD_TOO_LARGE => {
+                Err(io::Error::other("The file is bigger than the server accepts").into())
+            }
+            status if !status.is_success() => {
+                Err(io::Error::other(format!("Server answered {}", status)).into())
+            }
+            _ => Ok(()),
+        }
     }
 
+    /// Downloads `download_path` into the download location, how it went ends up in the status line
     fn download_file(&mut self) {
-        let uri = format!("http://{}/downloadfile", self.client.as_ref().unwrap().address);
+        if let Some(mode) = self.folder_download.take() {
+            return self.download_folders(mode);
+        }
+        let uri = self.client.as_ref().unwrap().address.url("/downloadfile");
         
-        let file_name = self.server_files.items.get(self.server_files.state.selected().unwrap()).unwrap();
-        let file_value = HeaderValue::from_str(file_name).unwrap();
+        if self.download_path.is_empty() {
+            return;
+        }
+        let file_name = self.download_path.rsplit('/').next().unwrap_or_default().to_string();
+        let mut file_path: PathBuf = PathBuf::from(&self.download_location);
+        file_path.push(&file_name);
+        self.last_transfer = None;
+
+        // an older copy only needs the changes
+        let client = self.client.as_mut().unwrap();
+        if let Ok(Some((size, received))) = block_on(client.download_delta(&self.download_path, &file_path)) {
+            self.status_message = Some(format!(
+                "Only the changes to {} were downloaded ({} of {})",
+                file_name,
+                format_size(received),
+                format_size(size)
+            ));
+            return;
+        }
+        let file_value = HeaderValue::from_str(&self.download_path).unwrap();
 
         let mut req: Request<BoxBody<Bytes, std::io::Error>> = Default::default();
 
         *req.uri_mut() = uri.parse().unwrap();
         req.headers_mut().insert("file", file_value);
-        
+        req.headers_mut().insert(
+            hyper::header::ACCEPT_ENCODING,
+            HeaderValue::from_static(shared::encoding::SUPPORTED),
+        );
+
         let response = block_on(self.client.as_mut().unwrap().send_request(req)).unwrap();
-        let (_, body) = response.into_parts();
+        if response.status() == StatusCode::UNAUTHORIZED {
+            self.require_login();
+            return;
+        }
+        let status = response.status();
+        let (parts, body) = response.into_parts();
 
         let body = body.collect();
         let body = block_on(body).unwrap().to_bytes();
+        if !status.is_success() {
+            self.status_message = Some(format!(
+                "Download failed: {}",
+                String::from_utf8_lossy(&body)
+            ));
+            return;
+        }
+        let body = match block_on(httpclient::decompress(&parts.headers, body)) {
+            Ok((body, stats)) => {
+                self.last_transfer = Some(stats);
+                body
+            }
+            Err(e) => {
+                self.status_message = Some(format!("Download failed: {}", e));
+                return;
+            }
+        };
 
-        let mut file_path: PathBuf = PathBuf::from(&self.download_location);
-        file_path.push(format!("{}", file_name));
+        let saved = block_on(async {
+            let mut file = tokio::fs::File::create(&file_path).await?;
+            file.write_all(&body).await
+        });
+        if let Err(e) = saved {
+            self.status_message = Some(format!("Unable to save {:?}: {}", file_path, e));
+            return;
+        }
+        let mut message = format!("Saved {}", file_path.display());
+        if let Some(stats) = &self.last_transfer {
+            message += &format!(", {}", transfer_text(stats, "received"));
+        }
+        self.status_message = Some(message);
+    }
+
+    /// Downloads `download_paths` into the download location as one archive or as a copy of
+    /// their folders
+    fn download_folders(&mut self, mode: FolderDownload) {
+        let paths = std::mem::take(&mut self.download_paths);
+        let location = PathBuf::from(&self.download_location);
+        self.last_transfer = None;
+        let client = self.client.as_mut().unwrap();
+        let result = match mode {
+            FolderDownload::Zip | FolderDownload::TarGz => {
+                let format = if matches!(mode, FolderDownload::Zip) { "zip" } else { "tar.gz" };
+                block_on(client.download_archive(&paths, format, &location))
+                    .map(|(local, size)| format!("Saved {} ({})", local.display(), format_size(size)))
+            }
+            FolderDownload::Tree => block_on(async {
+                let (mut saved, mut failed) = (0, Vec::new());
+                for path in &paths {
+                    let name = path.rsplit('/').next().unwrap_or_default();
+                    let local = location.join(name);
+                    match client.list_dir(path).await {
+                        Ok(_) => {
+                            let (tree_saved, tree_failed) = client.download_tree(path, &local).await?;
+                            saved += tree_saved;
+                            failed.extend(tree_failed);
+                        }
+                        // not a folder
+                        Err(_) => match client.download(path).await {
+                            Ok(contents) => {
+                                tokio::fs::write(&local, contents).await?;
+                                saved += 1;
+                            }
+                            Err(_) => failed.push(path.clone()),
+                        },
+                    }
+                }
+                Ok::<_, Box<dyn std::error::Error>>((saved, failed))
+            })
+            .map(|(saved, failed)| {
+                let mut message = format!("Downloaded {} files into {}", saved, location.display());
+                if !failed.is_empty() {
+                    message.push_str(&format!(", couldn't download /{}", failed.join(", /")));
+                }
+                message
+            }),
+        };
+        self.status_message = Some(match result {
+            Ok(message) => message,
+            Err(e) => format!("Download failed: {}", e),
+        });
+    }
 
-        let mut file = block_on(tokio::fs::File::create(file_path)).unwrap();
-        block_on(file.write_all(&body)).unwrap();
+    /// 'D' downloads the marked entries, or else the selected one. a single file downloads as
+    /// usual, folders and several entries ask how first
+    fn ask_folder_download(&mut self) {
+        let picked: Vec<(String, bool, bool)> = self
+            .server_files
+            .items
+            .iter()
+            .filter(|entry| self.marked.contains(&entry.name))
+            .chain(self.selected_entry().filter(|_| self.marked.is_empty()))
+            .map(|entry| (entry.name.clone(), entry.is_dir, entry.can_read))
+            .collect();
+        match picked.as_slice() {
+            [] => {}
+            [(name, false, true)] => self.ask_download(self.server_path(name)),
+            [(_, false, false)] => self.status_message = Some("You can't download this file".to_string()),
+            _ => {
+                self.download_paths = picked.iter().map(|(name, _, _)| self.server_path(name)).collect();
+                self.current_screen = CurrentScreen::FolderDownload;
+            }
+        }
+    }
+
+    fn handle_folder_download_screen(&mut self, key_event: KeyEvent) -> Result<()> {
+        let mode = match key_event.code {
+            KeyCode::Char('q') => {
+                self.exit();
+                None
+            }
+            KeyCode::Char('z') => Some(FolderDownload::Zip),
+            KeyCode::Char('t') => Some(FolderDownload::TarGz),
+            KeyCode::Char('r') => Some(FolderDownload::Tree),
+            KeyCode::Esc => {
+                self.download_paths.clear();
+                self.current_screen = CurrentScreen::ServerFiles;
+                None
+            }
+            _ => None,
+        };
+        if let Some(mode) = mode {
+            self.folder_download = Some(mode);
+            self.current_screen = CurrentScreen::Configuring;
+            self.currently_configuring = Some(CurrentlyConfiguring::DownloadLocation);
+        }
+        Ok(())
+    }
+
+    /// Asks where to save the file at `path` on the server, the download starts once it is typed
+    fn ask_download(&mut self, path: String) {
+        self.folder_download = None;
+        self.download_path = path;
+        self.current_screen = CurrentScreen::Configuring;
+        self.currently_configuring = Some(CurrentlyConfiguring::DownloadLocation);
+    }
+
+    /// Runs the typed search below the current folder
+    fn search(&mut self, input: &str) -> std::result::Result<(), String> {
+        let mut request = parse_search(input)?;
+        request.dir = self.current_dir.clone();
+        let client = self.client.as_mut().ok_or("No server configured")?;
+        let response = block_on(client.search(&request)).map_err(|e| format!("Search failed: {}", e))?;
+        self.search_query = input.trim().to_string();
+        self.search_truncated = response.truncated;
+        self.search_results = StatefulList::with_items(response.results);
+        Ok(())
+    }
+
+    /// Gives the selected entry exactly the typed tags (separated by spaces or commas)
+    fn tag_selected(&mut self, input: &str) -> std::result::Result<(), String> {
+        let i = self.server_files.state.selected().ok_or("Nothing is selected")?;
+        let entry = &self.server_files.items[i];
+        let path = self.server_path(&entry.name);
+        let mut wanted: Vec<String> = Vec::new();
+        for tag in input.split([' ', ',']).filter(|tag| !tag.is_empty()) {
+            if !wanted.iter().any(|known| known == tag) {
+                wanted.push(tag.to_string());
+            }
+        }
+        let add = wanted.iter().filter(|tag| !entry.tags.contains(tag)).cloned().collect();
+        let remove = entry.tags.iter().filter(|tag| !wanted.contains(tag)).cloned().collect();
+        let client = self.client.as_mut().ok_or("No server configured")?;
+        let labels = block_on(client.set_tags(&path, add, remove)).map_err(|e| format!("Unable to tag: {}", e))?;
+        let entry = &mut self.server_files.items[i];
+        entry.tags = labels.tags;
+        entry.note = labels.note;
+        Ok(())
+    }
+
+    /// Replaces the note of the selected entry, an empty one removes it
+    fn note_selected(&mut self, input: &str) -> std::result::Result<(), String> {
+        let i = self.server_files.state.selected().ok_or("Nothing is selected")?;
+        let path = self.server_path(&self.server_files.items[i].name);
+        let client = self.client.as_mut().ok_or("No server configured")?;
+        let labels = block_on(client.set_note(&path, input)).map_err(|e| format!("Unable to save the note: {}", e))?;
+        let entry = &mut self.server_files.items[i];
+        entry.tags = labels.tags;
+        entry.note = labels.note;
+        Ok(())
+    }
+
+    /// Compares the current folder with the typed local folder, the plan waits for approval
+    fn prepare_sync(&mut self, input: &str) -> std::result::Result<(), String> {
+        let local_dir = input.trim();
+        if local_dir.is_empty() {
+            return Err("Type the local folder to sync with".to_string());
+        }
+        let client = self.client.as_mut().ok_or("No server configured")?;
+        let (sync, plan) = block_on(FolderSync::prepare(client, &PathBuf::from(local_dir), &self.current_dir))
+            .map_err(|e| format!("Unable to compare the folders: {}", e))?;
+        let profile = self.profiles.get_mut(&client.address);
+        profile
+            .sync_folders
+            .insert(self.current_dir.clone(), sync.local_dir.display().to_string());
+        if let Err(e) = self.profiles.save() {
+            eprintln!("Unable to save server profiles: {}", e);
+        }
+        self.sync = Some(sync);
+        self.sync_plan = StatefulList::with_items(plan);
+        Ok(())
+    }
+
+    /// Carries out the approved sync plan and goes back to the listing
+    fn apply_sync(&mut self) {
+        let (Some(sync), Some(client)) = (self.sync.as_mut(), self.client.as_mut()) else {
+            return;
+        };
+        let conflicts = self
+            .sync_plan
+            .items
+            .iter()
+            .filter(|item| item.action == SyncAction::Conflict && !item.skipped)
+            .count();
+        self.status_message = Some(match block_on(sync.apply(client, &self.sync_plan.items)) {
+            Ok(_) if self.sync_plan.items.is_empty() => {
+                format!("'{}' is already in sync", sync.local_dir.display())
+            }
+            Ok(report) => {
+                let mut message = format!("Synced {} files with '{}'", report.done, sync.local_dir.display());
+                if conflicts > 0 {
+                    message.push_str(&format!(", {} conflicts left for next time", conflicts));
+                }
+                if let Some(first) = report.failed.first() {
+                    message.push_str(&format!(", {} failed ({})", report.failed.len(), first));
+                }
+                message
+            }
+            Err(e) => format!("Sync failed: {}", e),
+        });
+        self.sync = None;
+        self.current_screen = CurrentScreen::ServerFiles;
+        self.refresh_server_files();
+    }
+
+    /// Runs the typed full-text search below the current folder
+    fn text_search(&mut self, input: &str) -> std::result::Result<(), String> {
+        let client = self.client.as_mut().ok_or("No server configured")?;
+        let response = block_on(client.text_search(&self.current_dir, input))
+            .map_err(|e| format!("Search failed: {}", e))?;
+        self.search_query = input.trim().to_string();
+        self.search_truncated = response.truncated;
+        self.text_results = StatefulList::with_items(response.results);
+        Ok(())
     }
 
     fn set_server_location(&mut self) {
         // println!("setting server location");
     }
 
+    /// Validates the typed location and connects to it, errors are returned as popup text
+    fn connect_to_server(&mut self, location: &str) -> std::result::Result<(), String> {
+        let address: ServerAddress = location
+            .parse()
+            .map_err(|e| format!("Invalid server location: {}", e))?;
+        let client = block_on(CustomHTTPClient::connect(&address, &mut self.profiles))
+            .map_err(|e| format!("Unable to connect to {}: {}", address, e))?;
+        self.client = Some(client);
+        self.changes = None;
+        Ok(())
+    }
+
     fn get_server_files(&mut self) {
-        let uri = format!("http://{}/getfiles", self.client.as_ref().unwrap().address);
+        let uri = self.client.as_ref().unwrap().address.url("/getfiles");
         let mut req: Request<BoxBody<Bytes, std::io::Error>> = Default::default();
         *req.uri_mut() = uri.parse().unwrap();
+        req.headers_mut()
+            .insert("dir", HeaderValue::from_str(&self.current_dir).unwrap());
         // println!("sending request{:?}", req);
         let response = block_on(self.client.as_mut().unwrap().send_request(req)).unwrap();
+        if response.status() == StatusCode::UNAUTHORIZED {
+            self.require_login();
+            return;
+        }
+        let status = response.status();
         let (_, body) = response.into_parts();
 
         let body = body.collect();
         let body = block_on(body).unwrap().to_bytes();
+        if !status.is_success() {
+            // the folder went away or we lost access, fall back to the root
+            self.status_message = Some(format!(
+                "Unable to list '{}': {}",
+                self.current_dir,
+                String::from_utf8_lossy(&body)
+            ));
+            if !self.current_dir.is_empty() {
+                self.current_dir = String::new();
+                self.get_server_files();
+            }
+            return;
+        }
+
+        let server_files: GetFilesResponse = serrde_json::from_slice(&body).unwrap();
+
+        self.current_dir = server_files.dir;
+        self.dir_can_write = server_files.can_write;
+        self.marked.retain(|name| server_files.files.iter().any(|entry| entry.name == *name));
+        self.server_files = StatefulList::with_items(server_files.files);
+        self.load_usage();
+        if self.changes.is_none() {
+            // older servers don't send changes, the listing then only updates on 'g'
+            self.changes = block_on(self.client.as_ref().unwrap().watch()).ok();
+        }
+    }
+
+    /// Reloads the listing if any change that arrived touches the current folder
+    fn apply_changes(&mut self) {
+        let Some(changes) = &self.changes else {
+            return;
+        };
+        let mut touched = false;
+        loop {
+            match changes.try_recv() {
+                Ok(change) => touched |= self.touches_current_dir(&change),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.changes = None;
+                    break;
+                }
+            }
+        }
+        if touched && matches!(self.current_screen, CurrentScreen::ServerFiles) {
+            self.refresh_server_files();
+        }
+    }
+
+    /// Whether `change` shows in the listing of the current folder, or removes the folder
+    fn touches_current_dir(&self, change: &ChangeEvent) -> bool {
+        let dir = &self.current_dir;
+        let touches = |path: &str| {
+            let parent = path.rfind('/').map_or("", |i| &path[..i]);
+            parent == dir || *dir == path || dir.starts_with(&format!("{}/", path))
+        };
+        touches(&change.path) || change.to.as_deref().is_some_and(touches)
+    }
+
+    /// Lists the current folder again, keeping the selection on the same entry
+    fn refresh_server_files(&mut self) {
+        let selected = self.selected_entry().map(|entry| entry.name.clone());
+        self.get_server_files();
+        if let Some(i) = selected.and_then(|name| self.server_files.items.iter().position(|e| e.name == name)) {
+            self.server_files.state.select(Some(i));
+        }
+    }
 
-        let server_files: Vec<String> = serrde_json::from_slice(&body).unwrap();
+    /// Picks the quota of the current folder that is closest to full, if there is any
+    fn load_usage(&mut self) {
+        let client = self.client.as_mut().unwrap();
+        let quotas = block_on(client.usage(&self.current_dir)).map_or_else(|_| Vec::new(), |usage| usage.quotas);
+        self.usage = quotas
+            .into_iter()
+            .max_by(|a, b| used_ratio(a).total_cmp(&used_ratio(b)));
+    }
+
+    /// Loads the preview of the selected entry, unless it is what the pane shows already
+    fn update_preview(&mut self) {
+        if !self.show_preview || !matches!(self.current_screen, CurrentScreen::ServerFiles) {
+            return;
+        }
+        let Some(entry) = self.selected_entry().cloned() else {
+            self.preview = None;
+            return;
+        };
+        let path = self.server_path(&entry.name);
+        let key = Preview::key(&path, &entry);
+        if self.preview.as_ref().is_some_and(|preview| preview.key == key) {
+            return;
+        }
+        let Some(client) = self.client.as_mut() else {
+            return;
+        };
+        let preview = if entry.is_dir || ArchiveKind::of(&entry.name).is_some() {
+            let listing = block_on(client.list_dir(&path)).map_err(|e| e.to_string());
+            Preview::listing(key, &entry, listing)
+        } else if !entry.can_read {
+            Preview::failed(key, &entry, "You can't read this file")
+        } else if entry.size == 0 {
+            Preview::file(key, &entry, &[], 0)
+        } else {
+            match block_on(client.download_start(&path, PREVIEW_BYTES)) {
+                Ok((start, size)) => Preview::file(key, &entry, &start, size),
+                Err(e) => Preview::failed(key, &entry, &e.to_string()),
+            }
+        };
+        self.preview = Some(preview);
+    }
+
+    fn selected_entry(&self) -> Option<&FileEntry> {
+        self.server_files
+            .state
+            .selected()
+            .and_then(|i| self.server_files.items.get(i))
+    }
+
+    /// Path on the server of `name` inside the current folder
+    fn server_path(&self, name: &str) -> String {
+        if self.current_dir.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}/{}", self.current_dir, name)
+        }
+    }
+
+    fn enter_folder(&mut self, name: &str) {
+        self.marked.clear();
+        self.current_dir = self.server_path(name);
+        self.get_server_files();
+    }
 
-        self.server_files.items = server_files;
-        self.server_files.state.select(Some(0));
-        *self.server_files.state.offset_mut() = 0;
+    /// Browses `dir` on the ServerFiles screen, with the entry called `select` selected
+    fn open_folder(&mut self, dir: String, select: Option<&str>) {
+        self.marked.clear();
+        self.current_dir = dir;
+        self.current_screen = CurrentScreen::ServerFiles;
+        self.get_server_files();
+        if let Some(i) = select.and_then(|name| self.server_files.items.iter().position(|e| e.name == name)) {
+            self.server_files.state.select(Some(i));
+        }
+    }
+
+    fn leave_folder(&mut self) {
+        self.marked.clear();
+        self.current_dir = match self.current_dir.rfind('/') {
+            Some(i) => self.current_dir[..i].to_string(),
+            None => String::new(),
+        };
+        self.get_server_files();
+    }
+
+    fn create_folder(&mut self, name: &str) -> std::result::Result<(), String> {
+        let uri = self.client.as_ref().unwrap().address.url("/addfolder");
+        let body = Full::new(Bytes::from(self.server_path(name)))
+            .map_err(|never| match never {})
+            .boxed();
+        let req = Request::builder()
+            .method("POST")
+            .uri(uri)
+            .body(body)
+            .unwrap();
+        let response = block_on(self.client.as_mut().unwrap().send_request(req))
+            .map_err(|e| e.to_string())?;
+        let status = response.status();
+        let body = block_on(response.into_body().collect()).unwrap().to_bytes();
+        if status.is_success() {
+            Ok(())
+        } else {
+            Err(format!("Unable to create folder: {}", String::from_utf8_lossy(&body)))
+        }
+    }
+
+    /// Sends the user to the login popup, used whenever the server answers 401
+    fn require_login(&mut self) {
+        self.current_screen = CurrentScreen::Configuring;
+        self.currently_configuring = Some(CurrentlyConfiguring::Username);
+        self.input = String::new();
+        self.config_error = Some("Login required".to_string());
+    }
+
+    /// Logs in with the typed credentials and saves the token to the server profile
+    fn login(&mut self, username: &str, password: &str) -> std::result::Result<(), String> {
+        let client = self.client.as_mut().ok_or("No server configured")?;
+        let token = block_on(client.login(username, password))
+            .map_err(|e| format!("Login failed: {}", e))?;
+        self.profiles.get_mut(&client.address).token = Some(token);
+        // follow the changes as the new user
+        self.changes = None;
+        if let Err(e) = self.profiles.save() {
+            return Err(format!("Logged in, but unable to save the token: {}", e));
+        }
+        Ok(())
+    }
+
+    /// Creates a share link for the selected entry and copies it to the clipboard
+    fn create_share(&mut self, password: &str) -> std::result::Result<(), String> {
+        let entry = self.selected_entry().ok_or("Nothing selected")?;
+        let path = self.server_path(&entry.name);
+        let password = Some(password.to_string()).filter(|p| !p.is_empty());
+        let client = self.client.as_mut().ok_or("No server configured")?;
+        let share = block_on(client.create_share(&path, self.share_expiry_secs, password))
+            .map_err(|e| format!("Unable to create share link: {}", e))?;
+        copy_to_clipboard(&share.url);
+        self.share_link = Some(share);
+        Ok(())
+    }
+
+    /// Loads the versions of the selected file for the Versions screen
+    fn open_versions(&mut self) -> std::result::Result<(), String> {
+        let entry = self.selected_entry().ok_or("Nothing selected")?;
+        let path = self.server_path(&entry.name);
+        let can_write = entry.can_write;
+        let client = self.client.as_mut().ok_or("No server configured")?;
+        let response = block_on(client.list_versions(&path))
+            .map_err(|e| format!("Unable to get versions: {}", e))?;
+        if response.versions.is_empty() {
+            return Err(format!("No previous versions of '{}'", path));
+        }
+        self.versions = StatefulList::with_items(response.versions);
+        self.versions_path = response.path;
+        self.versions_can_write = can_write;
+        Ok(())
+    }
+
+    /// Puts the selected version back as the current contents
+    fn restore_version(&mut self) -> std::result::Result<(), String> {
+        if !self.versions_can_write {
+            return Err("You can't change this file".to_string());
+        }
+        let id = self
+            .versions
+            .state
+            .selected()
+            .and_then(|i| self.versions.items.get(i))
+            .map(|version| version.id)
+            .ok_or("Nothing selected")?;
+        let client = self.client.as_mut().ok_or("No server configured")?;
+        block_on(client.restore_version(&self.versions_path, id))
+            .map_err(|e| format!("Unable to restore: {}", e))
+    }
+
+    /// Deletes the selected entry (into the server's trash, if it has one)
+    fn delete_selected(&mut self) -> std::result::Result<String, String> {
+        let entry = self.selected_entry().ok_or("Nothing selected")?;
+        if !entry.can_write {
+            return Err("You can't delete this".to_string());
+        }
+        let path = self.server_path(&entry.name);
+        let client = self.client.as_mut().ok_or("No server configured")?;
+        block_on(client.delete(&path)).map_err(|e| format!("Unable to delete: {}", e))?;
+        Ok(path)
+    }
+
+    /// Unpacks the selected zip or tar file next to it on the server
+    fn extract_selected(&mut self) -> std::result::Result<String, String> {
+        let entry = self.selected_entry().ok_or("Nothing selected")?;
+        if entry.is_dir || ArchiveKind::of(&entry.name).is_none() {
+            return Err("Only zip, tar and tar.gz files can be extracted".to_string());
+        }
+        if !self.dir_can_write {
+            return Err("You can't write to this folder".to_string());
+        }
+        let path = self.server_path(&entry.name);
+        let client = self.client.as_mut().ok_or("No server configured")?;
+        block_on(client.extract(&path)).map_err(|e| format!("Unable to extract '{}': {}", path, e))
+    }
+
+    /// Loads the trash for the Trash screen, keeping the selection where it was
+    fn load_trash(&mut self) -> std::result::Result<(), String> {
+        let client = self.client.as_mut().ok_or("No server configured")?;
+        let response = block_on(client.list_trash()).map_err(|e| format!("Unable to get the trash: {}", e))?;
+        let selected = self.trash.state.selected();
+        self.trash = StatefulList::with_items(response.items);
+        if let Some(i) = selected.filter(|_| !self.trash.items.is_empty()) {
+            self.trash.state.select(Some(i.min(self.trash.items.len() - 1)));
+        }
+        Ok(())
+    }
+
+    fn selected_trash_item(&self) -> Option<&TrashItem> {
+        self.trash.state.selected().and_then(|i| self.trash.items.get(i))
     }
 
     fn exit(&mut self) {
@@ -188,6 +937,12 @@ impl<'a> App<'a> {
             CurrentScreen::Uploading => self.handle_uploading_screen(key_event)?,
             CurrentScreen::Downloading => self.handle_downloading_screen(key_event)?,
             CurrentScreen::Configuring => self.handle_configuring_screen(key_event)?,
+            CurrentScreen::Sharing => self.handle_sharing_screen(key_event)?,
+            CurrentScreen::Versions => self.handle_versions_screen(key_event)?,
+            CurrentScreen::Trash => self.handle_trash_screen(key_event)?,
+            CurrentScreen::Search | CurrentScreen::TextSearch => self.handle_search_screen(key_event)?,
+            CurrentScreen::Sync => self.handle_sync_screen(key_event)?,
+            CurrentScreen::FolderDownload => self.handle_folder_download_screen(key_event)?,
         }
 
         Ok(())
@@ -216,6 +971,7 @@ impl<'a> App<'a> {
         self.download_location = location;
     }
     fn handle_configuring_screen(&mut self, key_event: KeyEvent) -> Result<()> {
+        self.config_error = None;
         match key_event.code {
             KeyCode::Esc => {
                 self.input = String::new();
@@ -226,31 +982,137 @@ impl<'a> App<'a> {
                     match editing {
                         CurrentlyConfiguring::DownloadLocation => {
                             self.change_download_location(self.input.clone());
-                            self.download_file();
                             self.currently_configuring = None;
                             self.input = String::new();
+                            self.download_file();
                             self.current_screen = CurrentScreen::ServerFiles;
                         }
+                        CurrentlyConfiguring::Search => match self.search(&self.input.clone()) {
+                            Ok(_) => {
+                                self.currently_configuring = None;
+                                self.input = String::new();
+                                self.current_screen = CurrentScreen::Search;
+                            }
+                            Err(e) => self.config_error = Some(e),
+                        },
+                        CurrentlyConfiguring::TextSearch => match self.text_search(&self.input.clone()) {
+                            Ok(_) => {
+                                self.currently_configuring = None;
+                                self.input = String::new();
+                                self.current_screen = CurrentScreen::TextSearch;
+                            }
+                            Err(e) => self.config_error = Some(e),
+                        },
+                        CurrentlyConfiguring::SyncFolder => match self.prepare_sync(&self.input.clone()) {
+                            Ok(_) => {
+                                self.currently_configuring = None;
+                                self.input = String::new();
+                                self.current_screen = CurrentScreen::Sync;
+                                // nothing to approve
+                                if self.sync_plan.items.is_empty() {
+                                    self.apply_sync();
+                                }
+                            }
+                            Err(e) => self.config_error = Some(e),
+                        },
+                        CurrentlyConfiguring::Tags | CurrentlyConfiguring::Note => {
+                            let saved = match editing {
+                                CurrentlyConfiguring::Tags => self.tag_selected(&self.input.clone()),
+                                _ => self.note_selected(&self.input.clone()),
+                            };
+                            match saved {
+                                Ok(_) => {
+                                    self.currently_configuring = None;
+                                    self.input = String::new();
+                                    self.current_screen = CurrentScreen::ServerFiles;
+                                }
+                                Err(e) => self.config_error = Some(e),
+                            }
+                        }
+                        CurrentlyConfiguring::ShareExpiry => match parse_duration(&self.input) {
+                            Some(secs) => {
+                                self.share_expiry_secs = secs;
+                                self.input = String::new();
+                                self.currently_configuring = Some(CurrentlyConfiguring::SharePassword);
+                            }
+                            None => {
+                                self.config_error =
+                                    Some("Use a number with s, m, h or d, e.g. 30m or 7d".to_string());
+                            }
+                        },
+                        CurrentlyConfiguring::SharePassword => {
+                            match self.create_share(&self.input.clone()) {
+                                Ok(_) => {
+                                    self.currently_configuring = None;
+                                    self.input = String::new();
+                                    self.current_screen = CurrentScreen::Sharing;
+                                }
+                                Err(e) => {
+                                    self.input = String::new();
+                                    self.currently_configuring = Some(CurrentlyConfiguring::ShareExpiry);
+                                    self.config_error = Some(e);
+                                }
+                            }
+                        }
+                        CurrentlyConfiguring::FolderName => {
+                            match self.create_folder(&self.input.clone()) {
+                                Ok(_) => {
+                                    self.currently_configuring = None;
+                                    self.input = String::new();
+                                    self.current_screen = CurrentScreen::ServerFiles;
+                                    self.get_server_files();
+                                }
+                                Err(e) => {
+                                    self.config_error = Some(e);
+                                }
+                            }
+                        }
                         CurrentlyConfiguring::ServerLocation => {
-                            self.client =
-                                Some(block_on(CustomHTTPClient::new(&self.input)).unwrap());
-                            self.currently_configuring = None;
+                            match self.connect_to_server(&self.input.clone()) {
+                                Ok(_) => {
+                                    self.currently_configuring = None;
+                                    self.input = String::new();
+                                    self.current_screen = CurrentScreen::ServerFiles;
+                                    self.get_server_files();
+                                }
+                                Err(e) => {
+                                    self.config_error = Some(e);
+                                }
+                            }
+                        }
+                        CurrentlyConfiguring::Username => {
+                            self.login_username = self.input.clone();
                             self.input = String::new();
-                            self.get_server_files();
-                            self.current_screen = CurrentScreen::ServerFiles;
+                            self.currently_configuring = Some(CurrentlyConfiguring::Password);
+                        }
+                        CurrentlyConfiguring::Password => {
+                            let username = self.login_username.clone();
+                            match self.login(&username, &self.input.clone()) {
+                                Ok(_) => {
+                                    self.currently_configuring = None;
+                                    self.input = String::new();
+                                    self.current_screen = CurrentScreen::ServerFiles;
+                                    self.get_server_files();
+                                }
+                                Err(e) => {
+                                    self.input = String::new();
+                                    self.currently_configuring = Some(CurrentlyConfiguring::Username);
+                                    self.config_error = Some(e);
+                                }
+                            }
                         }
                         CurrentlyConfiguring::UploadLocation => {
                             let upload_output = self.upload_file(self.input.clone()); // this
                                                                                       // function has an async block
-                            self.get_server_files();
                             match upload_output {
                                 Ok(_) => {
                                     self.currently_configuring = None;
                                     self.input = String::new();
                                     self.current_screen = CurrentScreen::Uploading;
+                                    self.get_server_files();
                                 }
                                 Err(e) => {
-                                    self.input = format!("Error Uploading: {:?}", e);
+                                    self.input = format!("Error Uploading: {}", e);
                                 }
                             }
                         }
@@ -285,13 +1147,287 @@ impl<'a> App<'a> {
         Ok(())
     }
 
+    fn handle_sharing_screen(&mut self, key_event: KeyEvent) -> Result<()> {
+        match key_event.code {
+            KeyCode::Char('q') => self.exit(),
+            KeyCode::Char('y') => {
+                if let Some(share) = &self.share_link {
+                    copy_to_clipboard(&share.url);
+                }
+            }
+            _ => {
+                self.current_screen = CurrentScreen::ServerFiles;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_versions_screen(&mut self, key_event: KeyEvent) -> Result<()> {
+        self.status_message = None;
+        match key_event.code {
+            KeyCode::Char('q') => self.exit(),
+            KeyCode::Up | KeyCode::Char('k') => self.versions.previous(),
+            KeyCode::Down | KeyCode::Char('j') => self.versions.next(),
+            KeyCode::Char('r') | KeyCode::Enter => match self.restore_version() {
+                Ok(_) => {
+                    self.current_screen = CurrentScreen::ServerFiles;
+                    self.get_server_files();
+                    self.status_message = Some(format!("Restored an older version of '{}'", self.versions_path));
+                }
+                Err(e) => self.status_message = Some(e),
+            },
+            KeyCode::Esc => {
+                self.current_screen = CurrentScreen::ServerFiles;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn handle_trash_screen(&mut self, key_event: KeyEvent) -> Result<()> {
+        self.status_message = None;
+        match key_event.code {
+            KeyCode::Char('q') => self.exit(),
+            KeyCode::Up | KeyCode::Char('k') => self.trash.previous(),
+            KeyCode::Down | KeyCode::Char('j') => self.trash.next(),
+            KeyCode::Esc => {
+                self.current_screen = CurrentScreen::ServerFiles;
+                self.get_server_files();
+            }
+            KeyCode::Char('r') | KeyCode::Enter | KeyCode::Char('p') | KeyCode::Char('e') => {
+                self.status_message = Some(self.trash_action(key_event.code).unwrap_or_else(|e| e));
+                if let Err(e) = self.load_trash() {
+                    self.status_message = Some(e);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Keys of the Search and TextSearch screens
+    fn handle_search_screen(&mut self, key_event: KeyEvent) -> Result<()> {
+        self.status_message = None;
+        let in_text = matches!(self.current_screen, CurrentScreen::TextSearch);
+        let selected = if in_text {
+            self.text_results
+                .state
+                .selected()
+                .and_then(|i| self.text_results.items.get(i))
+                .map(|found| (found.path.clone(), false, found.entry.can_read))
+        } else {
+            self.search_results
+                .state
+                .selected()
+                .and_then(|i| self.search_results.items.get(i))
+                .map(|result| (result.path.clone(), result.entry.is_dir, result.entry.can_read))
+        };
+        match key_event.code {
+            KeyCode::Char('q') => self.exit(),
+            KeyCode::Up | KeyCode::Char('k') if in_text => self.text_results.previous(),
+            KeyCode::Down | KeyCode::Char('j') if in_text => self.text_results.next(),
+            KeyCode::Up | KeyCode::Char('k') => self.search_results.previous(),
+            KeyCode::Down | KeyCode::Char('j') => self.search_results.next(),
+            KeyCode::Char('d') | KeyCode::Enter => match selected {
+                Some((path, true, _)) => self.open_folder(path, None),
+                Some((path, false, true)) => self.ask_download(path),
+                Some((_, false, false)) => {
+                    self.status_message = Some("You can't download this file".to_string());
+                }
+                None => {}
+            },
+            // the folder the result is in
+            KeyCode::Char('o') => {
+                if let Some((path, _, _)) = selected {
+                    let (parent, name) = path.rsplit_once('/').unwrap_or(("", &path));
+                    self.open_folder(parent.to_string(), Some(name));
+                }
+            }
+            KeyCode::Char('/') => {
+                self.current_screen = CurrentScreen::Configuring;
+                self.currently_configuring = Some(CurrentlyConfiguring::Search);
+            }
+            KeyCode::Char('f') => {
+                self.current_screen = CurrentScreen::Configuring;
+                self.currently_configuring = Some(CurrentlyConfiguring::TextSearch);
+            }
+            KeyCode::Esc => {
+                self.current_screen = CurrentScreen::ServerFiles;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn handle_sync_screen(&mut self, key_event: KeyEvent) -> Result<()> {
+        self.status_message = None;
+        let selected = self.sync_plan.state.selected();
+        match key_event.code {
+            KeyCode::Char('q') => self.exit(),
+            KeyCode::Up | KeyCode::Char('k') => self.sync_plan.previous(),
+            KeyCode::Down | KeyCode::Char('j') => self.sync_plan.next(),
+            KeyCode::Char('l') | KeyCode::Char('r') => {
+                if let Some(item) = selected.and_then(|i| self.sync_plan.items.get_mut(i)) {
+                    item.keep(key_event.code == KeyCode::Char('l'));
+                }
+            }
+            KeyCode::Char('x') => {
+                if let Some(item) = selected.and_then(|i| self.sync_plan.items.get_mut(i)) {
+                    item.skipped = !item.skipped;
+                }
+            }
+            KeyCode::Char('y') | KeyCode::Enter => self.apply_sync(),
+            KeyCode::Esc => {
+                self.sync = None;
+                self.current_screen = CurrentScreen::ServerFiles;
+                self.status_message = Some("Sync cancelled, nothing was changed".to_string());
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Restores ('r'), purges ('p') the selected trash item or empties the trash ('e')
+    fn trash_action(&mut self, key: KeyCode) -> std::result::Result<String, String> {
+        let selected = self.selected_trash_item().map(|item| (item.id.clone(), item.path.clone()));
+        let client = self.client.as_mut().ok_or("No server configured")?;
+        match (key, selected) {
+            (KeyCode::Char('e'), _) => block_on(client.empty_trash())
+                .map(|_| "Emptied the trash".to_string())
+                .map_err(|e| format!("Unable to empty the trash: {}", e)),
+            (KeyCode::Char('p'), Some((id, path))) => block_on(client.purge_trash(&id))
+                .map(|_| format!("Deleted '{}' for good", path))
+                .map_err(|e| format!("Unable to purge '{}': {}", path, e)),
+            (_, Some((id, path))) => block_on(client.restore_trash(&id))
+                .map(|_| format!("Restored '{}'", path))
+                .map_err(|e| format!("Unable to restore '{}': {}", path, e)),
+            (_, None) => Err("The trash is empty".to_string()),
+        }
+    }
+
     fn handle_server_files_screen(&mut self, key_event: KeyEvent) -> Result<()> {
+        self.status_message = None;
         match key_event.code {
             KeyCode::Char('q') => self.exit(),
             KeyCode::Char('d') | KeyCode::Enter | KeyCode::Char('\n') => {
+                match self.selected_entry().map(|e| (e.name.clone(), e.is_dir, e.can_read)) {
+                    Some((name, true, _)) => self.enter_folder(&name),
+                    // Enter looks inside zip and tar files, 'd' downloads them
+                    Some((name, false, true))
+                        if key_event.code != KeyCode::Char('d') && ArchiveKind::of(&name).is_some() =>
+                    {
+                        self.enter_folder(&name)
+                    }
+                    Some((name, false, true)) => self.ask_download(self.server_path(&name)),
+                    Some((_, false, false)) => {
+                        self.status_message = Some("You can't download this file".to_string());
+                    }
+                    None => {}
+                }
+            }
+            KeyCode::Backspace | KeyCode::Char('h') | KeyCode::Left => {
+                self.leave_folder();
+            }
+            KeyCode::Char(' ') => {
+                if let Some(name) = self.selected_entry().map(|entry| entry.name.clone()) {
+                    match self.marked.iter().position(|marked| *marked == name) {
+                        Some(i) => {
+                            self.marked.remove(i);
+                        }
+                        None => self.marked.push(name),
+                    }
+                    self.server_files.next();
+                }
+            }
+            KeyCode::Char('D') => self.ask_folder_download(),
+            KeyCode::Char('p') => {
+                self.show_preview = !self.show_preview;
+                self.preview = None;
+            }
+            KeyCode::Char('u') | KeyCode::Char('n') if !self.dir_can_write => {
+                self.status_message = Some("You can't write to this folder".to_string());
+            }
+            KeyCode::Char('u') => {
+                self.current_screen = CurrentScreen::Configuring;
+                self.currently_configuring = Some(CurrentlyConfiguring::UploadLocation);
+            }
+            KeyCode::Char('n') => {
+                self.current_screen = CurrentScreen::Configuring;
+                self.currently_configuring = Some(CurrentlyConfiguring::FolderName);
+            }
+            KeyCode::Char('s') => match self.selected_entry() {
+                Some(entry) if entry.can_read => {
+                    self.current_screen = CurrentScreen::Configuring;
+                    self.currently_configuring = Some(CurrentlyConfiguring::ShareExpiry);
+                }
+                Some(_) => {
+                    self.status_message = Some("You can't share this".to_string());
+                }
+                None => {}
+            },
+            KeyCode::Char('x') => match self.delete_selected() {
+                Ok(path) => {
+                    self.get_server_files();
+                    self.status_message = Some(format!("Deleted '{}', press 't' to see the trash", path));
+                }
+                Err(e) => self.status_message = Some(e),
+            },
+            KeyCode::Char('X') => match self.extract_selected() {
+                Ok(message) => {
+                    self.refresh_server_files();
+                    self.status_message = Some(message);
+                }
+                Err(e) => self.status_message = Some(e),
+            },
+            KeyCode::Char('/') => {
+                self.current_screen = CurrentScreen::Configuring;
+                self.currently_configuring = Some(CurrentlyConfiguring::Search);
+            }
+            KeyCode::Char('f') => {
+                self.current_screen = CurrentScreen::Configuring;
+                self.currently_configuring = Some(CurrentlyConfiguring::TextSearch);
+            }
+            KeyCode::Char('y') => {
+                // start from the folder synced with this one last time
+                let client = self.client.as_ref().unwrap();
+                self.input = self
+                    .profiles
+                    .get(&client.address)
+                    .and_then(|profile| profile.sync_folders.get(&self.current_dir).cloned())
+                    .unwrap_or_default();
                 self.current_screen = CurrentScreen::Configuring;
-                self.currently_configuring = Some(CurrentlyConfiguring::DownloadLocation);
+                self.currently_configuring = Some(CurrentlyConfiguring::SyncFolder);
             }
+            KeyCode::Char('e') | KeyCode::Char('a') => match self.selected_entry() {
+                Some(entry) if entry.can_write => {
+                    // start from what it has now
+                    let (input, editing) = match key_event.code {
+                        KeyCode::Char('e') => (entry.tags.join(" "), CurrentlyConfiguring::Tags),
+                        _ => (entry.note.clone().unwrap_or_default(), CurrentlyConfiguring::Note),
+                    };
+                    self.input = input;
+                    self.current_screen = CurrentScreen::Configuring;
+                    self.currently_configuring = Some(editing);
+                }
+                Some(_) => {
+                    self.status_message = Some("You can't label this".to_string());
+                }
+                None => {}
+            },
+            KeyCode::Char('t') => match self.load_trash() {
+                Ok(_) => self.current_screen = CurrentScreen::Trash,
+                Err(e) => self.status_message = Some(e),
+            },
+            KeyCode::Char('v') => match self.selected_entry() {
+                Some(entry) if !entry.is_dir && entry.can_read => match self.open_versions() {
+                    Ok(_) => self.current_screen = CurrentScreen::Versions,
+                    Err(e) => self.status_message = Some(e),
+                },
+                Some(_) => {
+                    self.status_message = Some("Only files you can read have versions".to_string());
+                }
+                None => {}
+            },
             KeyCode::Char('c') => {
                 self.current_screen = CurrentScreen::Configuring;
                 self.currently_configuring = Some(CurrentlyConfiguring::ServerLocation);
@@ -312,20 +1448,31 @@ impl<'a> App<'a> {
     }
 
     fn handle_start_screen(&mut self, key_event: KeyEvent) -> Result<()> {
+        self.status_message = None;
         match key_event.code {
             KeyCode::Char('q') => self.exit(),
+            KeyCode::Char('u') if !self.dir_can_write => {
+                self.status_message = Some("You can't write to this folder".to_string());
+            }
             KeyCode::Char('u') => {
                 self.current_screen = CurrentScreen::Configuring;
                 self.currently_configuring = Some(CurrentlyConfiguring::UploadLocation);
             }
             KeyCode::Char('d') => {
-                self.current_screen = CurrentScreen::Configuring;
-                self.currently_configuring = Some(CurrentlyConfiguring::DownloadLocation);
+                let path = match self.selected_entry() {
+                    Some(entry) if !entry.is_dir => self.server_path(&entry.name),
+                    _ => String::new(),
+                };
+                self.ask_download(path);
             }
             KeyCode::Char('c') => {
                 self.current_screen = CurrentScreen::Configuring;
                 self.currently_configuring = Some(CurrentlyConfiguring::ServerLocation);
             }
+            KeyCode::Char('l') => {
+                self.current_screen = CurrentScreen::Configuring;
+                self.currently_configuring = Some(CurrentlyConfiguring::Username);
+            }
             KeyCode::Char('g') => {
                 self.current_screen = CurrentScreen::ServerFiles;
                 self.get_server_files();
@@ -335,3 +1482,91 @@ impl<'a> App<'a> {
         Ok(())
     }
 }
+
+/// Parses durations like `90s`, `30m`, `12h` or `7d` (a bare number is hours) into seconds
+fn parse_duration(input: &str) -> Option<u64> {
+    let input = input.trim();
+    let (number, unit) = match input.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => input.split_at(i),
+        None => (input, "h"),
+    };
+    let number: u64 = number.parse().ok()?;
+    let multiplier = match unit.trim() {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    number.checked_mul(multiplier).filter(|secs| *secs > 0)
+}
+
+/// Puts `text` on the system clipboard with an OSC 52 escape, most terminals support it
+fn copy_to_clipboard(text: &str) {
+    use base64::Engine;
+    use std::io::Write;
+    let encoded = base64::engine::general_purpose::STANDARD.encode(text);
+    let mut stdout = io::stdout();
+    let _ = write!(stdout, "\x1b]52;c;{}\x07", encoded);
+    let _ = stdout.flush();
+}
+
+/// How full a quota is, the fuller of its byte and file limits
+pub fn used_ratio(usage: &QuotaUsage) -> f64 {
+    let ratio = |used: u64, max: Option<u64>| match max {
+        Some(0) => 1.0,
+        Some(max) => used as f64 / max as f64,
+        None => 0.0,
+    };
+    ratio(usage.used_bytes, usage.max_bytes).max(ratio(usage.used_files, usage.max_files))
+}
+
+/// Turns what was typed in the search popup into a request (without the folder). words are
+/// - `size>10M` / `size<1G` - size bounds, with an optional K, M or G (1024 based) suffix
+/// - `newer:7d` / `older:2h` - modified within / before the duration, see `parse_duration`
+/// - `re:<regex>` - match names with a regex
+/// - anything else is the name, a glob when it has `*`, `?` or `[`, otherwise a substring
+fn parse_search(input: &str) -> std::result::Result<SearchRequest, String> {
+    let now = chrono::Utc::now().timestamp().max(0) as u64;
+    let mut request = SearchRequest::default();
+    let mut words = Vec::new();
+    for word in input.split_whitespace() {
+        if let Some(size) = word.strip_prefix("size>") {
+            request.min_size = Some(parse_size(size).ok_or_else(|| format!("Invalid size '{}'", size))?);
+        } else if let Some(size) = word.strip_prefix("size<") {
+            request.max_size = Some(parse_size(size).ok_or_else(|| format!("Invalid size '{}'", size))?);
+        } else if let Some(age) = word.strip_prefix("newer:") {
+            let secs = parse_duration(age).ok_or_else(|| format!("Invalid duration '{}'", age))?;
+            request.modified_after = Some(now.saturating_sub(secs));
+        } else if let Some(age) = word.strip_prefix("older:") {
+            let secs = parse_duration(age).ok_or_else(|| format!("Invalid duration '{}'", age))?;
+            request.modified_before = Some(now.saturating_sub(secs));
+        } else if let Some(tag) = word.strip_prefix("tag:") {
+            request.tags.push(tag.to_string());
+        } else if let Some(regex) = word.strip_prefix("re:") {
+            request.mode = SearchMode::Regex;
+            words.push(regex);
+        } else {
+            words.push(word);
+        }
+    }
+    request.pattern = words.join(" ");
+    if request.mode != SearchMode::Regex && request.pattern.contains(['*', '?', '[']) {
+        request.mode = SearchMode::Glob;
+    }
+    Ok(request)
+}
+
+/// `512`, `10K`, `1.5M`, `2G` (or with a trailing B) in bytes
+fn parse_size(input: &str) -> Option<u64> {
+    let input = input.to_ascii_uppercase();
+    let input = input.strip_suffix('B').unwrap_or(&input);
+    let (number, multiplier) = match input.chars().last()? {
+        'K' => (&input[..input.len() - 1], 1024.0),
+        'M' => (&input[..input.len() - 1], 1024.0 * 1024.0),
+        'G' => (&input[..input.len() - 1], 1024.0 * 1024.0 * 1024.0),
+        _ => (input, 1.0),
+    };
+    let number: f64 = number.parse().ok().filter(|n: &f64| *n >= 0.0)?;
+    Some((number * multiplier) as u64)
+}
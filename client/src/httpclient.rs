@@ -1,36 +1,158 @@
 use futures::TryStreamExt;
-use http_body_util::{combinators::BoxBody, BodyExt, StreamBody};
+use http_body_util::{combinators::BoxBody, BodyExt, Full, StreamBody};
 use hyper::body::{Body, Bytes, Frame};
 use hyper::client::conn::http1::SendRequest;
-use hyper::{Request, Response};
+use hyper::{Request, Response, StatusCode};
 use hyper_util::rt::TokioIo;
-use map_ok::MapOk;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use async_compression::tokio::bufread::{GzipDecoder, GzipEncoder, ZstdDecoder, ZstdEncoder};
+use sha2::{Digest, Sha256};
 use std::error::Error;
 use std::fmt;
-use std::net::IpAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::net::TcpStream;
-use tokio_util::io::ReaderStream;
+use tokio_rustls::TlsConnector;
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::address::{Scheme, ServerAddress};
+use crate::profiles::Profiles;
+use shared::delta::{self, Signature};
+use shared::encoding::{self, Encoding};
+use shared::{
+    ChangeEvent, GetFilesResponse, LabelsResponse, LoginRequest, LoginResponse, NoteRequest, SearchRequest, SearchResponse, ShareRequest, ShareResponse, TagsRequest, TextSearchRequest,
+    TextSearchResponse, TrashResponse, UsageResponse, VersionsResponse,
+};
 
 pub struct CustomHTTPClient {
-    pub address: String,
+    pub address: ServerAddress,
     pub sender: SendRequest<BoxBody<Bytes, std::io::Error>>,
+    /// sha256 fingerprint of the server certificate, https only
+    pub cert_fingerprint: Option<String>,
+    /// bearer token sent with every request once logged in
+    pub token: Option<String>,
+    /// what uploads get compressed with, learned from the `Accept-Encoding` the server answers with
+    pub upload_encoding: Option<Encoding>,
+}
+
+/// How much a transfer moved over the network compared to the file itself
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TransferStats {
+    /// size of the file
+    pub bytes: u64,
+    /// what was actually sent or received
+    pub wire_bytes: u64,
+    /// `None` when it went uncompressed
+    pub encoding: Option<Encoding>,
+}
+
+impl TransferStats {
+    /// How many times smaller the transfer was than the file
+    pub fn ratio(&self) -> f64 {
+        self.bytes as f64 / self.wire_bytes.max(1) as f64
+    }
 }
 
 impl CustomHTTPClient {
-    /// Creates a new HTTP client
-    pub async fn new(address: &str) -> Result<Self, Box<dyn Error>> {
-        let url = address.parse::<hyper::Uri>()?;
-        let address_clone = address.to_string();
-
-        // Get the host and port
-        let host = url.host().ok_or("Missing host in URL")?;
-        let port = url.port_u16().unwrap_or(80);
-        let address = format!("{}:{}", host, port);
-
-        // Open a TCP connection
-        let stream = TcpStream::connect(address).await?;
+    /// Connects using the saved profile for `address`, pinning the certificate on first use
+    pub async fn connect(
+        address: &ServerAddress,
+        profiles: &mut Profiles,
+    ) -> Result<Self, Box<dyn Error>> {
+        let pinned = profiles
+            .get(address)
+            .and_then(|profile| profile.cert_fingerprint.clone());
+        let mut client = CustomHTTPClient::new(address, pinned.as_deref()).await?;
+        client.token = profiles.get(address).and_then(|profile| profile.token.clone());
+
+        if pinned.is_none() && client.cert_fingerprint.is_some() {
+            profiles.get_mut(address).cert_fingerprint = client.cert_fingerprint.clone();
+            if let Err(e) = profiles.save() {
+                eprintln!("Unable to save server profiles: {}", e);
+            }
+        }
+        Ok(client)
+    }
+
+    /// Creates a new HTTP client, for https `pinned_fingerprint` must match the server certificate
+    pub async fn new(
+        address: &ServerAddress,
+        pinned_fingerprint: Option<&str>,
+    ) -> Result<Self, Box<dyn Error>> {
+        // Resolve the host and connect to the first address that answers
+        let mut last_err: Option<std::io::Error> = None;
+        let mut stream = None;
+        for socket_addr in address.resolve().await? {
+            match TcpStream::connect(socket_addr).await {
+                Ok(s) => {
+                    stream = Some(s);
+                    break;
+                }
+                Err(e) => last_err = Some(e),
+            }
+        }
+        let stream = match (stream, last_err) {
+            (Some(stream), _) => stream,
+            (None, Some(e)) => return Err(format!("could not connect to {}: {}", address, e).into()),
+            (None, None) => return Err(format!("could not connect to {}", address).into()),
+        };
+
+        let (sender, cert_fingerprint) = match address.scheme {
+            Scheme::Http => (CustomHTTPClient::handshake(stream).await?, None),
+            Scheme::Https => {
+                let provider = Arc::new(rustls::crypto::ring::default_provider());
+                let seen = Arc::new(Mutex::new(None));
+                let verifier = PinningVerifier {
+                    pinned: pinned_fingerprint.map(str::to_string),
+                    seen: Arc::clone(&seen),
+                    provider: Arc::clone(&provider),
+                };
+                let mut tls_config = rustls::ClientConfig::builder_with_provider(provider)
+                    .with_safe_default_protocol_versions()?
+                    .dangerous()
+                    .with_custom_certificate_verifier(Arc::new(verifier))
+                    .with_no_client_auth();
+                tls_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+                let server_name = ServerName::try_from(address.host.clone())?;
+                let tls_stream = TlsConnector::from(Arc::new(tls_config))
+                    .connect(server_name, stream)
+                    .await?;
+                let fingerprint = seen.lock().unwrap().take();
+                (CustomHTTPClient::handshake(tls_stream).await?, fingerprint)
+            }
+        };
+
+        Ok(CustomHTTPClient {
+            address: address.clone(),
+            sender,
+            cert_fingerprint,
+            token: None,
+            upload_encoding: None,
+        })
+    }
+
+    /// Opens a new connection to the same server, keeping the pinned certificate and login
+    async fn reconnect(&mut self) -> Result<(), Box<dyn Error>> {
+        let fresh = CustomHTTPClient::new(&self.address, self.cert_fingerprint.as_deref()).await?;
+        self.sender = fresh.sender;
+        Ok(())
+    }
+
+    /// HTTP/1 handshake over an open (plain or TLS) stream
+    async fn handshake<S>(
+        stream: S,
+    ) -> Result<SendRequest<BoxBody<Bytes, std::io::Error>>, hyper::Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
         let io = TokioIo::new(stream);
 
         // Create the Hyper client
@@ -42,11 +164,7 @@ impl CustomHTTPClient {
                 eprintln!("Connection failed: {:?}", err);
             }
         });
-
-        Ok(CustomHTTPClient {
-            address: address_clone,
-            sender,
-        })
+        Ok(sender)
     }
 
     /// Generic function to send a request
@@ -56,40 +174,625 @@ impl CustomHTTPClient {
         BoxBody<Bytes, std::io::Error>: From<B>,
         B::Error: Into<Box<dyn Error + Send + Sync>>,
     {
-        let request = request.map(Into::into);
+        let mut request = request.map(Into::into);
+        if let Some(token) = &self.token {
+            request.headers_mut().insert(
+                hyper::header::AUTHORIZATION,
+                format!("Bearer {}", token).parse()?,
+            );
+        }
+        // the server closes the connection after turning down an upload halfway
+        if self.sender.ready().await.is_err() {
+            self.reconnect().await?;
+        }
         let response: Response<hyper::body::Incoming> = self.sender.send_request(request).await?;
+        if let Some(accept) = response.headers().get(hyper::header::ACCEPT_ENCODING) {
+            self.upload_encoding = encoding::negotiate(accept.to_str().unwrap_or_default());
+        }
         Ok(response)
     }
 
+    /// Uploads a local file into the folder `dir` on the server, compressed when the server
+    /// takes that and the file is worth it
     pub async fn send_file(
         &mut self,
         filepath: PathBuf,
-    ) -> Result<Response<hyper::body::Incoming>, Box<dyn Error>>
+        dir: &str,
+    ) -> Result<(Response<hyper::body::Incoming>, TransferStats), Box<dyn Error>>
 where {
-        let file = File::open(&filepath).await;
-        if file.is_err() {
-            // eprintln!("ERROR: Unable to open file.");
-        }
-        
-        let file_name = filepath.file_stem().unwrap().to_str().unwrap();
-        let file_type = filepath.extension().unwrap().to_str().unwrap();
+        let file = File::open(&filepath).await?;
+        let (file_name, file_type) = name_parts(&filepath)?;
 
-        let uri = format!("http://{}/addfile", self.address);
-        let file: File = file.unwrap();
+        let uri = self.address.url("/addfile");
+        let bytes = file.metadata().await?.len();
+        let name = filepath.file_name().unwrap_or_default().to_string_lossy();
+        let encoding = self.upload_encoding.filter(|_| encoding::worth_compressing(&name, bytes));
 
         // Wrap to a tokio_util::io::ReaderStream
-        let reader_stream = ReaderStream::new(file);
+        let reader: Pin<Box<dyn AsyncRead + Send + Sync>> = match encoding {
+            Some(Encoding::Zstd) => Box::pin(ZstdEncoder::new(BufReader::new(file))),
+            Some(Encoding::Gzip) => Box::pin(GzipEncoder::new(BufReader::new(file))),
+            None => Box::pin(file),
+        };
+        let reader_stream = ReaderStream::new(reader);
+        // count what goes over the wire
+        let sent = Arc::new(AtomicU64::new(0));
+        let counter = sent.clone();
+        let reader_stream = reader_stream.inspect_ok(move |chunk| {
+            counter.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+        });
         // Convert to http_body_util::BoxBody
 
         let stream_body = StreamBody::new(reader_stream.map_ok(Frame::data));
         let boxed_body = stream_body.boxed();
 
         // Send request
-        let request = Request::builder().uri(uri).header("file_name",file_name).header("file_type",file_type).body(boxed_body).unwrap();
+        let mut request = Request::builder().method("POST").uri(uri).header("file_name",file_name).header("file_type",file_type).header("dir", dir);
+        if let Some(encoding) = encoding {
+            request = request.header(hyper::header::CONTENT_ENCODING, encoding.name());
+        }
+        let request = request.body(boxed_body)?;
 
-        let res = self.send_request(request).await.unwrap();
+        let res = self.send_request(request).await?;
         // println!("{:?}",res_bytes);
-        Ok(res)
+        let stats = TransferStats {
+            bytes,
+            wire_bytes: sent.load(Ordering::Relaxed),
+            encoding,
+        };
+        Ok((res, stats))
+    }
+}
+
+impl CustomHTTPClient {
+    /// Offers the server the hash of a local file instead of its contents. True when the server
+    /// already stores that content and created the file from it, nothing needs uploading then
+    pub async fn link_file(&mut self, filepath: &Path, dir: &str) -> Result<bool, Box<dyn Error>> {
+        let sha256 = file_sha256(filepath).await?;
+        let (file_name, file_type) = name_parts(filepath)?;
+        let body = Full::new(Bytes::new()).map_err(|never| match never {}).boxed();
+        let request = Request::builder()
+            .method("POST")
+            .uri(self.address.url("/linkfile"))
+            .header("file_name", file_name)
+            .header("file_type", file_type)
+            .header("dir", dir)
+            .header("content_sha256", sha256)
+            .body(body)?;
+
+        let response = self.send_request(request).await?;
+        let status = response.status();
+        // read the answer so the connection can be reused for the upload
+        response.into_body().collect().await?;
+        Ok(status.is_success())
+    }
+
+    /// Logs in and keeps the token for later requests, the token is returned for saving
+    pub async fn login(&mut self, username: &str, password: &str) -> Result<String, Box<dyn Error>> {
+        let login = LoginRequest {
+            username: username.to_string(),
+            password: password.to_string(),
+        };
+        let body = Full::new(Bytes::from(serde_json::to_vec(&login)?))
+            .map_err(|never| match never {})
+            .boxed();
+        let request = Request::builder()
+            .method("POST")
+            .uri(self.address.url("/login"))
+            .body(body)?;
+
+        let response = self.send_request(request).await?;
+        let status = response.status();
+        let body = response.into_body().collect().await?.to_bytes();
+        if !status.is_success() {
+            return Err(String::from_utf8_lossy(&body).into_owned().into());
+        }
+        let login: LoginResponse = serde_json::from_slice(&body)?;
+        self.token = Some(login.token.clone());
+        Ok(login.token)
+    }
+}
+
+impl CustomHTTPClient {
+    /// Asks the server for a browser link to `path`, valid for `expires_in_secs`
+    pub async fn create_share(
+        &mut self,
+        path: &str,
+        expires_in_secs: u64,
+        password: Option<String>,
+    ) -> Result<ShareResponse, Box<dyn Error>> {
+        let share = ShareRequest {
+            path: path.to_string(),
+            expires_in_secs,
+            password,
+        };
+        let body = Full::new(Bytes::from(serde_json::to_vec(&share)?))
+            .map_err(|never| match never {})
+            .boxed();
+        let request = Request::builder()
+            .method("POST")
+            .uri(self.address.url("/share"))
+            .body(body)?;
+
+        let response = self.send_request(request).await?;
+        let status = response.status();
+        let body = response.into_body().collect().await?.to_bytes();
+        if !status.is_success() {
+            return Err(String::from_utf8_lossy(&body).into_owned().into());
+        }
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// Searches the server recursively, see `SearchRequest` for the filters
+    pub async fn search(&mut self, search: &SearchRequest) -> Result<SearchResponse, Box<dyn Error>> {
+        let body = Full::new(Bytes::from(serde_json::to_vec(search)?))
+            .map_err(|never| match never {})
+            .boxed();
+        let request = Request::builder()
+            .method("POST")
+            .uri(self.address.url("/search"))
+            .body(body)?;
+
+        let response = self.send_request(request).await?;
+        let status = response.status();
+        let body = response.into_body().collect().await?.to_bytes();
+        if !status.is_success() {
+            return Err(String::from_utf8_lossy(&body).into_owned().into());
+        }
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// Searches inside the text files below `dir` for files with every word of `query`
+    pub async fn text_search(&mut self, dir: &str, query: &str) -> Result<TextSearchResponse, Box<dyn Error>> {
+        let search = TextSearchRequest {
+            dir: dir.to_string(),
+            query: query.to_string(),
+        };
+        let body = Full::new(Bytes::from(serde_json::to_vec(&search)?))
+            .map_err(|never| match never {})
+            .boxed();
+        let request = Request::builder()
+            .method("POST")
+            .uri(self.address.url("/textsearch"))
+            .body(body)?;
+
+        let response = self.send_request(request).await?;
+        let status = response.status();
+        let body = response.into_body().collect().await?.to_bytes();
+        if !status.is_success() {
+            return Err(String::from_utf8_lossy(&body).into_owned().into());
+        }
+        Ok(serde_json::from_slice(&body)?)
+    }
+}
+
+impl CustomHTTPClient {
+    /// Adds and removes tags of the file or folder at `path`
+    pub async fn set_tags(
+        &mut self,
+        path: &str,
+        add: Vec<String>,
+        remove: Vec<String>,
+    ) -> Result<LabelsResponse, Box<dyn Error>> {
+        let tags = TagsRequest {
+            path: path.to_string(),
+            add,
+            remove,
+        };
+        self.post_labels("/tags", serde_json::to_vec(&tags)?).await
+    }
+
+    /// Replaces the note of the file or folder at `path`, an empty one removes it
+    pub async fn set_note(&mut self, path: &str, note: &str) -> Result<LabelsResponse, Box<dyn Error>> {
+        let note = NoteRequest {
+            path: path.to_string(),
+            note: note.to_string(),
+        };
+        self.post_labels("/note", serde_json::to_vec(&note)?).await
+    }
+
+    async fn post_labels(&mut self, endpoint: &str, body: Vec<u8>) -> Result<LabelsResponse, Box<dyn Error>> {
+        let body = Full::new(Bytes::from(body)).map_err(|never| match never {}).boxed();
+        let request = Request::builder()
+            .method("POST")
+            .uri(self.address.url(endpoint))
+            .body(body)?;
+
+        let response = self.send_request(request).await?;
+        let status = response.status();
+        let body = response.into_body().collect().await?.to_bytes();
+        if !status.is_success() {
+            return Err(String::from_utf8_lossy(&body).into_owned().into());
+        }
+        Ok(serde_json::from_slice(&body)?)
+    }
+}
+
+impl CustomHTTPClient {
+    /// Previous versions the server kept of the file at `path`, newest first
+    pub async fn list_versions(&mut self, path: &str) -> Result<VersionsResponse, Box<dyn Error>> {
+        let body = self.call("GET", "/versions", &[("file", path)]).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// Makes version `id` the current contents of the file at `path` again
+    pub async fn restore_version(&mut self, path: &str, id: u64) -> Result<(), Box<dyn Error>> {
+        self.call("POST", "/restoreversion", &[("file", path), ("version", &id.to_string())])
+            .await?;
+        Ok(())
+    }
+
+    /// Deletes the file or folder at `path`, into the trash when the server has one
+    pub async fn delete(&mut self, path: &str) -> Result<(), Box<dyn Error>> {
+        self.call("POST", "/delete", &[("file", path)]).await?;
+        Ok(())
+    }
+
+    /// Unpacks the zip, tar or tar.gz file at `path` into the folder it is in, returns what the
+    /// server says it did
+    pub async fn extract(&mut self, path: &str) -> Result<String, Box<dyn Error>> {
+        let body = self.call("POST", "/extract", &[("file", path)]).await?;
+        Ok(String::from_utf8_lossy(&body).into_owned())
+    }
+
+    /// Entries of the folder `dir`
+    pub async fn list_dir(&mut self, dir: &str) -> Result<GetFilesResponse, Box<dyn Error>> {
+        let body = self.call("GET", "/getfiles", &[("dir", dir)]).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// Contents of the file at `path`
+    pub async fn download(&mut self, path: &str) -> Result<Bytes, Box<dyn Error>> {
+        let body = Full::new(Bytes::new()).map_err(|never| match never {}).boxed();
+        let request = Request::builder()
+            .method("GET")
+            .uri(self.address.url("/downloadfile"))
+            .header("file", path)
+            .header(hyper::header::ACCEPT_ENCODING, encoding::SUPPORTED)
+            .body(body)?;
+        let response = self.send_request(request).await?;
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = response.into_body().collect().await?.to_bytes();
+        if !status.is_success() {
+            return Err(String::from_utf8_lossy(&body).into_owned().into());
+        }
+        Ok(decompress(&headers, body).await?.0)
+    }
+
+    /// The first `len` bytes of the file at `path` (all of it when it's shorter) and its whole
+    /// size, for previews
+    pub async fn download_start(&mut self, path: &str, len: u64) -> Result<(Bytes, u64), Box<dyn Error>> {
+        let body = Full::new(Bytes::new()).map_err(|never| match never {}).boxed();
+        let request = Request::builder()
+            .method("GET")
+            .uri(self.address.url("/downloadfile"))
+            .header("file", path)
+            .header(hyper::header::RANGE, format!("bytes=0-{}", len.saturating_sub(1)))
+            .body(body)?;
+        let response = self.send_request(request).await?;
+        let status = response.status();
+        // "bytes 0-16383/52000"
+        let size = response
+            .headers()
+            .get(hyper::header::CONTENT_RANGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|range| range.rsplit('/').next()?.parse::<u64>().ok());
+        let mut body = response.into_body().collect().await?.to_bytes();
+        if !status.is_success() {
+            return Err(String::from_utf8_lossy(&body).into_owned().into());
+        }
+        // a server that doesn't do ranges sends the whole file
+        let size = size.unwrap_or(body.len() as u64);
+        body.truncate(len as usize);
+        Ok((body, size))
+    }
+
+    /// Saves the files and folders at `paths` as one `format` ("zip" or "tar.gz") archive in the
+    /// folder `local_dir`, written as it arrives under the name the server gives it. returns
+    /// where it was saved and its size
+    pub async fn download_archive(&mut self, paths: &[String], format: &str, local_dir: &Path) -> Result<(PathBuf, u64), Box<dyn Error>> {
+        let body = Full::new(Bytes::new()).map_err(|never| match never {}).boxed();
+        let mut request = Request::builder()
+            .method("GET")
+            .uri(self.address.url("/archive"))
+            .header("format", format);
+        for path in paths {
+            request = request.header("file", path.as_str());
+        }
+        let response = self.send_request(request.body(body)?).await?;
+        let status = response.status();
+        let name = response
+            .headers()
+            .get(hyper::header::CONTENT_DISPOSITION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split("filename=\"").nth(1))
+            .and_then(|name| name.split('"').next())
+            .filter(|name| !name.is_empty() && !name.contains(['/', '\\']) && *name != "..")
+            .map_or_else(|| format!("download.{}", format), str::to_string);
+        let mut body = response.into_body();
+        if !status.is_success() {
+            let body = body.collect().await?.to_bytes();
+            return Err(String::from_utf8_lossy(&body).into_owned().into());
+        }
+
+        let local = local_dir.join(name);
+        let mut file = File::create(&local).await?;
+        let mut written = 0;
+        while let Some(frame) = body.frame().await {
+            let frame = match frame {
+                Ok(frame) => frame,
+                Err(e) => {
+                    // a cut off archive is no use
+                    drop(file);
+                    let _ = tokio::fs::remove_file(&local).await;
+                    return Err(format!("The archive was cut off: {}", e).into());
+                }
+            };
+            if let Ok(data) = frame.into_data() {
+                file.write_all(&data).await?;
+                written += data.len() as u64;
+            }
+        }
+        file.flush().await?;
+        Ok((local, written))
+    }
+
+    /// Downloads the folder `remote` into `local` (created when missing) file by file, keeping
+    /// its layout. returns how many files were saved and the ones that couldn't be
+    pub async fn download_tree(&mut self, remote: &str, local: &Path) -> Result<(usize, Vec<String>), Box<dyn Error>> {
+        let mut saved = 0;
+        let mut failed = Vec::new();
+        let mut pending = vec![(remote.to_string(), local.to_path_buf())];
+        while let Some((dir, local_dir)) = pending.pop() {
+            tokio::fs::create_dir_all(&local_dir).await?;
+            for entry in self.list_dir(&dir).await?.files {
+                // names are single path parts, anything else stays out of `local`
+                if entry.name.contains(['/', '\\']) || entry.name == ".." || entry.name == "." {
+                    continue;
+                }
+                let path = if dir.is_empty() { entry.name.clone() } else { format!("{}/{}", dir, entry.name) };
+                let local_path = local_dir.join(&entry.name);
+                if entry.is_dir {
+                    pending.push((path, local_path));
+                } else if !entry.can_read {
+                    failed.push(path);
+                } else {
+                    match self.download(&path).await {
+                        Ok(contents) => {
+                            tokio::fs::write(&local_path, &contents).await?;
+                            saved += 1;
+                        }
+                        Err(_) => failed.push(path),
+                    }
+                }
+            }
+        }
+        Ok((saved, failed))
+    }
+
+    /// Creates the folder at `path`, its parent has to exist
+    pub async fn add_folder(&mut self, path: &str) -> Result<(), Box<dyn Error>> {
+        let body = Full::new(Bytes::from(path.to_string())).map_err(|never| match never {}).boxed();
+        let request = Request::builder().method("POST").uri(self.address.url("/addfolder")).body(body)?;
+        let response = self.send_request(request).await?;
+        let status = response.status();
+        let body = response.into_body().collect().await?.to_bytes();
+        if !status.is_success() {
+            return Err(String::from_utf8_lossy(&body).into_owned().into());
+        }
+        Ok(())
+    }
+
+    /// Quotas covering uploads into `dir`
+    pub async fn usage(&mut self, dir: &str) -> Result<UsageResponse, Box<dyn Error>> {
+        let body = self.call("GET", "/usage", &[("dir", dir)]).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// Deleted items we could restore, newest first
+    pub async fn list_trash(&mut self) -> Result<TrashResponse, Box<dyn Error>> {
+        let body = self.call("GET", "/trash", &[]).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    pub async fn restore_trash(&mut self, id: &str) -> Result<(), Box<dyn Error>> {
+        self.call("POST", "/restoretrash", &[("id", id)]).await?;
+        Ok(())
+    }
+
+    /// Deletes a trash item for good
+    pub async fn purge_trash(&mut self, id: &str) -> Result<(), Box<dyn Error>> {
+        self.call("POST", "/purgetrash", &[("id", id)]).await?;
+        Ok(())
+    }
+
+    /// Purges everything in the trash we could restore
+    pub async fn empty_trash(&mut self) -> Result<(), Box<dyn Error>> {
+        self.call("POST", "/emptytrash", &[]).await?;
+        Ok(())
+    }
+
+    /// Request without a body, the server's message becomes the error when it fails
+    async fn call(&mut self, method: &str, path: &str, headers: &[(&str, &str)]) -> Result<Bytes, Box<dyn Error>> {
+        let body = Full::new(Bytes::new()).map_err(|never| match never {}).boxed();
+        let mut request = Request::builder().method(method).uri(self.address.url(path));
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let response = self.send_request(request.body(body)?).await?;
+        let status = response.status();
+        let body = response.into_body().collect().await?.to_bytes();
+        if !status.is_success() {
+            return Err(String::from_utf8_lossy(&body).into_owned().into());
+        }
+        Ok(body)
+    }
+}
+
+impl CustomHTTPClient {
+    /// Follows `/events` on a connection of its own, the changes arrive on the returned
+    /// channel until the server goes away
+    pub async fn watch(&self) -> Result<std::sync::mpsc::Receiver<ChangeEvent>, Box<dyn Error>> {
+        let mut client = CustomHTTPClient::new(&self.address, self.cert_fingerprint.as_deref()).await?;
+        client.token = self.token.clone();
+        let body = Full::new(Bytes::new()).map_err(|never| match never {}).boxed();
+        let request = Request::builder().method("GET").uri(self.address.url("/events")).body(body)?;
+        let response = client.send_request(request).await?;
+        if !response.status().is_success() {
+            return Err(format!("The server doesn't send changes ({})", response.status()).into());
+        }
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let mut body = response.into_body();
+        tokio::spawn(async move {
+            // the connection lives as long as the client
+            let _client = client;
+            let mut buffer = Vec::new();
+            while let Some(Ok(frame)) = body.frame().await {
+                let Ok(chunk) = frame.into_data() else {
+                    continue;
+                };
+                buffer.extend_from_slice(&chunk);
+                // events end with a blank line
+                while let Some(end) = buffer.windows(2).position(|window| window == b"\n\n") {
+                    let message: Vec<u8> = buffer.drain(..end + 2).collect();
+                    let message = String::from_utf8_lossy(&message);
+                    let data = message.lines().find_map(|line| line.strip_prefix("data: "));
+                    let Some(change) = data.and_then(|data| serde_json::from_str(data).ok()) else {
+                        continue;
+                    };
+                    if sender.send(change).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        Ok(receiver)
+    }
+}
+
+/// files smaller than this are sent whole, a delta wouldn't save much
+const DELTA_MIN_BYTES: u64 = 64 * 1024;
+
+impl CustomHTTPClient {
+    /// Uploads only what changed in a local file the server already has an older copy of.
+    /// `None` when there is no older copy or most of it changed, upload the whole file then,
+    /// otherwise the bytes sent and the size of the file
+    pub async fn send_file_delta(&mut self, filepath: &Path, dir: &str) -> Result<Option<(u64, u64)>, Box<dyn Error>> {
+        if tokio::fs::metadata(filepath).await?.len() < DELTA_MIN_BYTES {
+            return Ok(None);
+        }
+        let name = filepath.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+        let path = if dir.is_empty() { name.to_string() } else { format!("{}/{}", dir, name) };
+        let Some(signature) = self.signature(&path).await? else {
+            return Ok(None);
+        };
+        // the delta goes to a temp file first, to see whether it is worth sending
+        let delta_path = std::env::temp_dir().join(format!("tuifs-delta-{:016x}", rand::random::<u64>()));
+        let result = self.send_delta(filepath, dir, &signature, &delta_path).await;
+        let _ = tokio::fs::remove_file(&delta_path).await;
+        result
+    }
+
+    async fn send_delta(
+        &mut self,
+        filepath: &Path,
+        dir: &str,
+        signature: &Signature,
+        delta_path: &Path,
+    ) -> Result<Option<(u64, u64)>, Box<dyn Error>> {
+        let (file_name, file_type) = name_parts(filepath)?;
+        let file = File::open(filepath).await?;
+        let size = file.metadata().await?.len();
+        let sent = delta::delta(signature, file, File::create(delta_path).await?).await?;
+        if sent * 4 > size * 3 {
+            return Ok(None);
+        }
+
+        let delta_file = File::open(delta_path).await?;
+        let body = StreamBody::new(ReaderStream::new(delta_file).map_ok(Frame::data)).boxed();
+        let request = Request::builder()
+            .method("POST")
+            .uri(self.address.url("/patchfile"))
+            .header("file_name", file_name)
+            .header("file_type", file_type)
+            .header("dir", dir)
+            .header("content_sha256", file_sha256(filepath).await?)
+            .body(body)?;
+        let response = self.send_request(request).await?;
+        let status = response.status();
+        let body = response.into_body().collect().await?.to_bytes();
+        match status {
+            // changed on the server meanwhile
+            StatusCode::CONFLICT => Ok(None),
+            status if status.is_success() => Ok(Some((sent, size))),
+            _ => Err(String::from_utf8_lossy(&body).into_owned().into()),
+        }
+    }
+
+    /// Downloads only what changed in the file at `path` compared to the older copy at
+    /// `local`, which is patched next to it and then replaced. `None` when there is no older
+    /// copy worth it, otherwise the size of the file and the bytes of it that came over
+    pub async fn download_delta(&mut self, path: &str, local: &Path) -> Result<Option<(u64, u64)>, Box<dyn Error>> {
+        let Ok(old) = File::open(local).await else {
+            return Ok(None);
+        };
+        let old_len = old.metadata().await?.len();
+        if old_len < DELTA_MIN_BYTES {
+            return Ok(None);
+        }
+        let signature = Signature::read(old, old_len).await?;
+        let body = Full::new(Bytes::from(signature.encode()))
+            .map_err(|never| match never {})
+            .boxed();
+        let request = Request::builder()
+            .method("POST")
+            .uri(self.address.url("/deltafile"))
+            .header("file", path)
+            .body(body)?;
+        let response = self.send_request(request).await?;
+        let status = response.status();
+        let expected = response
+            .headers()
+            .get("content_sha256")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        if !status.is_success() {
+            let body = response.into_body().collect().await?.to_bytes();
+            return Err(String::from_utf8_lossy(&body).into_owned().into());
+        }
+
+        let name = local.file_name().unwrap_or_default().to_string_lossy();
+        let patched_path = local.with_file_name(format!(".{}.tuifs-delta", name));
+        let delta = StreamReader::new(response.into_body().into_data_stream().map_err(std::io::Error::other));
+        let open_old = |offset| async move {
+            let mut old = File::open(local).await?;
+            old.seek(std::io::SeekFrom::Start(offset)).await?;
+            Ok(old)
+        };
+        let outcome = delta::patch(old_len, open_old, delta, File::create(&patched_path).await?, u64::MAX).await;
+        let patched = match outcome {
+            Ok(patched) if expected.is_none_or(|expected| expected == patched.sha256) => patched,
+            outcome => {
+                let _ = tokio::fs::remove_file(&patched_path).await;
+                return Err(match outcome {
+                    Ok(_) => "The patched file doesn't match the server's".into(),
+                    Err(e) if e.kind() == std::io::ErrorKind::InvalidData => "The server sent a delta that doesn't fit".into(),
+                    Err(e) => e.into(),
+                });
+            }
+        };
+        tokio::fs::rename(&patched_path, local).await?;
+        Ok(Some((patched.len, patched.literal_bytes)))
+    }
+
+    /// Signature of the file at `path` on the server, `None` when there is no such file
+    async fn signature(&mut self, path: &str) -> Result<Option<Signature>, Box<dyn Error>> {
+        match self.call("GET", "/signature", &[("file", path)]).await {
+            Ok(body) => Ok(Some(Signature::decode(&body).ok_or("The server sent an invalid signature")?)),
+            Err(_) => Ok(None),
+        }
     }
 }
 
@@ -97,43 +800,144 @@ impl Default for CustomHTTPClient {
     fn default() -> Self {
         tokio::runtime::Runtime::new()
             .unwrap()
-            .block_on(Self::new("127.0.0.1:3333"))
+            .block_on(Self::new(&ServerAddress::default(), None))
             .expect("Failed to create default HttpClient")
     }
 }
 
 impl fmt::Debug for CustomHTTPClient {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("CustomHTTPClient").finish()
+        f.debug_struct("CustomHTTPClient")
+            .field("address", &self.address)
+            .finish()
     }
 }
 
+/// Trust-on-first-use verification - any certificate is accepted while nothing is pinned,
+/// afterwards only the pinned one is. Handshake signatures are always checked.
 #[derive(Debug)]
-pub struct IpAndPort {
-    pub ip: IpAddr,
-    pub port: u16,
+struct PinningVerifier {
+    pinned: Option<String>,
+    seen: Arc<Mutex<Option<String>>>,
+    provider: Arc<CryptoProvider>,
 }
 
-impl Default for IpAndPort {
-    fn default() -> Self {
-        Self {
-            ip: "127.0.0.1".parse().unwrap(),
-            port: 3333,
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let fingerprint = fingerprint(end_entity);
+        if let Some(pinned) = &self.pinned {
+            if *pinned != fingerprint {
+                return Err(rustls::Error::General(format!(
+                    "server certificate changed! pinned {} but got {}",
+                    pinned, fingerprint
+                )));
+            }
         }
+        *self.seen.lock().unwrap() = Some(fingerprint);
+        Ok(ServerCertVerified::assertion())
     }
-}
 
-impl fmt::Display for IpAndPort {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}:{}", self.ip, self.port)
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
     }
 }
 
-impl IpAndPort {
-    pub fn new(ip_and_port: String) -> Self {
-        let parts: Vec<&str> = ip_and_port.split(':').collect();
-        let ip = parts[0].parse().unwrap();
-        let port = parts[1].parse().unwrap();
-        Self { ip, port }
+/// The `file_name` and `file_type` headers for uploading `path`, the type is empty for files
+/// without an extension
+fn name_parts(path: &Path) -> Result<(&str, &str), Box<dyn Error>> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or("Files need a name")?;
+    Ok(match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, extension),
+        _ => (name, ""),
+    })
+}
+
+/// Body of a response decompressed as its `Content-Encoding` says, with how much smaller it was
+pub async fn decompress(headers: &hyper::HeaderMap, body: Bytes) -> Result<(Bytes, TransferStats), Box<dyn Error>> {
+    let encoding = headers
+        .get(hyper::header::CONTENT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .and_then(Encoding::parse);
+    let wire_bytes = body.len() as u64;
+    let contents = match encoding {
+        Some(Encoding::Zstd) => read_all(ZstdDecoder::new(&body[..])).await?,
+        Some(Encoding::Gzip) => read_all(GzipDecoder::new(&body[..])).await?,
+        None => body,
+    };
+    let stats = TransferStats {
+        bytes: contents.len() as u64,
+        wire_bytes,
+        encoding,
+    };
+    Ok((contents, stats))
+}
+
+async fn read_all(mut reader: impl AsyncRead + Unpin) -> std::io::Result<Bytes> {
+    let mut out = Vec::new();
+    reader.read_to_end(&mut out).await?;
+    Ok(Bytes::from(out))
+}
+
+/// SHA-256 of a local file as lowercase hex
+pub async fn file_sha256(path: &Path) -> std::io::Result<String> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
     }
+    Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// SHA-256 of the DER certificate as colon separated hex, matches what the server prints
+fn fingerprint(cert: &CertificateDer<'_>) -> String {
+    Sha256::digest(cert.as_ref())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(":")
 }
@@ -1,30 +1,52 @@
+mod address;
 mod app;
 mod ui;
 mod httpclient;
+mod preview;
+mod profiles;
 mod statefullist;
+mod sync;
 
+use address::ServerAddress;
 use app::App;
-use httpclient::{CustomHTTPClient, IpAndPort};
+use httpclient::CustomHTTPClient;
+use profiles::Profiles;
 
 use color_eyre::Result;
 use std::env;
 #[tokio::main]
 async fn main() -> Result<()> {
 
-    let mut default_serverlocation = IpAndPort::default();
+    let mut default_serverlocation = ServerAddress::default();
 
     let args : Vec<String> = env::args().collect();
-    dbg!(&args);
     if args.len() > 1 {
-         default_serverlocation = IpAndPort::new(args[1].clone());
+        match args[1].parse::<ServerAddress>() {
+            Ok(address) => default_serverlocation = address,
+            Err(e) => {
+                eprintln!("Invalid server location '{}': {}", args[1], e);
+                std::process::exit(2);
+            }
+        }
     }
     color_eyre::install()?; // Setup error handling
 
-    let client = CustomHTTPClient::new(&default_serverlocation.to_string()).await.unwrap();
+    // start without a client if the server is unreachable, the app will ask for a location
+    let mut profiles = Profiles::load();
+    let client = match CustomHTTPClient::connect(&default_serverlocation, &mut profiles).await {
+        Ok(client) => Some(client),
+        Err(e) => {
+            eprintln!("Unable to connect to {}: {}", default_serverlocation, e);
+            None
+        }
+    };
 
     let mut terminal = ratatui::init();
 
-    let app_result = App::new(Some(client)).run(&mut terminal);
+    // the app waits on requests and file reads with its own block_on, so tokio never gets a
+    // chance to refill the cooperative budget of this task - without this everything it
+    // waits on stalls once the budget is used up
+    let app_result = tokio::task::unconstrained(async { App::new(client, profiles).run(&mut terminal) }).await;
 
     if let Err(err) = ratatui::try_restore() {
         eprintln!(
@@ -36,4 +58,3 @@ async fn main() -> Result<()> {
     app_result?;
     Ok(())
 }
-
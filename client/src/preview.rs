@@ -0,0 +1,269 @@
+/// preview.rs - what the preview pane next to the server files shows
+/// - of a file only the first `PREVIEW_BYTES` are fetched, with a range request. text is
+///   highlighted by syntect (going by the extension, else the first line), images get their
+///   format and size and anything else binary a hex dump
+/// - folders and zip/tar files list their entries
+/// - the lines are styled once when a preview loads, drawing only copies them
+use ratatui::style::{Color, Style};
+use ratatui::text::{Line, Span};
+use shared::{ArchiveKind, FileEntry, GetFilesResponse};
+use std::sync::OnceLock;
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{Theme, ThemeSet};
+use syntect::parsing::{SyntaxReference, SyntaxSet};
+use syntect::util::LinesWithEndings;
+
+use crate::ui::{format_size, format_time};
+
+/// how much of a file is fetched for its preview
+pub const PREVIEW_BYTES: u64 = 16 * 1024;
+/// more lines than this never fit the pane
+const MAX_LINES: usize = 200;
+
+/// server path, size and modification time of what a preview shows, it is loaded again when
+/// one of them changes
+pub type PreviewKey = (String, u64, u64);
+
+#[derive(Debug)]
+pub struct Preview {
+    pub key: PreviewKey,
+    /// what it is in a few words, for the title of the pane
+    pub kind: String,
+    pub lines: Vec<Line<'static>>,
+}
+
+impl Preview {
+    pub fn key(path: &str, entry: &FileEntry) -> PreviewKey {
+        (path.to_string(), entry.size, entry.modified)
+    }
+
+    /// A folder or zip/tar file with its entries, or why they couldn't be listed
+    pub fn listing(key: PreviewKey, entry: &FileEntry, listing: Result<GetFilesResponse, String>) -> Preview {
+        let kind = match ArchiveKind::of(&entry.name).filter(|_| !entry.is_dir) {
+            Some(ArchiveKind::Zip) => "zip archive",
+            Some(ArchiveKind::Tar) => "tar archive",
+            Some(ArchiveKind::TarGz) => "tar.gz archive",
+            None => "folder",
+        };
+        let mut lines = vec![info_line(entry, None)];
+        match listing {
+            Ok(listing) => {
+                let count = listing.files.len();
+                lines.push(Line::from(format!("{} {}", count, if count == 1 { "entry" } else { "entries" })));
+                lines.push(Line::default());
+                lines.extend(listing.files.iter().take(MAX_LINES).map(|file| {
+                    if file.is_dir {
+                        Line::from(Span::styled(format!("{}/", file.name), Style::default().fg(Color::LightCyan)))
+                    } else {
+                        Line::from(vec![
+                            Span::raw(file.name.clone()),
+                            Span::styled(format!("  {}", format_size(file.size)), Style::default().fg(Color::DarkGray)),
+                        ])
+                    }
+                }));
+            }
+            Err(e) => lines.push(Line::from(Span::styled(e, Style::default().fg(Color::Red)))),
+        }
+        Preview {
+            key,
+            kind: kind.to_string(),
+            lines,
+        }
+    }
+
+    /// A file, from the `start` of it. `total` is its whole size
+    pub fn file(key: PreviewKey, entry: &FileEntry, start: &[u8], total: u64) -> Preview {
+        let shown = (start.len() as u64) < total;
+        let mut lines = vec![info_line(entry, shown.then_some(start.len() as u64))];
+        let kind = if let Some((format, size)) = image_info(start) {
+            lines.push(Line::from(match size {
+                Some((width, height)) => format!("{} x {} pixels", width, height),
+                None => "size unknown".to_string(),
+            }));
+            format!("{} image", format)
+        } else if total == 0 {
+            lines.push(Line::from("empty file"));
+            "empty".to_string()
+        } else if let Some(text) = as_text(start) {
+            let syntax = find_syntax(&entry.name, text);
+            lines.push(Line::default());
+            lines.extend(highlight(syntax, text));
+            syntax.name.clone()
+        } else {
+            lines.push(Line::default());
+            lines.extend(hex_dump(start));
+            "binary".to_string()
+        };
+        Preview { key, kind, lines }
+    }
+
+    /// Something that couldn't be previewed, with why
+    pub fn failed(key: PreviewKey, entry: &FileEntry, message: &str) -> Preview {
+        Preview {
+            key,
+            kind: "unavailable".to_string(),
+            lines: vec![
+                info_line(entry, None),
+                Line::from(Span::styled(message.to_string(), Style::default().fg(Color::Red))),
+            ],
+        }
+    }
+}
+
+/// Size and age of an entry, and how much of it is shown when that's not all of it
+fn info_line(entry: &FileEntry, shown: Option<u64>) -> Line<'static> {
+    let mut info = format!("modified {}", format_time(entry.modified));
+    if !entry.is_dir {
+        info = format!("{}, {}", format_size(entry.size), info);
+    }
+    if let Some(shown) = shown {
+        info += &format!(", first {} shown", format_size(shown));
+    }
+    Line::from(Span::styled(info, Style::default().fg(Color::Gray)))
+}
+
+/// `data` as text, `None` when it looks binary. a character cut off at the end is dropped
+fn as_text(data: &[u8]) -> Option<&str> {
+    if data.contains(&0) {
+        return None;
+    }
+    match std::str::from_utf8(data) {
+        Ok(text) => Some(text),
+        Err(e) if e.error_len().is_none() => std::str::from_utf8(&data[..e.valid_up_to()]).ok(),
+        Err(_) => None,
+    }
+}
+
+/// Loads the syntaxes and the theme, they take a moment and the first text preview
+/// shouldn't wait for them
+pub fn load_highlighting() {
+    syntaxes();
+    theme();
+}
+
+fn syntaxes() -> &'static SyntaxSet {
+    static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAXES.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+fn theme() -> &'static Theme {
+    static THEME: OnceLock<Theme> = OnceLock::new();
+    THEME.get_or_init(|| {
+        let mut themes = ThemeSet::load_defaults().themes;
+        themes.remove("base16-ocean.dark").unwrap_or_default()
+    })
+}
+
+/// The syntax of a file called `name` starting with `text`, plain text when nothing fits
+fn find_syntax(name: &str, text: &str) -> &'static SyntaxReference {
+    let syntaxes = syntaxes();
+    let extension = name.rsplit_once('.').map_or(name, |(_, extension)| extension);
+    syntaxes
+        .find_syntax_by_extension(extension)
+        // files like Makefile and Dockerfile
+        .or_else(|| syntaxes.find_syntax_by_extension(name))
+        .or_else(|| syntaxes.find_syntax_by_first_line(text))
+        .unwrap_or_else(|| syntaxes.find_syntax_plain_text())
+}
+
+fn highlight(syntax: &SyntaxReference, text: &str) -> Vec<Line<'static>> {
+    let mut highlighter = HighlightLines::new(syntax, theme());
+    LinesWithEndings::from(text)
+        .take(MAX_LINES)
+        .map(|line| match highlighter.highlight_line(line, syntaxes()) {
+            Ok(pieces) => Line::from(
+                pieces
+                    .into_iter()
+                    .map(|(style, piece)| {
+                        let color = style.foreground;
+                        Span::styled(printable(piece), Style::default().fg(Color::Rgb(color.r, color.g, color.b)))
+                    })
+                    .collect::<Vec<_>>(),
+            ),
+            Err(_) => Line::from(printable(line)),
+        })
+        .collect()
+}
+
+/// `text` without line ends and control characters, tabs become spaces
+fn printable(text: &str) -> String {
+    text.replace('\t', "    ").chars().filter(|c| !c.is_control()).collect()
+}
+
+/// Lines like `00000010  0a 1b ...  |..|`, 16 bytes each
+fn hex_dump(data: &[u8]) -> Vec<Line<'static>> {
+    data.chunks(16)
+        .take(MAX_LINES)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let mut hex = String::new();
+            for (j, byte) in chunk.iter().enumerate() {
+                // an extra space in the middle
+                hex += if j == 8 { "  " } else { " " };
+                hex += &format!("{:02x}", byte);
+            }
+            let ascii: String = chunk
+                .iter()
+                .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+                .collect();
+            Line::from(vec![
+                Span::styled(format!("{:08x} ", i * 16), Style::default().fg(Color::DarkGray)),
+                Span::raw(format!("{:<49}", hex)),
+                Span::styled(format!("  |{}|", ascii), Style::default().fg(Color::Gray)),
+            ])
+        })
+        .collect()
+}
+
+/// Format and, when the start of the file has it, width and height of an image
+fn image_info(data: &[u8]) -> Option<(&'static str, Option<(u32, u32)>)> {
+    let be32 = |at: usize| data.get(at..at + 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]));
+    let le16 = |at: usize| data.get(at..at + 2).map(|b| u16::from_le_bytes([b[0], b[1]]) as u32);
+    let le32 = |at: usize| data.get(at..at + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return Some(("PNG", be32(16).zip(be32(20))));
+    }
+    if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        return Some(("GIF", le16(6).zip(le16(8))));
+    }
+    if data.starts_with(&[0xff, 0xd8, 0xff]) {
+        return Some(("JPEG", jpeg_size(data)));
+    }
+    // the size of the info header tells bitmaps from text starting with "BM"
+    if data.starts_with(b"BM") && le32(14).is_some_and(|header| [12, 40, 52, 56, 108, 124].contains(&header)) {
+        // a negative height is a bitmap stored top down
+        let size = le32(18).zip(le32(22)).map(|(w, h)| ((w as i32).unsigned_abs(), (h as i32).unsigned_abs()));
+        return Some(("BMP", size));
+    }
+    if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+        let le24 = |at: usize| data.get(at..at + 3).map(|b| u32::from_le_bytes([b[0], b[1], b[2], 0]));
+        let size = match data.get(12..16) {
+            Some(b"VP8 ") => le16(26).zip(le16(28)).map(|(w, h)| (w & 0x3fff, h & 0x3fff)),
+            Some(b"VP8L") => le32(21).map(|bits| ((bits & 0x3fff) + 1, ((bits >> 14) & 0x3fff) + 1)),
+            Some(b"VP8X") => le24(24).zip(le24(27)).map(|(w, h)| (w + 1, h + 1)),
+            _ => None,
+        };
+        return Some(("WebP", size));
+    }
+    None
+}
+
+/// Width and height from the first start-of-frame marker, `None` when it's past `data`
+fn jpeg_size(data: &[u8]) -> Option<(u32, u32)> {
+    let be16 = |at: usize| data.get(at..at + 2).map(|b| u16::from_be_bytes([b[0], b[1]]) as u32);
+    let mut at = 2;
+    while *data.get(at)? == 0xff {
+        let marker = *data.get(at + 1)?;
+        // padding
+        if marker == 0xff {
+            at += 1;
+            continue;
+        }
+        // SOF0 to SOF15, without DHT, JPG and DAC that share the range
+        if (0xc0..=0xcf).contains(&marker) && ![0xc4, 0xc8, 0xcc].contains(&marker) {
+            return be16(at + 7).zip(be16(at + 5));
+        }
+        at += 2 + be16(at + 2)? as usize;
+    }
+    None
+}
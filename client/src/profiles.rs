@@ -0,0 +1,84 @@
+/// profiles.rs - per server settings remembered between runs
+/// stored as json in `$XDG_CONFIG_HOME/tuifs/profiles.json` (or `~/.config/tuifs/profiles.json`)
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use crate::address::ServerAddress;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ServerProfile {
+    /// normalised address, `scheme://host:port`
+    pub address: String,
+    /// sha256 fingerprint of the certificate seen on first https connection
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cert_fingerprint: Option<String>,
+    /// bearer token from the last login
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    /// local folder last synced with each server folder
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub sync_folders: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Profiles {
+    #[serde(default)]
+    pub servers: Vec<ServerProfile>,
+}
+
+/// Where the client keeps its settings, `$XDG_CONFIG_HOME/tuifs` (or `~/.config/tuifs`)
+pub fn config_dir() -> Option<PathBuf> {
+    let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_dir.join("tuifs"))
+}
+
+impl Profiles {
+    fn path() -> Option<PathBuf> {
+        Some(config_dir()?.join("profiles.json"))
+    }
+
+    /// Loads saved profiles, a missing or unreadable file gives an empty set
+    pub fn load() -> Self {
+        Profiles::path()
+            .and_then(|path| fs::read(path).ok())
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let path = Profiles::path()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no home directory"))?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let json = serde_json::to_vec_pretty(self)?;
+        fs::write(path, json)
+    }
+
+    pub fn get(&self, address: &ServerAddress) -> Option<&ServerProfile> {
+        let key = address.to_string();
+        self.servers.iter().find(|profile| profile.address == key)
+    }
+
+    /// Profile for `address`, created if this is a new server
+    pub fn get_mut(&mut self, address: &ServerAddress) -> &mut ServerProfile {
+        let key = address.to_string();
+        match self.servers.iter().position(|profile| profile.address == key) {
+            Some(i) => &mut self.servers[i],
+            None => {
+                self.servers.push(ServerProfile {
+                    address: key,
+                    ..Default::default()
+                });
+                self.servers.last_mut().unwrap()
+            }
+        }
+    }
+}
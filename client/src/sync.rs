@@ -0,0 +1,412 @@
+/// sync.rs - keeping a local folder and a folder on the server the same
+/// - `FolderSync::prepare` lists the files on both sides and compares them with what both
+///   sides looked like after the last sync, to tell which side changed what. a change on one
+///   side is copied (or the delete repeated) on the other, a change on both is a conflict the
+///   user settles by picking a side. nothing happens until the plan is applied
+/// - what was synced is remembered per folder pair in `<config dir>/sync/`. sizes and
+///   modification times tell what changed since, sha256s settle it when only the time differs
+/// - only files are synced, folders are created as needed and empty ones are left alone.
+///   deletes on the server go to its trash
+use http_body_util::BodyExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::httpclient::{file_sha256, CustomHTTPClient};
+use crate::profiles;
+
+/// A file as one side has it
+#[derive(Debug, Clone)]
+pub struct FileState {
+    pub size: u64,
+    /// unix timestamp (seconds) of the last modification
+    pub modified: u64,
+    /// only known for files on the server, once its metadata index hashed them
+    pub sha256: Option<String>,
+}
+
+/// A file as both sides had it after the last sync
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SyncedFile {
+    size: u64,
+    local_modified: u64,
+    remote_modified: u64,
+    sha256: String,
+}
+
+/// Everything synced between one local folder and one server folder
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SyncState {
+    server: String,
+    remote_dir: String,
+    local_dir: PathBuf,
+    #[serde(default)]
+    files: BTreeMap<String, SyncedFile>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SyncAction {
+    Upload,
+    Download,
+    DeleteLocal,
+    DeleteRemote,
+    /// changed on both sides, skipped until the user picks one
+    #[default]
+    Conflict,
+}
+
+/// One file that differs between the two sides
+#[derive(Debug, Clone, Default)]
+pub struct PlanItem {
+    /// relative to both folders, `/` separated
+    pub path: String,
+    pub action: SyncAction,
+    /// what changed, like "changed here" or "deleted on the server"
+    pub reason: &'static str,
+    /// left out when the plan is applied
+    pub skipped: bool,
+    pub local: Option<FileState>,
+    pub remote: Option<FileState>,
+}
+
+impl PlanItem {
+    /// Settles the item by making both sides like the local one (`keep_local`) or the remote one
+    pub fn keep(&mut self, keep_local: bool) {
+        self.action = match (keep_local, self.local.is_some(), self.remote.is_some()) {
+            (true, true, _) => SyncAction::Upload,
+            (true, false, _) => SyncAction::DeleteRemote,
+            (false, _, true) => SyncAction::Download,
+            (false, _, false) => SyncAction::DeleteLocal,
+        };
+        self.skipped = false;
+    }
+}
+
+/// What applying a plan did
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    pub done: usize,
+    /// `path: error` for everything that failed
+    pub failed: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct FolderSync {
+    pub local_dir: PathBuf,
+    pub remote_dir: String,
+    state_file: PathBuf,
+    state: SyncState,
+    /// folders known to exist on the server
+    remote_dirs: BTreeSet<String>,
+}
+
+impl FolderSync {
+    /// Compares `local_dir` with the server folder `remote_dir`, the plan is sorted by path
+    pub async fn prepare(
+        client: &mut CustomHTTPClient,
+        local_dir: &Path,
+        remote_dir: &str,
+    ) -> Result<(FolderSync, Vec<PlanItem>), Box<dyn Error>> {
+        fs::create_dir_all(local_dir)?;
+        let local_dir = local_dir.canonicalize()?;
+        let server = client.address.to_string();
+        let state_file = state_file(&server, remote_dir, &local_dir)?;
+        let state = fs::read(&state_file)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_else(|| SyncState {
+                server,
+                remote_dir: remote_dir.to_string(),
+                local_dir: local_dir.clone(),
+                files: BTreeMap::new(),
+            });
+        let mut sync = FolderSync {
+            local_dir,
+            remote_dir: remote_dir.to_string(),
+            state_file,
+            state,
+            remote_dirs: BTreeSet::new(),
+        };
+
+        let local = scan_local(&sync.local_dir)?;
+        let remote = sync.scan_remote(client).await?;
+        let paths: BTreeSet<&String> = local.keys().chain(remote.keys()).chain(sync.state.files.keys()).collect();
+        let mut plan = Vec::new();
+        for path in paths.into_iter().cloned().collect::<Vec<_>>() {
+            let (local, remote) = (local.get(&path), remote.get(&path));
+            if let Some(item) = sync.compare(&path, local, remote).await? {
+                plan.push(item);
+            }
+        }
+        Ok((sync, plan))
+    }
+
+    /// What to do about `path`, `None` when both sides agree
+    async fn compare(
+        &mut self,
+        path: &str,
+        local: Option<&FileState>,
+        remote: Option<&FileState>,
+    ) -> io::Result<Option<PlanItem>> {
+        let base = self.state.files.get(path).cloned();
+        let local_changed = match (local, &base) {
+            (None, None) => false,
+            (Some(local), Some(base)) => {
+                local.size != base.size
+                    || (local.modified != base.local_modified
+                        && file_sha256(&self.local_path(path)).await? != base.sha256)
+            }
+            _ => true,
+        };
+        let remote_changed = match (remote, &base) {
+            (None, None) => false,
+            (Some(remote), Some(base)) => {
+                remote.size != base.size
+                    || (remote.modified != base.remote_modified && remote.sha256.as_ref() != Some(&base.sha256))
+            }
+            _ => true,
+        };
+
+        let (action, reason) = match (local_changed, remote_changed, local, remote) {
+            (false, false, Some(local), Some(remote)) => {
+                // only the times moved, remember them so the files aren't hashed again
+                self.record(path, local, remote).await?;
+                return Ok(None);
+            }
+            (false, false, _, _) => return Ok(None),
+            (true, false, Some(_), _) if base.is_none() => (SyncAction::Upload, "new here"),
+            (true, false, Some(_), _) => (SyncAction::Upload, "changed here"),
+            (true, false, None, _) => (SyncAction::DeleteRemote, "deleted here"),
+            (false, true, _, Some(_)) if base.is_none() => (SyncAction::Download, "new on the server"),
+            (false, true, _, Some(_)) => (SyncAction::Download, "changed on the server"),
+            (false, true, _, None) => (SyncAction::DeleteLocal, "deleted on the server"),
+            (true, true, None, None) => {
+                self.state.files.remove(path);
+                return Ok(None);
+            }
+            (true, true, Some(local), Some(remote)) => {
+                let same = local.size == remote.size
+                    && remote.sha256.is_some()
+                    && remote.sha256 == Some(file_sha256(&self.local_path(path)).await?);
+                if same {
+                    self.record(path, local, remote).await?;
+                    return Ok(None);
+                }
+                let reason = if base.is_none() { "added on both sides" } else { "changed on both sides" };
+                (SyncAction::Conflict, reason)
+            }
+            (true, true, Some(_), None) => (SyncAction::Conflict, "changed here, deleted on the server"),
+            (true, true, None, Some(_)) => (SyncAction::Conflict, "deleted here, changed on the server"),
+        };
+        Ok(Some(PlanItem {
+            path: path.to_string(),
+            action,
+            reason,
+            skipped: false,
+            local: local.cloned(),
+            remote: remote.cloned(),
+        }))
+    }
+
+    /// Carries out the plan (except skipped items and conflicts) and saves what was synced
+    pub async fn apply(&mut self, client: &mut CustomHTTPClient, plan: &[PlanItem]) -> Result<SyncReport, Box<dyn Error>> {
+        let mut report = SyncReport::default();
+        let mut done = Vec::new();
+        for item in plan.iter().filter(|item| !item.skipped) {
+            let result = match item.action {
+                SyncAction::Upload => self.upload(client, &item.path).await,
+                SyncAction::Download => self.download(client, &item.path).await,
+                SyncAction::DeleteLocal => fs::remove_file(self.local_path(&item.path)).map_err(Into::into),
+                SyncAction::DeleteRemote => client.delete(&self.remote_path(&item.path)).await,
+                SyncAction::Conflict => continue,
+            };
+            match result {
+                Ok(_) => done.push(item.path.clone()),
+                Err(e) => report.failed.push(format!("{}: {}", item.path, e)),
+            }
+        }
+        report.done = done.len();
+
+        // the server picks the modification times of uploads, so look again
+        if !done.is_empty() {
+            let local = scan_local(&self.local_dir)?;
+            let remote = self.scan_remote(client).await?;
+            for path in done {
+                match (local.get(&path), remote.get(&path)) {
+                    (Some(local), Some(remote)) => self.record(&path, local, remote).await?,
+                    _ => {
+                        self.state.files.remove(&path);
+                    }
+                }
+            }
+        }
+        self.save()?;
+        Ok(report)
+    }
+
+    /// Remembers that both sides have the same `path`
+    async fn record(&mut self, path: &str, local: &FileState, remote: &FileState) -> io::Result<()> {
+        let sha256 = match self.state.files.get(path) {
+            Some(base) if base.size == local.size && base.local_modified == local.modified => base.sha256.clone(),
+            _ => file_sha256(&self.local_path(path)).await?,
+        };
+        self.state.files.insert(
+            path.to_string(),
+            SyncedFile {
+                size: local.size,
+                local_modified: local.modified,
+                remote_modified: remote.modified,
+                sha256,
+            },
+        );
+        Ok(())
+    }
+
+    fn save(&self) -> io::Result<()> {
+        if let Some(parent) = self.state_file.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&self.state_file, serde_json::to_vec_pretty(&self.state)?)
+    }
+
+    async fn upload(&mut self, client: &mut CustomHTTPClient, path: &str) -> Result<(), Box<dyn Error>> {
+        let remote_path = self.remote_path(path);
+        let dir = remote_path.rsplit_once('/').map_or("", |(dir, _)| dir).to_string();
+        self.make_remote_dir(client, &dir).await?;
+        let local_path = self.local_path(path);
+        // the server may already have this content under another name
+        if client.link_file(&local_path, &dir).await.unwrap_or(false) {
+            return Ok(());
+        }
+        // or an older version of it
+        if client.send_file_delta(&local_path, &dir).await?.is_some() {
+            return Ok(());
+        }
+        let (response, _) = client.send_file(local_path, &dir).await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.into_body().collect().await?.to_bytes();
+            return Err(format!("{} {}", status, String::from_utf8_lossy(&body)).into());
+        }
+        Ok(())
+    }
+
+    async fn download(&self, client: &mut CustomHTTPClient, path: &str) -> Result<(), Box<dyn Error>> {
+        let local_path = self.local_path(path);
+        // an older copy is patched in place
+        if client.download_delta(&self.remote_path(path), &local_path).await?.is_some() {
+            return Ok(());
+        }
+        let contents = client.download(&self.remote_path(path)).await?;
+        if let Some(parent) = local_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(local_path, contents)?;
+        Ok(())
+    }
+
+    /// Creates the server folder `dir` and whatever is missing above it
+    async fn make_remote_dir(&mut self, client: &mut CustomHTTPClient, dir: &str) -> Result<(), Box<dyn Error>> {
+        if dir.is_empty() || self.remote_dirs.contains(dir) {
+            return Ok(());
+        }
+        if let Some((parent, _)) = dir.rsplit_once('/') {
+            Box::pin(self.make_remote_dir(client, parent)).await?;
+        }
+        client.add_folder(dir).await?;
+        self.remote_dirs.insert(dir.to_string());
+        Ok(())
+    }
+
+    /// Files below the server folder, keyed by their path relative to it
+    async fn scan_remote(&mut self, client: &mut CustomHTTPClient) -> Result<BTreeMap<String, FileState>, Box<dyn Error>> {
+        let mut files = BTreeMap::new();
+        self.remote_dirs.clear();
+        let mut pending = vec![self.remote_dir.clone()];
+        while let Some(dir) = pending.pop() {
+            let listing = client.list_dir(&dir).await?;
+            for entry in listing.files {
+                let path = join(&dir, &entry.name);
+                if entry.is_dir {
+                    pending.push(path);
+                } else if entry.can_read {
+                    let relative = path[self.remote_dir.len()..].trim_start_matches('/').to_string();
+                    files.insert(
+                        relative,
+                        FileState {
+                            size: entry.size,
+                            modified: entry.modified,
+                            sha256: entry.sha256,
+                        },
+                    );
+                }
+            }
+            self.remote_dirs.insert(dir);
+        }
+        Ok(files)
+    }
+
+    fn local_path(&self, path: &str) -> PathBuf {
+        self.local_dir.join(path)
+    }
+
+    fn remote_path(&self, path: &str) -> String {
+        join(&self.remote_dir, path)
+    }
+}
+
+/// Files below `root`, keyed by their `/` separated path relative to it. symlinks and names
+/// that aren't utf-8 are left out
+fn scan_local(root: &Path) -> io::Result<BTreeMap<String, FileState>> {
+    let mut files = BTreeMap::new();
+    let mut pending = vec![(root.to_path_buf(), String::new())];
+    while let Some((dir, relative)) = pending.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            let metadata = entry.metadata()?;
+            let path = join(&relative, &name);
+            if metadata.is_dir() {
+                pending.push((entry.path(), path));
+            } else if metadata.is_file() {
+                let modified = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                    .map_or(0, |since| since.as_secs());
+                files.insert(
+                    path,
+                    FileState {
+                        size: metadata.len(),
+                        modified,
+                        sha256: None,
+                    },
+                );
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Where the state of syncing `local_dir` with `remote_dir` on `server` is kept
+fn state_file(server: &str, remote_dir: &str, local_dir: &Path) -> io::Result<PathBuf> {
+    let config_dir = profiles::config_dir().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no home directory"))?;
+    let key = format!("{}\n{}\n{}", server, remote_dir, local_dir.display());
+    let name: String = Sha256::digest(key.as_bytes()).iter().take(8).map(|b| format!("{:02x}", b)).collect();
+    Ok(config_dir.join("sync").join(format!("{}.json", name)))
+}
+
+fn join(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
@@ -1,19 +1,19 @@
 /// ui.rs - all logic for ui display - dependent exclusively on app state
 /// contains
 /// - widget rendering logic
-///
-
-///
 use ratatui::{
     layout::{Constraint, Direction, Layout, Rect},
     style::{Color, Style},
     text::{Line, Span, Text},
-    widgets::{Block, Borders, Clear, List, ListItem, Paragraph, Wrap},
+    widgets::{Block, Borders, Clear, Gauge, List, ListItem, Paragraph, Wrap},
     Frame,
 };
 use std::rc::Rc;
 
-use crate::app::{App, CurrentScreen, CurrentlyConfiguring};
+use crate::app::{self, App, CurrentScreen, CurrentlyConfiguring};
+use crate::httpclient::TransferStats;
+use crate::sync::SyncAction;
+use shared::{ArchiveKind, QuotaUsage};
 
 fn centered_rect(percent_x: u16, percent_y: u16, r: Rect) -> Rect {
     // Cut the given rectangle into three vertical pieces
@@ -46,7 +46,8 @@ pub fn ui(frame: &mut Frame, app: &mut App) {
             [
                 Constraint::Length(3),
                 Constraint::Min(0),
-                Constraint::Length(3),
+                // two lines, the file list hints wrap
+                Constraint::Length(4),
             ]
             .as_ref(),
         )
@@ -58,37 +59,153 @@ pub fn ui(frame: &mut Frame, app: &mut App) {
     ));
     frame.render_widget(title, chunks[0]);
 
+    // the preview pane takes the right half when it's on
     let main_chunks = Layout::default()
         .direction(Direction::Horizontal)
-        .constraints([Constraint::Min(50)].as_ref())
+        .constraints(
+            [
+                Constraint::Min(50),
+                Constraint::Percentage(if app.show_preview { 50 } else { 0 }),
+            ]
+            .as_ref(),
+        )
         .split(chunks[1]);
 
     let main_block = Block::default().title("Server Files").borders(Borders::ALL);
     frame.render_widget(main_block, main_chunks[0]);
+    if app.show_preview {
+        render_preview(frame, app, main_chunks[1]);
+    }
 
     let help_text = vec![match app.current_screen {
         CurrentScreen::Start => Line::from(vec![Span::styled(
-            "Press 'g' to get server files, 'u' to upload files, 'c' to configure server",
+            "Press 'g' to get server files, 'u' to upload files, 'c' to configure server, 'l' to log in",
             Style::default().fg(Color::Yellow),
         )]),
         CurrentScreen::Configuring => Line::from(vec![Span::styled(
             "Press 'Enter' to submit your input, 'Esc' to cancel",
             Style::default().fg(Color::Yellow),
         )]),
-        CurrentScreen::ServerFiles => Line::from(vec![Span::styled(
-            "Press 'd' or 'Enter' to download the current file, 'Esc' to go back",
-            Style::default().fg(Color::Yellow),
-        )]),
+        CurrentScreen::ServerFiles => {
+            let selected = app
+                .server_files
+                .state
+                .selected()
+                .and_then(|i| app.server_files.items.get(i));
+            let can_download = selected.is_some_and(|entry| entry.is_dir || entry.can_read);
+            let can_delete = selected.is_some_and(|entry| entry.can_write);
+            let is_archive = selected.is_some_and(|entry| !entry.is_dir && ArchiveKind::of(&entry.name).is_some());
+            Line::from(vec![
+                action_hint("'d'/'Enter' open or download, ", can_download),
+                action_hint("'space' mark, ", selected.is_some()),
+                action_hint("'D' download folders and marked, ", can_download || !app.marked.is_empty()),
+                action_hint(if app.show_preview { "'p' hide preview, " } else { "'p' preview, " }, true),
+                action_hint("'h' up a folder, ", true),
+                action_hint("'u' upload here, ", app.dir_can_write),
+                action_hint("'n' new folder, ", app.dir_can_write),
+                action_hint("'s' share link, ", can_download),
+                action_hint("'v' versions, ", can_download),
+                action_hint("'x' delete, ", can_delete),
+                action_hint("'X' extract here, ", is_archive && app.dir_can_write),
+                action_hint("'e' tags, ", can_delete),
+                action_hint("'a' note, ", can_delete),
+                action_hint("'t' trash, ", true),
+                action_hint("'/' search, ", true),
+                action_hint("'f' search in files, ", true),
+                action_hint("'y' sync with a local folder, ", true),
+                action_hint("'Esc' to go back", true),
+            ])
+        }
+        CurrentScreen::Sync => {
+            let has_items = !app.sync_plan.items.is_empty();
+            Line::from(vec![
+                action_hint("'y'/'Enter' apply, ", true),
+                action_hint("'l' keep the local file, ", has_items),
+                action_hint("'r' keep the server file, ", has_items),
+                action_hint("'x' skip, ", has_items),
+                action_hint("'Esc' cancel", true),
+            ])
+        }
+        CurrentScreen::Search => {
+            let selected = app
+                .search_results
+                .state
+                .selected()
+                .and_then(|i| app.search_results.items.get(i));
+            let can_download = selected.is_some_and(|result| result.entry.is_dir || result.entry.can_read);
+            Line::from(vec![
+                action_hint("'d'/'Enter' open or download, ", can_download),
+                action_hint("'o' show in its folder, ", selected.is_some()),
+                action_hint("'/' new search, ", true),
+                action_hint("'f' search in files, ", true),
+                action_hint("'Esc' to go back", true),
+            ])
+        }
+        CurrentScreen::TextSearch => {
+            let selected = app
+                .text_results
+                .state
+                .selected()
+                .and_then(|i| app.text_results.items.get(i));
+            Line::from(vec![
+                action_hint("'d'/'Enter' download, ", selected.is_some_and(|found| found.entry.can_read)),
+                action_hint("'o' show in its folder, ", selected.is_some()),
+                action_hint("'/' search names, ", true),
+                action_hint("'f' new search, ", true),
+                action_hint("'Esc' to go back", true),
+            ])
+        }
+        CurrentScreen::Trash => {
+            let has_items = !app.trash.items.is_empty();
+            Line::from(vec![
+                action_hint("'r'/'Enter' restore, ", has_items),
+                action_hint("'p' delete for good, ", has_items),
+                action_hint("'e' empty the trash, ", has_items),
+                action_hint("'Esc' to go back", true),
+            ])
+        }
+        CurrentScreen::FolderDownload => Line::from(vec![
+            action_hint("'z' zip, ", true),
+            action_hint("'t' tar.gz, ", true),
+            action_hint("'r' recreate the folders, ", true),
+            action_hint("'Esc' cancel", true),
+        ]),
+        CurrentScreen::Versions => Line::from(vec![
+            action_hint("'j'/'k' select, ", true),
+            action_hint("'r'/'Enter' restore this version, ", app.versions_can_write),
+            action_hint("'Esc' to go back", true),
+        ]),
         _ => Line::from(vec![Span::styled(
             "Press 'g' to get server files, 'u' to upload files, 'c' to configure server",
             Style::default().fg(Color::Yellow),
         )]),
     }];
 
+    // the result of the last action replaces the hints until the next key press
+    let help_text = match &app.status_message {
+        Some(message) => vec![Line::from(Span::styled(
+            message.clone(),
+            Style::default().fg(Color::Red),
+        ))],
+        None => help_text,
+    };
+
     let help_box = Paragraph::new(Text::from(help_text))
         .style(Style::default().fg(Color::White))
+        .wrap(Wrap { trim: true })
         .block(Block::default().borders(Borders::ALL).title("Help"));
-    frame.render_widget(help_box, chunks[2]);
+    // the quota gauge takes the right end of the status area when the folder has one
+    let status_chunks = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([
+            Constraint::Min(0),
+            Constraint::Length(if app.usage.is_some() { 40 } else { 0 }),
+        ])
+        .split(chunks[2]);
+    frame.render_widget(help_box, status_chunks[0]);
+    if let Some(usage) = &app.usage {
+        render_quota_gauge(frame, usage, status_chunks[1]);
+    }
 
     // basic screen setup done - building app-specific ui now
 
@@ -96,10 +213,26 @@ pub fn ui(frame: &mut Frame, app: &mut App) {
         .server_files
         .items
         .iter()
-        .map(|item| ListItem::new(Span::styled(item, Style::default().fg(Color::White))));
+        .map(|item| {
+            // folders get a trailing slash, read-only entries are greyed out
+            let (name, color) = match (item.is_dir, item.can_write) {
+                (true, true) => (format!("{}/", item.name), Color::LightCyan),
+                (true, false) => (format!("{}/", item.name), Color::Cyan),
+                (false, true) => (item.name.clone(), Color::White),
+                (false, false) => (item.name.clone(), Color::Gray),
+            };
+            let mut spans = Vec::new();
+            if app.marked.contains(&item.name) {
+                spans.push(Span::styled("+ ", Style::default().fg(Color::LightYellow)));
+            }
+            spans.push(Span::styled(name, Style::default().fg(color)));
+            spans.extend(labels(&item.tags, item.note.as_deref()));
+            ListItem::new(Line::from(spans))
+        });
 
+    let files_title = format!("Server Files - /{}", app.current_dir);
     let server_files = List::new(server_files)
-        .block(Block::default().title("Server Files").borders(Borders::ALL))
+        .block(Block::default().title(files_title).borders(Borders::ALL))
         .highlight_style(Style::default().bg(Color::LightBlue).fg(Color::Black))
         .highlight_symbol(">>");
 
@@ -123,36 +256,86 @@ pub fn ui(frame: &mut Frame, app: &mut App) {
             render_config_screen(&app.currently_configuring, frame, app, popup_chunks);
         }
         CurrentScreen::Downloading => {
-            render_download_screen(frame, app, popup_chunks);
+            render_download_screen(frame, popup_chunks);
         }
         CurrentScreen::Uploading => {
             render_upload_screen(frame, app, popup_chunks);
         }
+        CurrentScreen::Sharing => {
+            render_share_screen(frame, app, popup_chunks);
+        }
+        CurrentScreen::Versions => {
+            render_versions_screen(frame, app, popup_chunks);
+        }
+        CurrentScreen::Trash => {
+            render_trash_screen(frame, app);
+        }
+        CurrentScreen::Search => {
+            render_search_screen(frame, app);
+        }
+        CurrentScreen::TextSearch => {
+            render_text_search_screen(frame, app);
+        }
+        CurrentScreen::Sync => {
+            render_sync_screen(frame, app);
+        }
+        CurrentScreen::FolderDownload => {
+            render_folder_download_screen(frame, app, popup_chunks);
+        }
         _ => {}
     };
 }
 
+/// Tags as coloured badges, each tag always gets the same colour, then the note
+fn labels<'a>(tags: &'a [String], note: Option<&'a str>) -> Vec<Span<'a>> {
+    const BADGE_COLORS: [Color; 6] = [
+        Color::LightGreen,
+        Color::LightYellow,
+        Color::LightMagenta,
+        Color::LightRed,
+        Color::LightCyan,
+        Color::LightBlue,
+    ];
+    let mut spans = Vec::new();
+    for tag in tags {
+        let color = BADGE_COLORS[tag.bytes().map(usize::from).sum::<usize>() % BADGE_COLORS.len()];
+        spans.push(Span::raw(" "));
+        spans.push(Span::styled(format!(" {} ", tag), Style::default().fg(Color::Black).bg(color)));
+    }
+    if let Some(note) = note {
+        spans.push(Span::styled(format!("  {}", note), Style::default().fg(Color::DarkGray)));
+    }
+    spans
+}
+
+/// Key hint, greyed out when the user isn't allowed to do it
+fn action_hint(text: &str, allowed: bool) -> Span<'_> {
+    let color = if allowed { Color::Yellow } else { Color::DarkGray };
+    Span::styled(text, Style::default().fg(color))
+}
+
 fn render_start_screen(frame: &mut Frame, app: &App, popup_chunks: Rc<[Rect]>) {
     let popup = Block::default()
         .title("Please Choose An Option:")
         .borders(Borders::ALL);
     let serverlocation = &app.client;
     let serverlocation = match serverlocation {
-        Some(client) => client.address.clone(),
+        Some(client) => client.address.to_string(),
         None => "No Server Configured".to_string(),
     };
-    let popup_text = vec![
+    let mut popup_text = vec![
         Line::from(vec![Span::raw("1. Download/View Server Files (g)")]),
+        Line::from(vec![action_hint(" 2. Upload Files (u)", app.dir_can_write)]),
         Line::from(vec![Span::styled(
-            " 2. Upload Files (u)",
+            " 3. Configure Server (c)",
             Style::default().fg(Color::Yellow),
         )]),
         Line::from(vec![Span::styled(
-            " 3. Configure Server (c)",
+            " 4. Login (l)",
             Style::default().fg(Color::Yellow),
         )]),
         Line::from(vec![Span::styled(
-            " 4. Exit (q)",
+            " 5. Exit (q)",
             Style::default().fg(Color::Red),
         )]),
         Line::from(vec![Span::styled(
@@ -160,6 +343,12 @@ fn render_start_screen(frame: &mut Frame, app: &App, popup_chunks: Rc<[Rect]>) {
             Style::default().fg(Color::White),
         )]),
     ];
+    if let Some(fingerprint) = app.client.as_ref().and_then(|c| c.cert_fingerprint.as_ref()) {
+        popup_text.push(Line::from(vec![Span::styled(
+            format!("Pinned Certificate (sha256): {}", fingerprint),
+            Style::default().fg(Color::Green),
+        )]));
+    }
     let input: Paragraph = Paragraph::new(Text::from(popup_text))
         .style(Style::default().fg(Color::White))
         .block(popup);
@@ -173,43 +362,58 @@ fn render_config_screen(
     popup_chunks: Rc<[Rect]>,
 ) {
     if let Some(current_config) = config {
-        match current_config {
-            CurrentlyConfiguring::DownloadLocation => {
-                let popup = Block::default()
-                    .title("Please Enter Where You'd Like Downloaded FilesTo Go:")
-                    .borders(Borders::ALL);
-                let popup_input_window = Paragraph::new(Span::raw(app.input.clone()))
-                    .style(Style::default().fg(Color::White))
-                    .block(popup)
-                    .wrap(Wrap { trim: true });
-                frame.render_widget(popup_input_window, popup_chunks[0]);
-            }
-            CurrentlyConfiguring::ServerLocation => {
-                let popup = Block::default()
-                    .title("Please Enter Server Location:")
-                    .borders(Borders::ALL);
-                let popup_input_window = Paragraph::new(Span::raw(app.input.clone()))
-                    .style(Style::default().fg(Color::White))
-                    .block(popup)
-                    .wrap(Wrap { trim: true });
-                frame.render_widget(popup_input_window, popup_chunks[0]);
-            }
-            CurrentlyConfiguring::UploadLocation => {
-                let popup = Block::default()
-                    .title("Please Enter The File Path Of What You're Uploading:")
-                    .borders(Borders::ALL);
-
-                let popup_input_window = Paragraph::new(Span::raw(app.input.clone()))
-                    .style(Style::default().fg(Color::White))
-                    .block(popup)
-                    .wrap(Wrap { trim: true });
-                frame.render_widget(popup_input_window, popup_chunks[0]);
-            }
+        let (title, input) = match current_config {
+            CurrentlyConfiguring::DownloadLocation => (
+                "Please Enter Where You'd Like Downloaded FilesTo Go:",
+                app.input.clone(),
+            ),
+            CurrentlyConfiguring::ServerLocation => (
+                "Please Enter Server Location (host[:port], http(s)://host[:port], [ipv6]:port):",
+                app.input.clone(),
+            ),
+            CurrentlyConfiguring::UploadLocation => (
+                "Please Enter The File Path Of What You're Uploading:",
+                app.input.clone(),
+            ),
+            CurrentlyConfiguring::FolderName => ("New Folder Name:", app.input.clone()),
+            CurrentlyConfiguring::Tags => ("Tags (separated by spaces):", app.input.clone()),
+            CurrentlyConfiguring::Note => ("Note (empty removes it):", app.input.clone()),
+            CurrentlyConfiguring::ShareExpiry => (
+                "Share Link - Valid For (e.g. 30m, 12h, 7d):",
+                app.input.clone(),
+            ),
+            CurrentlyConfiguring::SharePassword => (
+                "Share Link - Password (leave empty for none):",
+                "*".repeat(app.input.chars().count()),
+            ),
+            CurrentlyConfiguring::Search => (
+                "Search (name, *.glob, re:regex, size>10M, size<1G, newer:7d, older:1d, tag:name):",
+                app.input.clone(),
+            ),
+            CurrentlyConfiguring::TextSearch => ("Search Inside Text Files (words):", app.input.clone()),
+            CurrentlyConfiguring::SyncFolder => ("Local Folder To Sync This Folder With:", app.input.clone()),
+            CurrentlyConfiguring::Username => ("Login - Username:", app.input.clone()),
+            // never echo the password
+            CurrentlyConfiguring::Password => ("Login - Password:", "*".repeat(app.input.chars().count())),
+        };
+        let popup = Block::default().title(title).borders(Borders::ALL);
+
+        let mut popup_text = vec![Line::from(Span::raw(input))];
+        if let Some(error) = &app.config_error {
+            popup_text.push(Line::from(Span::styled(
+                error.clone(),
+                Style::default().fg(Color::Red),
+            )));
         }
+        let popup_input_window = Paragraph::new(Text::from(popup_text))
+            .style(Style::default().fg(Color::White))
+            .block(popup)
+            .wrap(Wrap { trim: true });
+        frame.render_widget(popup_input_window, popup_chunks[0]);
     }
 }
 
-fn render_download_screen(frame: &mut Frame, app: &App, popup_chunks: Rc<[Rect]>) {
+fn render_download_screen(frame: &mut Frame, popup_chunks: Rc<[Rect]>) {
     let popup = Block::default()
         .title("Downloading Files")
         .borders(Borders::ALL);
@@ -225,9 +429,335 @@ fn render_upload_screen(frame: &mut Frame, app: &App, popup_chunks: Rc<[Rect]>)
     let popup = Block::default()
         .title("Uploaded Files")
         .borders(Borders::ALL);
-    let upload_text = vec![Line::from(vec![Span::raw("Your file has been successfully uploaded!")]), Line::from(vec![Span::raw("Press 'g' to get server files, 'u' to upload files, 'c' to configure server")])];
+    let mut upload_text = vec![Line::from(vec![Span::raw("Your file has been successfully uploaded!")])];
+    upload_text.extend(transfer_line(app.last_transfer.as_ref(), "sent"));
+    upload_text.push(Line::from(vec![Span::raw("Press 'g' to get server files, 'u' to upload files, 'c' to configure server")]));
     let popup_text_window = Paragraph::new(Text::from(upload_text))
         .wrap(Wrap { trim: true })
         .block(popup);
     frame.render_widget(popup_text_window, popup_chunks[0]);
 }
+
+/// How much of the file went over the network, `moved` is "sent" or "received"
+fn transfer_line(stats: Option<&TransferStats>, moved: &str) -> Option<Line<'static>> {
+    let text = transfer_text(stats?, moved);
+    Some(Line::from(Span::styled(text, Style::default().fg(Color::DarkGray))))
+}
+
+/// How many bytes went over the network for a transfer, and how they were compressed
+pub fn transfer_text(stats: &TransferStats, moved: &str) -> String {
+    match stats.encoding {
+        Some(encoding) => format!(
+            "{} {} as {} with {} ({:.1}x smaller)",
+            format_size(stats.bytes),
+            moved,
+            format_size(stats.wire_bytes),
+            encoding,
+            stats.ratio()
+        ),
+        None => format!("{} {} uncompressed", format_size(stats.bytes), moved),
+    }
+}
+
+fn render_folder_download_screen(frame: &mut Frame, app: &App, popup_chunks: Rc<[Rect]>) {
+    let popup = Block::default()
+        .title(format!("Download {} from /{}", app.download_paths.len(), app.current_dir))
+        .borders(Borders::ALL);
+    let mut popup_text: Vec<Line> = app
+        .download_paths
+        .iter()
+        .map(|path| Line::from(Span::styled(format!("/{}", path), Style::default().fg(Color::LightCyan))))
+        .collect();
+    popup_text.push(Line::from(""));
+    popup_text.push(Line::from("'z' as one zip archive"));
+    popup_text.push(Line::from("'t' as one tar.gz archive"));
+    popup_text.push(Line::from("'r' file by file, recreating the folders"));
+    frame.render_widget(Clear, popup_chunks[0]);
+    let popup_text_window = Paragraph::new(Text::from(popup_text))
+        .wrap(Wrap { trim: true })
+        .block(popup);
+    frame.render_widget(popup_text_window, popup_chunks[0]);
+}
+
+fn render_share_screen(frame: &mut Frame, app: &App, popup_chunks: Rc<[Rect]>) {
+    let popup = Block::default()
+        .title("Share Link Created")
+        .borders(Borders::ALL);
+    let mut share_text = Vec::new();
+    if let Some(share) = &app.share_link {
+        let expires = chrono::DateTime::from_timestamp(share.expires_at as i64, 0)
+            .map(|t| t.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_default();
+        share_text.push(Line::from(vec![Span::styled(
+            share.url.clone(),
+            Style::default().fg(Color::LightBlue),
+        )]));
+        share_text.push(Line::from(vec![Span::raw(format!("Expires: {}", expires))]));
+    }
+    share_text.push(Line::from(vec![Span::raw(
+        "Copied to your clipboard. Press 'y' to copy again, any other key to go back",
+    )]));
+    let popup_text_window = Paragraph::new(Text::from(share_text))
+        .wrap(Wrap { trim: false })
+        .block(popup);
+    frame.render_widget(popup_text_window, popup_chunks[0]);
+}
+
+fn render_versions_screen(frame: &mut Frame, app: &mut App, popup_chunks: Rc<[Rect]>) {
+    let versions = app.versions.items.iter().map(|version| {
+        // the popup is narrow, keep columns tight
+        let uploader = version.uploader.as_deref().unwrap_or("-");
+        ListItem::new(Line::from(vec![
+            Span::raw(format!("{} ", format_time(version.modified))),
+            Span::styled(
+                format!("{:>9} ", format_size(version.size)),
+                Style::default().fg(Color::LightCyan),
+            ),
+            Span::styled(uploader.to_string(), Style::default().fg(Color::Gray)),
+        ]))
+    });
+    let title = format!("Versions of /{}", app.versions_path);
+    let versions = List::new(versions)
+        .block(Block::default().title(title).borders(Borders::ALL))
+        .highlight_style(Style::default().bg(Color::LightBlue).fg(Color::Black))
+        .highlight_symbol(">>");
+    frame.render_widget(Clear, popup_chunks[0]);
+    frame.render_stateful_widget(versions, popup_chunks[0], &mut app.versions.state);
+}
+
+fn render_trash_screen(frame: &mut Frame, app: &mut App) {
+    // wider than the other popups, paths can be long
+    let area = centered_rect(80, 60, frame.area());
+    let items: Vec<ListItem> = app
+        .trash
+        .items
+        .iter()
+        .map(|item| {
+            let path = if item.is_dir {
+                format!("/{}/", item.path)
+            } else {
+                format!("/{}", item.path)
+            };
+            let mut spans = vec![
+                Span::raw(format!("{} ", format_time(item.deleted_at))),
+                Span::styled(
+                    format!("{:>9} ", format_size(item.size)),
+                    Style::default().fg(Color::LightCyan),
+                ),
+                Span::styled(path, Style::default().fg(if item.is_dir { Color::LightCyan } else { Color::White })),
+            ];
+            if let Some(deleted_by) = &item.deleted_by {
+                spans.push(Span::styled(format!("  by {}", deleted_by), Style::default().fg(Color::Gray)));
+            }
+            spans.push(Span::styled(
+                format!("  purged {}", format_time(item.purge_at)),
+                Style::default().fg(Color::DarkGray),
+            ));
+            ListItem::new(Line::from(spans))
+        })
+        .collect();
+    let title = if items.is_empty() {
+        "Trash - empty".to_string()
+    } else {
+        format!("Trash - {} items", items.len())
+    };
+    let trash = List::new(items)
+        .block(Block::default().title(title).borders(Borders::ALL))
+        .highlight_style(Style::default().bg(Color::LightBlue).fg(Color::Black))
+        .highlight_symbol(">>");
+    frame.render_widget(Clear, area);
+    frame.render_stateful_widget(trash, area, &mut app.trash.state);
+}
+
+fn render_search_screen(frame: &mut Frame, app: &mut App) {
+    let area = centered_rect(80, 60, frame.area());
+    let items: Vec<ListItem> = app
+        .search_results
+        .items
+        .iter()
+        .map(|result| {
+            let (path, size, color) = match (result.entry.is_dir, result.entry.can_read) {
+                (true, _) => (format!("/{}/", result.path), String::new(), Color::LightCyan),
+                (false, true) => (format!("/{}", result.path), format_size(result.entry.size), Color::White),
+                (false, false) => (format!("/{}", result.path), format_size(result.entry.size), Color::Gray),
+            };
+            let mut spans = vec![
+                Span::raw(format!("{} ", format_time(result.entry.modified))),
+                Span::styled(format!("{:>9} ", size), Style::default().fg(Color::LightCyan)),
+                Span::styled(path, Style::default().fg(color)),
+            ];
+            spans.extend(labels(&result.entry.tags, result.entry.note.as_deref()));
+            ListItem::new(Line::from(spans))
+        })
+        .collect();
+    let count = match (items.len(), app.search_truncated) {
+        (0, _) => "nothing found".to_string(),
+        (n, true) => format!("first {} results", n),
+        (1, false) => "1 result".to_string(),
+        (n, false) => format!("{} results", n),
+    };
+    let title = format!("Search '{}' in /{} - {}", app.search_query, app.current_dir, count);
+    let results = List::new(items)
+        .block(Block::default().title(title).borders(Borders::ALL))
+        .highlight_style(Style::default().bg(Color::LightBlue).fg(Color::Black))
+        .highlight_symbol(">>");
+    frame.render_widget(Clear, area);
+    frame.render_stateful_widget(results, area, &mut app.search_results.state);
+}
+
+fn render_text_search_screen(frame: &mut Frame, app: &mut App) {
+    let area = centered_rect(80, 60, frame.area());
+    let highlight = Style::default().fg(Color::Black).bg(Color::Yellow);
+    let items: Vec<ListItem> = app
+        .text_results
+        .items
+        .iter()
+        .map(|found| {
+            let mut heading = vec![
+                Span::styled(format!("/{}", found.path), Style::default().fg(Color::White)),
+                Span::styled(
+                    format!("  {}  {}", format_size(found.entry.size), format_time(found.entry.modified)),
+                    Style::default().fg(Color::DarkGray),
+                ),
+            ];
+            heading.extend(labels(&found.entry.tags, found.entry.note.as_deref()));
+            let mut lines = vec![Line::from(heading)];
+            // each snippet on its own line, the matched words highlighted
+            for snippet in &found.snippets {
+                let mut spans = vec![Span::styled(
+                    format!("  {:>5}: ", snippet.line),
+                    Style::default().fg(Color::DarkGray),
+                )];
+                let mut at = 0;
+                for &(start, end) in &snippet.highlights {
+                    let (Some(before), Some(word)) = (snippet.text.get(at..start), snippet.text.get(start..end)) else {
+                        break;
+                    };
+                    spans.push(Span::styled(before.to_string(), Style::default().fg(Color::Gray)));
+                    spans.push(Span::styled(word.to_string(), highlight));
+                    at = end;
+                }
+                spans.push(Span::styled(
+                    snippet.text.get(at..).unwrap_or_default().to_string(),
+                    Style::default().fg(Color::Gray),
+                ));
+                lines.push(Line::from(spans));
+            }
+            ListItem::new(lines)
+        })
+        .collect();
+    let count = match (items.len(), app.search_truncated) {
+        (0, _) => "nothing found".to_string(),
+        (n, true) => format!("best {} files", n),
+        (1, false) => "1 file".to_string(),
+        (n, false) => format!("{} files", n),
+    };
+    let title = format!("Files in /{} containing '{}' - {}", app.current_dir, app.search_query, count);
+    let results = List::new(items)
+        .block(Block::default().title(title).borders(Borders::ALL))
+        .highlight_style(Style::default().bg(Color::DarkGray))
+        .highlight_symbol(">>");
+    frame.render_widget(Clear, area);
+    frame.render_stateful_widget(results, area, &mut app.text_results.state);
+}
+
+fn render_sync_screen(frame: &mut Frame, app: &mut App) {
+    let area = centered_rect(80, 60, frame.area());
+    let items: Vec<ListItem> = app
+        .sync_plan
+        .items
+        .iter()
+        .map(|item| {
+            let (action, color) = match item.action {
+                SyncAction::Upload => ("upload", Color::LightGreen),
+                SyncAction::Download => ("download", Color::LightCyan),
+                SyncAction::DeleteLocal => ("delete here", Color::LightRed),
+                SyncAction::DeleteRemote => ("delete server", Color::LightRed),
+                SyncAction::Conflict => ("conflict", Color::LightYellow),
+            };
+            let (action, color) = if item.skipped { ("skip", Color::DarkGray) } else { (action, color) };
+            // the size of the file being copied or deleted
+            let (first, second) = match item.action {
+                SyncAction::Download | SyncAction::DeleteRemote => (&item.remote, &item.local),
+                _ => (&item.local, &item.remote),
+            };
+            let size = first.as_ref().or(second.as_ref()).map_or(String::new(), |file| format_size(file.size));
+            ListItem::new(Line::from(vec![
+                Span::styled(format!("{:<14}", action), Style::default().fg(color)),
+                Span::styled(format!("{:>9} ", size), Style::default().fg(Color::LightCyan)),
+                Span::raw(item.path.clone()),
+                Span::styled(format!("  {}", item.reason), Style::default().fg(Color::Gray)),
+            ]))
+        })
+        .collect();
+    let local_dir = app.sync.as_ref().map_or(String::new(), |sync| sync.local_dir.display().to_string());
+    let title = format!("Sync /{} with {} - {} changes", app.current_dir, local_dir, items.len());
+    let plan = List::new(items)
+        .block(Block::default().title(title).borders(Borders::ALL))
+        .highlight_style(Style::default().bg(Color::LightBlue).fg(Color::Black))
+        .highlight_symbol(">>");
+    frame.render_widget(Clear, area);
+    frame.render_stateful_widget(plan, area, &mut app.sync_plan.state);
+}
+
+fn render_quota_gauge(frame: &mut Frame, usage: &QuotaUsage, area: Rect) {
+    let ratio = app::used_ratio(usage).min(1.0);
+    let color = if ratio >= 0.9 {
+        Color::Red
+    } else if ratio >= 0.75 {
+        Color::Yellow
+    } else {
+        Color::Green
+    };
+    let mut label = match usage.max_bytes {
+        Some(max) => format!("{} / {}", format_size(usage.used_bytes), format_size(max)),
+        None => format_size(usage.used_bytes),
+    };
+    if let Some(max) = usage.max_files {
+        label.push_str(&format!(", {}/{} files", usage.used_files, max));
+    }
+    let title = if usage.path.is_empty() {
+        "Quota".to_string()
+    } else {
+        format!("Quota of {}", usage.path)
+    };
+    let gauge = Gauge::default()
+        .block(Block::default().borders(Borders::ALL).title(title))
+        .gauge_style(Style::default().fg(color))
+        .ratio(ratio)
+        .label(label);
+    frame.render_widget(gauge, area);
+}
+
+/// The preview of the selected entry, empty until it has loaded
+fn render_preview(frame: &mut Frame, app: &App, area: Rect) {
+    let (title, lines) = match &app.preview {
+        Some(preview) => (format!("Preview - {}", preview.kind), preview.lines.clone()),
+        None => ("Preview".to_string(), Vec::new()),
+    };
+    let preview = Paragraph::new(Text::from(lines)).block(Block::default().title(title).borders(Borders::ALL));
+    frame.render_widget(preview, area);
+}
+
+/// Unix timestamp as local `YYYY-MM-DD HH:MM`
+pub fn format_time(timestamp: u64) -> String {
+    chrono::DateTime::from_timestamp(timestamp as i64, 0)
+        .map(|t| t.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M").to_string())
+        .unwrap_or_default()
+}
+
+/// Human readable byte count
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
@@ -0,0 +1,297 @@
+/// acl.rs - who may read and write where
+/// - every user gets `home/<username>`, only they (and admins) can see it
+/// - shared folders are listed in the config with read/write lists of users, `@group`s or `*`
+/// - without authentication there is no user and everything is allowed
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::paths;
+
+/// Top level folder holding the per-user homes
+pub const HOME_ROOT: &str = "home";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Permissions {
+    pub read: bool,
+    pub write: bool,
+}
+
+impl Permissions {
+    pub const NONE: Permissions = Permissions { read: false, write: false };
+    pub const READ: Permissions = Permissions { read: true, write: false };
+    pub const ALL: Permissions = Permissions { read: true, write: true };
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SharedFolder {
+    /// folder relative to the storage root, created at startup
+    pub path: String,
+    #[serde(default)]
+    pub read: Vec<String>,
+    #[serde(default)]
+    pub write: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Acl {
+    /// users that can read and write everything
+    pub admins: Vec<String>,
+    /// group name -> members, referenced as `@name`
+    pub groups: HashMap<String, Vec<String>>,
+    pub shared: Vec<SharedFolder>,
+}
+
+impl Acl {
+    /// Shared folder paths cleaned up, invalid ones are reported
+    pub fn normalize(&mut self) -> Result<(), String> {
+        for folder in &mut self.shared {
+            match paths::normalize(&folder.path) {
+                Some(path) if !path.is_empty() && path.split('/').next() != Some(HOME_ROOT) => {
+                    folder.path = path
+                }
+                _ => return Err(format!("invalid shared folder path '{}'", folder.path)),
+            }
+        }
+        Ok(())
+    }
+
+    /// What `user` may do with the normalized path `path`, `None` means authentication is off
+    pub fn permissions(&self, user: Option<&str>, path: &str) -> Permissions {
+        let Some(user) = user else {
+            return Permissions::ALL;
+        };
+        if self.admins.iter().any(|admin| admin == user) {
+            return Permissions::ALL;
+        }
+
+        let mut parts = path.split('/').filter(|p| !p.is_empty());
+        match (parts.next(), parts.next()) {
+            (None, _) => return Permissions::READ,
+            (Some(HOME_ROOT), None) => return Permissions::READ,
+            (Some(HOME_ROOT), Some(owner)) if owner == user => return Permissions::ALL,
+            (Some(HOME_ROOT), Some(_)) => return Permissions::NONE,
+            _ => {}
+        }
+
+        if let Some(folder) = self.shared_folder(path) {
+            return Permissions {
+                read: self.matches(user, &folder.read) || self.matches(user, &folder.write),
+                write: self.matches(user, &folder.write),
+            };
+        }
+
+        // folders above a readable shared folder can be browsed to reach it
+        let prefix = format!("{}/", path);
+        let leads_somewhere = self.shared.iter().any(|f| {
+            f.path.starts_with(&prefix)
+                && (self.matches(user, &f.read) || self.matches(user, &f.write))
+        });
+        if leads_somewhere {
+            Permissions::READ
+        } else {
+            Permissions::NONE
+        }
+    }
+
+    /// What `user` may do with `path` and everything below it - shared folders further down win
+    /// inside themselves, so they can allow less than `path` does. for deleting, moving or
+    /// copying a whole folder
+    pub fn subtree_permissions(&self, user: Option<&str>, path: &str) -> Permissions {
+        let mut permissions = self.permissions(user, path);
+        let Some(user) = user else {
+            return permissions;
+        };
+        if self.admins.iter().any(|admin| admin == user) {
+            return permissions;
+        }
+        // other users' homes
+        if paths::is_below(HOME_ROOT, path) {
+            return Permissions::NONE;
+        }
+        let below = self.shared.iter().filter(|f| f.path != path && paths::is_below(&f.path, path));
+        for folder in below {
+            let inner = self.permissions(Some(user), &folder.path);
+            permissions.read &= inner.read;
+            permissions.write &= inner.write;
+        }
+        permissions
+    }
+
+    /// Whether `user` can read `path` only to get to something below it, like the root, `home`
+    /// and the folders above shared ones. what is in them isn't the user's to hand out
+    pub fn browse_only(&self, user: Option<&str>, path: &str) -> bool {
+        let Some(user) = user else {
+            return false;
+        };
+        if self.admins.iter().any(|admin| admin == user) {
+            return false;
+        }
+        let mut parts = path.split('/').filter(|p| !p.is_empty());
+        match (parts.next(), parts.next()) {
+            (None, _) | (Some(HOME_ROOT), None) => true,
+            (Some(HOME_ROOT), Some(_)) => false,
+            _ => self.shared_folder(path).is_none(),
+        }
+    }
+
+    /// The most specific shared folder containing `path`, it decides what may be done there
+    fn shared_folder(&self, path: &str) -> Option<&SharedFolder> {
+        self.shared
+            .iter()
+            .filter(|f| paths::is_below(path, &f.path))
+            .max_by_key(|f| f.path.len())
+    }
+
+    fn matches(&self, user: &str, entries: &[String]) -> bool {
+        entries.iter().any(|entry| match entry.strip_prefix('@') {
+            _ if entry == "*" => true,
+            Some(group) => self
+                .groups
+                .get(group)
+                .is_some_and(|members| members.iter().any(|m| m == user)),
+            None => entry == user,
+        })
+    }
+}
+
+/// Home folder of `user`
+pub fn home_dir(user: &str) -> String {
+    paths::join(HOME_ROOT, user)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shared(path: &str, read: &[&str], write: &[&str]) -> SharedFolder {
+        SharedFolder {
+            path: path.to_string(),
+            read: read.iter().map(|s| s.to_string()).collect(),
+            write: write.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    fn acl() -> Acl {
+        Acl {
+            admins: vec!["root".to_string()],
+            groups: HashMap::from([(
+                "devs".to_string(),
+                vec!["alice".to_string(), "bob".to_string()],
+            )]),
+            shared: vec![
+                shared("releases", &["*"], &["@devs"]),
+                shared("releases/signed", &["*"], &["alice"]),
+                shared("team/docs", &["bob"], &["carol"]),
+                shared("team/docs/hr", &[], &["carol"]),
+            ],
+        }
+    }
+
+    #[test]
+    fn without_auth_everything_is_allowed() {
+        let acl = acl();
+        for path in ["", "home", "home/alice", "releases/signed", "team/docs/hr", "elsewhere"] {
+            assert_eq!(acl.permissions(None, path), Permissions::ALL);
+            assert_eq!(acl.subtree_permissions(None, path), Permissions::ALL);
+            assert!(!acl.browse_only(None, path));
+        }
+    }
+
+    #[test]
+    fn homes() {
+        let acl = acl();
+        assert_eq!(acl.permissions(Some("alice"), "home"), Permissions::READ);
+        assert_eq!(acl.permissions(Some("alice"), "home/alice"), Permissions::ALL);
+        assert_eq!(acl.permissions(Some("alice"), "home/alice/notes/a.txt"), Permissions::ALL);
+        assert_eq!(acl.permissions(Some("alice"), "home/bob"), Permissions::NONE);
+        assert_eq!(acl.permissions(Some("alice"), "home/bob/a.txt"), Permissions::NONE);
+        // a name that only starts like the user's
+        assert_eq!(acl.permissions(Some("alice"), "home/alice2"), Permissions::NONE);
+    }
+
+    #[test]
+    fn admins_can_do_anything() {
+        let acl = acl();
+        for path in ["", "home", "home/alice", "team/docs/hr", "elsewhere"] {
+            assert_eq!(acl.permissions(Some("root"), path), Permissions::ALL);
+            assert_eq!(acl.subtree_permissions(Some("root"), path), Permissions::ALL);
+            assert!(!acl.browse_only(Some("root"), path));
+        }
+    }
+
+    #[test]
+    fn groups_and_everyone() {
+        let acl = acl();
+        assert_eq!(acl.permissions(Some("bob"), "releases"), Permissions::ALL);
+        assert_eq!(acl.permissions(Some("bob"), "releases/v1/app.tar"), Permissions::ALL);
+        assert_eq!(acl.permissions(Some("dave"), "releases"), Permissions::READ);
+        assert_eq!(acl.permissions(Some("dave"), "releases/v1/app.tar"), Permissions::READ);
+    }
+
+    #[test]
+    fn most_specific_folder_wins() {
+        let acl = acl();
+        assert_eq!(acl.permissions(Some("bob"), "releases/signed"), Permissions::READ);
+        assert_eq!(acl.permissions(Some("bob"), "releases/signed/app.sig"), Permissions::READ);
+        assert_eq!(acl.permissions(Some("alice"), "releases/signed"), Permissions::ALL);
+        // write implies read
+        assert_eq!(acl.permissions(Some("carol"), "team/docs"), Permissions::ALL);
+        assert_eq!(acl.permissions(Some("bob"), "team/docs"), Permissions::READ);
+        assert_eq!(acl.permissions(Some("bob"), "team/docs/hr"), Permissions::NONE);
+        // a sibling that only starts with the same name isn't inside
+        assert_eq!(acl.permissions(Some("bob"), "releases-old"), Permissions::NONE);
+    }
+
+    #[test]
+    fn folders_above_shared_ones_can_be_browsed() {
+        let acl = acl();
+        assert_eq!(acl.permissions(Some("dave"), ""), Permissions::READ);
+        assert_eq!(acl.permissions(Some("bob"), "team"), Permissions::READ);
+        assert_eq!(acl.permissions(Some("dave"), "team"), Permissions::NONE);
+        assert_eq!(acl.permissions(Some("bob"), "team/other"), Permissions::NONE);
+        assert_eq!(acl.permissions(Some("bob"), "elsewhere"), Permissions::NONE);
+
+        assert!(acl.browse_only(Some("bob"), ""));
+        assert!(acl.browse_only(Some("bob"), "home"));
+        assert!(acl.browse_only(Some("bob"), "team"));
+        assert!(!acl.browse_only(Some("bob"), "team/docs"));
+        assert!(!acl.browse_only(Some("bob"), "home/bob"));
+    }
+
+    #[test]
+    fn subtrees_are_limited_by_the_folders_below() {
+        let acl = acl();
+        // bob can write releases but not releases/signed inside it
+        assert!(acl.permissions(Some("bob"), "releases").write);
+        assert_eq!(acl.subtree_permissions(Some("bob"), "releases"), Permissions::READ);
+        assert_eq!(acl.subtree_permissions(Some("alice"), "releases"), Permissions::ALL);
+        assert_eq!(acl.subtree_permissions(Some("bob"), "releases/v1"), Permissions::ALL);
+        // bob can read team/docs but not team/docs/hr
+        assert_eq!(acl.subtree_permissions(Some("carol"), "team/docs"), Permissions::ALL);
+        assert_eq!(acl.subtree_permissions(Some("bob"), "team/docs"), Permissions::NONE);
+        // the root and home hold other people's homes
+        assert_eq!(acl.subtree_permissions(Some("bob"), ""), Permissions::NONE);
+        assert_eq!(acl.subtree_permissions(Some("bob"), "home"), Permissions::NONE);
+        assert_eq!(acl.subtree_permissions(Some("bob"), "home/bob"), Permissions::ALL);
+    }
+
+    #[test]
+    fn shared_folders_are_normalized() {
+        let mut acl = Acl {
+            shared: vec![shared("/releases//v1/", &[], &[])],
+            ..Acl::default()
+        };
+        acl.normalize().unwrap();
+        assert_eq!(acl.shared[0].path, "releases/v1");
+
+        for bad in ["", "/", "home", "home/alice", "../up"] {
+            let mut acl = Acl {
+                shared: vec![shared(bad, &[], &[])],
+                ..Acl::default()
+            };
+            assert!(acl.normalize().is_err(), "{:?}", bad);
+        }
+    }
+}
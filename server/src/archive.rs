@@ -0,0 +1,469 @@
+/// archive.rs - folders as zip or tar.gz archives, written while they are sent
+/// - `stream` packs the entries collected by the server in a background task and hands back
+///   a response body reading from the other end of a pipe (`piped`), contents are read from
+///   the storage one file at a time so nothing ends up in a temp file or whole in memory
+/// - zip entries are deflated (stored when that isn't worth it, see `shared::encoding`) with data
+///   descriptors, the compressed size is only known afterwards. zip64 records are added when
+///   sizes or offsets don't fit in 32 bits
+/// - tar entries are ustar, with a pax header for names over 100 bytes and files over 8 GiB
+use async_compression::tokio::bufread::DeflateEncoder;
+use async_compression::tokio::write::GzipEncoder;
+use bytes::{Bytes, BytesMut};
+use chrono::{Datelike, Timelike};
+use hyper::body::{Body, Frame, SizeHint};
+use shared::encoding;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, DuplexStream, ReadBuf};
+use tokio::sync::oneshot;
+use tokio_util::io::poll_read_buf;
+
+use crate::storage::{Metadata, StorageBackend};
+
+/// size of the pipe between the writer and the response, and of each chunk sent
+const CHUNK: usize = 64 * 1024;
+/// sizes and offsets from here on need zip64 records
+const ZIP64_LIMIT: u64 = 0xFFFF_FFFF;
+/// biggest size a ustar header holds (11 octal digits)
+const TAR_MAX_SIZE: u64 = 0o77777777777;
+const ZIP_UTF8: u16 = 1 << 11;
+const ZIP_DESCRIPTOR: u16 = 1 << 3;
+const ZIP_STORED: u16 = 0;
+const ZIP_DEFLATED: u16 = 8;
+/// unix, zip spec 4.5
+const ZIP_MADE_BY: u16 = (3 << 8) | 45;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Zip,
+    TarGz,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "zip" => Some(Format::Zip),
+            "tar.gz" | "tgz" => Some(Format::TarGz),
+            _ => None,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Zip => "zip",
+            Format::TarGz => "tar.gz",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Zip => "application/zip",
+            Format::TarGz => "application/gzip",
+        }
+    }
+}
+
+/// One file or folder to pack
+pub struct Entry {
+    /// path inside the archive, folders without a trailing slash
+    pub name: String,
+    /// where it is in the storage
+    pub path: String,
+    pub metadata: Metadata,
+}
+
+/// Starts writing `entries` as a `format` archive, the returned body is what gets written
+pub fn stream(storage: Arc<dyn StorageBackend>, entries: Vec<Entry>, format: Format) -> PipeBody {
+    piped(move |writer| async move {
+        let outcome = write(storage.as_ref(), &entries, format, writer).await;
+        // a client that went away shows up as a broken pipe
+        if let Err(e) = &outcome {
+            eprintln!("Stopped writing the archive: {}", e);
+        }
+        outcome
+    })
+}
+
+/// Runs `write` in a background task, the returned body is what it writes to the pipe it
+/// is handed. other responses made while they are sent use it too
+pub fn piped<F, Fut>(write: F) -> PipeBody
+where
+    F: FnOnce(DuplexStream) -> Fut,
+    Fut: Future<Output = io::Result<()>> + Send + 'static,
+{
+    let (reader, writer) = tokio::io::duplex(CHUNK);
+    let (sender, result) = oneshot::channel();
+    let writing = write(writer);
+    tokio::spawn(async move {
+        let _ = sender.send(writing.await);
+    });
+    PipeBody {
+        reader,
+        buffer: BytesMut::new(),
+        result: Some(result),
+    }
+}
+
+/// Response body reading what a `piped` task writes, it fails when the writing did so a cut
+/// off archive isn't taken for a whole one
+pub struct PipeBody {
+    reader: DuplexStream,
+    buffer: BytesMut,
+    result: Option<oneshot::Receiver<io::Result<()>>>,
+}
+
+impl Body for PipeBody {
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_frame(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, io::Error>>> {
+        let this = self.get_mut();
+        this.buffer.reserve(CHUNK);
+        match ready!(poll_read_buf(Pin::new(&mut this.reader), cx, &mut this.buffer)) {
+            Ok(0) => {}
+            Ok(_) => return Poll::Ready(Some(Ok(Frame::data(this.buffer.split().freeze())))),
+            Err(e) => return Poll::Ready(Some(Err(e))),
+        }
+        // everything written was sent, did the writing finish?
+        let Some(result) = this.result.as_mut() else {
+            return Poll::Ready(None);
+        };
+        let outcome = ready!(Pin::new(result).poll(cx));
+        this.result = None;
+        match outcome {
+            Ok(Err(e)) => Poll::Ready(Some(Err(e))),
+            _ => Poll::Ready(None),
+        }
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        SizeHint::default()
+    }
+}
+
+/// Writes `entries` as a `format` archive to `out`, shutting it down at the end
+pub async fn write<W>(storage: &dyn StorageBackend, entries: &[Entry], format: Format, out: W) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    match format {
+        Format::Zip => {
+            let mut zip = ZipWriter {
+                out,
+                offset: 0,
+                central: Vec::new(),
+                count: 0,
+            };
+            for entry in entries {
+                zip.add(storage, entry).await?;
+            }
+            zip.finish().await
+        }
+        Format::TarGz => {
+            let mut out = GzipEncoder::new(out);
+            for entry in entries {
+                tar_entry(&mut out, storage, entry).await?;
+            }
+            // two empty blocks end a tar
+            out.write_all(&[0; 1024]).await?;
+            out.shutdown().await
+        }
+    }
+}
+
+struct ZipWriter<W> {
+    out: W,
+    /// bytes written so far
+    offset: u64,
+    /// central directory, written once all entries are
+    central: Vec<u8>,
+    count: u64,
+}
+
+impl<W: AsyncWrite + Unpin> ZipWriter<W> {
+    async fn put(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.out.write_all(bytes).await?;
+        self.offset += bytes.len() as u64;
+        Ok(())
+    }
+
+    async fn add(&mut self, storage: &dyn StorageBackend, entry: &Entry) -> io::Result<()> {
+        let is_dir = entry.metadata.is_dir;
+        let name = if is_dir { format!("{}/", entry.name) } else { entry.name.clone() };
+        let (time, date) = dos_time(entry.metadata.modified);
+        let offset = self.offset;
+        let method = if is_dir || !encoding::worth_compressing(&entry.name, entry.metadata.size) { ZIP_STORED } else { ZIP_DEFLATED };
+        let flags = if is_dir { ZIP_UTF8 } else { ZIP_UTF8 | ZIP_DESCRIPTOR };
+        // sizes aren't known yet, a file close to 4 GiB may end up past it once deflated
+        let zip64 = entry.metadata.size >= ZIP64_LIMIT - (1 << 20);
+
+        let mut header = Vec::with_capacity(30 + name.len() + 20);
+        header.extend_from_slice(&0x04034b50u32.to_le_bytes());
+        header.extend_from_slice(&(if zip64 { 45u16 } else { 20 }).to_le_bytes());
+        header.extend_from_slice(&flags.to_le_bytes());
+        header.extend_from_slice(&method.to_le_bytes());
+        header.extend_from_slice(&time.to_le_bytes());
+        header.extend_from_slice(&date.to_le_bytes());
+        // crc and sizes follow in the data descriptor
+        header.extend_from_slice(&0u32.to_le_bytes());
+        let unknown = if zip64 { u32::MAX } else { 0 };
+        header.extend_from_slice(&unknown.to_le_bytes());
+        header.extend_from_slice(&unknown.to_le_bytes());
+        header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        header.extend_from_slice(&(if zip64 { 20u16 } else { 0 }).to_le_bytes());
+        header.extend_from_slice(name.as_bytes());
+        if zip64 {
+            header.extend_from_slice(&1u16.to_le_bytes());
+            header.extend_from_slice(&16u16.to_le_bytes());
+            header.extend_from_slice(&[0; 16]);
+        }
+        self.put(&header).await?;
+
+        let (crc, size, compressed) = if is_dir {
+            (0, 0, 0)
+        } else {
+            let source = Checksummed::new(storage.read(&entry.path).await?);
+            let (source, compressed) = if method == ZIP_DEFLATED {
+                let mut encoder = DeflateEncoder::new(BufReader::new(source));
+                let compressed = tokio::io::copy(&mut encoder, &mut self.out).await?;
+                (encoder.into_inner().into_inner(), compressed)
+            } else {
+                let mut source = source;
+                let compressed = tokio::io::copy(&mut source, &mut self.out).await?;
+                (source, compressed)
+            };
+            self.offset += compressed;
+            let (crc, size) = (source.hasher.finalize(), source.len);
+            if !zip64 && (size >= ZIP64_LIMIT || compressed >= ZIP64_LIMIT) {
+                return Err(io::Error::other(format!("'{}' grew past 4 GiB while it was packed", entry.path)));
+            }
+
+            let mut descriptor = Vec::with_capacity(24);
+            descriptor.extend_from_slice(&0x08074b50u32.to_le_bytes());
+            descriptor.extend_from_slice(&crc.to_le_bytes());
+            if zip64 {
+                descriptor.extend_from_slice(&compressed.to_le_bytes());
+                descriptor.extend_from_slice(&size.to_le_bytes());
+            } else {
+                descriptor.extend_from_slice(&(compressed as u32).to_le_bytes());
+                descriptor.extend_from_slice(&(size as u32).to_le_bytes());
+            }
+            self.put(&descriptor).await?;
+            (crc, size, compressed)
+        };
+
+        // the central directory only takes zip64 fields for what doesn't fit
+        let mut extra = Vec::new();
+        for value in [size, compressed, offset] {
+            if value >= ZIP64_LIMIT {
+                extra.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        if !extra.is_empty() {
+            let fields = extra;
+            extra = Vec::with_capacity(4 + fields.len());
+            extra.extend_from_slice(&1u16.to_le_bytes());
+            extra.extend_from_slice(&(fields.len() as u16).to_le_bytes());
+            extra.extend_from_slice(&fields);
+        }
+        let needed: u16 = if zip64 || !extra.is_empty() { 45 } else { 20 };
+        let attributes: u32 = if is_dir { (0o40755 << 16) | 0x10 } else { 0o100644 << 16 };
+        let central = &mut self.central;
+        central.extend_from_slice(&0x02014b50u32.to_le_bytes());
+        central.extend_from_slice(&ZIP_MADE_BY.to_le_bytes());
+        central.extend_from_slice(&needed.to_le_bytes());
+        central.extend_from_slice(&flags.to_le_bytes());
+        central.extend_from_slice(&method.to_le_bytes());
+        central.extend_from_slice(&time.to_le_bytes());
+        central.extend_from_slice(&date.to_le_bytes());
+        central.extend_from_slice(&crc.to_le_bytes());
+        central.extend_from_slice(&zip32(compressed).to_le_bytes());
+        central.extend_from_slice(&zip32(size).to_le_bytes());
+        central.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        central.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+        // comment length, disk, internal attributes
+        central.extend_from_slice(&[0; 6]);
+        central.extend_from_slice(&attributes.to_le_bytes());
+        central.extend_from_slice(&zip32(offset).to_le_bytes());
+        central.extend_from_slice(name.as_bytes());
+        central.extend_from_slice(&extra);
+        self.count += 1;
+        Ok(())
+    }
+
+    async fn finish(mut self) -> io::Result<()> {
+        let central_offset = self.offset;
+        let central = std::mem::take(&mut self.central);
+        self.put(&central).await?;
+        let central_size = central.len() as u64;
+
+        let mut end = Vec::with_capacity(98);
+        if self.count >= 0xFFFF || central_offset >= ZIP64_LIMIT || central_size >= ZIP64_LIMIT {
+            let record_offset = self.offset;
+            end.extend_from_slice(&0x06064b50u32.to_le_bytes());
+            end.extend_from_slice(&44u64.to_le_bytes());
+            end.extend_from_slice(&ZIP_MADE_BY.to_le_bytes());
+            end.extend_from_slice(&45u16.to_le_bytes());
+            // this disk and the one the central directory starts on
+            end.extend_from_slice(&[0; 8]);
+            end.extend_from_slice(&self.count.to_le_bytes());
+            end.extend_from_slice(&self.count.to_le_bytes());
+            end.extend_from_slice(&central_size.to_le_bytes());
+            end.extend_from_slice(&central_offset.to_le_bytes());
+            // locator
+            end.extend_from_slice(&0x07064b50u32.to_le_bytes());
+            end.extend_from_slice(&0u32.to_le_bytes());
+            end.extend_from_slice(&record_offset.to_le_bytes());
+            end.extend_from_slice(&1u32.to_le_bytes());
+        }
+        let count = self.count.min(0xFFFF) as u16;
+        end.extend_from_slice(&0x06054b50u32.to_le_bytes());
+        end.extend_from_slice(&[0; 4]);
+        end.extend_from_slice(&count.to_le_bytes());
+        end.extend_from_slice(&count.to_le_bytes());
+        end.extend_from_slice(&zip32(central_size).to_le_bytes());
+        end.extend_from_slice(&zip32(central_offset).to_le_bytes());
+        // comment length
+        end.extend_from_slice(&0u16.to_le_bytes());
+        self.put(&end).await?;
+        self.out.shutdown().await
+    }
+}
+
+/// A 32 bit size or offset, all ones when the real one is in a zip64 field
+fn zip32(value: u64) -> u32 {
+    if value >= ZIP64_LIMIT {
+        u32::MAX
+    } else {
+        value as u32
+    }
+}
+
+/// MS-DOS time and date of a unix timestamp, zip can't go before 1980
+fn dos_time(unix: u64) -> (u16, u16) {
+    let Some(time) = chrono::DateTime::from_timestamp(unix as i64, 0).filter(|time| time.year() >= 1980) else {
+        return (0, (1 << 5) | 1);
+    };
+    let year = (time.year() - 1980).min(127) as u16;
+    let date = (year << 9) | ((time.month() as u16) << 5) | time.day() as u16;
+    let clock = ((time.hour() as u16) << 11) | ((time.minute() as u16) << 5) | (time.second() / 2) as u16;
+    (clock, date)
+}
+
+/// Reader keeping the crc32 and length of what passed through
+struct Checksummed<R> {
+    inner: R,
+    hasher: crc32fast::Hasher,
+    len: u64,
+}
+
+impl<R> Checksummed<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: crc32fast::Hasher::new(),
+            len: 0,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Checksummed<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        let read = &buf.filled()[before..];
+        this.hasher.update(read);
+        this.len += read.len() as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+async fn tar_entry<W: AsyncWrite + Unpin>(out: &mut W, storage: &dyn StorageBackend, entry: &Entry) -> io::Result<()> {
+    let is_dir = entry.metadata.is_dir;
+    let name = if is_dir { format!("{}/", entry.name) } else { entry.name.clone() };
+    let size = if is_dir { 0 } else { entry.metadata.size };
+    let modified = entry.metadata.modified;
+
+    // what doesn't fit in the ustar header goes in a pax header in front of it
+    let mut pax = String::new();
+    if name.len() > 100 {
+        pax_record(&mut pax, "path", &name);
+    }
+    if size > TAR_MAX_SIZE {
+        pax_record(&mut pax, "size", &size.to_string());
+    }
+    if !pax.is_empty() {
+        out.write_all(&tar_header("././@PaxHeader", pax.len() as u64, b'x', modified, 0o644)).await?;
+        out.write_all(pax.as_bytes()).await?;
+        out.write_all(&[0; 512][..tar_padding(pax.len() as u64)]).await?;
+    }
+
+    if is_dir {
+        return out.write_all(&tar_header(&name, 0, b'5', modified, 0o755)).await;
+    }
+    out.write_all(&tar_header(&name, size, b'0', modified, 0o644)).await?;
+    // the header promised `size` bytes, a file that changed meanwhile is cut or padded to that
+    let copied = tokio::io::copy(&mut storage.read(&entry.path).await?.take(size), out).await?;
+    let mut missing = size - copied;
+    while missing > 0 {
+        let zeros = missing.min(CHUNK as u64);
+        out.write_all(&vec![0; zeros as usize]).await?;
+        missing -= zeros;
+    }
+    out.write_all(&[0; 512][..tar_padding(size)]).await
+}
+
+fn tar_header(name: &str, size: u64, kind: u8, modified: u64, mode: u32) -> [u8; 512] {
+    let mut header = [0u8; 512];
+    let name = truncated(name, 100);
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    octal(&mut header[100..108], mode as u64);
+    // owner and group
+    octal(&mut header[108..116], 0);
+    octal(&mut header[116..124], 0);
+    octal(&mut header[124..136], size.min(TAR_MAX_SIZE));
+    octal(&mut header[136..148], modified.min(TAR_MAX_SIZE));
+    header[156] = kind;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    // the checksum is taken with its own field as spaces
+    header[148..156].fill(b' ');
+    let sum: u32 = header.iter().map(|&byte| byte as u32).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+    header
+}
+
+/// Zero padded octal filling `field` but the closing NUL
+fn octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    let end = field.len() - 1;
+    field[..end].copy_from_slice(digits.as_bytes());
+    field[end] = 0;
+}
+
+/// Zeros after `len` bytes of contents to fill the last 512 byte block
+fn tar_padding(len: u64) -> usize {
+    ((512 - len % 512) % 512) as usize
+}
+
+/// "<length> key=value\n", the length counts its own digits
+fn pax_record(records: &mut String, key: &str, value: &str) {
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + 1;
+    while len != rest + len.to_string().len() {
+        len = rest + len.to_string().len();
+    }
+    records.push_str(&format!("{} {}={}\n", len, key, value));
+}
+
+fn truncated(name: &str, max: usize) -> &str {
+    let mut end = name.len().min(max);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    &name[..end]
+}
@@ -0,0 +1,160 @@
+/// auth.rs - user accounts and login tokens
+/// - users live in a json file mapping username -> argon2 password hash
+/// - `POST /login` trades a username/password for a bearer token
+/// - tokens are kept in memory, restarting the server logs everybody out
+/// - browsers (the web index) send HTTP basic auth instead, checked on every request
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hyper::header::{HeaderMap, AUTHORIZATION};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::AuthConfig;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct UsersFile {
+    #[serde(default)]
+    pub users: BTreeMap<String, String>,
+}
+
+impl UsersFile {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        match fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(UsersFile::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let json = serde_json::to_vec_pretty(self)?;
+        fs::write(path, json)
+    }
+
+    /// Adds the user or replaces their password
+    pub fn set_password(&mut self, username: &str, password: &str) -> io::Result<()> {
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        self.users.insert(username.to_string(), hash.to_string());
+        Ok(())
+    }
+}
+
+struct Session {
+    username: String,
+    expires: SystemTime,
+}
+
+pub struct Auth {
+    users_file: PathBuf,
+    token_ttl: Duration,
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+impl Auth {
+    pub fn new(config: &AuthConfig) -> io::Result<Self> {
+        let users = UsersFile::load(&config.users_file)?;
+        if users.users.is_empty() {
+            println!(
+                "Warning: no users in {:?}, add one with `server add-user <name>`",
+                config.users_file
+            );
+        }
+        Ok(Self {
+            users_file: config.users_file.clone(),
+            token_ttl: Duration::from_secs(config.token_ttl_secs),
+            sessions: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Checks the password and hands out a new token with its expiry (unix seconds)
+    pub fn login(&self, username: &str, password: &str) -> Option<(String, u64)> {
+        if !self.check_password(username, password) {
+            return None;
+        }
+
+        let token: String = rand::thread_rng()
+            .sample_iter(&rand::distributions::Alphanumeric)
+            .take(48)
+            .map(char::from)
+            .collect();
+        let expires = SystemTime::now() + self.token_ttl;
+
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, session| session.expires > SystemTime::now());
+        sessions.insert(
+            token.clone(),
+            Session {
+                username: username.to_string(),
+                expires,
+            },
+        );
+        let expires_at = expires.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        Some((token, expires_at))
+    }
+
+    /// Whether `password` is right for `username`
+    pub fn check_password(&self, username: &str, password: &str) -> bool {
+        // re-read so users added while the server runs can log in
+        let Ok(users) = UsersFile::load(&self.users_file) else {
+            return false;
+        };
+        let Some(hash) = users.users.get(username).and_then(|s| PasswordHash::new(s).ok()) else {
+            return false;
+        };
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    }
+
+    /// Username from an `Authorization: Basic` header, if the password is right
+    pub fn authenticate_basic(&self, headers: &HeaderMap) -> Option<String> {
+        let encoded = headers
+            .get(AUTHORIZATION)?
+            .to_str()
+            .ok()?
+            .strip_prefix("Basic ")?;
+        let decoded = STANDARD.decode(encoded.trim()).ok()?;
+        let (username, password) = std::str::from_utf8(&decoded).ok()?.split_once(':')?;
+        self.check_password(username, password)
+            .then(|| username.to_string())
+    }
+
+    /// Username for the bearer token in `headers`, if it is valid
+    pub fn authenticate(&self, headers: &HeaderMap) -> Option<String> {
+        let token = bearer_token(headers)?;
+        let sessions = self.sessions.lock().unwrap();
+        let session = sessions.get(token)?;
+        if session.expires <= SystemTime::now() {
+            return None;
+        }
+        Some(session.username.clone())
+    }
+
+    pub fn logout(&self, headers: &HeaderMap) {
+        if let Some(token) = bearer_token(headers) {
+            self.sessions.lock().unwrap().remove(token);
+        }
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
@@ -0,0 +1,28 @@
+/// compression.rs - zstd and gzip for request and response bodies
+/// which encoding to use and for what is decided in `shared::encoding`, this does the work
+use async_compression::tokio::bufread::{GzipDecoder, GzipEncoder, ZstdDecoder, ZstdEncoder};
+use shared::encoding::Encoding;
+use std::pin::Pin;
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt};
+
+/// `data` compressed with `encoding`
+pub async fn compress(encoding: Encoding, data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    match encoding {
+        Encoding::Zstd => ZstdEncoder::new(data).read_to_end(&mut out).await?,
+        Encoding::Gzip => GzipEncoder::new(data).read_to_end(&mut out).await?,
+    };
+    Ok(out)
+}
+
+/// Reads what `reader` gives decompressed, `None` passes it through as it is
+pub fn decoder<'a, R>(encoding: Option<Encoding>, reader: R) -> Pin<Box<dyn AsyncRead + Send + 'a>>
+where
+    R: AsyncBufRead + Send + 'a,
+{
+    match encoding {
+        Some(Encoding::Zstd) => Box::pin(ZstdDecoder::new(reader)),
+        Some(Encoding::Gzip) => Box::pin(GzipDecoder::new(reader)),
+        None => Box::pin(reader),
+    }
+}
@@ -0,0 +1,701 @@
+/// config.rs - command line and config file handling for the server
+/// settings are resolved in this order (later wins):
+/// - built in defaults
+/// - the TOML file passed with `--config`
+/// - command line flags
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+
+use crate::acl::Acl;
+use crate::quota::Quotas;
+
+pub const DEFAULT_PORT: u16 = 3333;
+
+#[derive(Parser, Debug)]
+#[command(name = "server", version, about = "tuifs file server")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Path to a TOML config file
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// Address to listen on, can be given more than once (default 127.0.0.1)
+    #[arg(short, long = "bind", value_name = "IP")]
+    pub bind: Vec<IpAddr>,
+
+    /// Port to listen on (default 3333)
+    #[arg(short, long)]
+    pub port: Option<u16>,
+
+    /// Directory files are stored in (default `server/storage`)
+    #[arg(short, long)]
+    pub storage_dir: Option<PathBuf>,
+
+    /// Where files are kept (default local)
+    #[arg(long, value_enum)]
+    pub storage_backend: Option<StorageBackendKind>,
+
+    /// Name this server reports
+    #[arg(short, long)]
+    pub name: Option<String>,
+
+    /// Largest accepted upload in bytes
+    #[arg(long)]
+    pub max_upload_bytes: Option<u64>,
+
+    /// Most connections served at once
+    #[arg(long)]
+    pub max_connections: Option<usize>,
+
+    /// PEM certificate chain, enables TLS (needs --tls-key)
+    #[arg(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Generate a self-signed certificate if the cert/key files don't exist (development only)
+    #[arg(long)]
+    pub tls_self_signed: bool,
+
+    /// Users file, enables authentication
+    #[arg(long)]
+    pub users_file: Option<PathBuf>,
+
+    /// Serve a read-only HTML listing of the storage at /files/ for browsers
+    #[arg(long)]
+    pub web_index: bool,
+
+    /// Serve the storage over WebDAV at /dav/
+    #[arg(long)]
+    pub webdav: bool,
+
+    /// Hand out expiring share links, signed with a key kept in share-secret.key
+    #[arg(long)]
+    pub shares: bool,
+
+    /// Keep previous versions of replaced files, this many per file (10 without a number).
+    /// 0 turns versioning off
+    #[arg(long, num_args = 0..=1, default_missing_value = "10")]
+    pub keep_versions: Option<usize>,
+
+    /// Keep previous versions of replaced files, dropping them after this many days
+    #[arg(long)]
+    pub version_max_age_days: Option<u64>,
+
+    /// Days deleted files stay in the trash, 0 deletes right away (default 30)
+    #[arg(long)]
+    pub trash_retention_days: Option<u64>,
+
+    /// Index the words in text files for searching inside them
+    #[arg(long)]
+    pub full_text: bool,
+
+    /// Keep the metadata of every file in a database, for fast listings and search
+    #[arg(long)]
+    pub metadata_index: bool,
+}
+
+/// One-off commands, the server exits after running them
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Add a user (or change their password) in the users file
+    AddUser {
+        username: String,
+        /// read from stdin when not given
+        #[arg(long)]
+        password: Option<String>,
+    },
+    /// Remove a user from the users file
+    RemoveUser { username: String },
+}
+
+/// Which `StorageBackend` holds the files
+#[derive(Deserialize, ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackendKind {
+    /// a folder on this machine, `storage_dir`
+    #[default]
+    Local,
+    /// in ram, lost when the server stops
+    Memory,
+    /// `storage_dir` as a content-addressed store, identical files are kept once
+    Dedup,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// uploads larger than this are rejected with 413
+    pub max_upload_bytes: Option<u64>,
+    /// connections past this wait until another one closes
+    pub max_connections: Option<usize>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain
+    pub cert: PathBuf,
+    /// PEM private key
+    pub key: PathBuf,
+    /// generate a self-signed pair at `cert`/`key` when they don't exist yet
+    #[serde(default)]
+    pub self_signed: bool,
+    /// names the generated certificate is valid for
+    #[serde(default = "TlsConfig::default_hostnames")]
+    pub hostnames: Vec<String>,
+}
+
+impl TlsConfig {
+    fn default_hostnames() -> Vec<String> {
+        vec!["localhost".to_string(), "127.0.0.1".to_string(), "::1".to_string()]
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// json file of usernames and argon2 password hashes
+    pub users_file: PathBuf,
+    /// how long a login token stays valid
+    pub token_ttl_secs: u64,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            users_file: PathBuf::from("users.json"),
+            token_ttl_secs: 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShareConfig {
+    /// key share links are signed with, generated on first start
+    pub secret_file: PathBuf,
+    /// longest a share link may stay valid
+    pub max_expiry_secs: u64,
+    /// base used when handing out links, e.g. `https://files.example.com`,
+    /// defaults to the address the client connected to
+    pub public_url: Option<String>,
+}
+
+impl Default for ShareConfig {
+    fn default() -> Self {
+        Self {
+            secret_file: PathBuf::from("share-secret.key"),
+            max_expiry_secs: 30 * 24 * 60 * 60,
+            public_url: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct S3Key {
+    pub access_key: String,
+    pub secret_key: String,
+    /// user whose permissions the key gets, a key without one can access everything
+    #[serde(default)]
+    pub user: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct S3Config {
+    /// region reported to clients that ask for a bucket's location, requests have to be
+    /// signed for it
+    pub region: String,
+    /// where multipart uploads are kept until they are completed
+    pub uploads_dir: PathBuf,
+    /// access keys requests can be signed with
+    pub keys: Vec<S3Key>,
+}
+
+impl Default for S3Config {
+    fn default() -> Self {
+        Self {
+            region: "us-east-1".to_string(),
+            uploads_dir: PathBuf::from("s3-uploads"),
+            keys: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct VersionsConfig {
+    /// previous versions kept per file, 0 keeps none. without `max_age_days` this
+    /// defaults to 10, with it only the age counts unless this is set too
+    pub keep: Option<usize>,
+    /// versions replaced longer ago than this are dropped
+    pub max_age_days: Option<u64>,
+    /// where versions are kept, for the local and dedup storage backends
+    pub dir: PathBuf,
+}
+
+impl VersionsConfig {
+    const DEFAULT_KEEP: usize = 10;
+
+    /// Most versions kept per file, `None` for no limit on the count
+    pub fn keep(&self) -> Option<usize> {
+        match (self.keep, self.max_age_days) {
+            (None, None) => Some(Self::DEFAULT_KEEP),
+            (keep, _) => keep,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.keep != Some(0)
+    }
+}
+
+impl Default for VersionsConfig {
+    fn default() -> Self {
+        Self {
+            keep: None,
+            max_age_days: None,
+            dir: PathBuf::from("versions"),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrashConfig {
+    /// days deleted things can be restored for, 0 turns the trash off
+    pub retention_days: u64,
+}
+
+impl Default for TrashConfig {
+    fn default() -> Self {
+        Self { retention_days: 30 }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct FullTextConfig {
+    /// where the index is saved between runs
+    pub index_file: PathBuf,
+    /// larger files aren't read
+    pub max_file_bytes: u64,
+}
+
+impl Default for FullTextConfig {
+    fn default() -> Self {
+        Self {
+            index_file: PathBuf::from("fulltext-index.json"),
+            max_file_bytes: 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetadataIndexConfig {
+    /// sqlite database, created when missing
+    pub db_file: PathBuf,
+}
+
+impl Default for MetadataIndexConfig {
+    fn default() -> Self {
+        Self {
+            db_file: PathBuf::from("metadata.db"),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub name: String,
+    pub bind: Vec<IpAddr>,
+    pub port: u16,
+    pub storage_dir: Option<PathBuf>,
+    pub storage_backend: StorageBackendKind,
+    pub limits: Limits,
+    /// serve https instead of http when set
+    pub tls: Option<TlsConfig>,
+    /// require a login for every request when set
+    pub auth: Option<AuthConfig>,
+    /// shared folders and admins, only used with auth
+    pub acl: Acl,
+    /// expiring download links when set
+    pub shares: Option<ShareConfig>,
+    /// read-only HTML listing at `/files/`, logins use HTTP basic auth
+    pub web_index: bool,
+    /// WebDAV at `/dav/`, same logins (basic auth or token) and permissions as the client
+    pub webdav: bool,
+    /// S3-compatible api at `/s3/`, buckets are the top level folders
+    pub s3: Option<S3Config>,
+    /// previous versions of uploaded files when set
+    pub versions: Option<VersionsConfig>,
+    /// where deletes go before they are gone for good
+    pub trash: TrashConfig,
+    /// size and file count limits for homes and folders
+    pub quotas: Quotas,
+    /// search inside text files when set
+    pub full_text: Option<FullTextConfig>,
+    /// listings, search and usage from a database instead of walking the storage when set
+    pub metadata_index: Option<MetadataIndexConfig>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            name: "server1".to_string(),
+            bind: vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+            port: DEFAULT_PORT,
+            storage_dir: None,
+            storage_backend: StorageBackendKind::Local,
+            limits: Limits::default(),
+            tls: None,
+            auth: None,
+            acl: Acl::default(),
+            shares: None,
+            web_index: false,
+            webdav: false,
+            s3: None,
+            versions: None,
+            trash: TrashConfig::default(),
+            quotas: Quotas::default(),
+            full_text: None,
+            metadata_index: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "unable to read {:?}: {}", path, e),
+            ConfigError::Parse(path, e) => write!(f, "invalid config file {:?}: {}", path, e),
+            ConfigError::Invalid(msg) => write!(f, "invalid configuration: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl ServerConfig {
+    /// Builds the final configuration from the config file (if any) and command line flags
+    pub fn load(cli: Cli) -> Result<Self, ConfigError> {
+        let mut config = match &cli.config {
+            Some(path) => {
+                let text =
+                    fs::read_to_string(path).map_err(|e| ConfigError::Read(path.clone(), e))?;
+                toml::from_str(&text).map_err(|e| ConfigError::Parse(path.clone(), e))?
+            }
+            None => ServerConfig::default(),
+        };
+
+        if !cli.bind.is_empty() {
+            config.bind = cli.bind;
+        }
+        if let Some(port) = cli.port {
+            config.port = port;
+        }
+        if cli.storage_dir.is_some() {
+            config.storage_dir = cli.storage_dir;
+        }
+        if let Some(storage_backend) = cli.storage_backend {
+            config.storage_backend = storage_backend;
+        }
+        if let Some(name) = cli.name {
+            config.name = name;
+        }
+        if cli.max_upload_bytes.is_some() {
+            config.limits.max_upload_bytes = cli.max_upload_bytes;
+        }
+        if cli.max_connections.is_some() {
+            config.limits.max_connections = cli.max_connections;
+        }
+        if let (Some(cert), Some(key)) = (cli.tls_cert, cli.tls_key) {
+            config.tls = Some(TlsConfig {
+                cert,
+                key,
+                self_signed: cli.tls_self_signed,
+                hostnames: TlsConfig::default_hostnames(),
+            });
+        } else if cli.tls_self_signed {
+            match &mut config.tls {
+                Some(tls) => tls.self_signed = true,
+                None => {
+                    config.tls = Some(TlsConfig {
+                        cert: PathBuf::from("tuifs-cert.pem"),
+                        key: PathBuf::from("tuifs-key.pem"),
+                        self_signed: true,
+                        hostnames: TlsConfig::default_hostnames(),
+                    })
+                }
+            }
+        }
+        if let Some(users_file) = cli.users_file {
+            config.auth.get_or_insert_with(AuthConfig::default).users_file = users_file;
+        }
+        if cli.web_index {
+            config.web_index = true;
+        }
+        if cli.webdav {
+            config.webdav = true;
+        }
+        if cli.shares && config.shares.is_none() {
+            config.shares = Some(ShareConfig::default());
+        }
+        if cli.keep_versions.is_some() {
+            config.versions.get_or_insert_with(VersionsConfig::default).keep = cli.keep_versions;
+        }
+        if cli.version_max_age_days.is_some() {
+            config.versions.get_or_insert_with(VersionsConfig::default).max_age_days = cli.version_max_age_days;
+        }
+        if let Some(retention_days) = cli.trash_retention_days {
+            config.trash.retention_days = retention_days;
+        }
+        if cli.full_text && config.full_text.is_none() {
+            config.full_text = Some(FullTextConfig::default());
+        }
+        if cli.metadata_index && config.metadata_index.is_none() {
+            config.metadata_index = Some(MetadataIndexConfig::default());
+        }
+
+        config.validate()?;
+        config.acl.normalize().map_err(ConfigError::Invalid)?;
+        config.quotas.normalize().map_err(ConfigError::Invalid)?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.bind.is_empty() {
+            return Err(ConfigError::Invalid("at least one bind address is required".into()));
+        }
+        if self.port == 0 {
+            return Err(ConfigError::Invalid("port must be between 1 and 65535".into()));
+        }
+        if self.s3.as_ref().is_some_and(|s3| s3.keys.is_empty()) {
+            return Err(ConfigError::Invalid("[s3] needs at least one key".into()));
+        }
+        if self.limits.max_connections == Some(0) {
+            return Err(ConfigError::Invalid("max_connections must be at least 1".into()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `ServerConfig::load` with `toml` as the config file (when given) and `args` as flags
+    fn load(toml: Option<&str>, args: &[&str]) -> Result<ServerConfig, ConfigError> {
+        let path = std::env::temp_dir().join(format!("tuifs-config-{:016x}.toml", rand::random::<u64>()));
+        let mut argv = vec!["server".to_string()];
+        if let Some(toml) = toml {
+            fs::write(&path, toml).unwrap();
+            argv.push("--config".to_string());
+            argv.push(path.to_str().unwrap().to_string());
+        }
+        argv.extend(args.iter().map(|arg| arg.to_string()));
+        let config = ServerConfig::load(Cli::parse_from(argv));
+        let _ = fs::remove_file(&path);
+        config
+    }
+
+    fn invalid(toml: Option<&str>, args: &[&str]) -> String {
+        match load(toml, args) {
+            Err(ConfigError::Invalid(message)) => message,
+            other => panic!("expected an invalid config, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn defaults() {
+        let config = load(None, &[]).unwrap();
+        assert_eq!(config.name, "server1");
+        assert_eq!(config.port, DEFAULT_PORT);
+        assert_eq!(config.bind, [IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+        assert_eq!(config.storage_backend, StorageBackendKind::Local);
+        assert_eq!(config.trash.retention_days, 30);
+        assert!(config.auth.is_none());
+        assert!(config.tls.is_none());
+        // the opt-in features stay off
+        assert!(config.shares.is_none());
+        assert!(config.versions.is_none());
+        assert!(config.full_text.is_none());
+        assert!(config.metadata_index.is_none());
+        assert!(!config.webdav && !config.web_index);
+    }
+
+    const TOML: &str = r#"
+        name = "from-toml"
+        port = 4000
+        bind = ["0.0.0.0"]
+        storage_backend = "dedup"
+        webdav = true
+
+        [limits]
+        max_upload_bytes = 100
+
+        [auth]
+        users_file = "toml-users.json"
+        token_ttl_secs = 60
+
+        [shares]
+        max_expiry_secs = 3600
+
+        [versions]
+        keep = 3
+        dir = "toml-versions"
+
+        [trash]
+        retention_days = 5
+    "#;
+
+    #[test]
+    fn toml_overrides_defaults() {
+        let config = load(Some(TOML), &[]).unwrap();
+        assert_eq!(config.name, "from-toml");
+        assert_eq!(config.port, 4000);
+        assert_eq!(config.bind, [IpAddr::V4(Ipv4Addr::UNSPECIFIED)]);
+        assert_eq!(config.storage_backend, StorageBackendKind::Dedup);
+        assert!(config.webdav);
+        assert_eq!(config.limits.max_upload_bytes, Some(100));
+        let auth = config.auth.unwrap();
+        assert_eq!(auth.users_file, PathBuf::from("toml-users.json"));
+        assert_eq!(auth.token_ttl_secs, 60);
+        // sections only name what they change
+        let shares = config.shares.unwrap();
+        assert_eq!(shares.max_expiry_secs, 3600);
+        assert_eq!(shares.secret_file, PathBuf::from("share-secret.key"));
+        let versions = config.versions.unwrap();
+        assert_eq!(versions.keep(), Some(3));
+        assert_eq!(versions.dir, PathBuf::from("toml-versions"));
+        assert_eq!(config.trash.retention_days, 5);
+        // what the file leaves out keeps its default
+        assert!(config.full_text.is_none());
+        assert_eq!(config.limits.max_connections, None);
+    }
+
+    #[test]
+    fn flags_override_toml() {
+        let args = [
+            "--name",
+            "from-flags",
+            "-p",
+            "5000",
+            "-b",
+            "127.0.0.1",
+            "-b",
+            "::1",
+            "--storage-backend",
+            "memory",
+            "--max-upload-bytes",
+            "200",
+            "--users-file",
+            "flag-users.json",
+            "--trash-retention-days",
+            "0",
+            "--keep-versions",
+            "7",
+            "--shares",
+        ];
+        let config = load(Some(TOML), &args).unwrap();
+        assert_eq!(config.name, "from-flags");
+        assert_eq!(config.port, 5000);
+        assert_eq!(config.bind.len(), 2);
+        assert_eq!(config.storage_backend, StorageBackendKind::Memory);
+        assert_eq!(config.limits.max_upload_bytes, Some(200));
+        assert_eq!(config.trash.retention_days, 0);
+        // a flag only replaces its own setting, the rest of the section stays
+        let auth = config.auth.unwrap();
+        assert_eq!(auth.users_file, PathBuf::from("flag-users.json"));
+        assert_eq!(auth.token_ttl_secs, 60);
+        let versions = config.versions.unwrap();
+        assert_eq!(versions.keep(), Some(7));
+        assert_eq!(versions.dir, PathBuf::from("toml-versions"));
+        // --shares doesn't reset a configured [shares]
+        assert_eq!(config.shares.unwrap().max_expiry_secs, 3600);
+        // flags that aren't given don't undo the file
+        assert!(config.webdav);
+    }
+
+    #[test]
+    fn flags_opt_into_features() {
+        let config = load(None, &["--shares", "--keep-versions", "--webdav", "--full-text", "--metadata-index"]).unwrap();
+        let shares = config.shares.unwrap();
+        assert_eq!(shares.max_expiry_secs, ShareConfig::default().max_expiry_secs);
+        assert_eq!(config.versions.as_ref().unwrap().keep(), Some(10));
+        assert!(config.webdav);
+        assert!(config.full_text.is_some());
+        assert!(config.metadata_index.is_some());
+
+        let config = load(None, &["--keep-versions", "0"]).unwrap();
+        assert!(!config.versions.unwrap().enabled());
+        // only an age limit means no limit on the count
+        let config = load(None, &["--version-max-age-days", "7"]).unwrap();
+        let versions = config.versions.unwrap();
+        assert_eq!(versions.keep(), None);
+        assert_eq!(versions.max_age_days, Some(7));
+        // the same in the file
+        let config = load(Some("[versions]\nmax_age_days = 7\n"), &[]).unwrap();
+        assert_eq!(config.versions.unwrap().keep(), None);
+        let config = load(Some("[versions]\n"), &[]).unwrap();
+        assert_eq!(config.versions.unwrap().keep(), Some(10));
+    }
+
+    #[test]
+    fn tls_flags() {
+        let config = load(None, &["--tls-self-signed"]).unwrap();
+        let tls = config.tls.unwrap();
+        assert!(tls.self_signed);
+        assert_eq!(tls.cert, PathBuf::from("tuifs-cert.pem"));
+        let toml = "[tls]\ncert = \"c.pem\"\nkey = \"k.pem\"\n";
+        let tls = load(Some(toml), &["--tls-self-signed"]).unwrap().tls.unwrap();
+        assert!(tls.self_signed);
+        assert_eq!(tls.cert, PathBuf::from("c.pem"));
+        let tls = load(Some(toml), &["--tls-cert", "flag.pem", "--tls-key", "flag-key.pem"]).unwrap().tls.unwrap();
+        assert_eq!(tls.cert, PathBuf::from("flag.pem"));
+        assert!(!tls.self_signed);
+    }
+
+    #[test]
+    fn invalid_configs() {
+        assert!(invalid(Some("port = 0"), &[]).contains("port"));
+        assert!(invalid(None, &["-p", "0"]).contains("port"));
+        assert!(invalid(Some("bind = []"), &[]).contains("bind"));
+        assert!(invalid(Some("[s3]\nregion = \"eu\"\n"), &[]).contains("[s3]"));
+        assert!(invalid(Some("[limits]\nmax_connections = 0\n"), &[]).contains("max_connections"));
+        assert!(invalid(None, &["--max-connections", "0"]).contains("max_connections"));
+        let acl = "[[acl.shared]]\npath = \"../up\"\n";
+        assert!(invalid(Some(acl), &[]).contains("shared folder"));
+        let quotas = "[quotas]\nfolders = [{ path = \"../up\", max_bytes = 1 }]\n";
+        assert!(invalid(Some(quotas), &[]).contains("quota"));
+        // a flag can fix what the file gets wrong
+        assert_eq!(load(Some("port = 0"), &["-p", "1"]).unwrap().port, 1);
+    }
+
+    #[test]
+    fn unreadable_configs() {
+        assert!(matches!(load(Some("no_such_setting = 1"), &[]), Err(ConfigError::Parse(..))));
+        assert!(matches!(load(Some("port = \"x\""), &[]), Err(ConfigError::Parse(..))));
+        let missing = Cli::parse_from(["server", "--config", "/no/such/tuifs.toml"]);
+        assert!(matches!(ServerConfig::load(missing), Err(ConfigError::Read(..))));
+    }
+}
@@ -0,0 +1,179 @@
+/// events.rs - telling clients about changes as they happen
+/// - the server publishes every change it makes to `Events`, `GET /events` streams them to a
+///   client as server-sent events (`data: <ChangeEvent json>`), keeping it alive with comments
+/// - with the local disk backend `watch` also reports changes made to the storage directory by
+///   anything else, the server re-indexes and publishes those too
+use bytes::Bytes;
+use hyper::body::{Body, Frame, SizeHint};
+use notify::event::{ModifyKind, RenameMode};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use shared::{ChangeEvent, ChangeKind};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc};
+
+use crate::paths;
+use crate::storage;
+
+/// events a slow client may fall behind by before it misses some
+const BACKLOG: usize = 1024;
+/// how long after the server changed something the watcher reporting it is taken as an echo
+const ECHO: Duration = Duration::from_secs(2);
+
+#[derive(Clone)]
+pub struct Events {
+    sender: broadcast::Sender<ChangeEvent>,
+    /// what was last published about each path, and when
+    published: Arc<Mutex<HashMap<String, (ChangeEvent, Instant)>>>,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Self {
+            sender: broadcast::channel(BACKLOG).0,
+            published: Arc::default(),
+        }
+    }
+}
+
+impl Events {
+    pub fn publish(&self, event: ChangeEvent) {
+        {
+            let mut published = self.published.lock().unwrap();
+            let now = Instant::now();
+            published.retain(|_, (_, at)| now.duration_since(*at) < ECHO);
+            published.insert(event.path.clone(), (event.clone(), now));
+        }
+        // nobody listening is fine
+        let _ = self.sender.send(event);
+    }
+
+    /// Whether `event` was published a moment ago, writing a file shows up on disk as both
+    /// created and modified so those count as the same
+    pub fn recently_published(&self, event: &ChangeEvent) -> bool {
+        let published = self.published.lock().unwrap();
+        let written = |kind: ChangeKind| matches!(kind, ChangeKind::Created | ChangeKind::Modified);
+        published.get(&event.path).is_some_and(|(last, at)| {
+            at.elapsed() < ECHO
+                && last.to == event.to
+                && (last.kind == event.kind || (written(last.kind) && written(event.kind)))
+        })
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ChangeEvent> {
+        self.sender.subscribe()
+    }
+}
+
+/// Response body that sends whatever arrives on the channel, ending when the sender is dropped
+pub struct EventStream {
+    receiver: mpsc::Receiver<Bytes>,
+}
+
+impl EventStream {
+    pub fn new(receiver: mpsc::Receiver<Bytes>) -> Self {
+        Self { receiver }
+    }
+}
+
+impl Body for EventStream {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+        self.receiver.poll_recv(cx).map(|chunk| chunk.map(|chunk| Ok(Frame::data(chunk))))
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        SizeHint::default()
+    }
+}
+
+/// One server-sent event
+pub fn message(event: &ChangeEvent) -> Bytes {
+    Bytes::from(format!("event: change\ndata: {}\n\n", serde_json::to_string(event).unwrap()))
+}
+
+/// Watches the storage directory `root`, sending what changed below it except in `hidden`
+/// (a folder at the top of the storage). keep the watcher for as long as events are wanted
+pub fn watch(root: &Path, hidden: &'static str) -> notify::Result<(RecommendedWatcher, mpsc::UnboundedReceiver<ChangeEvent>)> {
+    let root = root.canonicalize()?;
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let watched = root.clone();
+    let mut watcher = notify::recommended_watcher(move |result: notify::Result<notify::Event>| {
+        let event = match result {
+            Ok(event) => event,
+            Err(e) => {
+                eprintln!("Error watching the storage directory: {}", e);
+                return;
+            }
+        };
+        for change in changes(&watched, hidden, event) {
+            let _ = sender.send(change);
+        }
+    })?;
+    watcher.watch(&root, RecursiveMode::Recursive)?;
+    Ok((watcher, receiver))
+}
+
+/// Drops the halves of a rename that was also reported whole, some platforms send all three
+pub fn merge_renames(changes: &mut Vec<ChangeEvent>) {
+    let renames: Vec<(String, String)> = changes
+        .iter()
+        .filter_map(|change| Some((change.path.clone(), change.to.clone()?)))
+        .collect();
+    changes.retain(|change| {
+        !renames.iter().any(|(from, to)| {
+            (change.kind == ChangeKind::Deleted && change.path == *from)
+                || (change.kind == ChangeKind::Created && change.path == *to)
+        })
+    });
+}
+
+/// What a filesystem event means for the storage
+fn changes(root: &Path, hidden: &str, event: notify::Event) -> Vec<ChangeEvent> {
+    let visible = |path: &PathBuf| -> Option<String> {
+        let rel = path.strip_prefix(root).ok()?.to_str()?.replace('\\', "/");
+        let rel = paths::normalize(&rel)?;
+        let top = rel.split('/').next().unwrap_or_default();
+        let name = rel.rsplit('/').next().unwrap_or_default();
+        // uploads in progress only show up once they are moved into place
+        (!rel.is_empty() && top != hidden && !storage::is_upload_temp(name)).then_some(rel)
+    };
+    let change = |kind: ChangeKind, path: String| ChangeEvent { kind, path, to: None };
+    match event.kind {
+        // moves into or out of a hidden folder (like the trash) look like deletes and creates
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if event.paths.len() == 2 => {
+            match (visible(&event.paths[0]), visible(&event.paths[1])) {
+                (Some(from), Some(to)) => vec![ChangeEvent {
+                    kind: ChangeKind::Renamed,
+                    path: from,
+                    to: Some(to),
+                }],
+                (Some(from), None) => vec![change(ChangeKind::Deleted, from)],
+                (None, Some(to)) => vec![change(ChangeKind::Created, to)],
+                (None, None) => Vec::new(),
+            }
+        }
+        EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+            event.paths.iter().filter_map(visible).map(|path| change(ChangeKind::Deleted, path)).collect()
+        }
+        EventKind::Modify(ModifyKind::Name(_)) | EventKind::Create(_) => {
+            event.paths.iter().filter_map(visible).map(|path| change(ChangeKind::Created, path)).collect()
+        }
+        // permissions and access times don't show in listings
+        EventKind::Modify(ModifyKind::Metadata(_)) => Vec::new(),
+        EventKind::Modify(_) => {
+            event.paths.iter().filter_map(visible).map(|path| change(ChangeKind::Modified, path)).collect()
+        }
+        EventKind::Remove(_) => {
+            event.paths.iter().filter_map(visible).map(|path| change(ChangeKind::Deleted, path)).collect()
+        }
+        _ => Vec::new(),
+    }
+}
@@ -0,0 +1,370 @@
+/// fulltext.rs - an index of the words in text files, for searching inside them
+/// - files up to `max_file_bytes` that are valid UTF-8 without NUL bytes count as text. their
+///   words (runs of letters and digits, lowercased, 2 to 64 characters) are indexed with how
+///   often they appear
+/// - `update` looks again at whatever is at a path (a file, a folder or nothing) in the
+///   background, the server calls it after every change. `reconcile` does the same for the
+///   whole storage on startup, only re-reading files whose size or modification time changed
+///   since the index was saved
+/// - the index lives in memory and is saved as json to `index_file` after changes
+use serde::{Deserialize, Serialize};
+use shared::{FileEntry, TextMatch, TextSnippet};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::acl::Acl;
+use crate::config::FullTextConfig;
+use crate::paths;
+use crate::storage::{self, Metadata, StorageBackend};
+
+pub const MAX_RESULTS: usize = 100;
+const MAX_SNIPPETS: usize = 3;
+/// longest snippet in characters, longer lines are cut around the first match
+const SNIPPET_CHARS: usize = 160;
+const MIN_WORD_CHARS: usize = 2;
+const MAX_WORD_CHARS: usize = 64;
+
+/// What the index knows about one file, files that aren't text have no words
+#[derive(Serialize, Deserialize)]
+struct Doc {
+    size: u64,
+    modified: u64,
+    /// word -> how often it appears
+    words: HashMap<String, u32>,
+}
+
+#[derive(Default)]
+struct Index {
+    docs: HashMap<String, Doc>,
+    /// word -> files containing it
+    postings: HashMap<String, HashSet<String>>,
+}
+
+impl Index {
+    fn insert(&mut self, path: String, doc: Doc) {
+        self.remove(&path);
+        for word in doc.words.keys() {
+            self.postings.entry(word.clone()).or_default().insert(path.clone());
+        }
+        self.docs.insert(path, doc);
+    }
+
+    fn remove(&mut self, path: &str) {
+        let Some(doc) = self.docs.remove(path) else {
+            return;
+        };
+        for word in doc.words.keys() {
+            if let Some(paths) = self.postings.get_mut(word) {
+                paths.remove(path);
+                if paths.is_empty() {
+                    self.postings.remove(word);
+                }
+            }
+        }
+    }
+}
+
+/// Cheap to clone, clones share the index
+#[derive(Clone)]
+pub struct FullText {
+    store: Arc<dyn StorageBackend>,
+    index: Arc<Mutex<Index>>,
+    /// the index file is written by one save at a time
+    save_lock: Arc<tokio::sync::Mutex<()>>,
+    index_file: PathBuf,
+    max_file_bytes: u64,
+}
+
+impl FullText {
+    /// Loads the index saved by an earlier run, if there is one - call `reconcile` before
+    /// trusting it
+    pub fn open(store: Arc<dyn StorageBackend>, config: &FullTextConfig) -> io::Result<Self> {
+        let mut index = Index::default();
+        match std::fs::read(&config.index_file) {
+            Ok(contents) => match serde_json::from_slice::<HashMap<String, Doc>>(&contents) {
+                Ok(docs) => {
+                    for (path, doc) in docs {
+                        index.insert(path, doc);
+                    }
+                }
+                Err(e) => eprintln!("Ignoring the unreadable full-text index {:?}: {}", config.index_file, e),
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        Ok(Self {
+            store,
+            index: Arc::new(Mutex::new(index)),
+            save_lock: Arc::new(tokio::sync::Mutex::new(())),
+            index_file: config.index_file.clone(),
+            max_file_bytes: config.max_file_bytes,
+        })
+    }
+
+    /// Re-reads whatever is at `path` now in the background, call it after changing it
+    pub fn update(&self, path: &str) {
+        let full_text = self.clone();
+        let path = path.to_string();
+        tokio::spawn(async move {
+            if let Err(e) = full_text.reindex(&path, true).await {
+                eprintln!("Unable to update the full-text index of '{}': {}", path, e);
+            }
+        });
+    }
+
+    /// Brings the index in line with the whole storage, returns how many files were read
+    pub async fn reconcile(&self) -> io::Result<usize> {
+        self.reindex("", false).await
+    }
+
+    /// Files below `dir` that `user` can read with every one of `words` in them, most
+    /// occurrences first, and whether there were more than `MAX_RESULTS`
+    pub async fn search(&self, words: &[String], dir: &str, acl: &Acl, user: Option<&str>) -> (Vec<TextMatch>, bool) {
+        let mut found: Vec<(String, u32)> = {
+            let index = self.index.lock().unwrap();
+            let Some(mut postings) = words
+                .iter()
+                .map(|word| index.postings.get(word))
+                .collect::<Option<Vec<_>>>()
+            else {
+                return (Vec::new(), false);
+            };
+            // walk the rarest word's files, checking the others
+            postings.sort_by_key(|paths| paths.len());
+            let Some((rarest, others)) = postings.split_first() else {
+                return (Vec::new(), false);
+            };
+            rarest
+                .iter()
+                .filter(|path| paths::is_below(path, dir) && others.iter().all(|paths| paths.contains(*path)))
+                .filter(|path| acl.permissions(user, path).read)
+                .map(|path| {
+                    let doc = &index.docs[path];
+                    let count = words.iter().filter_map(|word| doc.words.get(word)).sum();
+                    (path.clone(), count)
+                })
+                .collect()
+        };
+        found.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        let truncated = found.len() > MAX_RESULTS;
+        found.truncate(MAX_RESULTS);
+
+        let mut results = Vec::new();
+        for (path, _) in found {
+            // gone since it was indexed
+            let Ok(metadata) = self.store.stat(&path).await else {
+                continue;
+            };
+            let snippets = match self.read_text(&path).await {
+                Some(text) => snippets(&text, words),
+                None => Vec::new(),
+            };
+            let permissions = acl.permissions(user, &path);
+            results.push(TextMatch {
+                entry: FileEntry {
+                    name: path.rsplit('/').next().unwrap_or_default().to_string(),
+                    is_dir: false,
+                    size: metadata.size,
+                    modified: metadata.modified,
+                    can_read: permissions.read,
+                    can_write: permissions.write,
+                    tags: Vec::new(),
+                    note: None,
+                    sha256: None,
+                },
+                path,
+                snippets,
+            });
+        }
+        (results, truncated)
+    }
+
+    /// Drops what the index has at or below `path` but the storage doesn't and reads the
+    /// files that are new, or changed unless `force` reads them all. returns how many were read
+    async fn reindex(&self, path: &str, force: bool) -> io::Result<usize> {
+        let files = list_files(self.store.as_ref(), path).await?;
+        let mut changed = false;
+        {
+            let mut index = self.index.lock().unwrap();
+            let gone: Vec<String> = index
+                .docs
+                .keys()
+                .filter(|known| paths::is_below(known, path) && !files.contains_key(*known))
+                .cloned()
+                .collect();
+            changed |= !gone.is_empty();
+            for known in gone {
+                index.remove(&known);
+            }
+        }
+
+        let mut read = 0;
+        for (file, metadata) in files {
+            let unchanged = self.index.lock().unwrap().docs.get(&file).is_some_and(|doc| {
+                doc.size == metadata.size && doc.modified == metadata.modified
+            });
+            if unchanged && !force {
+                continue;
+            }
+            let doc = self.read_doc(&file, &metadata).await;
+            self.index.lock().unwrap().insert(file, doc);
+            read += 1;
+            changed = true;
+        }
+        if changed {
+            self.save().await?;
+        }
+        Ok(read)
+    }
+
+    async fn read_doc(&self, path: &str, metadata: &Metadata) -> Doc {
+        let mut doc = Doc {
+            size: metadata.size,
+            modified: metadata.modified,
+            words: HashMap::new(),
+        };
+        if metadata.size > self.max_file_bytes {
+            return doc;
+        }
+        if let Some(text) = self.read_text(path).await {
+            for (start, end) in word_ranges(&text) {
+                *doc.words.entry(text[start..end].to_lowercase()).or_default() += 1;
+            }
+        }
+        doc
+    }
+
+    /// Contents of the file at `path`, if it is text
+    async fn read_text(&self, path: &str) -> Option<String> {
+        let contents = storage::read_all(self.store.as_ref(), path).await.ok()?;
+        if contents.len() as u64 > self.max_file_bytes || contents.contains(&0) {
+            return None;
+        }
+        String::from_utf8(contents).ok()
+    }
+
+    async fn save(&self) -> io::Result<()> {
+        let _guard = self.save_lock.lock().await;
+        let contents = serde_json::to_vec(&self.index.lock().unwrap().docs).map_err(io::Error::other)?;
+        // a crash halfway leaves the old index in place
+        let partial = self.index_file.with_extension("partial");
+        tokio::fs::write(&partial, contents).await?;
+        tokio::fs::rename(&partial, &self.index_file).await
+    }
+}
+
+/// The distinct words of a query, the same way file contents are split
+pub fn query_words(query: &str) -> Vec<String> {
+    let mut words: Vec<String> = Vec::new();
+    for (start, end) in word_ranges(query) {
+        let word = query[start..end].to_lowercase();
+        if !words.contains(&word) {
+            words.push(word);
+        }
+    }
+    words
+}
+
+/// Every file at or below `path` (which may be a file itself), nothing when it doesn't exist
+async fn list_files(store: &dyn StorageBackend, path: &str) -> io::Result<HashMap<String, Metadata>> {
+    let mut files = HashMap::new();
+    match store.stat(path).await {
+        Ok(metadata) if !metadata.is_dir => {
+            files.insert(path.to_string(), metadata);
+            return Ok(files);
+        }
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(files),
+        Err(e) => return Err(e),
+    }
+    let mut pending = vec![path.to_string()];
+    while let Some(dir) = pending.pop() {
+        let entries = match store.list(&dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let entry_path = paths::join(&dir, &entry.name);
+            if entry.metadata.is_dir {
+                pending.push(entry_path);
+            } else {
+                files.insert(entry_path, entry.metadata);
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Byte ranges of the words in `text`
+fn word_ranges(text: &str) -> Vec<(usize, usize)> {
+    let mut ranges = Vec::new();
+    let mut word: Option<(usize, usize)> = None;
+    for (i, c) in text.char_indices() {
+        match (&mut word, c.is_alphanumeric()) {
+            (Some((_, chars)), true) => *chars += 1,
+            (None, true) => word = Some((i, 1)),
+            (Some((start, chars)), false) => {
+                if (MIN_WORD_CHARS..=MAX_WORD_CHARS).contains(chars) {
+                    ranges.push((*start, i));
+                }
+                word = None;
+            }
+            (None, false) => {}
+        }
+    }
+    if let Some((start, chars)) = word {
+        if (MIN_WORD_CHARS..=MAX_WORD_CHARS).contains(&chars) {
+            ranges.push((start, text.len()));
+        }
+    }
+    ranges
+}
+
+/// The first lines of `text` with any of `words` in them
+fn snippets(text: &str, words: &[String]) -> Vec<TextSnippet> {
+    let mut snippets = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        let hits: Vec<(usize, usize)> = word_ranges(line)
+            .into_iter()
+            .filter(|&(start, end)| words.contains(&line[start..end].to_lowercase()))
+            .collect();
+        let Some(&(first, _)) = hits.first() else {
+            continue;
+        };
+        let (text, offset) = cut(line, first);
+        let highlights = hits
+            .into_iter()
+            .filter(|&(start, end)| start >= offset && end <= offset + text.len())
+            .map(|(start, end)| (start - offset, end - offset))
+            .collect();
+        snippets.push(TextSnippet {
+            line: i as u64 + 1,
+            text: text.to_string(),
+            highlights,
+        });
+        if snippets.len() == MAX_SNIPPETS {
+            break;
+        }
+    }
+    snippets
+}
+
+/// At most `SNIPPET_CHARS` of `line`, starting a little before the byte offset `at`, and the
+/// byte offset the piece starts at
+fn cut(line: &str, at: usize) -> (&str, usize) {
+    if line.chars().count() <= SNIPPET_CHARS {
+        return (line, 0);
+    }
+    let start = line[..at]
+        .char_indices()
+        .rev()
+        .nth(SNIPPET_CHARS / 4)
+        .map_or(0, |(i, _)| i);
+    let rest = &line[start..];
+    let end = rest.char_indices().nth(SNIPPET_CHARS).map_or(rest.len(), |(i, _)| i);
+    (&rest[..end], start)
+}
@@ -0,0 +1,193 @@
+/// html.rs - small helpers for the pages served to browsers
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+
+/// characters escaped in a single path segment of a link
+const SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Percent-encodes each segment of a normalized path for use in a URL
+pub fn encode_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| utf8_percent_encode(segment, SEGMENT).to_string())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Wraps `body` in a minimal page
+pub fn page(title: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{title}</title>\
+         <style>body{{font-family:sans-serif;margin:2em}}td{{padding:0 1em}}\
+         .err{{color:#b00}}</style></head>\n<body>\n<h1>{title}</h1>\n{body}\n</body></html>\n",
+        title = escape(title),
+        body = body
+    )
+}
+
+/// Human readable byte count
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// one line of a folder listing
+pub struct Row {
+    pub name: String,
+    pub href: String,
+    pub is_dir: bool,
+    pub size: u64,
+    /// unix timestamp (seconds)
+    pub modified: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    Name,
+    Size,
+    Modified,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Sort {
+    pub key: SortKey,
+    pub descending: bool,
+}
+
+impl Sort {
+    /// Reads `?sort=name|size|modified&order=asc|desc`, defaulting to name ascending
+    pub fn from_query(query: Option<&str>) -> Self {
+        let mut sort = Sort {
+            key: SortKey::Name,
+            descending: false,
+        };
+        for (key, value) in url::form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+            match (key.as_ref(), value.as_ref()) {
+                ("sort", "size") => sort.key = SortKey::Size,
+                ("sort", "modified") => sort.key = SortKey::Modified,
+                ("sort", _) => sort.key = SortKey::Name,
+                ("order", "desc") => sort.descending = true,
+                ("order", _) => sort.descending = false,
+                _ => {}
+            }
+        }
+        sort
+    }
+
+    /// Sorts folders first, then by the chosen column
+    pub fn apply(&self, rows: &mut [Row]) {
+        rows.sort_by(|a, b| {
+            let ordering = match self.key {
+                SortKey::Name => a.name.to_lowercase().cmp(&b.name.to_lowercase()),
+                SortKey::Size => a.size.cmp(&b.size),
+                SortKey::Modified => a.modified.cmp(&b.modified),
+            };
+            let ordering = if self.descending { ordering.reverse() } else { ordering };
+            b.is_dir.cmp(&a.is_dir).then(ordering)
+        });
+    }
+
+    /// Header link for `key` - clicking the current column flips the order
+    fn header(&self, key: SortKey, label: &str) -> String {
+        let name = match key {
+            SortKey::Name => "name",
+            SortKey::Size => "size",
+            SortKey::Modified => "modified",
+        };
+        let (order, arrow) = match (self.key == key, self.descending) {
+            (true, false) => ("desc", " &#9650;"),
+            (true, true) => ("asc", " &#9660;"),
+            (false, _) => ("asc", ""),
+        };
+        format!("<th><a href=\"?sort={}&amp;order={}\">{}</a>{}</th>", name, order, label, arrow)
+    }
+}
+
+/// Table of `rows`, with a `../` row first when `parent_href` is given
+pub fn table(rows: &[Row], parent_href: Option<&str>, sort: &Sort) -> String {
+    let mut html = format!(
+        "<table>\n<tr>{}{}{}</tr>\n",
+        sort.header(SortKey::Name, "Name"),
+        sort.header(SortKey::Size, "Size"),
+        sort.header(SortKey::Modified, "Modified")
+    );
+    if let Some(parent_href) = parent_href {
+        html.push_str(&format!(
+            "<tr><td><a href=\"{}\">../</a></td><td></td><td></td></tr>\n",
+            escape(parent_href)
+        ));
+    }
+    for row in rows {
+        let (label, size) = if row.is_dir {
+            (format!("{}/", row.name), String::new())
+        } else {
+            (row.name.clone(), format_size(row.size))
+        };
+        html.push_str(&format!(
+            "<tr><td><a href=\"{}\">{}</a></td><td>{}</td><td>{}</td></tr>\n",
+            escape(&row.href),
+            escape(&label),
+            size,
+            format_time(row.modified)
+        ));
+    }
+    html.push_str("</table>");
+    html
+}
+
+/// `home / alice / docs` style links, `base` is the url of the root folder (ending in '/')
+pub fn breadcrumbs(base: &str, root_label: &str, path: &str) -> String {
+    let mut html = format!("<p><a href=\"{}\">{}</a>", escape(base), escape(root_label));
+    let mut href = base.to_string();
+    for segment in path.split('/').filter(|s| !s.is_empty()) {
+        href.push_str(&encode_path(segment));
+        href.push('/');
+        html.push_str(&format!(
+            " / <a href=\"{}\">{}</a>",
+            escape(&href),
+            escape(segment)
+        ));
+    }
+    html.push_str("</p>");
+    html
+}
+
+fn format_time(unix: u64) -> String {
+    chrono::DateTime::from_timestamp(unix as i64, 0)
+        .map(|t| t.format("%Y-%m-%d %H:%M UTC").to_string())
+        .unwrap_or_default()
+}
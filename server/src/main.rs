@@ -1,73 +1,485 @@
 use bytes::Bytes;
+use clap::Parser;
+use http_body_util::combinators::BoxBody;
 use http_body_util::{BodyExt, Full};
 use hyper::{Request, Response};
 use std::sync::Arc;
-// use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
+use tokio_rustls::TlsAcceptor;
 use hyper::server::conn::http1::Builder;
 use hyper_util::rt::TokioIo;
 use hyper::service::service_fn;
+mod acl;
+mod archive;
+mod auth;
+mod compression;
+mod config;
+mod events;
+mod fulltext;
+mod html;
+mod metadata;
+mod paths;
+mod quota;
 mod server;
-use std::env;
+mod s3;
+mod search;
+mod share;
+mod storage;
+mod tls;
+mod trash;
+mod unpack;
+mod versions;
+mod webdav;
+use crate::auth::{Auth, UsersFile};
+use crate::config::{Cli, Command, ServerConfig, StorageBackendKind};
 use crate::server::Server;
+use crate::storage::{Dedup, LocalDisk, Memory, StorageBackend};
+use crate::versions::Versions;
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
-    let listener = TcpListener::bind("127.0.0.1:3333").await.unwrap();
+    let mut cli = Cli::parse();
+    let command = cli.command.take();
+    let config = match ServerConfig::load(cli) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    if let Some(command) = command {
+        return run_command(command, &config);
+    }
 
-    let args: Vec<String> = env::args().collect();
+    let mut server = Server::new(&config.name, config.port).await;
+    match config.storage_backend {
+        StorageBackendKind::Local => {
+            if let Some(storage_dir) = &config.storage_dir {
+                server.set_storage_dir(storage_dir)?;
+            }
+        }
+        StorageBackendKind::Memory => {
+            if config.storage_dir.is_some() {
+                println!("Warning: storage_dir has no effect with the memory storage backend");
+            }
+            server.set_storage(Box::new(Memory::new()));
+            println!("Keeping files in memory, they are lost when the server stops");
+        }
+        StorageBackendKind::Dedup => {
+            let storage_dir = config
+                .storage_dir
+                .clone()
+                .unwrap_or_else(Server::get_default_storage_path);
+            server.set_storage(Box::new(Dedup::open(storage_dir)?));
+        }
+    }
+    if config.trash.retention_days > 0 {
+        server.set_trash(config.trash.retention_days);
+    }
+    if let Some(full_text_config) = &config.full_text {
+        server.set_full_text(full_text_config)?;
+        println!("Full-text index at {:?}", full_text_config.index_file);
+    }
+    if let Some(metadata_config) = &config.metadata_index {
+        server.set_metadata_index(metadata_config)?;
+        println!("Metadata index at {:?}", metadata_config.db_file);
+    }
+    if let Some(versions_config) = config.versions.as_ref().filter(|versions| versions.enabled()) {
+        let store = side_store(config.storage_backend, &versions_config.dir)?;
+        server.set_versions(Versions::new(store, versions_config));
+        println!("Keeping previous versions in {:?}", versions_config.dir);
+    }
+    server.set_limits(config.limits.clone());
+    server.set_quotas(config.quotas.clone());
+    if let Some(auth_config) = &config.auth {
+        server.set_auth(Auth::new(auth_config)?);
+        println!("Authentication enabled, users from {:?}", auth_config.users_file);
+    } else if !config.acl.shared.is_empty() || !config.acl.admins.is_empty() {
+        println!("Warning: [acl] has no effect without authentication, everyone can access everything");
+    }
+    server.set_acl(config.acl.clone()).await?;
+    // before serving, listings come from the index
+    server.reconcile_metadata().await;
 
+    let tls_acceptor = match &config.tls {
+        Some(tls_config) => Some(tls::load_acceptor(tls_config)?),
+        None => None,
+    };
+    let scheme = if tls_acceptor.is_some() { "https" } else { "http" };
+    if let Some(share_config) = &config.shares {
+        server.set_shares(share_config.clone(), scheme)?;
+    }
+    server.set_web_index(config.web_index);
+    server.set_webdav(config.webdav);
+    if let Some(s3_config) = &config.s3 {
+        server.set_s3(s3_config.clone())?;
+    }
 
-    let server = Server::new("server1", 3333).await;
-    let binding = &String::from("./storage");
-    // let storage_dir = args.get(1).unwrap_or(binding);
+    // bind everything up front so a bad address fails before we start serving
+    let mut listeners = Vec::new();
+    for ip in &config.bind {
+        let listener = TcpListener::bind((*ip, server.port)).await?;
+        println!(
+            "Server '{}' listening on {}://{}",
+            server.name,
+            scheme,
+            listener.local_addr()?
+        );
+        listeners.push(listener);
+    }
 
-    println!("Server listening on http://127.0.0.1:{}", server.port);
     // Wrap `Server` in an `Arc` for shared ownership
     let server_arc = Arc::new(server);
+    // changes made straight to the storage directory, kept for as long as the server runs
+    let _watcher = match config.storage_backend {
+        StorageBackendKind::Local => {
+            let storage_dir = config
+                .storage_dir
+                .clone()
+                .unwrap_or_else(Server::get_default_storage_path);
+            match events::watch(&storage_dir, trash::TRASH_DIR) {
+                Ok((watcher, changes)) => {
+                    println!("Watching {:?} for changes", storage_dir);
+                    tokio::spawn(apply_disk_changes(Arc::clone(&server_arc), changes));
+                    Some(watcher)
+                }
+                Err(e) => {
+                    eprintln!("Unable to watch the storage directory, changes made to it won't be noticed: {}", e);
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+    tokio::spawn(purge_trash(Arc::clone(&server_arc)));
+    let reconciling = Arc::clone(&server_arc);
+    tokio::spawn(async move { reconciling.reconcile_full_text().await });
+    let connection_limit = config
+        .limits
+        .max_connections
+        .map(|max| Arc::new(Semaphore::new(max)));
+
+    let mut tasks = Vec::new();
+    for listener in listeners {
+        tasks.push(tokio::spawn(serve(
+            listener,
+            Arc::clone(&server_arc),
+            connection_limit.clone(),
+            tls_acceptor.clone(),
+        )));
+    }
+    for task in tasks {
+        task.await.expect("listener task panicked")?;
+    }
+    Ok(())
+}
+
+/// Purges expired trash items now and every hour after
+async fn purge_trash(server: Arc<Server>) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(60 * 60));
+    loop {
+        interval.tick().await;
+        server.purge_expired_trash().await;
+    }
+}
+
+/// Applies changes the watcher noticed, a burst of them (like a file being written) at once
+async fn apply_disk_changes(server: Arc<Server>, mut changes: tokio::sync::mpsc::UnboundedReceiver<shared::ChangeEvent>) {
+    while let Some(first) = changes.recv().await {
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        let mut batch = vec![first];
+        while let Ok(change) = changes.try_recv() {
+            if !batch.contains(&change) {
+                batch.push(change);
+            }
+        }
+        events::merge_renames(&mut batch);
+        for change in batch {
+            server.disk_changed(change).await;
+        }
+    }
+}
+
+/// Store for data kept next to the files (like old versions), the same kind as the storage
+/// backend so nothing ends up on disk when files are kept in memory
+fn side_store(kind: StorageBackendKind, dir: &std::path::Path) -> std::io::Result<Box<dyn StorageBackend>> {
+    Ok(match kind {
+        StorageBackendKind::Local => {
+            std::fs::create_dir_all(dir)?;
+            Box::new(LocalDisk::new(dir.to_path_buf()))
+        }
+        StorageBackendKind::Memory => Box::new(Memory::new()),
+        StorageBackendKind::Dedup => Box::new(Dedup::open(dir.to_path_buf())?),
+    })
+}
+
+/// Runs a one-off user management command against the configured users file
+fn run_command(command: Command, config: &ServerConfig) -> std::io::Result<()> {
+    let auth_config = config.auth.clone().unwrap_or_default();
+    let mut users = UsersFile::load(&auth_config.users_file)?;
+    match command {
+        Command::AddUser { username, password } => {
+            let password = match password {
+                Some(password) => password,
+                None => {
+                    println!("Password for {}:", username);
+                    let mut line = String::new();
+                    std::io::stdin().read_line(&mut line)?;
+                    line.trim_end_matches(['\r', '\n']).to_string()
+                }
+            };
+            if password.is_empty() {
+                eprintln!("Password can't be empty");
+                std::process::exit(2);
+            }
+            users.set_password(&username, &password)?;
+            println!("Saved user '{}' to {:?}", username, auth_config.users_file);
+        }
+        Command::RemoveUser { username } => {
+            if users.users.remove(&username).is_none() {
+                eprintln!("No user named '{}'", username);
+                std::process::exit(1);
+            }
+            println!("Removed user '{}' from {:?}", username, auth_config.users_file);
+        }
+    }
+    users.save(&auth_config.users_file)
+}
+
+/// Accepts connections on one listener until it fails
+async fn serve(
+    listener: TcpListener,
+    server_arc: Arc<Server>,
+    connection_limit: Option<Arc<Semaphore>>,
+    tls_acceptor: Option<TlsAcceptor>,
+) -> std::io::Result<()> {
     loop {
-        let (stream, _) = listener.accept().await?; // Use tokio::net::TcpStream
+        // hold a permit for the lifetime of the connection
+        let permit = match &connection_limit {
+            Some(limit) => Some(
+                Arc::clone(limit)
+                    .acquire_owned()
+                    .await
+                    .expect("connection semaphore closed"),
+            ),
+            None => None,
+        };
+        let (stream, peer) = listener.accept().await?; // Use tokio::net::TcpStream
         let server_arc = Arc::clone(&server_arc);
-        let io = TokioIo::new(stream);
+        let tls_acceptor = tls_acceptor.clone();
         tokio::task::spawn(async move {
-            if let Err(err) = Builder::new()
-                .serve_connection(
-                    io,
-                    service_fn(move | req| handle_request(req, Arc::clone(&server_arc))),
-                )
-                .await
-            {
-                eprintln!("Error serving connection: {:?}", err);
+            match tls_acceptor {
+                Some(acceptor) => match acceptor.accept(stream).await {
+                    Ok(tls_stream) => serve_connection(tls_stream, server_arc).await,
+                    Err(err) => eprintln!("TLS handshake with {} failed: {:?}", peer, err),
+                },
+                None => serve_connection(stream, server_arc).await,
             }
+            drop(permit);
         });
     }
 }
 
+/// Serves HTTP/1 on an accepted (plain or TLS) stream
+async fn serve_connection<S>(stream: S, server_arc: Arc<Server>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let io = TokioIo::new(stream);
+    if let Err(err) = Builder::new()
+        .serve_connection(
+            io,
+            service_fn(move | req| route(req, Arc::clone(&server_arc))),
+        )
+        .await
+    {
+        eprintln!("Error serving connection: {:?}", err);
+    }
+}
+
+/// `/events`, `/archive` and `/deltafile` stream their responses, everything else is answered
+/// in one go
+async fn route(
+    req: Request<hyper::body::Incoming>,
+    server: Arc<Server>,
+) -> Result<Response<BoxBody<Bytes, std::io::Error>>, hyper::Error> {
+    if req.uri().path() == "/events" {
+        println!("Received events request");
+        return Ok(server.handle_events(req.headers()).map(|body| body.map_err(|never| match never {}).boxed()));
+    }
+    if req.uri().path() == "/archive" {
+        println!("Received archive request");
+        return Ok(server.handle_archive(req.headers()).await);
+    }
+    if req.uri().path() == "/deltafile" {
+        println!("Received deltafile request");
+        return Ok(server.handle_deltafile(req).await);
+    }
+    // S3 clients expect compressed bodies to be stored as they are, everything else tells
+    // clients they may compress uploads
+    let compressed_uploads = !req.uri().path().starts_with(s3::PREFIX);
+    let mut response = handle_request(req, server).await?;
+    if compressed_uploads {
+        response.headers_mut().insert(
+            hyper::header::ACCEPT_ENCODING,
+            hyper::header::HeaderValue::from_static(shared::encoding::SUPPORTED),
+        );
+    }
+    Ok(response.map(|body| body.map_err(|never| match never {}).boxed()))
+}
+
 async fn handle_request(
         req: Request<hyper::body::Incoming>,
         server: Arc<Server>,
     ) -> Result<Response<Full<Bytes>>, hyper::Error> {
+        // share links are opened by people without an account
+        if req.uri().path().starts_with("/s/") {
+            println!("Received share link request");
+            return server.handle_sharelink(req).await;
+        }
+        // the web index, WebDAV and S3 do their own authentication
+        let path = req.uri().path();
+        if path == "/files" || path.starts_with("/files/") {
+            println!("Received web index request");
+            return server.handle_webindex(req).await;
+        }
+        if path == webdav::PREFIX || path.starts_with(&format!("{}/", webdav::PREFIX)) {
+            println!("Received WebDAV {} request", req.method());
+            return server.handle_webdav(req).await;
+        }
+        if path == s3::PREFIX || path.starts_with(&format!("{}/", s3::PREFIX)) {
+            println!("Received S3 {} request", req.method());
+            return server.handle_s3(req).await;
+        }
+        if path == "/" {
+            return server.handle_root();
+        }
+        if req.uri().path() == "/login" {
+            let whole_body = req.collect().await?.to_bytes();
+            println!("Received login request");
+            return server.handle_login(whole_body).await;
+        }
+
+        // everything else needs a valid token when authentication is on
+        let user = match server.authenticate(req.headers()) {
+            Ok(user) => user,
+            Err(response) => return Ok(*response),
+        };
+        if let Some(user) = &user {
+            println!("Request from user '{}'", user);
+        }
+        let user = user.as_deref();
+
         match req.uri().path() {
+            "/logout" => server.handle_logout(req.headers()),
+            "/share" => {
+                let (parts, body) = req.into_parts();
+                let whole_body = body.collect().await?.to_bytes();
+                println!("Received share request");
+                server.handle_createshare(whole_body, &parts.headers, user).await
+            }
             "/addfile" => {
                 let (parts, body) = req.into_parts();
                 let req_headers = parts.headers;
             
                 println!("Received addfile request");
-                server.handle_addfile(body, req_headers).await
+                server.handle_addfile(body, req_headers, user).await
             }
-            "/getfiles" => {
-                
+            "/linkfile" => {
+                let (parts, _) = req.into_parts();
+                println!("Received linkfile request");
+                server.handle_linkfile(parts.headers, user).await
+            }
+            "/addfolder" => {
                 let whole_body = req.collect().await?.to_bytes();
+                println!("Received addfolder request");
+                server.handle_addfolder(whole_body, user).await
+            }
+            "/signature" => {
+                let (parts, _) = req.into_parts();
+                println!("Received signature request");
+                server.handle_signature(parts.headers, user).await
+            }
+            "/patchfile" => {
+                let (parts, body) = req.into_parts();
+                println!("Received patchfile request");
+                server.handle_patchfile(body, parts.headers, user).await
+            }
+            "/getfiles" => {
+                let (parts, _) = req.into_parts();
                 println!("Received getfiles request");
-                server.handle_getfiles(whole_body).await
+                server.handle_getfiles(parts.headers, user).await
             }
             "/downloadfile" => {
-                let (parts, body) = req.into_parts();
-                let req_headers = parts.headers;
+                let (parts, _) = req.into_parts();
                 println!("Received downloadfile request");
-                server.handle_downloadfile(body, req_headers).await
+                server.handle_downloadfile(parts.headers, user).await
+            }
+            "/extract" => {
+                let (parts, _) = req.into_parts();
+                println!("Received extract request");
+                server.handle_extract(parts.headers, user).await
+            }
+            "/delete" => {
+                let (parts, _) = req.into_parts();
+                println!("Received delete request");
+                server.handle_delete(parts.headers, user).await
+            }
+            "/trash" => {
+                println!("Received trash request");
+                server.handle_trash(user).await
+            }
+            "/restoretrash" => {
+                let (parts, _) = req.into_parts();
+                println!("Received restoretrash request");
+                server.handle_restoretrash(parts.headers, user).await
+            }
+            "/purgetrash" => {
+                let (parts, _) = req.into_parts();
+                println!("Received purgetrash request");
+                server.handle_purgetrash(parts.headers, user).await
+            }
+            "/emptytrash" => {
+                println!("Received emptytrash request");
+                server.handle_emptytrash(user).await
+            }
+            "/versions" => {
+                let (parts, _) = req.into_parts();
+                println!("Received versions request");
+                server.handle_versions(parts.headers, user).await
+            }
+            "/restoreversion" => {
+                let (parts, _) = req.into_parts();
+                println!("Received restoreversion request");
+                server.handle_restoreversion(parts.headers, user).await
+            }
+            "/usage" => {
+                let (parts, _) = req.into_parts();
+                println!("Received usage request");
+                server.handle_usage(parts.headers, user).await
+            }
+            "/search" => {
+                let whole_body = req.into_body().collect().await?.to_bytes();
+                println!("Received search request");
+                server.handle_search(whole_body, user).await
+            }
+            "/textsearch" => {
+                let whole_body = req.into_body().collect().await?.to_bytes();
+                println!("Received textsearch request");
+                server.handle_textsearch(whole_body, user).await
+            }
+            "/tags" => {
+                let whole_body = req.into_body().collect().await?.to_bytes();
+                println!("Received tags request");
+                server.handle_tags(whole_body, user).await
+            }
+            "/note" => {
+                let whole_body = req.into_body().collect().await?.to_bytes();
+                println!("Received note request");
+                server.handle_note(whole_body, user).await
             }
 
             _ => server.handle_std_request(),
         }
     }
-
@@ -0,0 +1,453 @@
+/// metadata.rs - a database of everything in the storage, so listings don't walk folders
+/// - one sqlite row per file and folder with its size, modification time and, for files, the
+///   sha256 of its contents. tags and notes are kept per path in their own tables, they go
+///   when the path does and follow it when it is moved
+/// - `update` brings the rows at, below and above a path in line with the storage right away,
+///   the server calls it after every change. sha256s are filled in afterwards in the background
+/// - `reconcile` does the same for the whole storage on startup, catching whatever changed
+///   while the server was stopped
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::paths;
+use crate::quota::Usage;
+use crate::storage::{self, DirEntry, Metadata, StorageBackend};
+
+const SCHEMA: &str = "
+    PRAGMA journal_mode = WAL;
+    CREATE TABLE IF NOT EXISTS entries (
+        path TEXT PRIMARY KEY NOT NULL,
+        parent TEXT NOT NULL,
+        name TEXT NOT NULL,
+        is_dir INTEGER NOT NULL,
+        size INTEGER NOT NULL,
+        modified INTEGER NOT NULL,
+        -- lowercase hex, NULL for folders and files that aren't hashed yet
+        sha256 TEXT
+    );
+    CREATE INDEX IF NOT EXISTS entries_parent ON entries (parent);
+    CREATE TABLE IF NOT EXISTS tags (
+        path TEXT NOT NULL,
+        tag TEXT NOT NULL,
+        PRIMARY KEY (path, tag)
+    );
+    CREATE TABLE IF NOT EXISTS notes (
+        path TEXT PRIMARY KEY NOT NULL,
+        note TEXT NOT NULL
+    );
+";
+
+pub const MAX_TAG_CHARS: usize = 32;
+pub const MAX_NOTE_CHARS: usize = 1000;
+
+/// Rows at or below `?1`, with `?2` and `?3` from `bounds`
+const AT_OR_BELOW: &str = "(path = ?1 OR (path >= ?2 AND (?3 IS NULL OR path < ?3)))";
+
+/// Cheap to clone, clones share the database
+#[derive(Clone)]
+pub struct MetadataIndex {
+    store: Arc<dyn StorageBackend>,
+    db: Arc<Mutex<Connection>>,
+    /// one hashing task at a time
+    hashing: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl MetadataIndex {
+    /// Opens (or creates) the database - call `reconcile` before trusting it
+    pub fn open(store: Arc<dyn StorageBackend>, db_file: &Path) -> io::Result<Self> {
+        let db = Connection::open(db_file).map_err(db_error)?;
+        db.execute_batch(SCHEMA).map_err(db_error)?;
+        Ok(Self {
+            store,
+            db: Arc::new(Mutex::new(db)),
+            hashing: Arc::new(tokio::sync::Mutex::new(())),
+        })
+    }
+
+    /// Re-reads whatever is at `path` now (a file, a folder or nothing) and the folders above
+    /// it, call it after changing it. returns how many rows changed
+    pub async fn update(&self, path: &str) -> io::Result<usize> {
+        let found = walk(self.store.as_ref(), path).await?;
+        let mut above = Vec::new();
+        let mut dir = paths::parent(path);
+        while !dir.is_empty() {
+            if let Ok(metadata) = self.store.stat(dir).await {
+                above.push((dir.to_string(), metadata));
+            }
+            dir = paths::parent(dir);
+        }
+
+        let changed = {
+            let mut db = self.db.lock().unwrap();
+            let tx = db.transaction().map_err(db_error)?;
+            let (lower, upper) = bounds(path);
+            let mut known: HashMap<String, Metadata> = HashMap::new();
+            {
+                let mut select = tx
+                    .prepare(&format!("SELECT path, is_dir, size, modified FROM entries WHERE {}", AT_OR_BELOW))
+                    .map_err(db_error)?;
+                let rows = select
+                    .query_map(params![path, lower, upper], |row| {
+                        let metadata = Metadata {
+                            is_dir: row.get(1)?,
+                            size: row.get(2)?,
+                            modified: row.get(3)?,
+                        };
+                        Ok((row.get::<_, String>(0)?, metadata))
+                    })
+                    .map_err(db_error)?;
+                for row in rows {
+                    let (known_path, metadata) = row.map_err(db_error)?;
+                    known.insert(known_path, metadata);
+                }
+            }
+
+            let mut changed = 0;
+            for gone in known.keys().filter(|known_path| !found.contains_key(*known_path)) {
+                tx.execute("DELETE FROM entries WHERE path = ?1", params![gone])
+                    .map_err(db_error)?;
+                tx.execute("DELETE FROM tags WHERE path = ?1", params![gone])
+                    .map_err(db_error)?;
+                tx.execute("DELETE FROM notes WHERE path = ?1", params![gone])
+                    .map_err(db_error)?;
+                changed += 1;
+            }
+            // the changed file itself is always read again, it may have been rewritten within
+            // the same second with the same size
+            let rows = found.iter().map(|(entry_path, metadata)| (entry_path, metadata, entry_path == path));
+            let above = above.iter().map(|(dir, metadata)| (dir, metadata, false));
+            for (entry_path, metadata, force) in rows.chain(above) {
+                if !force && known.get(entry_path) == Some(metadata) {
+                    continue;
+                }
+                let name = entry_path.rsplit('/').next().unwrap_or_default();
+                tx.execute(
+                    "INSERT INTO entries (path, parent, name, is_dir, size, modified, sha256)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, NULL)
+                     ON CONFLICT (path) DO UPDATE SET is_dir = excluded.is_dir, size = excluded.size,
+                        modified = excluded.modified, sha256 = NULL",
+                    params![
+                        entry_path,
+                        paths::parent(entry_path),
+                        name,
+                        metadata.is_dir,
+                        metadata.size,
+                        metadata.modified
+                    ],
+                )
+                .map_err(db_error)?;
+                changed += 1;
+            }
+            tx.commit().map_err(db_error)?;
+            changed
+        };
+        if changed > 0 {
+            self.hash_later();
+        }
+        Ok(changed)
+    }
+
+    /// Brings the whole database in line with the storage, returns how many rows changed
+    pub async fn reconcile(&self) -> io::Result<usize> {
+        let changed = self.update("").await?;
+        // hashing may have been cut short by the last stop
+        self.hash_later();
+        Ok(changed)
+    }
+
+    /// Entries of the folder `dir`, `None` when there is no such folder
+    pub fn list(&self, dir: &str) -> io::Result<Option<Vec<DirEntry>>> {
+        let db = self.db.lock().unwrap();
+        if !is_dir(&db, dir)? {
+            return Ok(None);
+        }
+        let mut select = db
+            .prepare("SELECT name, is_dir, size, modified FROM entries WHERE parent = ?1")
+            .map_err(db_error)?;
+        let rows = select
+            .query_map(params![dir], |row| {
+                Ok(DirEntry {
+                    name: row.get(0)?,
+                    metadata: Metadata {
+                        is_dir: row.get(1)?,
+                        size: row.get(2)?,
+                        modified: row.get(3)?,
+                    },
+                })
+            })
+            .map_err(db_error)?;
+        rows.collect::<Result<_, _>>().map(Some).map_err(db_error)
+    }
+
+    /// Everything below the folder `dir` by path, `None` when there is no such folder
+    pub fn below(&self, dir: &str) -> io::Result<Option<Vec<(String, Metadata)>>> {
+        let db = self.db.lock().unwrap();
+        if !is_dir(&db, dir)? {
+            return Ok(None);
+        }
+        let (lower, upper) = bounds(dir);
+        let mut select = db
+            .prepare(&format!(
+                "SELECT path, is_dir, size, modified FROM entries WHERE path != ?1 AND {} ORDER BY path",
+                AT_OR_BELOW
+            ))
+            .map_err(db_error)?;
+        let rows = select
+            .query_map(params![dir, lower, upper], |row| {
+                let metadata = Metadata {
+                    is_dir: row.get(1)?,
+                    size: row.get(2)?,
+                    modified: row.get(3)?,
+                };
+                Ok((row.get(0)?, metadata))
+            })
+            .map_err(db_error)?;
+        rows.collect::<Result<_, _>>().map(Some).map_err(db_error)
+    }
+
+    /// What is stored at or below `folder`, nothing when it doesn't exist
+    pub fn usage(&self, folder: &str) -> io::Result<Usage> {
+        let db = self.db.lock().unwrap();
+        let (lower, upper) = bounds(folder);
+        db.query_row(
+            &format!(
+                "SELECT COUNT(*), COALESCE(SUM(size), 0) FROM entries WHERE NOT is_dir AND {}",
+                AT_OR_BELOW
+            ),
+            params![folder, lower, upper],
+            |row| {
+                Ok(Usage {
+                    files: row.get(0)?,
+                    bytes: row.get(1)?,
+                })
+            },
+        )
+        .map_err(db_error)
+    }
+
+    /// Tags (sorted) and note of `path`
+    pub fn labels(&self, path: &str) -> io::Result<(Vec<String>, Option<String>)> {
+        let db = self.db.lock().unwrap();
+        let mut select = db
+            .prepare("SELECT tag FROM tags WHERE path = ?1 ORDER BY tag")
+            .map_err(db_error)?;
+        let tags = select
+            .query_map(params![path], |row| row.get(0))
+            .map_err(db_error)?
+            .collect::<Result<_, _>>()
+            .map_err(db_error)?;
+        let note = db
+            .query_row("SELECT note FROM notes WHERE path = ?1", params![path], |row| row.get(0))
+            .optional()
+            .map_err(db_error)?;
+        Ok((tags, note))
+    }
+
+    /// sha256 of the file at `path`, once the background hashing got to it
+    pub fn sha256(&self, path: &str) -> io::Result<Option<String>> {
+        let db = self.db.lock().unwrap();
+        db.query_row("SELECT sha256 FROM entries WHERE path = ?1", params![path], |row| row.get(0))
+            .optional()
+            .map(Option::flatten)
+            .map_err(db_error)
+    }
+
+    /// Adds and removes tags of `path`, `false` when there is nothing at `path`
+    pub fn set_tags(&self, path: &str, add: &[String], remove: &[String]) -> io::Result<bool> {
+        let mut db = self.db.lock().unwrap();
+        let tx = db.transaction().map_err(db_error)?;
+        if !exists(&tx, path)? {
+            return Ok(false);
+        }
+        for tag in remove {
+            tx.execute("DELETE FROM tags WHERE path = ?1 AND tag = ?2", params![path, tag])
+                .map_err(db_error)?;
+        }
+        for tag in add {
+            tx.execute("INSERT OR IGNORE INTO tags (path, tag) VALUES (?1, ?2)", params![path, tag])
+                .map_err(db_error)?;
+        }
+        tx.commit().map_err(db_error)?;
+        Ok(true)
+    }
+
+    /// Replaces the note of `path`, `None` removes it. `false` when there is nothing at `path`
+    pub fn set_note(&self, path: &str, note: Option<&str>) -> io::Result<bool> {
+        let db = self.db.lock().unwrap();
+        if !exists(&db, path)? {
+            return Ok(false);
+        }
+        match note {
+            Some(note) => db.execute(
+                "INSERT INTO notes (path, note) VALUES (?1, ?2) ON CONFLICT (path) DO UPDATE SET note = excluded.note",
+                params![path, note],
+            ),
+            None => db.execute("DELETE FROM notes WHERE path = ?1", params![path]),
+        }
+        .map_err(db_error)?;
+        Ok(true)
+    }
+
+    /// Paths below `dir` with every one of `tags`
+    pub fn tagged(&self, dir: &str, tags: &[String]) -> io::Result<HashSet<String>> {
+        let db = self.db.lock().unwrap();
+        let (lower, upper) = bounds(dir);
+        let mut select = db
+            .prepare(&format!("SELECT path FROM tags WHERE tag = ?4 AND {}", AT_OR_BELOW))
+            .map_err(db_error)?;
+        let mut tagged: Option<HashSet<String>> = None;
+        for tag in tags {
+            let paths: HashSet<String> = select
+                .query_map(params![dir, lower, upper, tag], |row| row.get(0))
+                .map_err(db_error)?
+                .collect::<Result<_, _>>()
+                .map_err(db_error)?;
+            tagged = Some(match tagged {
+                Some(tagged) => tagged.intersection(&paths).cloned().collect(),
+                None => paths,
+            });
+        }
+        Ok(tagged.unwrap_or_default())
+    }
+
+    /// Moves the tags and notes at or below `from` to `to`, replacing any there - call before
+    /// `update`ing either path
+    pub fn moved(&self, from: &str, to: &str) -> io::Result<()> {
+        let mut db = self.db.lock().unwrap();
+        let tx = db.transaction().map_err(db_error)?;
+        for table in ["tags", "notes"] {
+            let (lower, upper) = bounds(to);
+            tx.execute(
+                &format!("DELETE FROM {} WHERE {}", table, AT_OR_BELOW),
+                params![to, lower, upper],
+            )
+            .map_err(db_error)?;
+            let (lower, upper) = bounds(from);
+            tx.execute(
+                &format!(
+                    "UPDATE {} SET path = ?4 || substr(path, length(?1) + 1) WHERE {}",
+                    table, AT_OR_BELOW
+                ),
+                params![from, lower, upper, to],
+            )
+            .map_err(db_error)?;
+        }
+        tx.commit().map_err(db_error)
+    }
+
+    /// Hashes the files that have no sha256 yet in the background
+    fn hash_later(&self) {
+        let index = self.clone();
+        tokio::spawn(async move {
+            let _guard = index.hashing.lock().await;
+            if let Err(e) = index.hash_unhashed().await {
+                eprintln!("Unable to hash the files in the metadata index: {}", e);
+            }
+        });
+    }
+
+    async fn hash_unhashed(&self) -> io::Result<()> {
+        let unhashed: Vec<(String, u64, u64)> = {
+            let db = self.db.lock().unwrap();
+            let mut select = db
+                .prepare("SELECT path, size, modified FROM entries WHERE NOT is_dir AND sha256 IS NULL")
+                .map_err(db_error)?;
+            let rows = select
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+                .map_err(db_error)?;
+            rows.collect::<Result<_, _>>().map_err(db_error)?
+        };
+        for (path, size, modified) in unhashed {
+            let sha256 = match storage::sha256(self.store.as_ref(), &path).await {
+                Ok(sha256) => sha256,
+                // gone or changed since, `update` takes care of it
+                Err(_) => continue,
+            };
+            // only if it is still the content that was hashed
+            self.db
+                .lock()
+                .unwrap()
+                .execute(
+                    "UPDATE entries SET sha256 = ?1 WHERE path = ?2 AND size = ?3 AND modified = ?4",
+                    params![sha256, path, size, modified],
+                )
+                .map_err(db_error)?;
+        }
+        Ok(())
+    }
+}
+
+/// Tags are short words of letters, digits, '-', '_' and '.'
+pub fn valid_tag(tag: &str) -> bool {
+    (1..=MAX_TAG_CHARS).contains(&tag.chars().count())
+        && tag.chars().all(|c| c.is_alphanumeric() || "-_.".contains(c))
+}
+
+/// Bounds of the paths strictly below `dir` - '0' sorts right after '/'
+fn bounds(dir: &str) -> (String, Option<String>) {
+    if dir.is_empty() {
+        (String::new(), None)
+    } else {
+        (format!("{}/", dir), Some(format!("{}0", dir)))
+    }
+}
+
+/// Whether the database has `dir` as a folder, the root always is one
+fn is_dir(db: &Connection, dir: &str) -> io::Result<bool> {
+    if dir.is_empty() {
+        return Ok(true);
+    }
+    let is_dir: Option<bool> = db
+        .query_row("SELECT is_dir FROM entries WHERE path = ?1", params![dir], |row| row.get(0))
+        .optional()
+        .map_err(db_error)?;
+    Ok(is_dir == Some(true))
+}
+
+/// Whether the database has a row for `path`
+fn exists(db: &Connection, path: &str) -> io::Result<bool> {
+    db.query_row("SELECT 1 FROM entries WHERE path = ?1", params![path], |_| Ok(()))
+        .optional()
+        .map(|row| row.is_some())
+        .map_err(db_error)
+}
+
+/// Every file and folder at or below `path` except the root, nothing when it doesn't exist
+async fn walk(store: &dyn StorageBackend, path: &str) -> io::Result<HashMap<String, Metadata>> {
+    let mut found = HashMap::new();
+    if !path.is_empty() {
+        match store.stat(path).await {
+            Ok(metadata) => {
+                found.insert(path.to_string(), metadata);
+                if !metadata.is_dir {
+                    return Ok(found);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(found),
+            Err(e) => return Err(e),
+        }
+    }
+    let mut pending = vec![path.to_string()];
+    while let Some(dir) = pending.pop() {
+        let entries = match store.list(&dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let entry_path = paths::join(&dir, &entry.name);
+            if entry.metadata.is_dir {
+                pending.push(entry_path.clone());
+            }
+            found.insert(entry_path, entry.metadata);
+        }
+    }
+    Ok(found)
+}
+
+fn db_error(e: rusqlite::Error) -> io::Error {
+    io::Error::other(e)
+}
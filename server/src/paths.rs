@@ -0,0 +1,113 @@
+/// paths.rs - validation of client supplied paths
+/// every path coming from a request goes through `normalize` before it touches the disk,
+/// the result is always relative to the storage root and can't climb out of it
+use std::path::{Path, PathBuf};
+
+/// Cleans `path` into `a/b/c` form, `""` is the storage root.
+/// Returns `None` for `..`, `.`, backslashes or control characters.
+pub fn normalize(path: &str) -> Option<String> {
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" => continue,
+            "." | ".." => return None,
+            _ if part.contains('\\') || part.chars().any(char::is_control) => return None,
+            _ => parts.push(part),
+        }
+    }
+    Some(parts.join("/"))
+}
+
+/// Joins a normalized relative path onto `root`
+pub fn resolve(root: &Path, rel: &str) -> PathBuf {
+    let mut full = root.to_path_buf();
+    for part in rel.split('/').filter(|p| !p.is_empty()) {
+        full.push(part);
+    }
+    full
+}
+
+/// Parent directory of a normalized path (`""` for top level entries)
+pub fn parent(rel: &str) -> &str {
+    match rel.rfind('/') {
+        Some(i) => &rel[..i],
+        None => "",
+    }
+}
+
+/// `dir` + `name` as a normalized path, an empty `name` is `dir` itself
+pub fn join(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else if name.is_empty() {
+        dir.to_string()
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+/// Whether the normalized `path` is `dir` or somewhere below it
+pub fn is_below(path: &str, dir: &str) -> bool {
+    dir.is_empty() || path == dir || path.strip_prefix(dir).is_some_and(|rest| rest.starts_with('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_cleans_up() {
+        assert_eq!(normalize("").as_deref(), Some(""));
+        assert_eq!(normalize("/").as_deref(), Some(""));
+        assert_eq!(normalize("a/b/c").as_deref(), Some("a/b/c"));
+        assert_eq!(normalize("//a///b/").as_deref(), Some("a/b"));
+        // absolute paths stay inside the storage root
+        assert_eq!(normalize("/etc/passwd").as_deref(), Some("etc/passwd"));
+        // a drive letter is just a name on the server's side
+        assert_eq!(normalize("C:/x").as_deref(), Some("C:/x"));
+        assert_eq!(normalize("...").as_deref(), Some("..."));
+        assert_eq!(normalize("a..b/.c").as_deref(), Some("a..b/.c"));
+        assert_eq!(normalize("spaces are fine/ünïcödé").as_deref(), Some("spaces are fine/ünïcödé"));
+    }
+
+    #[test]
+    fn normalize_rejects() {
+        for path in [
+            "..",
+            "../a",
+            "a/../../b",
+            "a/..",
+            "/..",
+            ".",
+            "./a",
+            "a/./b",
+            "a\\b",
+            "..\\a",
+            "C:\\Windows",
+            "a\nb",
+            "a\0b",
+            "tab\there",
+            "del\u{7f}",
+            "c1\u{85}",
+        ] {
+            assert_eq!(normalize(path), None, "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn parent_join_and_below() {
+        assert_eq!(parent("a/b/c"), "a/b");
+        assert_eq!(parent("a"), "");
+        assert_eq!(parent(""), "");
+        assert_eq!(join("", "a"), "a");
+        assert_eq!(join("a", ""), "a");
+        assert_eq!(join("a", "b"), "a/b");
+        assert!(is_below("a/b", "a"));
+        assert!(is_below("a", "a"));
+        assert!(is_below("a", ""));
+        assert!(!is_below("ab", "a"));
+        assert!(!is_below("a", "a/b"));
+        assert_eq!(resolve(Path::new("/srv"), "a/b"), Path::new("/srv/a/b"));
+        assert_eq!(resolve(Path::new("/srv"), ""), Path::new("/srv"));
+    }
+}
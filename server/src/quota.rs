@@ -0,0 +1,210 @@
+/// quota.rs - how much may be stored where
+/// - `home` limits every user's home folder, `users` overrides it for single users. these only
+///   cover the homes, an upload to a shared folder isn't charged to whoever uploads it
+/// - `folders` limits any other folder, shared ones included
+/// - a limit covers everything below its folder and an upload has to fit in every limit
+///   above it. usage is counted by walking the folder when an upload starts, the trash and
+///   old versions don't count
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io;
+
+use crate::acl::{self, HOME_ROOT};
+use crate::paths;
+use crate::storage::StorageBackend;
+
+/// Most bytes and files allowed below a folder, a missing one is unlimited
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limit {
+    pub max_bytes: Option<u64>,
+    pub max_files: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct FolderQuota {
+    /// folder relative to the storage root
+    pub path: String,
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+    #[serde(default)]
+    pub max_files: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Quotas {
+    /// for every `home/<username>`
+    pub home: Option<Limit>,
+    /// username -> limit for their home, instead of `home`
+    pub users: HashMap<String, Limit>,
+    pub folders: Vec<FolderQuota>,
+}
+
+/// What is stored below a folder
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Usage {
+    pub bytes: u64,
+    pub files: u64,
+}
+
+impl Quotas {
+    /// Folder paths cleaned up, invalid ones are reported
+    pub fn normalize(&mut self) -> Result<(), String> {
+        for folder in &mut self.folders {
+            match paths::normalize(&folder.path) {
+                Some(path) => folder.path = path,
+                None => return Err(format!("invalid quota folder path '{}'", folder.path)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Folders with a limit that `path` is in (or is), outermost first
+    pub fn applying(&self, path: &str) -> Vec<(String, Limit)> {
+        let mut applying: Vec<(String, Limit)> = self
+            .folders
+            .iter()
+            .filter(|folder| paths::is_below(path, &folder.path))
+            .map(|folder| {
+                let limit = Limit {
+                    max_bytes: folder.max_bytes,
+                    max_files: folder.max_files,
+                };
+                (folder.path.clone(), limit)
+            })
+            .collect();
+
+        let mut parts = path.split('/');
+        if let (Some(HOME_ROOT), Some(owner)) = (parts.next(), parts.next()) {
+            if let Some(limit) = self.users.get(owner).or(self.home.as_ref()) {
+                applying.push((acl::home_dir(owner), *limit));
+            }
+        }
+        applying.sort_by_key(|(folder, _)| folder.len());
+        applying
+    }
+}
+
+/// Counts what is stored at or below `folder`, nothing when it doesn't exist yet
+pub async fn usage(storage: &dyn StorageBackend, folder: &str) -> io::Result<Usage> {
+    let mut usage = Usage::default();
+    let mut pending = vec![folder.to_string()];
+    while let Some(dir) = pending.pop() {
+        let entries = match storage.list(&dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            if entry.metadata.is_dir {
+                pending.push(paths::join(&dir, &entry.name));
+            } else {
+                usage.bytes += entry.metadata.size;
+                usage.files += 1;
+            }
+        }
+    }
+    Ok(usage)
+}
+
+/// Counts the file or the folder tree at `path`
+pub async fn usage_of(storage: &dyn StorageBackend, path: &str) -> io::Result<Usage> {
+    let metadata = storage.stat(path).await?;
+    if !metadata.is_dir {
+        return Ok(Usage {
+            bytes: metadata.size,
+            files: 1,
+        });
+    }
+    usage(storage, path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{self, Memory};
+
+    fn limit(max_bytes: u64) -> Limit {
+        Limit {
+            max_bytes: Some(max_bytes),
+            max_files: None,
+        }
+    }
+
+    fn quotas() -> Quotas {
+        let mut quotas = Quotas {
+            home: Some(limit(100)),
+            users: HashMap::from([("alice".to_string(), limit(500))]),
+            folders: vec![
+                FolderQuota {
+                    path: "/shared/".to_string(),
+                    max_bytes: Some(1000),
+                    max_files: None,
+                },
+                FolderQuota {
+                    path: "shared/projects".to_string(),
+                    max_bytes: None,
+                    max_files: Some(10),
+                },
+            ],
+        };
+        quotas.normalize().unwrap();
+        quotas
+    }
+
+    fn folders(applying: Vec<(String, Limit)>) -> Vec<String> {
+        applying.into_iter().map(|(folder, _)| folder).collect()
+    }
+
+    #[test]
+    fn homes_have_their_owners_limit() {
+        let quotas = quotas();
+        assert_eq!(quotas.applying("home/bob/a.txt"), [("home/bob".to_string(), limit(100))]);
+        assert_eq!(quotas.applying("home/alice/deep/a.txt"), [("home/alice".to_string(), limit(500))]);
+        assert_eq!(quotas.applying("home/bob"), [("home/bob".to_string(), limit(100))]);
+        // the folder holding the homes isn't anyone's
+        assert!(quotas.applying("home").is_empty());
+        assert!(quotas.applying("").is_empty());
+        assert!(quotas.applying("homework/a.txt").is_empty());
+    }
+
+    #[test]
+    fn folders_apply_outermost_first() {
+        let quotas = quotas();
+        assert_eq!(folders(quotas.applying("shared/projects/x/a.txt")), ["shared", "shared/projects"]);
+        assert_eq!(folders(quotas.applying("shared/a.txt")), ["shared"]);
+        assert!(quotas.applying("sharedstuff/a.txt").is_empty());
+        // home quotas don't follow a user into shared folders
+        assert_eq!(folders(quotas.applying("shared/home/alice")), ["shared"]);
+    }
+
+    #[test]
+    fn invalid_folders_are_refused() {
+        let mut quotas = Quotas {
+            folders: vec![FolderQuota {
+                path: "../outside".to_string(),
+                max_bytes: Some(1),
+                max_files: None,
+            }],
+            ..Quotas::default()
+        };
+        assert!(quotas.normalize().is_err());
+    }
+
+    #[tokio::test]
+    async fn usage_counts_files_below() {
+        let memory = Memory::new();
+        for (path, contents) in [("a/one", "1"), ("a/b/two", "22"), ("a/b/c/three", "333")] {
+            storage::create_dir_all(&memory, paths::parent(path)).await.unwrap();
+            storage::write_all(&memory, path, contents.as_bytes()).await.unwrap();
+        }
+        let counted = usage(&memory, "a").await.unwrap();
+        assert_eq!((counted.bytes, counted.files), (6, 3));
+        let counted = usage_of(&memory, "a/b/two").await.unwrap();
+        assert_eq!((counted.bytes, counted.files), (2, 1));
+        let counted = usage(&memory, "missing").await.unwrap();
+        assert_eq!((counted.bytes, counted.files), (0, 0));
+    }
+}
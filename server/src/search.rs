@@ -0,0 +1,200 @@
+/// search.rs - finding files and folders by name, size and modification time
+/// - the folder is walked recursively, skipping whatever the user can't read. the storage
+///   hides the trash itself
+/// - names are matched as a substring, a glob or a regex, see `SearchMode`
+/// - at most `MAX_RESULTS` matches are sent back, the walk stops there
+/// - with the metadata index on, `search_entries` looks through its rows instead of walking
+use regex::{Regex, RegexBuilder};
+use shared::{FileEntry, SearchMode, SearchRequest, SearchResult};
+use std::io;
+
+use crate::acl::Acl;
+use crate::paths;
+use crate::storage::{Metadata, StorageBackend};
+
+pub const MAX_RESULTS: usize = 500;
+
+/// A checked `SearchRequest`
+pub struct Query {
+    name: NameMatcher,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    modified_after: Option<u64>,
+    modified_before: Option<u64>,
+}
+
+enum NameMatcher {
+    Any,
+    /// lowercased
+    Substring(String),
+    Regex(Regex),
+}
+
+impl Query {
+    /// `Err` holds why the pattern can't be used
+    pub fn new(request: &SearchRequest) -> Result<Self, String> {
+        let pattern = request.pattern.as_str();
+        let name = match request.mode {
+            _ if pattern.is_empty() => NameMatcher::Any,
+            SearchMode::Substring => NameMatcher::Substring(pattern.to_lowercase()),
+            SearchMode::Glob => NameMatcher::Regex(
+                RegexBuilder::new(&glob_to_regex(pattern))
+                    .case_insensitive(true)
+                    .build()
+                    .map_err(|_| format!("Invalid glob '{}'", pattern))?,
+            ),
+            SearchMode::Regex => NameMatcher::Regex(Regex::new(pattern).map_err(|e| format!("Invalid regex: {}", e))?),
+        };
+        Ok(Self {
+            name,
+            min_size: request.min_size,
+            max_size: request.max_size,
+            modified_after: request.modified_after,
+            modified_before: request.modified_before,
+        })
+    }
+
+    pub fn matches(&self, name: &str, metadata: &Metadata) -> bool {
+        let sized = self.min_size.is_some() || self.max_size.is_some();
+        if sized && metadata.is_dir {
+            return false;
+        }
+        if self.min_size.is_some_and(|min| metadata.size < min)
+            || self.max_size.is_some_and(|max| metadata.size > max)
+            || self.modified_after.is_some_and(|after| metadata.modified < after)
+            || self.modified_before.is_some_and(|before| metadata.modified > before)
+        {
+            return false;
+        }
+        match &self.name {
+            NameMatcher::Any => true,
+            NameMatcher::Substring(part) => name.to_lowercase().contains(part.as_str()),
+            NameMatcher::Regex(regex) => regex.is_match(name),
+        }
+    }
+}
+
+/// Matches below `dir` that `user` can read, sorted by path, and whether there were more
+pub async fn search(
+    storage: &dyn StorageBackend,
+    acl: &Acl,
+    user: Option<&str>,
+    dir: &str,
+    query: &Query,
+) -> io::Result<(Vec<SearchResult>, bool)> {
+    let mut results = Vec::new();
+    let mut truncated = false;
+    let mut pending = vec![dir.to_string()];
+    'walk: while let Some(folder) = pending.pop() {
+        let entries = match storage.list(&folder).await {
+            Ok(entries) => entries,
+            // gone while searching
+            Err(e) if e.kind() == io::ErrorKind::NotFound && folder != dir => continue,
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let path = paths::join(&folder, &entry.name);
+            let permissions = acl.permissions(user, &path);
+            if !permissions.read {
+                continue;
+            }
+            if entry.metadata.is_dir {
+                pending.push(path.clone());
+            }
+            if !query.matches(&entry.name, &entry.metadata) {
+                continue;
+            }
+            if results.len() == MAX_RESULTS {
+                truncated = true;
+                break 'walk;
+            }
+            results.push(SearchResult {
+                path,
+                entry: FileEntry {
+                    name: entry.name,
+                    is_dir: entry.metadata.is_dir,
+                    size: entry.metadata.size,
+                    modified: entry.metadata.modified,
+                    can_read: permissions.read,
+                    can_write: permissions.write,
+                    tags: Vec::new(),
+                    note: None,
+                    sha256: None,
+                },
+            });
+        }
+    }
+    results.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok((results, truncated))
+}
+
+/// Matches among `entries` (everything below the searched folder, by path) that `user` can
+/// read, and whether there were more
+pub fn search_entries(
+    entries: Vec<(String, Metadata)>,
+    acl: &Acl,
+    user: Option<&str>,
+    query: &Query,
+) -> (Vec<SearchResult>, bool) {
+    let mut results = Vec::new();
+    for (path, metadata) in entries {
+        let name = path.rsplit('/').next().unwrap_or_default();
+        if !query.matches(name, &metadata) {
+            continue;
+        }
+        let permissions = acl.permissions(user, &path);
+        if !permissions.read {
+            continue;
+        }
+        if results.len() == MAX_RESULTS {
+            return (results, true);
+        }
+        results.push(SearchResult {
+            entry: FileEntry {
+                name: name.to_string(),
+                is_dir: metadata.is_dir,
+                size: metadata.size,
+                modified: metadata.modified,
+                can_read: permissions.read,
+                can_write: permissions.write,
+                tags: Vec::new(),
+                note: None,
+                sha256: None,
+            },
+            path,
+        });
+    }
+    (results, false)
+}
+
+/// Anchored regex for a glob: `*` is any run of characters, `?` one character and `[...]`
+/// (or `[!...]`) a class
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            '[' => {
+                regex.push('[');
+                if chars.next_if_eq(&'!').is_some() {
+                    regex.push('^');
+                }
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        break;
+                    }
+                    if c == '\\' || c == '[' {
+                        regex.push('\\');
+                    }
+                    regex.push(c);
+                }
+                regex.push(']');
+            }
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
@@ -1,32 +1,73 @@
 use bytes::Bytes;
 use hyper::header::HeaderName;
-use serde::{Deserialize, Serialize};
 use std::env;
 use std::fs;
 use std::path::Path;
-use tokio::fs::File;
-use tokio::io::AsyncWriteExt; // for write_all()
-use tokio_util::io::ReaderStream;
-// use http_body_util::BodyExt;
-use http_body_util::{BodyExt, Full};
+use tokio::io::{AsyncReadExt, AsyncWriteExt}; // for read() and write_all()
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyDataStream, BodyExt, Full, LengthLimitError, Limited};
 use hyper::Response;
 // use rand::Rng;
 // use std::collections::HashMap;
+use std::convert::Infallible;
 // use std::net::IpAddr;
 // use std::sync::Arc;
 // use url::form_urlencoded;
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::acl::{self, Acl};
+use crate::archive;
+use crate::auth::Auth;
+use crate::compression;
+use crate::config::{FullTextConfig, Limits, MetadataIndexConfig, S3Config, ShareConfig};
+use crate::events::{self, EventStream, Events};
+use crate::fulltext::{self, FullText};
+use crate::html;
+use crate::metadata::{self, MetadataIndex};
+use crate::paths;
+use crate::quota::{self, Quotas};
+use crate::search::{self, Query};
+use crate::s3::{self, ListParams, ListRequest, S3Error, Signed, Target, Upload, S3};
+use crate::share::{ShareClaims, ShareError, ShareSigner};
+use crate::storage::{self, DirEntry, HideDir, LocalDisk, StorageBackend, WriteStream};
+use crate::trash::{self, Trash};
+use crate::unpack;
+use crate::versions::Versions;
+use crate::webdav::{self, Depth, Locks, PropEntry};
+use percent_encoding::percent_decode_str;
+use shared::delta::{self, Signature};
+use shared::encoding::{self, Encoding};
+use shared::{
+    ArchiveKind, ChangeEvent, ChangeKind, FileEntry, GetFilesResponse, LabelsResponse, LoginRequest, LoginResponse, NoteRequest, QuotaUsage,
+    SearchRequest, SearchResponse, SearchResult, ShareRequest, ShareResponse, TagsRequest, TextSearchRequest,
+    TextSearchResponse, TrashResponse, UsageResponse, VersionsResponse,
+};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio_util::io::StreamReader;
+
 pub struct Server {
     pub name: String,
     pub port: u16,
-    storage_dir: PathBuf,
-}
-fn get_current_working_dir() -> String {
-    let res = env::current_dir();
-    match res {
-        Ok(path) => path.into_os_string().into_string().unwrap(),
-        Err(_) => "FAILED".to_string(),
-    }
+    storage: Arc<dyn StorageBackend>,
+    limits: Limits,
+    auth: Option<Auth>,
+    acl: Acl,
+    share_signer: Option<ShareSigner>,
+    share_config: ShareConfig,
+    /// "http" or "https", used when building links
+    scheme: &'static str,
+    web_index: bool,
+    webdav: bool,
+    locks: Locks,
+    s3: Option<S3>,
+    versions: Option<Versions>,
+    trash: Option<Trash>,
+    quotas: Quotas,
+    full_text: Option<FullText>,
+    metadata: Option<MetadataIndex>,
+    events: Events,
 }
 
 // basic server setup
@@ -36,11 +77,27 @@ impl Server {
         Self {
             name: name.to_string(),
             port,
-            storage_dir,
+            storage: Arc::new(LocalDisk::new(storage_dir)),
+            limits: Limits::default(),
+            auth: None,
+            acl: Acl::default(),
+            share_signer: None,
+            share_config: ShareConfig::default(),
+            scheme: "http",
+            web_index: false,
+            webdav: false,
+            locks: Locks::default(),
+            s3: None,
+            versions: None,
+            trash: None,
+            quotas: Quotas::default(),
+            full_text: None,
+            metadata: None,
+            events: Events::default(),
         }
     }
     /// Get default storage path in `server/storage`
-    fn get_default_storage_path() -> PathBuf {
+    pub fn get_default_storage_path() -> PathBuf {
         // Find the executable's directory and resolve "server/storage" relative to it
         let exe_dir = env::current_exe()
             .expect("Failed to find the executable path")
@@ -67,108 +124,3489 @@ impl Server {
     }
 
     /// Set a custom storage directory
-    pub fn set_storage_dir(&mut self, storage_dir: &str) -> std::io::Result<()> {
-        let path = Path::new(storage_dir).to_path_buf();
+    pub fn set_storage_dir(&mut self, storage_dir: impl AsRef<Path>) -> std::io::Result<()> {
+        let path = storage_dir.as_ref().to_path_buf();
         if !path.exists() {
             fs::create_dir_all(&path)?;
             println!("Created custom storage directory at {:?}", path);
         } else {
             println!("Custom storage directory already exists at {:?}", path);
         }
-        self.storage_dir = path;
+        self.storage = Arc::new(LocalDisk::new(path));
+        Ok(())
+    }
+
+    /// Keep files somewhere other than a folder on this machine
+    pub fn set_storage(&mut self, storage: Box<dyn StorageBackend>) {
+        self.storage = Arc::from(storage);
+    }
+
+    /// Keep a full-text index of the storage, call after `set_trash` so the trash isn't indexed
+    pub fn set_full_text(&mut self, config: &FullTextConfig) -> std::io::Result<()> {
+        self.full_text = Some(FullText::open(Arc::clone(&self.storage), config)?);
+        Ok(())
+    }
+
+    /// Serve listings, search and usage from a database of the storage, call after `set_trash`
+    /// so the trash isn't in it
+    pub fn set_metadata_index(&mut self, config: &MetadataIndexConfig) -> std::io::Result<()> {
+        self.metadata = Some(MetadataIndex::open(Arc::clone(&self.storage), &config.db_file)?);
+        Ok(())
+    }
+
+    /// Set size and file count limits
+    pub fn set_quotas(&mut self, quotas: Quotas) {
+        self.quotas = quotas;
+    }
+
+    /// Move deletes into a trash inside the storage instead, call after the storage is set
+    pub fn set_trash(&mut self, retention_days: u64) {
+        let whole = Arc::clone(&self.storage);
+        self.storage = Arc::new(HideDir::new(Arc::clone(&whole), trash::TRASH_DIR));
+        self.trash = Some(Trash::new(whole, retention_days));
+    }
+
+    /// Set upload/connection limits
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Require a login for every request
+    pub fn set_auth(&mut self, auth: Auth) {
+        self.auth = Some(auth);
+    }
+
+    /// Set shared folders/admins, creating any shared folder that doesn't exist yet
+    pub async fn set_acl(&mut self, acl: Acl) -> std::io::Result<()> {
+        for folder in &acl.shared {
+            storage::create_dir_all(self.storage.as_ref(), &folder.path).await?;
+        }
+        self.acl = acl;
+        Ok(())
+    }
+
+    /// Enable share links, `scheme` is what clients connect with ("http"/"https")
+    pub fn set_shares(&mut self, config: ShareConfig, scheme: &'static str) -> std::io::Result<()> {
+        self.share_signer = Some(ShareSigner::load_or_create(&config.secret_file)?);
+        self.share_config = config;
+        self.scheme = scheme;
+        Ok(())
+    }
+
+    /// Serve the read-only HTML listing at `/files/`
+    pub fn set_web_index(&mut self, enabled: bool) {
+        self.web_index = enabled;
+    }
+
+    /// Serve the storage over WebDAV at `/dav/`
+    pub fn set_webdav(&mut self, enabled: bool) {
+        self.webdav = enabled;
+    }
+
+    /// Serve the S3-compatible api at `/s3/`
+    pub fn set_s3(&mut self, config: S3Config) -> std::io::Result<()> {
+        self.s3 = Some(S3::new(config)?);
         Ok(())
     }
+
+    /// Keep previous versions of files that uploads replace
+    pub fn set_versions(&mut self, versions: Versions) {
+        self.versions = Some(versions);
+    }
 }
 
-// active server stuff
+// authentication
 impl Server {
-    pub async fn handle_addfile(
+    /// The user making the request - `Ok(None)` when authentication is off,
+    /// `Err` holds the 401 to send back when the token is missing or invalid
+    pub fn authenticate(
         &self,
-        req_body: hyper::body::Incoming,
-        req_headers: hyper::header::HeaderMap,
-    ) -> Result<Response<Full<Bytes>>, hyper::Error> {
-        let mut file_path: PathBuf = self.storage_dir.clone();
-
-        let file_name_header = HeaderName::from_static("file_name").clone();
-        let file_type_header = HeaderName::from_static("file_type");
-
-        let file_name = &req_headers[file_name_header].to_str().unwrap();
-        let file_type = &req_headers[file_type_header].to_str().unwrap();
+        req_headers: &hyper::header::HeaderMap,
+    ) -> Result<Option<String>, Box<Response<Full<Bytes>>>> {
+        let Some(auth) = &self.auth else {
+            return Ok(None);
+        };
+        match auth.authenticate(req_headers) {
+            Some(username) => Ok(Some(username)),
+            None => Err(Box::new(
+                hyper::Response::builder()
+                    .status(401)
+                    .header(hyper::header::WWW_AUTHENTICATE, "Bearer")
+                    .body(Full::from(Bytes::from("Login required")))
+                    .unwrap(),
+            )),
+        }
+    }
 
-        let final_file_name = format!("{}.{}", file_name, file_type);
+    /// Like `authenticate`, for browsers and WebDAV clients - HTTP basic auth is accepted
+    /// as well as a token, and the 401 asks for a username and password
+    pub fn authenticate_basic(
+        &self,
+        req_headers: &hyper::header::HeaderMap,
+    ) -> Result<Option<String>, Box<Response<Full<Bytes>>>> {
+        let Some(auth) = &self.auth else {
+            return Ok(None);
+        };
+        match auth
+            .authenticate(req_headers)
+            .or_else(|| auth.authenticate_basic(req_headers))
+        {
+            Some(username) => Ok(Some(username)),
+            None => Err(Box::new(
+                hyper::Response::builder()
+                    .status(401)
+                    .header(hyper::header::WWW_AUTHENTICATE, "Basic realm=\"tuifs\", charset=\"UTF-8\"")
+                    .header(hyper::header::CONTENT_TYPE, "text/html; charset=utf-8")
+                    .body(Full::from(Bytes::from(html::page(
+                        "Login required",
+                        "<p>Log in with your tuifs username and password.</p>",
+                    ))))
+                    .unwrap(),
+            )),
+        }
+    }
 
-        file_path.push(final_file_name);
+    pub async fn handle_login(&self, req_bytes: Bytes) -> Result<Response<Full<Bytes>>, hyper::Error> {
+        let Some(auth) = &self.auth else {
+            return Ok(Server::text_response(404, "Authentication is not enabled"));
+        };
+        let Ok(login) = serde_json::from_slice::<LoginRequest>(&req_bytes) else {
+            return Ok(Server::text_response(400, "Expected a json username and password"));
+        };
+        match auth.login(&login.username, &login.password) {
+            Some((token, expires_at)) => {
+                println!("User '{}' logged in", login.username);
+                let home = acl::home_dir(&login.username);
+                if !storage::is_dir(self.storage.as_ref(), &home).await {
+                    match storage::create_dir_all(self.storage.as_ref(), &home).await {
+                        Ok(_) => self.changed(&home, ChangeKind::Created).await,
+                        Err(e) => eprintln!("Unable to create home folder '{}': {}", home, e),
+                    }
+                }
+                let response_body = serde_json::to_string(&LoginResponse { token, expires_at }).unwrap();
+                Ok(hyper::Response::builder()
+                    .status(200)
+                    .body(Full::from(Bytes::from(response_body)))
+                    .unwrap())
+            }
+            None => Ok(Server::text_response(401, "Wrong username or password")),
+        }
+    }
 
-        let mut file = File::create(file_path).await.unwrap();
+    pub fn handle_logout(&self, req_headers: &hyper::header::HeaderMap) -> Result<Response<Full<Bytes>>, hyper::Error> {
+        if let Some(auth) = &self.auth {
+            auth.logout(req_headers);
+        }
+        Ok(Server::text_response(200, ""))
+    }
+}
 
-        let response_bytes = req_body.collect().await?.to_bytes();
-        file.write_all(&response_bytes).await.unwrap();
+// share links
+impl Server {
+    pub async fn handle_createshare(
+        &self,
+        req_bytes: Bytes,
+        req_headers: &hyper::header::HeaderMap,
+        user: Option<&str>,
+    ) -> Result<Response<Full<Bytes>>, hyper::Error> {
+        let Some(signer) = &self.share_signer else {
+            return Ok(Server::text_response(404, "Share links are not enabled"));
+        };
+        let Ok(share) = serde_json::from_slice::<ShareRequest>(&req_bytes) else {
+            return Ok(Server::text_response(400, "Expected a json share request"));
+        };
+        let Some(rel_path) = paths::normalize(&share.path) else {
+            return Ok(Server::text_response(400, "Invalid path"));
+        };
+        if !self.acl.permissions(user, &rel_path).read {
+            return Ok(Server::text_response(403, "You can't share this"));
+        }
+        // a link to these would reach everything below them that others can read
+        if rel_path.is_empty() || self.acl.browse_only(user, &rel_path) {
+            return Ok(Server::text_response(
+                403,
+                "Only files and folders you can open yourself can be shared",
+            ));
+        }
+        if !storage::exists(self.storage.as_ref(), &rel_path).await {
+            return Ok(Server::text_response(404, "No such file or folder"));
+        }
+        let max_expiry = self.share_config.max_expiry_secs;
+        if share.expires_in_secs == 0 || share.expires_in_secs > max_expiry {
+            return Ok(Server::text_response(
+                400,
+                &format!("Expiry must be between 1 and {} seconds", max_expiry),
+            ));
+        }
 
-        let response_body = "";
+        let expires_at = unix_now() + share.expires_in_secs;
+        let password = share.password.as_deref().filter(|p| !p.is_empty());
+        let token = signer.create(&rel_path, user, expires_at, password);
 
+        let base = match &self.share_config.public_url {
+            Some(url) => url.trim_end_matches('/').to_string(),
+            None => {
+                let host = req_headers
+                    .get(hyper::header::HOST)
+                    .and_then(|h| h.to_str().ok())
+                    .map(str::to_string)
+                    .unwrap_or_else(|| format!("localhost:{}", self.port));
+                format!("{}://{}", self.scheme, host)
+            }
+        };
+        println!(
+            "Created share link for '{}' (expires {}, password: {})",
+            rel_path,
+            expires_at,
+            password.is_some()
+        );
+        let response_body = serde_json::to_string(&ShareResponse {
+            url: format!("{}/s/{}", base, token),
+            expires_at,
+        })
+        .unwrap();
         Ok(hyper::Response::builder()
             .status(200)
             .body(Full::from(Bytes::from(response_body)))
             .unwrap())
     }
 
-    pub async fn handle_downloadfile(
+    /// Serves `/s/<token>[/<path inside a shared folder>]` to browsers, no login needed
+    pub async fn handle_sharelink(
         &self,
-        req_body: hyper::body::Incoming,
-        req_headers: hyper::header::HeaderMap,
+        req: hyper::Request<hyper::body::Incoming>,
     ) -> Result<Response<Full<Bytes>>, hyper::Error> {
-        let mut file_path: PathBuf = self.storage_dir.clone();
-        file_path.push(req_headers.get("file").unwrap().to_str().unwrap());
+        let Some(signer) = &self.share_signer else {
+            return Ok(Server::text_response(404, "Not Found"));
+        };
+        let rest = req.uri().path().trim_start_matches("/s/").to_string();
+        let (token, sub_path) = match rest.split_once('/') {
+            Some((token, sub_path)) => (token.to_string(), sub_path.to_string()),
+            None => (rest, String::new()),
+        };
+        let now = unix_now();
+        let claims = match signer.verify(&token, now) {
+            Ok(claims) => claims,
+            Err(ShareError::Expired) => {
+                return Ok(Server::html_response(
+                    410,
+                    html::page("Link expired", "<p>This share link has expired.</p>"),
+                ))
+            }
+            Err(_) => return Ok(Server::invalid_share_link()),
+        };
+        // links from before they were tied to a user don't say whose permissions they have
+        if self.auth.is_some() && claims.creator.is_none() {
+            return Ok(Server::invalid_share_link());
+        }
+        let creator = claims.creator.as_deref();
 
-        let mut file = File::create(file_path).await.unwrap();
+        if claims.password.is_some() {
+            let unlocked = cookie(req.headers(), "share_unlock")
+                .is_some_and(|value| signer.check_unlock_cookie(&token, value));
+            if req.method() == hyper::Method::POST {
+                let form = req.collect().await?.to_bytes();
+                let password = url::form_urlencoded::parse(&form)
+                    .find(|(key, _)| key == "password")
+                    .map(|(_, value)| value.into_owned())
+                    .unwrap_or_default();
+                if !signer.check_password(&claims, &password) {
+                    return Ok(Server::html_response(
+                        403,
+                        share_password_form(&token, Some("Wrong password")),
+                    ));
+                }
+                return Ok(hyper::Response::builder()
+                    .status(303)
+                    .header(hyper::header::LOCATION, format!("/s/{}/", token))
+                    .header(
+                        hyper::header::SET_COOKIE,
+                        format!(
+                            "share_unlock={}; Path=/s/{}; Max-Age={}; HttpOnly; SameSite=Strict",
+                            signer.unlock_cookie(&token),
+                            token,
+                            claims.expires_at - now
+                        ),
+                    )
+                    .body(Full::from(Bytes::new()))
+                    .unwrap());
+            }
+            if !unlocked {
+                return Ok(Server::html_response(200, share_password_form(&token, None)));
+            }
+        }
 
-        let response_bytes = req_body.collect().await?.to_bytes();
-        file.write_all(&response_bytes).await.unwrap();
+        let Some(sub_path) = percent_decode_str(&sub_path)
+            .decode_utf8()
+            .ok()
+            .and_then(|sub| paths::normalize(&sub))
+        else {
+            return Ok(Server::text_response(400, "Invalid path"));
+        };
+        let rel_path = paths::join(&claims.path, &sub_path);
+        let not_found = || {
+            Server::html_response(
+                404,
+                html::page("Not found", "<p>The shared file no longer exists.</p>"),
+            )
+        };
+        // the link reaches what its creator can still read, and no more
+        if !self.acl.permissions(creator, &rel_path).read
+            || self.acl.browse_only(creator, &claims.path)
+        {
+            return Ok(not_found());
+        }
+        if storage::is_dir(self.storage.as_ref(), &rel_path).await {
+            let sort = html::Sort::from_query(req.uri().query());
+            return Ok(Server::html_response(
+                200,
+                self.share_folder_page(&token, &claims, &sub_path, &sort).await,
+            ));
+        }
+        Ok(self.file_response(&rel_path).await.unwrap_or_else(not_found))
+    }
 
-        let response_body = "";
+    fn invalid_share_link() -> Response<Full<Bytes>> {
+        Server::html_response(
+            404,
+            html::page("Invalid link", "<p>This share link is not valid.</p>"),
+        )
+    }
 
-        Ok(hyper::Response::builder()
-            .status(200)
-            .body(Full::from(Bytes::from(response_body)))
-            .unwrap())
+    /// Listing of a folder inside a shared folder, links stay under the share token
+    async fn share_folder_page(
+        &self,
+        token: &str,
+        claims: &ShareClaims,
+        sub_path: &str,
+        sort: &html::Sort,
+    ) -> String {
+        let base = format!("/s/{}/", token);
+        let shared_root = claims.path.as_str();
+        let creator = claims.creator.as_deref();
+        let mut rows = self
+            .dir_rows(&paths::join(shared_root, sub_path), &base, sub_path, |entry| {
+                self.acl.permissions(creator, &paths::join(shared_root, entry)).read
+            })
+            .await;
+        sort.apply(&mut rows);
+        let parent_href = (!sub_path.is_empty())
+            .then(|| Server::dir_href(&base, paths::parent(sub_path)));
+        let title = format!("/{}", paths::join(shared_root, sub_path).trim_end_matches('/'));
+        html::page(&title, &html::table(&rows, parent_href.as_deref(), sort))
     }
+}
 
-    pub async fn handle_addfolder(
+/// Password prompt for a protected share link
+fn share_password_form(token: &str, error: Option<&str>) -> String {
+    let error = error
+        .map(|e| format!("<p class=\"err\">{}</p>", html::escape(e)))
+        .unwrap_or_default();
+    html::page(
+        "Password required",
+        &format!(
+            "{}<form method=\"post\" action=\"/s/{}\">\
+             <input type=\"password\" name=\"password\" autofocus> \
+             <button type=\"submit\">Open</button></form>",
+            error, token
+        ),
+    )
+}
+
+/// Value of the cookie `name` in the request, if sent
+fn cookie<'a>(req_headers: &'a hyper::header::HeaderMap, name: &str) -> Option<&'a str> {
+    req_headers
+        .get_all(hyper::header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+// web index
+impl Server {
+    /// Serves `/files/<path>` - folders as sortable HTML tables, files as downloads.
+    /// With authentication on, browsers log in with HTTP basic auth and see what the ACL allows
+    pub async fn handle_webindex(
         &self,
-        _req_bytes: Bytes,
+        req: hyper::Request<hyper::body::Incoming>,
     ) -> Result<Response<Full<Bytes>>, hyper::Error> {
-        let response_body = "to be implemented";
+        if !self.web_index {
+            return Ok(Server::text_response(404, "Not Found"));
+        }
+        if req.method() != hyper::Method::GET && req.method() != hyper::Method::HEAD {
+            return Ok(Server::text_response(405, "The web index is read-only"));
+        }
+        let user = match self.authenticate_basic(req.headers()) {
+            Ok(user) => user,
+            Err(response) => return Ok(*response),
+        };
+        let user = user.as_deref();
+
+        let raw_path = req.uri().path().trim_start_matches("/files");
+        let Some(rel_path) = percent_decode_str(raw_path)
+            .decode_utf8()
+            .ok()
+            .and_then(|path| paths::normalize(&path))
+        else {
+            return Ok(Server::text_response(400, "Invalid path"));
+        };
+        if !self.acl.permissions(user, &rel_path).read {
+            return Ok(Server::html_response(
+                403,
+                html::page("Forbidden", "<p>You can't see this.</p>"),
+            ));
+        }
+
+        if storage::is_dir(self.storage.as_ref(), &rel_path).await {
+            // folder links are relative to the trailing slash
+            if !raw_path.ends_with('/') {
+                return Ok(hyper::Response::builder()
+                    .status(301)
+                    .header(hyper::header::LOCATION, Server::dir_href("/files/", &rel_path))
+                    .body(Full::from(Bytes::new()))
+                    .unwrap());
+            }
+            let sort = html::Sort::from_query(req.uri().query());
+            let mut rows = self
+                .dir_rows(&rel_path, "/files/", &rel_path, |entry| self.acl.permissions(user, entry).read)
+                .await;
+            sort.apply(&mut rows);
+            let parent_href = (!rel_path.is_empty())
+                .then(|| Server::dir_href("/files/", paths::parent(&rel_path)));
+            let body = format!(
+                "{}\n{}",
+                html::breadcrumbs("/files/", &self.name, &rel_path),
+                html::table(&rows, parent_href.as_deref(), &sort)
+            );
+            return Ok(Server::html_response(
+                200,
+                html::page(&format!("{} - /{}", self.name, rel_path), &body),
+            ));
+        }
+        match self.file_response(&rel_path).await {
+            Some(response) => Ok(response),
+            None => Ok(Server::html_response(
+                404,
+                html::page("Not found", "<p>No such file or folder.</p>"),
+            )),
+        }
+    }
+
+    /// `/` sends browsers to the web index when it is on
+    pub fn handle_root(&self) -> Result<Response<Full<Bytes>>, hyper::Error> {
+        if !self.web_index {
+            return self.handle_std_request();
+        }
         Ok(hyper::Response::builder()
-            .status(200)
-            .body(Full::from(Bytes::from(response_body)))
+            .status(302)
+            .header(hyper::header::LOCATION, "/files/")
+            .body(Full::from(Bytes::new()))
             .unwrap())
     }
+}
 
-    pub async fn handle_getfiles(
+// webdav
+impl Server {
+    /// Serves `/dav/<path>` - a WebDAV class 2 subset (PROPFIND, GET, PUT, DELETE, MKCOL,
+    /// COPY, MOVE, LOCK, UNLOCK) over the same storage, paths and permissions as the client
+    pub async fn handle_webdav(
         &self,
-        _req_bytes: Bytes,
+        req: hyper::Request<hyper::body::Incoming>,
     ) -> Result<Response<Full<Bytes>>, hyper::Error> {
-        let paths = fs::read_dir(&self.storage_dir).unwrap();
-        let mut paths_list: Vec<String> = Vec::new();
-        for path in paths {
-            let current_path = path.unwrap().path();
-            let file_name = current_path.file_stem().unwrap().to_str().unwrap();
-            let file_type = current_path.extension().unwrap().to_str().unwrap();
-            paths_list.push(format!("{}.{}", file_name, file_type));
+        if !self.webdav {
+            return Ok(Server::text_response(404, "Not Found"));
         }
-        println!("Recieved getfiles request, sending \n{:?}", paths_list);
+        let user = match self.authenticate_basic(req.headers()) {
+            Ok(user) => user,
+            Err(response) => return Ok(*response),
+        };
+        let user = user.as_deref();
+        let Some(rel_path) = webdav::request_path(req.uri().path()) else {
+            return Ok(Server::text_response(400, "Invalid path"));
+        };
 
-        let response_body = serde_json::to_string(&paths_list).unwrap();
-        Ok(hyper::Response::builder()
+        let (parts, body) = req.into_parts();
+        let tokens = webdav::if_tokens(&parts.headers);
+        match parts.method.as_str() {
+            "OPTIONS" => Ok(hyper::Response::builder()
+                .status(200)
+                .header("DAV", "1, 2")
+                .header("MS-Author-Via", "DAV")
+                .header(hyper::header::ALLOW, DAV_METHODS)
+                .body(Full::from(Bytes::new()))
+                .unwrap()),
+            "PROPFIND" => {
+                let body = body.collect().await?.to_bytes();
+                Ok(self.dav_propfind(&rel_path, &parts.headers, &body, user).await)
+            }
+            "GET" | "HEAD" => Ok(self.dav_get(&rel_path, parts.method == hyper::Method::HEAD, user).await),
+            "PUT" => match Server::content_encoding(&parts.headers) {
+                Ok(encoding) => self.dav_put(&rel_path, body, encoding, &tokens, user).await,
+                Err(response) => Ok(*response),
+            },
+            "DELETE" => Ok(self.dav_delete(&rel_path, &tokens, user).await),
+            "MKCOL" => {
+                let body = body.collect().await?.to_bytes();
+                if !body.is_empty() {
+                    return Ok(Server::text_response(415, "MKCOL doesn't take a body"));
+                }
+                Ok(self.dav_mkcol(&rel_path, &tokens, user).await)
+            }
+            "COPY" => Ok(self.dav_copy_move(&rel_path, &parts.headers, false, &tokens, user).await),
+            "MOVE" => Ok(self.dav_copy_move(&rel_path, &parts.headers, true, &tokens, user).await),
+            "LOCK" => {
+                let body = body.collect().await?.to_bytes();
+                Ok(self.dav_lock(&rel_path, &parts.headers, &body, &tokens, user).await)
+            }
+            "UNLOCK" => Ok(self.dav_unlock(&rel_path, &parts.headers, user)),
+            _ => Ok(hyper::Response::builder()
+                .status(405)
+                .header(hyper::header::ALLOW, DAV_METHODS)
+                .body(Full::from(Bytes::from("Method not supported")))
+                .unwrap()),
+        }
+    }
+
+    async fn dav_propfind(
+        &self,
+        rel_path: &str,
+        req_headers: &hyper::header::HeaderMap,
+        body: &[u8],
+        user: Option<&str>,
+    ) -> Response<Full<Bytes>> {
+        let Some(depth) = Depth::from_headers(req_headers, Depth::Infinity) else {
+            return Server::text_response(400, "Invalid Depth header");
+        };
+        if depth == Depth::Infinity {
+            return Server::xml_response(403, webdav::finite_depth_error());
+        }
+        let Some(request) = webdav::parse_propfind(body) else {
+            return Server::text_response(400, "Invalid PROPFIND body");
+        };
+        if !self.acl.permissions(user, rel_path).read {
+            return Server::text_response(403, "You can't see this");
+        }
+        let Ok(metadata) = self.storage.stat(rel_path).await else {
+            return Server::text_response(404, "No such file or folder");
+        };
+
+        let mut entries = vec![PropEntry::from_metadata(
+            rel_path.to_string(),
+            &metadata,
+            self.locks.discovery(rel_path),
+        )];
+        if depth == Depth::One && metadata.is_dir {
+            for entry in self.storage.list(rel_path).await.unwrap_or_default() {
+                let child = paths::join(rel_path, &entry.name);
+                if !self.acl.permissions(user, &child).read {
+                    continue;
+                }
+                let lock = self.locks.discovery(&child);
+                entries.push(PropEntry::from_metadata(child, &entry.metadata, lock));
+            }
+        }
+        Server::xml_response(207, webdav::multistatus(&entries, &request))
+    }
+
+    async fn dav_get(&self, rel_path: &str, head: bool, user: Option<&str>) -> Response<Full<Bytes>> {
+        if !self.acl.permissions(user, rel_path).read {
+            return Server::text_response(403, "You can't read this");
+        }
+        let Ok(metadata) = self.storage.stat(rel_path).await else {
+            return Server::text_response(404, "No such file or folder");
+        };
+        // browsers pointed at the dav url get a plain listing
+        if metadata.is_dir {
+            let sort = html::Sort::from_query(None);
+            let base = webdav::href("", true);
+            let mut rows = self
+                .dir_rows(rel_path, &base, rel_path, |entry| self.acl.permissions(user, entry).read)
+                .await;
+            sort.apply(&mut rows);
+            let title = format!("/{}", rel_path);
+            return Server::html_response(200, html::page(&title, &html::table(&rows, None, &sort)));
+        }
+
+        let entry = PropEntry::from_metadata(rel_path.to_string(), &metadata, None);
+        let contents = if head {
+            Bytes::new()
+        } else {
+            match storage::read_all(self.storage.as_ref(), rel_path).await {
+                Ok(contents) => Bytes::from(contents),
+                Err(_) => return Server::text_response(404, "No such file"),
+            }
+        };
+        hyper::Response::builder()
             .status(200)
-            .body(Full::from(Bytes::from(response_body)))
-            .unwrap())
+            .header(
+                hyper::header::CONTENT_TYPE,
+                mime_guess::from_path(rel_path).first_or_octet_stream().as_ref(),
+            )
+            .header(hyper::header::CONTENT_LENGTH, entry.size)
+            .header(hyper::header::LAST_MODIFIED, webdav::http_date(entry.modified))
+            .header(hyper::header::ETAG, webdav::etag(entry.size, entry.modified))
+            .body(Full::from(contents))
+            .unwrap()
     }
 
-    pub fn handle_std_request(&self) -> Result<Response<Full<Bytes>>, hyper::Error> {
-        return Ok(hyper::Response::builder()
-            .status(404)
-            .body(Full::from(Bytes::from("Not Found")))
-            .unwrap());
+    async fn dav_put(
+        &self,
+        rel_path: &str,
+        req_body: hyper::body::Incoming,
+        encoding: Option<Encoding>,
+        tokens: &[String],
+        user: Option<&str>,
+    ) -> Result<Response<Full<Bytes>>, hyper::Error> {
+        if rel_path.is_empty() || !self.acl.permissions(user, rel_path).write {
+            return Ok(Server::text_response(403, "You can't write here"));
+        }
+        if !self.locks.can_write(rel_path, tokens) {
+            return Ok(Server::text_response(423, "Locked"));
+        }
+        let existed = match self.storage.stat(rel_path).await {
+            Ok(metadata) if metadata.is_dir => return Ok(Server::text_response(405, "That's a folder")),
+            Ok(_) => true,
+            Err(_) => false,
+        };
+        let room = match self.upload_room(rel_path).await {
+            Ok(room) => room,
+            Err(message) => return Ok(Server::quota_response(&message)),
+        };
+        let temp_path = storage::upload_temp_path(rel_path);
+        let Ok(file) = self.storage.write(&temp_path).await else {
+            return Ok(Server::text_response(409, "No such parent folder"));
+        };
+        if let Some(response) = self.receive_file(req_body, encoding, file, &temp_path, room).await {
+            return Ok(response);
+        }
+        if let Some(response) = self.finish_upload(&temp_path, rel_path, user).await {
+            return Ok(response);
+        }
+        self.changed(rel_path, if existed { ChangeKind::Modified } else { ChangeKind::Created }).await;
+        Ok(Server::text_response(if existed { 204 } else { 201 }, ""))
+    }
+
+    async fn dav_delete(&self, rel_path: &str, tokens: &[String], user: Option<&str>) -> Response<Full<Bytes>> {
+        if rel_path.is_empty() || !self.acl.subtree_permissions(user, rel_path).write {
+            return Server::text_response(403, "You can't delete this");
+        }
+        if !self.locks.can_write(rel_path, tokens) {
+            return Server::text_response(423, "Locked");
+        }
+        match self.remove(rel_path, user).await {
+            Ok(_) => {
+                self.locks.remove_below(rel_path);
+                Server::text_response(204, "")
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Server::text_response(404, "No such file or folder")
+            }
+            Err(e) => {
+                eprintln!("Unable to delete '{}': {}", rel_path, e);
+                Server::text_response(500, "Unable to delete")
+            }
+        }
+    }
+
+    async fn dav_mkcol(&self, rel_path: &str, tokens: &[String], user: Option<&str>) -> Response<Full<Bytes>> {
+        if rel_path.is_empty() || !self.acl.permissions(user, rel_path).write {
+            return Server::text_response(403, "You can't create folders here");
+        }
+        if !self.locks.can_write(rel_path, tokens) {
+            return Server::text_response(423, "Locked");
+        }
+        match self.storage.create_dir(rel_path).await {
+            Ok(_) => {
+                self.changed(rel_path, ChangeKind::Created).await;
+                Server::text_response(201, "")
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                Server::text_response(405, "That name is already taken")
+            }
+            Err(_) => Server::text_response(409, "No such parent folder"),
+        }
+    }
+
+    async fn dav_copy_move(
+        &self,
+        rel_path: &str,
+        req_headers: &hyper::header::HeaderMap,
+        is_move: bool,
+        tokens: &[String],
+        user: Option<&str>,
+    ) -> Response<Full<Bytes>> {
+        let Some(destination) = webdav::destination(req_headers) else {
+            return Server::text_response(400, "Missing or invalid Destination header");
+        };
+        let recursive = match Depth::from_headers(req_headers, Depth::Infinity) {
+            Some(Depth::Infinity) => true,
+            Some(Depth::Zero) if !is_move => false,
+            _ => return Server::text_response(400, "Invalid Depth header"),
+        };
+        if rel_path.is_empty() || destination.is_empty() || destination == rel_path {
+            return Server::text_response(403, "Source and destination must differ");
+        }
+        if destination.starts_with(&format!("{}/", rel_path)) {
+            return Server::text_response(409, "Can't put a folder inside itself");
+        }
+        // whole folders are only taken, or written over, where every shared folder in them allows it
+        let source_permissions = if recursive {
+            self.acl.subtree_permissions(user, rel_path)
+        } else {
+            self.acl.permissions(user, rel_path)
+        };
+        let allowed = if is_move { source_permissions.write } else { source_permissions.read };
+        if !allowed || !self.acl.subtree_permissions(user, &destination).write {
+            return Server::text_response(403, "You can't do this here");
+        }
+        if (is_move && !self.locks.can_write(rel_path, tokens))
+            || !self.locks.can_write(&destination, tokens)
+        {
+            return Server::text_response(423, "Locked");
+        }
+
+        let store = self.storage.as_ref();
+        if !storage::exists(store, rel_path).await {
+            return Server::text_response(404, "No such file or folder");
+        }
+        if !storage::is_dir(store, paths::parent(&destination)).await {
+            return Server::text_response(409, "No such destination folder");
+        }
+        let moved_from = is_move.then_some(rel_path);
+        if let Err(message) = self.check_room(&destination, moved_from, quota::usage_of(store, rel_path)).await {
+            return Server::quota_response(&message);
+        }
+        let existed = storage::exists(store, &destination).await;
+        // a file replaced by a file keeps a version like an upload does, anything else in
+        // the way goes to the trash like a delete
+        let replaces_file = existed && !storage::is_dir(store, &destination).await && !storage::is_dir(store, rel_path).await;
+        if existed {
+            if !webdav::overwrite(req_headers) {
+                return Server::text_response(412, "Destination exists");
+            }
+            if replaces_file {
+                self.save_version(&destination, user).await;
+            } else if let Err(e) = self.remove(&destination, user).await {
+                eprintln!("Unable to replace '{}': {}", destination, e);
+                return Server::text_response(500, "Unable to replace the destination");
+            }
+            self.locks.remove_below(&destination);
+        }
+
+        let result = if is_move {
+            store.rename(rel_path, &destination).await
+        } else {
+            storage::copy_all(store, rel_path, &destination, recursive).await
+        };
+        match result {
+            Ok(_) => {
+                if is_move {
+                    self.locks.remove_below(rel_path);
+                    self.renamed(rel_path, &destination).await;
+                } else {
+                    let kind = if replaces_file { ChangeKind::Modified } else { ChangeKind::Created };
+                    self.changed(&destination, kind).await;
+                }
+                Server::text_response(if existed { 204 } else { 201 }, "")
+            }
+            Err(e) => {
+                eprintln!("Unable to copy/move '{}' to '{}': {}", rel_path, destination, e);
+                Server::text_response(500, "Unable to copy or move")
+            }
+        }
+    }
+
+    async fn dav_lock(
+        &self,
+        rel_path: &str,
+        req_headers: &hyper::header::HeaderMap,
+        body: &[u8],
+        tokens: &[String],
+        user: Option<&str>,
+    ) -> Response<Full<Bytes>> {
+        if !self.acl.permissions(user, rel_path).write {
+            return Server::text_response(403, "You can't lock this");
+        }
+        let timeout = webdav::timeout(req_headers);
+
+        // an empty body refreshes a lock named in the If header
+        if body.iter().all(u8::is_ascii_whitespace) {
+            return match self.locks.refresh(rel_path, tokens, timeout) {
+                Some(activelock) => Server::xml_response(200, webdav::lock_response(&activelock)),
+                None => Server::text_response(412, "No matching lock to refresh"),
+            };
+        }
+        let Some(owner) = webdav::parse_lockinfo(body) else {
+            return Server::text_response(400, "Invalid LOCK body");
+        };
+        let infinite = match Depth::from_headers(req_headers, Depth::Infinity) {
+            Some(Depth::Infinity) => true,
+            Some(Depth::Zero) => false,
+            _ => return Server::text_response(400, "Invalid Depth header"),
+        };
+
+        let Some((token, activelock)) = self.locks.lock(rel_path, infinite, owner, timeout) else {
+            return Server::text_response(423, "Locked");
+        };
+        // locking a name that doesn't exist yet reserves it with an empty file
+        let mut status = 200;
+        if !storage::exists(self.storage.as_ref(), rel_path).await {
+            if storage::write_all(self.storage.as_ref(), rel_path, b"").await.is_err() {
+                self.locks.unlock(rel_path, &token);
+                return Server::text_response(409, "No such parent folder");
+            }
+            self.changed(rel_path, ChangeKind::Created).await;
+            status = 201;
+        }
+        hyper::Response::builder()
+            .status(status)
+            .header(hyper::header::CONTENT_TYPE, "application/xml; charset=utf-8")
+            .header("Lock-Token", format!("<{}>", token))
+            .body(Full::from(Bytes::from(webdav::lock_response(&activelock))))
+            .unwrap()
+    }
+
+    fn dav_unlock(
+        &self,
+        rel_path: &str,
+        req_headers: &hyper::header::HeaderMap,
+        user: Option<&str>,
+    ) -> Response<Full<Bytes>> {
+        if !self.acl.permissions(user, rel_path).write {
+            return Server::text_response(403, "You can't unlock this");
+        }
+        let Some(token) = req_headers
+            .get("lock-token")
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.trim().trim_start_matches('<').trim_end_matches('>'))
+        else {
+            return Server::text_response(400, "Missing Lock-Token header");
+        };
+        if self.locks.unlock(rel_path, token) {
+            Server::text_response(204, "")
+        } else {
+            Server::text_response(409, "No such lock on this resource")
+        }
+    }
+}
+
+const DAV_METHODS: &str = "OPTIONS, GET, HEAD, PUT, DELETE, PROPFIND, MKCOL, COPY, MOVE, LOCK, UNLOCK";
+
+// s3
+impl Server {
+    /// Serves `/s3/...` - an S3-compatible api where buckets are the top level folders.
+    /// Requests are SigV4 signed with a configured key and get that key's user's permissions
+    pub async fn handle_s3(
+        &self,
+        req: hyper::Request<hyper::body::Incoming>,
+    ) -> Result<Response<Full<Bytes>>, hyper::Error> {
+        let Some(s3) = &self.s3 else {
+            return Ok(Server::text_response(404, "Not Found"));
+        };
+        let resource = req.uri().path().to_string();
+        match self.s3_request(s3, req).await {
+            Ok(response) => Ok(response),
+            Err(error) => {
+                println!("S3 request for '{}' failed: {}", resource, error.code);
+                Ok(Server::xml_response(error.status, error.to_xml(&resource)))
+            }
+        }
+    }
+
+    async fn s3_request(
+        &self,
+        s3: &S3,
+        req: hyper::Request<hyper::body::Incoming>,
+    ) -> Result<Response<Full<Bytes>>, S3Error> {
+        let (parts, body) = req.into_parts();
+        let signed = s3.verify(
+            parts.method.as_str(),
+            parts.uri.path(),
+            parts.uri.query(),
+            &parts.headers,
+            unix_now(),
+        )?;
+        let user = signed.user.as_deref();
+        let query: HashMap<String, String> =
+            url::form_urlencoded::parse(parts.uri.query().unwrap_or_default().as_bytes())
+                .into_owned()
+                .collect();
+        let method = parts.method.as_str();
+
+        match Target::parse(parts.uri.path())? {
+            Target::Service if method == "GET" => self.s3_list_buckets(user).await,
+            Target::Service => Err(S3Error::not_implemented()),
+            Target::Bucket(bucket) => match method {
+                "GET" if query.contains_key("location") => {
+                    self.s3_check_bucket(&bucket, user).await?;
+                    Ok(Server::xml_response(200, s3::location_xml(s3.region())))
+                }
+                "GET" if query.contains_key("uploads") => Err(S3Error::not_implemented()),
+                "GET" => self.s3_list_objects(s3, &bucket, &query, user).await,
+                "HEAD" => {
+                    self.s3_check_bucket(&bucket, user).await?;
+                    Ok(Server::text_response(200, ""))
+                }
+                "PUT" => self.s3_create_bucket(&bucket, user).await,
+                "DELETE" => self.s3_delete_bucket(&bucket, user).await,
+                "POST" if query.contains_key("delete") => {
+                    let body = self.s3_body(body, &signed).await?;
+                    self.s3_delete_objects(&bucket, &body, user).await
+                }
+                _ => Err(S3Error::not_implemented()),
+            },
+            Target::Object { bucket, key, is_folder } => {
+                let rel_path = paths::join(&bucket, &key);
+                let upload_id = query.get("uploadId");
+                match method {
+                    "GET" | "HEAD" if upload_id.is_none() => {
+                        self.s3_get_object(s3, &bucket, &rel_path, &parts.headers, method == "HEAD", user)
+                            .await
+                    }
+                    "PUT" if parts.headers.contains_key("x-amz-copy-source") => Err(S3Error::not_implemented()),
+                    "PUT" => {
+                        self.s3_writable(&bucket, &rel_path, user).await?;
+                        let data = self.s3_body(body, &signed).await?;
+                        s3::check_content_md5(&parts.headers, &data)?;
+                        match (upload_id, query.get("partNumber")) {
+                            (Some(upload_id), Some(number)) => {
+                                self.s3_upload(s3, upload_id, &bucket, &key, user)?;
+                                let number = number
+                                    .parse()
+                                    .map_err(|_| S3Error::invalid_argument("Invalid partNumber"))?;
+                                let etag = s3.write_part(upload_id, number, &data)?;
+                                Ok(s3_etag_response(&etag))
+                            }
+                            (None, None) => self.s3_put_object(s3, &rel_path, is_folder, &data, user).await,
+                            _ => Err(S3Error::invalid_argument("partNumber and uploadId go together")),
+                        }
+                    }
+                    "POST" => match upload_id {
+                        None if query.contains_key("uploads") => {
+                            self.s3_writable(&bucket, &rel_path, user).await?;
+                            let upload = Upload {
+                                bucket: bucket.clone(),
+                                key: key.clone(),
+                                user: user.map(str::to_string),
+                            };
+                            let upload_id = s3
+                                .create_upload(&upload)
+                                .map_err(|_| S3Error::new(500, "InternalError", "Unable to start the upload"))?;
+                            Ok(Server::xml_response(200, s3::initiate_xml(&bucket, &key, &upload_id)))
+                        }
+                        Some(upload_id) => {
+                            self.s3_writable(&bucket, &rel_path, user).await?;
+                            self.s3_upload(s3, upload_id, &bucket, &key, user)?;
+                            let body = self.s3_body(body, &signed).await?;
+                            let parts_list = s3::parse_complete(&body)?;
+                            self.s3_make_parent(&rel_path).await?;
+                            let room = self.upload_room(&rel_path).await.map_err(S3Error::quota_exceeded)?;
+                            self.save_version(&rel_path, user).await;
+                            let kind = self.upload_kind(&rel_path).await;
+                            let etag = s3
+                                .complete_upload(
+                                    self.storage.as_ref(),
+                                    upload_id,
+                                    &parts_list,
+                                    &rel_path,
+                                    self.limits.max_upload_bytes,
+                                    room,
+                                )
+                                .await?;
+                            println!("S3 multipart upload of '{}' completed ({} parts)", rel_path, parts_list.len());
+                            self.changed(&rel_path, kind).await;
+                            let location = format!("{}/{}/{}", s3::PREFIX, bucket, html::encode_path(&key));
+                            Ok(Server::xml_response(200, s3::complete_xml(&location, &bucket, &key, &etag)))
+                        }
+                        None => Err(S3Error::not_implemented()),
+                    },
+                    "DELETE" => match upload_id {
+                        Some(upload_id) => {
+                            self.s3_upload(s3, upload_id, &bucket, &key, user)?;
+                            s3.abort_upload(upload_id)?;
+                            Ok(Server::text_response(204, ""))
+                        }
+                        None => {
+                            self.s3_delete_object(&bucket, &rel_path, is_folder, user).await?;
+                            Ok(Server::text_response(204, ""))
+                        }
+                    },
+                    _ => Err(S3Error::not_implemented()),
+                }
+            }
+        }
+    }
+
+    async fn s3_list_buckets(&self, user: Option<&str>) -> Result<Response<Full<Bytes>>, S3Error> {
+        let mut buckets: Vec<(String, u64)> = self
+            .storage
+            .list("")
+            .await
+            .map_err(|_| S3Error::new(500, "InternalError", "Unable to list buckets"))?
+            .into_iter()
+            .filter(|entry| entry.metadata.is_dir && self.acl.permissions(user, &entry.name).read)
+            .map(|entry| (entry.name, entry.metadata.modified))
+            .collect();
+        buckets.sort();
+        Ok(Server::xml_response(200, s3::list_buckets_xml(&buckets)))
+    }
+
+    async fn s3_list_objects(
+        &self,
+        s3: &S3,
+        bucket: &str,
+        query: &HashMap<String, String>,
+        user: Option<&str>,
+    ) -> Result<Response<Full<Bytes>>, S3Error> {
+        self.s3_check_bucket(bucket, user).await?;
+        let v2 = query.get("list-type").map(String::as_str) == Some("2");
+        let continuation_token = query.get("continuation-token").map(String::as_str);
+        let start_after = query.get("start-after").map(String::as_str);
+        let marker = query.get("marker").map(String::as_str);
+        let after = if v2 {
+            match continuation_token {
+                Some(token) => s3::decode_continuation(token)
+                    .ok_or_else(|| S3Error::invalid_argument("The continuation token provided is incorrect"))?,
+                None => start_after.unwrap_or_default().to_string(),
+            }
+        } else {
+            marker.unwrap_or_default().to_string()
+        };
+        let request = ListRequest {
+            prefix: query.get("prefix").cloned().unwrap_or_default(),
+            delimiter: query.get("delimiter").cloned().unwrap_or_default(),
+            after,
+            max_keys: query
+                .get("max-keys")
+                .and_then(|m| m.parse().ok())
+                .unwrap_or(1000)
+                .min(1000),
+        };
+
+        let listing = s3::list_objects(self.storage.as_ref(), bucket, &request, &|key| {
+            self.acl.permissions(user, &paths::join(bucket, key.trim_end_matches('/'))).read
+        })
+        .await;
+        let mut etags = Vec::new();
+        for object in &listing.objects {
+            etags.push(match object.key.ends_with('/') {
+                // folder markers are empty objects
+                true => s3::EMPTY_ETAG.to_string(),
+                false => s3.etag(self.storage.as_ref(), &object.path).await.unwrap_or_default(),
+            });
+        }
+        let params = ListParams {
+            bucket,
+            request: &request,
+            v2,
+            url_encode: query.get("encoding-type").map(String::as_str) == Some("url"),
+            continuation_token,
+            start_after,
+            marker,
+        };
+        Ok(Server::xml_response(200, s3::list_objects_xml(&params, &listing, &etags)))
+    }
+
+    async fn s3_create_bucket(&self, bucket: &str, user: Option<&str>) -> Result<Response<Full<Bytes>>, S3Error> {
+        if !self.acl.permissions(user, bucket).write {
+            return Err(S3Error::access_denied());
+        }
+        match self.storage.create_dir(bucket).await {
+            Ok(_) => {
+                self.changed(bucket, ChangeKind::Created).await;
+                Ok(hyper::Response::builder()
+                    .status(200)
+                    .header(hyper::header::LOCATION, format!("{}/{}", s3::PREFIX, bucket))
+                    .body(Full::from(Bytes::new()))
+                    .unwrap())
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Err(S3Error::new(
+                409,
+                "BucketAlreadyOwnedByYou",
+                "Your previous request to create the named bucket succeeded and you already own it",
+            )),
+            Err(_) => Err(S3Error::new(500, "InternalError", "Unable to create the bucket")),
+        }
+    }
+
+    async fn s3_delete_bucket(&self, bucket: &str, user: Option<&str>) -> Result<Response<Full<Bytes>>, S3Error> {
+        self.s3_check_bucket(bucket, user).await?;
+        if !self.acl.permissions(user, bucket).write {
+            return Err(S3Error::access_denied());
+        }
+        if self.storage.list(bucket).await.map_or(true, |entries| !entries.is_empty()) {
+            return Err(S3Error::new(409, "BucketNotEmpty", "The bucket you tried to delete is not empty"));
+        }
+        self.storage
+            .delete(bucket)
+            .await
+            .map_err(|_| S3Error::new(500, "InternalError", "Unable to delete the bucket"))?;
+        self.changed(bucket, ChangeKind::Deleted).await;
+        Ok(Server::text_response(204, ""))
+    }
+
+    async fn s3_get_object(
+        &self,
+        s3: &S3,
+        bucket: &str,
+        rel_path: &str,
+        req_headers: &hyper::header::HeaderMap,
+        head: bool,
+        user: Option<&str>,
+    ) -> Result<Response<Full<Bytes>>, S3Error> {
+        self.s3_check_bucket(bucket, user).await?;
+        if !self.acl.permissions(user, rel_path).read {
+            return Err(S3Error::access_denied());
+        }
+        let metadata = self.storage.stat(rel_path).await.map_err(|_| S3Error::no_such_key())?;
+        if metadata.is_dir {
+            return Err(S3Error::no_such_key());
+        }
+        let etag = s3
+            .etag(self.storage.as_ref(), rel_path)
+            .await
+            .map_err(|_| S3Error::new(500, "InternalError", "Unable to read the object"))?;
+
+        let size = metadata.size;
+        let range = s3::parse_range(req_headers, size)?;
+        let (start, end) = range.unwrap_or((0, size.saturating_sub(1)));
+        let length = if size == 0 { 0 } else { end - start + 1 };
+        let contents = if head || length == 0 {
+            Bytes::new()
+        } else {
+            let contents = storage::read_all(self.storage.as_ref(), rel_path)
+                .await
+                .map_err(|_| S3Error::no_such_key())?;
+            Bytes::from(contents).slice(start as usize..=end as usize)
+        };
+
+        let mut response = hyper::Response::builder()
+            .status(if range.is_some() { 206 } else { 200 })
+            .header(
+                hyper::header::CONTENT_TYPE,
+                mime_guess::from_path(rel_path).first_or_octet_stream().as_ref(),
+            )
+            .header(hyper::header::CONTENT_LENGTH, length)
+            .header(hyper::header::ETAG, etag)
+            .header(hyper::header::LAST_MODIFIED, webdav::http_date(metadata.modified))
+            .header(hyper::header::ACCEPT_RANGES, "bytes");
+        if range.is_some() {
+            response = response.header(
+                hyper::header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, end, size),
+            );
+        }
+        Ok(response.body(Full::from(contents)).unwrap())
+    }
+
+    async fn s3_put_object(
+        &self,
+        s3: &S3,
+        rel_path: &str,
+        is_folder: bool,
+        data: &[u8],
+        user: Option<&str>,
+    ) -> Result<Response<Full<Bytes>>, S3Error> {
+        let store = self.storage.as_ref();
+        // "folder/" keys are how S3 tools make empty folders
+        if is_folder {
+            if !data.is_empty() {
+                return Err(S3Error::invalid_argument("Folder markers must be empty"));
+            }
+            storage::create_dir_all(store, rel_path)
+                .await
+                .map_err(|_| S3Error::invalid_argument("A file is in the way of that folder"))?;
+            self.changed(rel_path, ChangeKind::Created).await;
+            return Ok(s3_etag_response(s3::EMPTY_ETAG));
+        }
+        if storage::is_dir(store, rel_path).await {
+            return Err(S3Error::invalid_argument("A folder with that key already exists"));
+        }
+        self.s3_make_parent(rel_path).await?;
+        let room = self.upload_room(rel_path).await.map_err(S3Error::quota_exceeded)?;
+        if room.is_some_and(|room| data.len() as u64 > room) {
+            return Err(S3Error::quota_exceeded("The object doesn't fit in the quota"));
+        }
+        self.save_version(rel_path, user).await;
+        let kind = self.upload_kind(rel_path).await;
+        storage::write_all(store, rel_path, data)
+            .await
+            .map_err(|_| S3Error::new(500, "InternalError", "Unable to store the object"))?;
+        self.changed(rel_path, kind).await;
+        Ok(s3_etag_response(&s3.remember_etag(store, rel_path, data).await))
+    }
+
+    async fn s3_delete_object(
+        &self,
+        bucket: &str,
+        rel_path: &str,
+        is_folder: bool,
+        user: Option<&str>,
+    ) -> Result<(), S3Error> {
+        self.s3_writable(bucket, rel_path, user).await?;
+        // deleting something that isn't there succeeds, like on S3
+        let result = match (is_folder, self.storage.stat(rel_path).await) {
+            (true, Ok(metadata)) if metadata.is_dir => match self.storage.list(rel_path).await {
+                Ok(entries) if entries.is_empty() => {
+                    let deleted = self.storage.delete(rel_path).await;
+                    self.changed(rel_path, ChangeKind::Deleted).await;
+                    deleted
+                }
+                _ => Err(std::io::ErrorKind::DirectoryNotEmpty.into()),
+            },
+            (false, Ok(metadata)) if !metadata.is_dir => self.remove(rel_path, user).await,
+            _ => Ok(()),
+        };
+        result.map_err(|_| S3Error::new(409, "InvalidRequest", "Only empty folders can be deleted"))
+    }
+
+    async fn s3_delete_objects(&self, bucket: &str, body: &[u8], user: Option<&str>) -> Result<Response<Full<Bytes>>, S3Error> {
+        self.s3_check_bucket(bucket, user).await?;
+        let (keys, quiet) = s3::parse_delete(body)?;
+        let mut deleted = Vec::new();
+        let mut errors = Vec::new();
+        for key in keys {
+            let path = format!("{}/{}/{}", s3::PREFIX, bucket, key);
+            let result = match Target::parse(&path) {
+                Ok(Target::Object { key: normalized, is_folder, .. }) => {
+                    self.s3_delete_object(bucket, &paths::join(bucket, &normalized), is_folder, user)
+                        .await
+                }
+                Ok(_) => Err(S3Error::invalid_argument("Invalid key")),
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(_) => deleted.push(key),
+                Err(e) => errors.push((key, e)),
+            }
+        }
+        Ok(Server::xml_response(200, s3::delete_xml(&deleted, &errors, quiet)))
+    }
+
+    /// Fails unless `bucket` exists and the user can see it
+    async fn s3_check_bucket(&self, bucket: &str, user: Option<&str>) -> Result<(), S3Error> {
+        if !storage::is_dir(self.storage.as_ref(), bucket).await {
+            return Err(S3Error::no_such_bucket());
+        }
+        if !self.acl.permissions(user, bucket).read {
+            return Err(S3Error::access_denied());
+        }
+        Ok(())
+    }
+
+    async fn s3_writable(&self, bucket: &str, rel_path: &str, user: Option<&str>) -> Result<(), S3Error> {
+        self.s3_check_bucket(bucket, user).await?;
+        if !self.acl.permissions(user, rel_path).write {
+            return Err(S3Error::access_denied());
+        }
+        Ok(())
+    }
+
+    /// Checks `upload_id` is an upload of this object by this user
+    fn s3_upload(&self, s3: &S3, upload_id: &str, bucket: &str, key: &str, user: Option<&str>) -> Result<(), S3Error> {
+        match s3.get_upload(upload_id) {
+            Some(upload) if upload.bucket == bucket && upload.key == key && upload.user.as_deref() == user => Ok(()),
+            _ => Err(S3Error::no_such_upload()),
+        }
+    }
+
+    /// Creates the folders a key implies
+    async fn s3_make_parent(&self, rel_path: &str) -> Result<(), S3Error> {
+        storage::create_dir_all(self.storage.as_ref(), paths::parent(rel_path))
+            .await
+            .map_err(|_| S3Error::invalid_argument("A file is in the way of that key"))
+    }
+
+    /// Whole request body, checked against its signature and the upload limit
+    async fn s3_body(&self, mut req_body: hyper::body::Incoming, signed: &Signed) -> Result<Vec<u8>, S3Error> {
+        let mut body = Vec::new();
+        while let Some(frame) = req_body.frame().await {
+            let frame = frame.map_err(|_| S3Error::new(400, "IncompleteBody", "The request body was cut off"))?;
+            let Ok(data) = frame.into_data() else {
+                continue;
+            };
+            body.extend_from_slice(&data);
+            if self.limits.max_upload_bytes.is_some_and(|max| body.len() as u64 > max) {
+                return Err(S3Error::new(400, "EntityTooLarge", "Your proposed upload exceeds the maximum allowed size"));
+            }
+        }
+        signed.decode_payload(body)
+    }
+}
+
+fn s3_etag_response(etag: &str) -> Response<Full<Bytes>> {
+    hyper::Response::builder()
+        .status(200)
+        .header(hyper::header::ETAG, etag)
+        .body(Full::from(Bytes::new()))
+        .unwrap()
+}
+
+// versions
+impl Server {
+    /// Previous versions of the file in the `file` header, newest first
+    pub async fn handle_versions(
+        &self,
+        req_headers: hyper::header::HeaderMap,
+        user: Option<&str>,
+    ) -> Result<Response<Full<Bytes>>, hyper::Error> {
+        let Some(versions) = &self.versions else {
+            return Ok(Server::text_response(404, "Versioning is not enabled"));
+        };
+        let Some(rel_path) = Server::header_path(&req_headers, "file").filter(|rel| !rel.is_empty()) else {
+            return Ok(Server::text_response(400, "Invalid file"));
+        };
+        if !self.acl.permissions(user, &rel_path).read {
+            return Ok(Server::text_response(403, "You can't read this file"));
+        }
+        match versions.list(&rel_path).await {
+            Ok(list) => {
+                let response_body = serde_json::to_string(&VersionsResponse {
+                    path: rel_path,
+                    versions: list,
+                })
+                .unwrap();
+                Ok(hyper::Response::builder()
+                    .status(200)
+                    .body(Full::from(Bytes::from(response_body)))
+                    .unwrap())
+            }
+            Err(e) => {
+                eprintln!("Unable to read the versions of '{}': {}", rel_path, e);
+                Ok(Server::text_response(500, "Unable to read the versions"))
+            }
+        }
+    }
+
+    /// Puts back the version in the `version` header of the file in the `file` header,
+    /// what it replaces is kept as a version too
+    pub async fn handle_restoreversion(
+        &self,
+        req_headers: hyper::header::HeaderMap,
+        user: Option<&str>,
+    ) -> Result<Response<Full<Bytes>>, hyper::Error> {
+        let Some(versions) = &self.versions else {
+            return Ok(Server::text_response(404, "Versioning is not enabled"));
+        };
+        let Some(rel_path) = Server::header_path(&req_headers, "file").filter(|rel| !rel.is_empty()) else {
+            return Ok(Server::text_response(400, "Invalid file"));
+        };
+        let Some(id) = req_headers
+            .get("version")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+        else {
+            return Ok(Server::text_response(400, "Missing or invalid version header"));
+        };
+        if !self.acl.permissions(user, &rel_path).write {
+            return Ok(Server::text_response(403, "You can't change this file"));
+        }
+        if storage::is_dir(self.storage.as_ref(), &rel_path).await {
+            return Ok(Server::text_response(409, "A folder with that name exists now"));
+        }
+        let size = match versions.list(&rel_path).await {
+            Ok(list) => list.iter().find(|version| version.id == id).map(|version| version.size),
+            Err(_) => None,
+        };
+        let Some(size) = size else {
+            return Ok(Server::text_response(404, "No such version"));
+        };
+        let adding = std::future::ready(Ok(quota::Usage { bytes: size, files: 1 }));
+        if let Err(message) = self.check_room(&rel_path, None, adding).await {
+            return Ok(Server::quota_response(&message));
+        }
+        let kind = self.upload_kind(&rel_path).await;
+        match versions.restore(self.storage.as_ref(), &rel_path, id).await {
+            Ok(Some(version)) => {
+                println!("Restored version {} of '{}' ({} bytes)", id, rel_path, version.size);
+                self.changed(&rel_path, kind).await;
+                Ok(Server::text_response(200, ""))
+            }
+            Ok(None) => Ok(Server::text_response(404, "No such version")),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Ok(Server::text_response(404, "No such folder"))
+            }
+            Err(e) => {
+                eprintln!("Unable to restore version {} of '{}': {}", id, rel_path, e);
+                Ok(Server::text_response(500, "Unable to restore the version"))
+            }
+        }
+    }
+
+    /// Keeps the file at `rel_path` as a version before an upload from `user` replaces it.
+    /// Failing to keep it is logged but doesn't stop the upload
+    async fn save_version(&self, rel_path: &str, user: Option<&str>) {
+        if let Some(versions) = &self.versions {
+            if let Err(e) = versions.save(self.storage.as_ref(), rel_path, user).await {
+                eprintln!("Unable to keep the previous version of '{}': {}", rel_path, e);
+            }
+        }
+    }
+}
+
+// trash
+impl Server {
+    /// Deletes the file or folder in the `file` header, into the trash when there is one
+    pub async fn handle_delete(
+        &self,
+        req_headers: hyper::header::HeaderMap,
+        user: Option<&str>,
+    ) -> Result<Response<Full<Bytes>>, hyper::Error> {
+        let Some(rel_path) = Server::header_path(&req_headers, "file").filter(|rel| !rel.is_empty()) else {
+            return Ok(Server::text_response(400, "Invalid file"));
+        };
+        if !self.acl.permissions(user, &rel_path).write {
+            return Ok(Server::text_response(403, "You can't delete this"));
+        }
+        if !self.acl.subtree_permissions(user, &rel_path).write {
+            return Ok(Server::text_response(403, "A folder below this one can't be changed by you"));
+        }
+        match self.remove(&rel_path, user).await {
+            Ok(_) => {
+                self.locks.remove_below(&rel_path);
+                Ok(Server::text_response(200, ""))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Ok(Server::text_response(404, "No such file or folder"))
+            }
+            Err(e) => {
+                eprintln!("Unable to delete '{}': {}", rel_path, e);
+                Ok(Server::text_response(500, "Unable to delete"))
+            }
+        }
+    }
+
+    /// Trash items the user could restore, newest first
+    pub async fn handle_trash(&self, user: Option<&str>) -> Result<Response<Full<Bytes>>, hyper::Error> {
+        let Some(trash) = &self.trash else {
+            return Ok(Server::text_response(404, "The trash is not enabled"));
+        };
+        let items = match trash.list().await {
+            Ok(items) => items,
+            Err(e) => {
+                eprintln!("Unable to list the trash: {}", e);
+                return Ok(Server::text_response(500, "Unable to list the trash"));
+            }
+        };
+        let items = items
+            .into_iter()
+            .filter(|item| self.acl.permissions(user, &item.path).write)
+            .collect();
+        let response_body = serde_json::to_string(&TrashResponse { items }).unwrap();
+        Ok(hyper::Response::builder()
+            .status(200)
+            .body(Full::from(Bytes::from(response_body)))
+            .unwrap())
+    }
+
+    /// Puts the trash item in the `id` header back where it was
+    pub async fn handle_restoretrash(
+        &self,
+        req_headers: hyper::header::HeaderMap,
+        user: Option<&str>,
+    ) -> Result<Response<Full<Bytes>>, hyper::Error> {
+        let (trash, id) = match self.trash_item(&req_headers, user).await {
+            Ok(found) => found,
+            Err(response) => return Ok(*response),
+        };
+        if let Ok(item) = trash.item(&id).await {
+            if let Err(message) = self.check_room(&item.path, None, trash.usage(&id)).await {
+                return Ok(Server::quota_response(&message));
+            }
+        }
+        match trash.restore(&id).await {
+            Ok(item) => {
+                println!("Restored '{}' from the trash", item.path);
+                self.changed(&item.path, ChangeKind::Created).await;
+                Ok(Server::text_response(200, ""))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                Ok(Server::text_response(409, "Something else is in its place now"))
+            }
+            Err(e) => {
+                eprintln!("Unable to restore trash item {}: {}", id, e);
+                Ok(Server::text_response(500, "Unable to restore"))
+            }
+        }
+    }
+
+    /// Deletes the trash item in the `id` header for good
+    pub async fn handle_purgetrash(
+        &self,
+        req_headers: hyper::header::HeaderMap,
+        user: Option<&str>,
+    ) -> Result<Response<Full<Bytes>>, hyper::Error> {
+        let (trash, id) = match self.trash_item(&req_headers, user).await {
+            Ok(found) => found,
+            Err(response) => return Ok(*response),
+        };
+        match trash.purge(&id).await {
+            Ok(_) => Ok(Server::text_response(200, "")),
+            Err(e) => {
+                eprintln!("Unable to purge trash item {}: {}", id, e);
+                Ok(Server::text_response(500, "Unable to purge"))
+            }
+        }
+    }
+
+    /// Purges every trash item the user could restore
+    pub async fn handle_emptytrash(&self, user: Option<&str>) -> Result<Response<Full<Bytes>>, hyper::Error> {
+        let Some(trash) = &self.trash else {
+            return Ok(Server::text_response(404, "The trash is not enabled"));
+        };
+        let Ok(items) = trash.list().await else {
+            return Ok(Server::text_response(500, "Unable to list the trash"));
+        };
+        let mut purged = 0;
+        for item in items {
+            if !self.acl.permissions(user, &item.path).write {
+                continue;
+            }
+            match trash.purge(&item.id).await {
+                Ok(_) => purged += 1,
+                Err(e) => eprintln!("Unable to purge trash item {}: {}", item.id, e),
+            }
+        }
+        Ok(Server::text_response(200, &purged.to_string()))
+    }
+
+    /// Purges trash items past the retention, run periodically
+    pub async fn purge_expired_trash(&self) {
+        let Some(trash) = &self.trash else {
+            return;
+        };
+        match trash.purge_expired().await {
+            Ok(0) => {}
+            Ok(purged) => println!("Purged {} expired trash items", purged),
+            Err(e) => eprintln!("Unable to purge the trash: {}", e),
+        }
+    }
+
+    /// Deletes the file or folder at `rel_path` on behalf of `user`, into the trash when
+    /// there is one
+    async fn remove(&self, rel_path: &str, user: Option<&str>) -> std::io::Result<()> {
+        match &self.trash {
+            Some(trash) => {
+                let item = trash.delete(rel_path, user).await?;
+                println!("Moved '{}' to the trash ({} bytes)", rel_path, item.size);
+            }
+            None => self.storage.delete(rel_path).await?,
+        }
+        self.changed(rel_path, ChangeKind::Deleted).await;
+        Ok(())
+    }
+
+    /// The trash and the item named in the `id` header, if the user may touch it -
+    /// `Err` holds the response to send back otherwise
+    async fn trash_item(
+        &self,
+        req_headers: &hyper::header::HeaderMap,
+        user: Option<&str>,
+    ) -> Result<(&Trash, String), Box<Response<Full<Bytes>>>> {
+        let Some(trash) = &self.trash else {
+            return Err(Box::new(Server::text_response(404, "The trash is not enabled")));
+        };
+        let Some(id) = req_headers.get("id").and_then(|v| v.to_str().ok()) else {
+            return Err(Box::new(Server::text_response(400, "Missing id header")));
+        };
+        let Ok(item) = trash.item(id).await else {
+            return Err(Box::new(Server::text_response(404, "No such item in the trash")));
+        };
+        // putting a folder back writes into any shared folders below it too
+        if !self.acl.subtree_permissions(user, &item.path).write {
+            return Err(Box::new(Server::text_response(403, "You can't restore this")));
+        }
+        Ok((trash, item.id))
+    }
+}
+
+// quotas
+impl Server {
+    /// Quotas that uploads into the folder in the `dir` header have to fit in, with their usage
+    pub async fn handle_usage(
+        &self,
+        req_headers: hyper::header::HeaderMap,
+        user: Option<&str>,
+    ) -> Result<Response<Full<Bytes>>, hyper::Error> {
+        let Some(dir) = Server::header_path(&req_headers, "dir") else {
+            return Ok(Server::text_response(400, "Invalid dir"));
+        };
+        if !self.acl.permissions(user, &dir).read {
+            return Ok(Server::text_response(403, "You can't read this folder"));
+        }
+        let mut quotas = Vec::new();
+        for (folder, limit) in self.quotas.applying(&dir) {
+            let usage = match self.folder_usage(&folder).await {
+                Ok(usage) => usage,
+                Err(e) => {
+                    eprintln!("Unable to count the usage of '{}': {}", folder, e);
+                    return Ok(Server::text_response(500, "Unable to count the usage"));
+                }
+            };
+            quotas.push(QuotaUsage {
+                path: folder,
+                used_bytes: usage.bytes,
+                max_bytes: limit.max_bytes,
+                used_files: usage.files,
+                max_files: limit.max_files,
+            });
+        }
+        let response_body = serde_json::to_string(&UsageResponse { dir, quotas }).unwrap();
+        Ok(hyper::Response::builder()
+            .status(200)
+            .body(Full::from(Bytes::from(response_body)))
+            .unwrap())
+    }
+
+    /// Bytes an upload to `rel_path` may write before it breaks a quota above it, `None` when
+    /// nothing limits it. `Err` holds why not even an empty file fits
+    async fn upload_room(&self, rel_path: &str) -> Result<Option<u64>, String> {
+        // a file that gets replaced doesn't count against its own upload
+        let replaced = match self.storage.stat(rel_path).await {
+            Ok(metadata) if !metadata.is_dir => Some(metadata.size),
+            _ => None,
+        };
+        let mut room: Option<u64> = None;
+        for (folder, limit) in self.quotas.applying(rel_path) {
+            let usage = match self.folder_usage(&folder).await {
+                Ok(usage) => usage,
+                Err(e) => {
+                    eprintln!("Unable to count the usage of '{}', not enforcing its quota: {}", folder, e);
+                    continue;
+                }
+            };
+            if replaced.is_none() && limit.max_files.is_some_and(|max| usage.files >= max) {
+                return Err(format!("'{}' already holds as many files as its quota allows", folder));
+            }
+            if let Some(max) = limit.max_bytes {
+                let left = max.saturating_sub(usage.bytes.saturating_sub(replaced.unwrap_or(0)));
+                room = Some(room.map_or(left, |room| room.min(left)));
+            }
+        }
+        Ok(room)
+    }
+
+    /// Whether what `adding` counts fits at `rel_path`, for copies and restores. what is at
+    /// `rel_path` now is replaced and doesn't count. a move from `moved_from` doesn't change
+    /// the quotas of folders it stays in. `adding` is only counted when a quota applies
+    async fn check_room(
+        &self,
+        rel_path: &str,
+        moved_from: Option<&str>,
+        adding: impl std::future::Future<Output = std::io::Result<quota::Usage>>,
+    ) -> Result<(), String> {
+        let applying: Vec<(String, quota::Limit)> = self
+            .quotas
+            .applying(rel_path)
+            .into_iter()
+            .filter(|(folder, _)| !moved_from.is_some_and(|from| paths::is_below(from, folder)))
+            .collect();
+        if applying.is_empty() {
+            return Ok(());
+        }
+        let adding = match adding.await {
+            Ok(adding) => adding,
+            Err(e) => {
+                eprintln!("Unable to count what goes to '{}', not enforcing its quotas: {}", rel_path, e);
+                return Ok(());
+            }
+        };
+        let replaced = quota::usage_of(self.storage.as_ref(), rel_path).await.unwrap_or_default();
+        for (folder, limit) in applying {
+            let usage = match self.folder_usage(&folder).await {
+                Ok(usage) => usage,
+                Err(e) => {
+                    eprintln!("Unable to count the usage of '{}', not enforcing its quota: {}", folder, e);
+                    continue;
+                }
+            };
+            let files = usage.files.saturating_sub(replaced.files) + adding.files;
+            if adding.files > replaced.files && limit.max_files.is_some_and(|max| files > max) {
+                return Err(format!("'{}' can't hold that many more files", folder));
+            }
+            let bytes = usage.bytes.saturating_sub(replaced.bytes) + adding.bytes;
+            if adding.bytes > replaced.bytes && limit.max_bytes.is_some_and(|max| bytes > max) {
+                return Err(format!("That doesn't fit in the quota of '{}'", folder));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether new files, each a path and its size, fit in every quota they fall under, for
+    /// extracting archives. nothing is replaced by them
+    async fn check_new_files(&self, files: impl IntoIterator<Item = (String, u64)>) -> Result<(), String> {
+        let mut adding: Vec<(String, quota::Limit, quota::Usage)> = Vec::new();
+        for (path, size) in files {
+            for (folder, limit) in self.quotas.applying(&path) {
+                let index = match adding.iter().position(|(known, _, _)| *known == folder) {
+                    Some(index) => index,
+                    None => {
+                        adding.push((folder, limit, quota::Usage::default()));
+                        adding.len() - 1
+                    }
+                };
+                adding[index].2.bytes += size;
+                adding[index].2.files += 1;
+            }
+        }
+        for (folder, limit, adding) in adding {
+            let usage = match self.folder_usage(&folder).await {
+                Ok(usage) => usage,
+                Err(e) => {
+                    eprintln!("Unable to count the usage of '{}', not enforcing its quota: {}", folder, e);
+                    continue;
+                }
+            };
+            if limit.max_files.is_some_and(|max| usage.files + adding.files > max) {
+                return Err(format!("'{}' can't hold that many more files", folder));
+            }
+            if limit.max_bytes.is_some_and(|max| usage.bytes + adding.bytes > max) {
+                return Err(format!("That doesn't fit in the quota of '{}'", folder));
+            }
+        }
+        Ok(())
+    }
+
+    fn quota_response(message: &str) -> Response<Full<Bytes>> {
+        Server::text_response(507, message)
+    }
+}
+
+// search
+impl Server {
+    /// Files and folders below `dir` matching a `SearchRequest`, see search.rs
+    pub async fn handle_search(
+        &self,
+        req_bytes: Bytes,
+        user: Option<&str>,
+    ) -> Result<Response<Full<Bytes>>, hyper::Error> {
+        let Ok(request) = serde_json::from_slice::<SearchRequest>(&req_bytes) else {
+            return Ok(Server::text_response(400, "Expected a json search request"));
+        };
+        let Some(dir) = paths::normalize(&request.dir) else {
+            return Ok(Server::text_response(400, "Invalid dir"));
+        };
+        if !self.acl.permissions(user, &dir).read {
+            return Ok(Server::text_response(403, "You can't search this folder"));
+        }
+        let query = match Query::new(&request) {
+            Ok(query) => query,
+            Err(message) => return Ok(Server::text_response(400, &message)),
+        };
+        if !request.tags.is_empty() && self.metadata.is_none() {
+            return Ok(Server::text_response(400, "Searching by tag needs the metadata index"));
+        }
+        let found = match &self.metadata {
+            Some(metadata) => self.search_index(metadata, &dir, &request.tags, &query, user),
+            None => search::search(self.storage.as_ref(), &self.acl, user, &dir, &query).await,
+        };
+        let (mut results, truncated) = match found {
+            Ok(found) => found,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Server::text_response(404, "No such folder"));
+            }
+            Err(e) => {
+                eprintln!("Unable to search '{}': {}", dir, e);
+                return Ok(Server::text_response(500, "Unable to search"));
+            }
+        };
+        for result in &mut results {
+            self.annotate(&result.path, &mut result.entry);
+        }
+        println!(
+            "Searched '{}' for '{}' ({:?}), {} results{}",
+            dir,
+            request.pattern,
+            request.mode,
+            results.len(),
+            if truncated { ", truncated" } else { "" }
+        );
+        let response_body = serde_json::to_string(&SearchResponse {
+            dir,
+            results,
+            truncated,
+        })
+        .unwrap();
+        Ok(hyper::Response::builder()
+            .status(200)
+            .body(Full::from(Bytes::from(response_body)))
+            .unwrap())
+    }
+
+    /// Like `search::search` but from the metadata index, only entries with all of `tags`
+    fn search_index(
+        &self,
+        metadata: &MetadataIndex,
+        dir: &str,
+        tags: &[String],
+        query: &Query,
+        user: Option<&str>,
+    ) -> std::io::Result<(Vec<SearchResult>, bool)> {
+        let Some(mut entries) = metadata.below(dir)? else {
+            return Err(std::io::ErrorKind::NotFound.into());
+        };
+        if !tags.is_empty() {
+            let tagged = metadata.tagged(dir, tags)?;
+            entries.retain(|(path, _)| tagged.contains(path));
+        }
+        Ok(search::search_entries(entries, &self.acl, user, query))
+    }
+}
+
+// full-text search
+impl Server {
+    /// Text files below `dir` containing every word of `query`, see fulltext.rs
+    pub async fn handle_textsearch(
+        &self,
+        req_bytes: Bytes,
+        user: Option<&str>,
+    ) -> Result<Response<Full<Bytes>>, hyper::Error> {
+        let Some(full_text) = &self.full_text else {
+            return Ok(Server::text_response(404, "Full-text search is not enabled"));
+        };
+        let Ok(request) = serde_json::from_slice::<TextSearchRequest>(&req_bytes) else {
+            return Ok(Server::text_response(400, "Expected a json text search request"));
+        };
+        let Some(dir) = paths::normalize(&request.dir) else {
+            return Ok(Server::text_response(400, "Invalid dir"));
+        };
+        if !self.acl.permissions(user, &dir).read {
+            return Ok(Server::text_response(403, "You can't search this folder"));
+        }
+        let words = fulltext::query_words(&request.query);
+        if words.is_empty() {
+            return Ok(Server::text_response(400, "Search for at least one word of 2 or more letters"));
+        }
+        let (mut results, truncated) = full_text.search(&words, &dir, &self.acl, user).await;
+        for found in &mut results {
+            self.annotate(&found.path, &mut found.entry);
+        }
+        println!("Searched the text of '{}' for {:?}, {} files", dir, words, results.len());
+        let response_body = serde_json::to_string(&TextSearchResponse {
+            dir,
+            results,
+            truncated,
+        })
+        .unwrap();
+        Ok(hyper::Response::builder()
+            .status(200)
+            .body(Full::from(Bytes::from(response_body)))
+            .unwrap())
+    }
+
+    /// Catches the index up with changes made while the server was stopped
+    pub async fn reconcile_full_text(&self) {
+        let Some(full_text) = &self.full_text else {
+            return;
+        };
+        match full_text.reconcile().await {
+            Ok(read) => println!("Full-text index is up to date ({} files read)", read),
+            Err(e) => eprintln!("Unable to update the full-text index: {}", e),
+        }
+    }
+}
+
+// metadata index
+impl Server {
+    /// Catches the index up with changes made while the server was stopped, listings are
+    /// only right once this is done
+    pub async fn reconcile_metadata(&self) {
+        let Some(metadata) = &self.metadata else {
+            return;
+        };
+        match metadata.reconcile().await {
+            Ok(changed) => println!("Metadata index is up to date ({} entries changed)", changed),
+            Err(e) => eprintln!("Unable to update the metadata index: {}", e),
+        }
+    }
+
+    /// Tells the indexes that the file or folder at `rel_path` changed
+    async fn reindex(&self, rel_path: &str) {
+        if let Some(metadata) = &self.metadata {
+            if let Err(e) = metadata.update(rel_path).await {
+                eprintln!("Unable to update the metadata index of '{}': {}", rel_path, e);
+            }
+        }
+        if let Some(full_text) = &self.full_text {
+            full_text.update(rel_path);
+        }
+    }
+
+    /// Tells the indexes and anyone following `/events` that something changed at `rel_path`
+    async fn changed(&self, rel_path: &str, kind: ChangeKind) {
+        self.reindex(rel_path).await;
+        self.events.publish(ChangeEvent {
+            kind,
+            path: rel_path.to_string(),
+            to: None,
+        });
+    }
+
+    /// Like `changed` for something moved from `from` to `to`, its tags and note go along
+    async fn renamed(&self, from: &str, to: &str) {
+        if let Some(metadata) = &self.metadata {
+            if let Err(e) = metadata.moved(from, to) {
+                eprintln!("Unable to move the tags of '{}': {}", from, e);
+            }
+        }
+        self.reindex(from).await;
+        self.reindex(to).await;
+        self.events.publish(ChangeEvent {
+            kind: ChangeKind::Renamed,
+            path: from.to_string(),
+            to: Some(to.to_string()),
+        });
+    }
+
+    /// Applies a change made to the storage directory by something other than the server
+    pub async fn disk_changed(&self, event: ChangeEvent) {
+        // the server's own writes show up on disk as well, they are indexed already
+        if self.events.recently_published(&event) {
+            return;
+        }
+        match event.to {
+            Some(to) if event.kind == ChangeKind::Renamed => self.renamed(&event.path, &to).await,
+            _ => self.changed(&event.path, event.kind).await,
+        }
+    }
+
+    /// Whether writing `rel_path` creates it or modifies it
+    async fn upload_kind(&self, rel_path: &str) -> ChangeKind {
+        if storage::exists(self.storage.as_ref(), rel_path).await {
+            ChangeKind::Modified
+        } else {
+            ChangeKind::Created
+        }
+    }
+
+    /// Entries of the folder `dir`, from the index when there is one
+    async fn list_dir(&self, dir: &str) -> std::io::Result<Vec<DirEntry>> {
+        match &self.metadata {
+            Some(metadata) => metadata.list(dir)?.ok_or_else(|| std::io::ErrorKind::NotFound.into()),
+            None => self.storage.list(dir).await,
+        }
+    }
+
+    /// What is stored at or below `folder`, from the index when there is one
+    async fn folder_usage(&self, folder: &str) -> std::io::Result<quota::Usage> {
+        match &self.metadata {
+            Some(metadata) => metadata.usage(folder),
+            None => quota::usage(self.storage.as_ref(), folder).await,
+        }
+    }
+}
+
+// tags and notes
+impl Server {
+    /// Adds and removes tags of a file or folder, see `TagsRequest`
+    pub async fn handle_tags(&self, req_bytes: Bytes, user: Option<&str>) -> Result<Response<Full<Bytes>>, hyper::Error> {
+        let Some(index) = &self.metadata else {
+            return Ok(Server::text_response(404, "Tags need the metadata index"));
+        };
+        let Ok(request) = serde_json::from_slice::<TagsRequest>(&req_bytes) else {
+            return Ok(Server::text_response(400, "Expected a json tags request"));
+        };
+        let Some(rel_path) = paths::normalize(&request.path).filter(|rel| !rel.is_empty()) else {
+            return Ok(Server::text_response(400, "Invalid path"));
+        };
+        if !self.acl.permissions(user, &rel_path).write {
+            return Ok(Server::text_response(403, "You can't tag this"));
+        }
+        if let Some(tag) = request.add.iter().find(|tag| !metadata::valid_tag(tag)) {
+            return Ok(Server::text_response(
+                400,
+                &format!(
+                    "Invalid tag '{}', use up to {} letters, digits, '-', '_' and '.'",
+                    tag,
+                    metadata::MAX_TAG_CHARS
+                ),
+            ));
+        }
+        match index.set_tags(&rel_path, &request.add, &request.remove) {
+            Ok(true) => {}
+            Ok(false) => return Ok(Server::text_response(404, "No such file or folder")),
+            Err(e) => {
+                eprintln!("Unable to tag '{}': {}", rel_path, e);
+                return Ok(Server::text_response(500, "Unable to save the tags"));
+            }
+        }
+        println!("Tagged '{}', added {:?}, removed {:?}", rel_path, request.add, request.remove);
+        Ok(Server::labels_response(index, rel_path))
+    }
+
+    /// Sets or removes the note of a file or folder, see `NoteRequest`
+    pub async fn handle_note(&self, req_bytes: Bytes, user: Option<&str>) -> Result<Response<Full<Bytes>>, hyper::Error> {
+        let Some(index) = &self.metadata else {
+            return Ok(Server::text_response(404, "Notes need the metadata index"));
+        };
+        let Ok(request) = serde_json::from_slice::<NoteRequest>(&req_bytes) else {
+            return Ok(Server::text_response(400, "Expected a json note request"));
+        };
+        let Some(rel_path) = paths::normalize(&request.path).filter(|rel| !rel.is_empty()) else {
+            return Ok(Server::text_response(400, "Invalid path"));
+        };
+        if !self.acl.permissions(user, &rel_path).write {
+            return Ok(Server::text_response(403, "You can't add a note to this"));
+        }
+        let note = request.note.trim();
+        if note.chars().count() > metadata::MAX_NOTE_CHARS {
+            return Ok(Server::text_response(
+                400,
+                &format!("Notes can be at most {} characters", metadata::MAX_NOTE_CHARS),
+            ));
+        }
+        match index.set_note(&rel_path, Some(note).filter(|note| !note.is_empty())) {
+            Ok(true) => {}
+            Ok(false) => return Ok(Server::text_response(404, "No such file or folder")),
+            Err(e) => {
+                eprintln!("Unable to set the note of '{}': {}", rel_path, e);
+                return Ok(Server::text_response(500, "Unable to save the note"));
+            }
+        }
+        println!("Set the note of '{}'", rel_path);
+        Ok(Server::labels_response(index, rel_path))
+    }
+
+    /// What `path` has now, as the reply to a change
+    fn labels_response(index: &MetadataIndex, path: String) -> Response<Full<Bytes>> {
+        let (tags, note) = match index.labels(&path) {
+            Ok(labels) => labels,
+            Err(e) => {
+                eprintln!("Unable to read the tags of '{}': {}", path, e);
+                return Server::text_response(500, "Unable to read the tags");
+            }
+        };
+        let response_body = serde_json::to_string(&LabelsResponse { path, tags, note }).unwrap();
+        hyper::Response::builder()
+            .status(200)
+            .body(Full::from(Bytes::from(response_body)))
+            .unwrap()
+    }
+
+    /// Fills in the tags, note and sha256 of the entry at `rel_path`, when there is a metadata index
+    fn annotate(&self, rel_path: &str, entry: &mut FileEntry) {
+        let Some(index) = &self.metadata else {
+            return;
+        };
+        match index.labels(rel_path) {
+            Ok((tags, note)) => {
+                entry.tags = tags;
+                entry.note = note;
+            }
+            Err(e) => eprintln!("Unable to read the tags of '{}': {}", rel_path, e),
+        }
+        if !entry.is_dir {
+            entry.sha256 = index.sha256(rel_path).unwrap_or_default();
+        }
+    }
+}
+
+// change events
+impl Server {
+    /// Streams the changes the user can see as server-sent events, see `events.rs`
+    pub fn handle_events(&self, req_headers: &hyper::header::HeaderMap) -> Response<BoxBody<Bytes, Infallible>> {
+        let user = match self.authenticate(req_headers) {
+            Ok(user) => user,
+            Err(response) => return response.map(BodyExt::boxed),
+        };
+        let mut changes = self.events.subscribe();
+        let acl = self.acl.clone();
+        let (sender, receiver) = tokio::sync::mpsc::channel(16);
+        tokio::spawn(async move {
+            let mut keepalive = tokio::time::interval(std::time::Duration::from_secs(30));
+            loop {
+                let chunk = tokio::select! {
+                    change = changes.recv() => match change {
+                        Ok(change) => match Server::visible_change(&acl, user.as_deref(), change) {
+                            Some(change) => events::message(&change),
+                            None => continue,
+                        },
+                        // tell the client it missed some so it reloads
+                        Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => Bytes::from("event: lagged\ndata:\n\n"),
+                        Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                    },
+                    _ = keepalive.tick() => Bytes::from(": keepalive\n\n"),
+                };
+                // the client went away
+                if sender.send(chunk).await.is_err() {
+                    break;
+                }
+            }
+        });
+        Response::builder()
+            .status(200)
+            .header(hyper::header::CONTENT_TYPE, "text/event-stream")
+            .header(hyper::header::CACHE_CONTROL, "no-cache")
+            .body(EventStream::new(receiver).boxed())
+            .unwrap()
+    }
+
+    /// `change` as seen by `user`, a rename across what they can read is a delete or a create
+    fn visible_change(acl: &Acl, user: Option<&str>, change: ChangeEvent) -> Option<ChangeEvent> {
+        let readable = |path: &str| acl.permissions(user, path).read;
+        match change.to {
+            Some(to) if change.kind == ChangeKind::Renamed => match (readable(&change.path), readable(&to)) {
+                (true, true) => Some(ChangeEvent { to: Some(to), ..change }),
+                (true, false) => Some(ChangeEvent {
+                    kind: ChangeKind::Deleted,
+                    path: change.path,
+                    to: None,
+                }),
+                (false, true) => Some(ChangeEvent {
+                    kind: ChangeKind::Created,
+                    path: to,
+                    to: None,
+                }),
+                (false, false) => None,
+            },
+            _ => readable(&change.path).then_some(change),
+        }
+    }
+}
+
+// delta transfer
+impl Server {
+    /// Signature of the stored file in the `file` header, for a client that wants to upload
+    /// only what it changed
+    pub async fn handle_signature(
+        &self,
+        req_headers: hyper::header::HeaderMap,
+        user: Option<&str>,
+    ) -> Result<Response<Full<Bytes>>, hyper::Error> {
+        let (rel_path, size) = match self.delta_base(&req_headers, user).await {
+            Ok(base) => base,
+            Err(response) => return Ok(*response),
+        };
+        let signature = match self.storage.read(&rel_path).await {
+            Ok(reader) => Signature::read(reader, size).await,
+            Err(e) => Err(e),
+        };
+        match signature {
+            Ok(signature) => Ok(Server::binary_response(signature.encode())),
+            Err(e) => {
+                eprintln!("Unable to read '{}': {}", rel_path, e);
+                Ok(Server::text_response(500, "Unable to read the file"))
+            }
+        }
+    }
+
+    /// Like `/addfile` for a file the server has - the body is a delta against the stored
+    /// file, `content_sha256` is what the patched file has to hash to. it is patched into an
+    /// upload temp file while the delta comes in
+    pub async fn handle_patchfile(
+        &self,
+        req_body: hyper::body::Incoming,
+        req_headers: hyper::header::HeaderMap,
+        user: Option<&str>,
+    ) -> Result<Response<Full<Bytes>>, hyper::Error> {
+        let rel_path = match self.upload_path(&req_headers, user).await {
+            Ok(rel_path) => rel_path,
+            Err(response) => return Ok(*response),
+        };
+        let Some(expected) = req_headers.get("content_sha256").and_then(|v| v.to_str().ok()) else {
+            return Ok(Server::text_response(400, "Missing content_sha256 header"));
+        };
+        let base = match self.storage.stat(&rel_path).await {
+            Ok(metadata) if !metadata.is_dir => metadata,
+            _ => return Ok(Server::text_response(404, "Nothing to patch, upload the whole file")),
+        };
+        // the patched file has to fit the same limits as an upload, it isn't built past them
+        let room = match self.upload_room(&rel_path).await {
+            Ok(room) => room,
+            Err(message) => return Ok(Server::quota_response(&message)),
+        };
+        let max_len = [self.limits.max_upload_bytes, room].into_iter().flatten().min();
+
+        let temp_path = storage::upload_temp_path(&rel_path);
+        let Ok(mut file) = self.storage.write(&temp_path).await else {
+            return Ok(Server::text_response(500, "Unable to store the file"));
+        };
+        let delta = StreamReader::new(BodyDataStream::new(req_body.map_err(std::io::Error::other)));
+        let open_base = |offset| self.storage.read_from(&rel_path, offset);
+        let outcome = match delta::patch(base.size, open_base, delta, &mut file, max_len.unwrap_or(u64::MAX)).await {
+            Ok(patched) => file.shutdown().await.map(|_| patched),
+            Err(e) => Err(e),
+        };
+        let patched = match outcome {
+            Ok(patched) if patched.sha256 == expected.to_ascii_lowercase() => patched,
+            outcome => {
+                drop(file);
+                let _ = self.storage.delete(&temp_path).await;
+                return Ok(match outcome {
+                    // someone else changed it since the signature was made
+                    Ok(_) => Server::text_response(409, "The file changed on the server, upload it again"),
+                    Err(e) if e.kind() == std::io::ErrorKind::InvalidData => match max_len {
+                        Some(max) if room == Some(max) => Server::quota_response(
+                            "The delta doesn't fit the stored file or the file doesn't fit in the quota",
+                        ),
+                        Some(max) => Server::text_response(
+                            413,
+                            &format!("The delta doesn't fit the stored file or the file exceeds the {} byte limit", max),
+                        ),
+                        None => Server::text_response(400, "The delta doesn't fit the stored file"),
+                    },
+                    Err(e) => {
+                        eprintln!("Unable to patch '{}': {}", rel_path, e);
+                        Server::text_response(500, "Unable to store the file")
+                    }
+                });
+            }
+        };
+        if let Some(response) = self.finish_upload(&temp_path, &rel_path, user).await {
+            return Ok(response);
+        }
+        println!(
+            "Patched '{}', {} of {} bytes were sent",
+            rel_path, patched.literal_bytes, patched.len
+        );
+        self.changed(&rel_path, ChangeKind::Modified).await;
+        Ok(Server::text_response(200, ""))
+    }
+
+    /// Like `/downloadfile` for a client with an older copy - the body is the signature of
+    /// that copy and the answer a delta against it, written while it is sent, with the sha256
+    /// of the whole file in the `content_sha256` header
+    pub async fn handle_deltafile(&self, req: hyper::Request<hyper::body::Incoming>) -> Response<BoxBody<Bytes, std::io::Error>> {
+        let boxed = |response: Response<Full<Bytes>>| response.map(|body| body.map_err(|never| match never {}).boxed());
+        let user = match self.authenticate(req.headers()) {
+            Ok(user) => user,
+            Err(response) => return boxed(*response),
+        };
+        let (parts, body) = req.into_parts();
+        let (rel_path, _) = match self.delta_base(&parts.headers, user.as_deref()).await {
+            Ok(base) => base,
+            Err(response) => return boxed(*response),
+        };
+        let body = match Limited::new(body, MAX_SIGNATURE_BYTES).collect().await {
+            Ok(body) => body.to_bytes(),
+            Err(e) if e.is::<LengthLimitError>() => return boxed(Server::text_response(413, "The signature is too big")),
+            Err(_) => return boxed(Server::text_response(400, "The signature was cut off")),
+        };
+        let Some(signature) = Signature::decode(&body) else {
+            return boxed(Server::text_response(400, "Expected a signature"));
+        };
+        // the hash goes in a header, so it takes a read of its own before the delta
+        let sha256 = match storage::sha256(self.storage.as_ref(), &rel_path).await {
+            Ok(sha256) => sha256,
+            Err(e) => {
+                eprintln!("Unable to read '{}': {}", rel_path, e);
+                return boxed(Server::text_response(500, "Unable to read the file"));
+            }
+        };
+        let storage = Arc::clone(&self.storage);
+        let body = archive::piped(move |out| async move {
+            let reader = storage.read(&rel_path).await?;
+            delta::delta(&signature, reader, out).await?;
+            Ok(())
+        });
+        hyper::Response::builder()
+            .status(200)
+            .header(hyper::header::CONTENT_TYPE, "application/octet-stream")
+            .header("content_sha256", sha256)
+            .body(body.boxed())
+            .unwrap()
+    }
+
+    /// The readable file in the `file` header and its size
+    async fn delta_base(
+        &self,
+        req_headers: &hyper::header::HeaderMap,
+        user: Option<&str>,
+    ) -> Result<(String, u64), Box<Response<Full<Bytes>>>> {
+        let Some(rel_path) = Server::header_path(req_headers, "file").filter(|rel| !rel.is_empty()) else {
+            return Err(Box::new(Server::text_response(400, "Invalid file")));
+        };
+        if !self.acl.permissions(user, &rel_path).read {
+            return Err(Box::new(Server::text_response(403, "You can't read this file")));
+        }
+        match self.storage.stat(&rel_path).await {
+            Ok(metadata) if metadata.is_dir => Err(Box::new(Server::text_response(400, "Folders have no contents"))),
+            Ok(metadata) => Ok((rel_path, metadata.size)),
+            Err(_) => Err(Box::new(Server::text_response(404, "No such file"))),
+        }
+    }
+
+    fn binary_response(body: Vec<u8>) -> Response<Full<Bytes>> {
+        hyper::Response::builder()
+            .status(200)
+            .header(hyper::header::CONTENT_TYPE, "application/octet-stream")
+            .body(Full::from(Bytes::from(body)))
+            .unwrap()
+    }
+}
+
+/// a signature is 20 bytes per block of at least 2 KiB, this covers copies of 800 GiB and more
+const MAX_SIGNATURE_BYTES: usize = 16 << 20;
+
+// archives
+impl Server {
+    /// Streams the files and folders named by the `file` headers (one or more, a missing one
+    /// means the storage root) as one archive, zip or tar.gz as the `format` header says. each
+    /// is packed under its own name, what the user can't read is left out
+    pub async fn handle_archive(&self, req_headers: &hyper::header::HeaderMap) -> Response<BoxBody<Bytes, std::io::Error>> {
+        let boxed = |response: Response<Full<Bytes>>| response.map(|body| body.map_err(|never| match never {}).boxed());
+        let user = match self.authenticate(req_headers) {
+            Ok(user) => user,
+            Err(response) => return boxed(*response),
+        };
+        let user = user.as_deref();
+        let format = match req_headers.get("format") {
+            None => archive::Format::Zip,
+            Some(value) => match value.to_str().ok().and_then(archive::Format::parse) {
+                Some(format) => format,
+                None => return boxed(Server::text_response(400, "Unknown archive format, use zip or tar.gz")),
+            },
+        };
+        let mut selected: Vec<String> = Vec::new();
+        for value in req_headers.get_all("file") {
+            let Some(rel_path) = value.to_str().ok().and_then(paths::normalize) else {
+                return boxed(Server::text_response(400, "Invalid file"));
+            };
+            selected.push(rel_path);
+        }
+        if selected.is_empty() {
+            selected.push(String::new());
+        }
+
+        let mut entries = Vec::new();
+        for (i, rel_path) in selected.iter().enumerate() {
+            let name = rel_path.rsplit('/').next().unwrap_or_default();
+            if selected[..i].iter().any(|other| other.rsplit('/').next() == Some(name)) {
+                return boxed(Server::text_response(400, &format!("More than one selected item is called '{}'", name)));
+            }
+            if !self.acl.permissions(user, rel_path).read {
+                return boxed(Server::text_response(403, &format!("You can't read '{}'", rel_path)));
+            }
+            if self.archive_entries(rel_path, name, user, &mut entries).await.is_err() {
+                return boxed(Server::text_response(404, &format!("No such file or folder '{}'", rel_path)));
+            }
+        }
+
+        // named after what was picked, or the folder it was picked in
+        let parent = paths::parent(&selected[0]);
+        let stem = match selected.as_slice() {
+            [one] if !one.is_empty() => one.rsplit('/').next().unwrap_or_default(),
+            _ if !parent.is_empty() && selected.iter().all(|rel_path| paths::parent(rel_path) == parent) => {
+                parent.rsplit('/').next().unwrap_or_default()
+            }
+            _ => &self.name,
+        };
+        let file_name = format!("{}.{}", stem, format.extension());
+        println!("Sending {} files and folders as '{}'", entries.len(), file_name);
+        hyper::Response::builder()
+            .status(200)
+            .header(hyper::header::CONTENT_TYPE, format.content_type())
+            .header(
+                hyper::header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", file_name.replace(['"', '\\'], "_")),
+            )
+            .body(archive::stream(self.storage.clone(), entries, format).boxed())
+            .unwrap()
+    }
+
+    /// Adds `rel_path` and everything below it to `entries`, named from `name` on ("" packs a
+    /// folder's contents without the folder itself)
+    async fn archive_entries(
+        &self,
+        rel_path: &str,
+        name: &str,
+        user: Option<&str>,
+        entries: &mut Vec<archive::Entry>,
+    ) -> std::io::Result<()> {
+        let metadata = match rel_path {
+            "" => storage::Metadata {
+                is_dir: true,
+                size: 0,
+                modified: 0,
+            },
+            _ => self.storage.stat(rel_path).await?,
+        };
+        if !name.is_empty() {
+            entries.push(archive::Entry {
+                name: name.to_string(),
+                path: rel_path.to_string(),
+                metadata,
+            });
+        }
+        let mut pending = std::collections::VecDeque::new();
+        if metadata.is_dir {
+            pending.push_back((rel_path.to_string(), name.to_string()));
+        }
+        while let Some((dir, prefix)) = pending.pop_front() {
+            let mut listed = self.storage.list(&dir).await?;
+            listed.sort_by(|a, b| a.name.cmp(&b.name));
+            for entry in listed {
+                let path = paths::join(&dir, &entry.name);
+                if !self.acl.permissions(user, &path).read {
+                    continue;
+                }
+                let name = paths::join(&prefix, &entry.name);
+                if entry.metadata.is_dir {
+                    pending.push_back((path.clone(), name.clone()));
+                }
+                entries.push(archive::Entry {
+                    name,
+                    path,
+                    metadata: entry.metadata,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+// archive contents
+impl Server {
+    /// Unpacks the zip, tar or tar.gz file named by the `file` header into the folder it is in,
+    /// or into the folder the `dir` header names. nothing is overwritten: when a file of the
+    /// archive is there already nothing is extracted. members that would land outside the
+    /// folder, links and special files are left out
+    pub async fn handle_extract(
+        &self,
+        req_headers: hyper::header::HeaderMap,
+        user: Option<&str>,
+    ) -> Result<Response<Full<Bytes>>, hyper::Error> {
+        let Some(rel_path) = Server::header_path(&req_headers, "file").filter(|path| !path.is_empty()) else {
+            return Ok(Server::text_response(400, "Invalid file"));
+        };
+        let Some(kind) = ArchiveKind::of(&rel_path) else {
+            return Ok(Server::text_response(400, "Only zip, tar and tar.gz files can be extracted"));
+        };
+        if !self.acl.permissions(user, &rel_path).read {
+            return Ok(Server::text_response(403, "You can't read this file"));
+        }
+        let dest = match req_headers.get("dir") {
+            None => paths::parent(&rel_path).to_string(),
+            Some(_) => match Server::header_path(&req_headers, "dir") {
+                Some(dest) => dest,
+                None => return Ok(Server::text_response(400, "Invalid dir")),
+            },
+        };
+        if !storage::is_dir(self.storage.as_ref(), &dest).await {
+            return Ok(Server::text_response(404, "No such folder"));
+        }
+        let data = match self.load_archive(&rel_path).await {
+            Ok(data) => Arc::new(data),
+            Err(response) => return Ok(*response),
+        };
+        let members = {
+            let data = data.clone();
+            tokio::task::spawn_blocking(move || unpack::members(kind, &data)).await
+        };
+        let (members, skipped) = match members {
+            Ok(Ok(members)) => members,
+            Ok(Err(e)) => return Ok(Server::text_response(422, &format!("The archive can't be read: {}", e))),
+            Err(_) => return Ok(Server::text_response(500, "Unable to read the archive")),
+        };
+
+        // everything is checked before anything is written
+        for member in &members {
+            let target = paths::join(&dest, &member.name);
+            if !self.acl.permissions(user, &target).write {
+                return Ok(Server::text_response(403, &format!("You can't write '/{}'", target)));
+            }
+            match self.storage.stat(&target).await {
+                Ok(metadata) if metadata.is_dir && member.is_dir => {}
+                Ok(_) => return Ok(Server::text_response(409, &format!("'/{}' already exists", target))),
+                Err(_) => {}
+            }
+            if !member.is_dir {
+                if let Some(max) = self.limits.max_upload_bytes {
+                    if member.size > max {
+                        return Ok(Server::text_response(
+                            413,
+                            &format!("'{}' exceeds the {} byte upload limit", member.name, max),
+                        ));
+                    }
+                }
+            }
+        }
+        // every file counts against the quotas of where it ends up, which can be deeper than `dest`
+        let files = members
+            .iter()
+            .filter(|member| !member.is_dir)
+            .map(|member| (paths::join(&dest, &member.name), member.size));
+        if let Err(message) = self.check_new_files(files).await {
+            return Ok(Server::quota_response(&message));
+        }
+
+        // folders come before what is in them
+        for member in members.iter().filter(|member| member.is_dir) {
+            let target = paths::join(&dest, &member.name);
+            if storage::exists(self.storage.as_ref(), &target).await {
+                continue;
+            }
+            if let Err(e) = self.storage.create_dir(&target).await {
+                eprintln!("Unable to create '{}': {}", target, e);
+                return Ok(Server::text_response(500, &format!("Unable to create '/{}'", target)));
+            }
+            self.changed(&target, ChangeKind::Created).await;
+        }
+        let wanted: HashSet<String> = members
+            .iter()
+            .filter(|member| !member.is_dir)
+            .map(|member| member.name.clone())
+            .collect();
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(8);
+        let reader = tokio::task::spawn_blocking(move || unpack::extract(kind, &data, &wanted, sender));
+        // each file is written next to where it goes and only gets its name once it's complete
+        let mut extracted = 0;
+        let mut current: Option<(String, String, WriteStream)> = None;
+        while let Some(piece) = receiver.recv().await {
+            let written = match piece {
+                unpack::Piece::File(name) => {
+                    if let Some((target, temp, file)) = current.take() {
+                        if self.finish_extracted(&target, &temp, file).await.is_ok() {
+                            extracted += 1;
+                        }
+                    }
+                    let target = paths::join(&dest, &name);
+                    let temp = storage::upload_temp_path(&target);
+                    self.storage.write(&temp).await.map(|file| current = Some((target, temp, file)))
+                }
+                unpack::Piece::Data(chunk) => match current.as_mut() {
+                    Some((_, _, file)) => file.write_all(&chunk).await,
+                    None => Ok(()),
+                },
+            };
+            if let Err(e) = written {
+                eprintln!("Unable to extract '{}': {}", rel_path, e);
+                if let Some((_, temp, _)) = current.take() {
+                    let _ = self.storage.delete(&temp).await;
+                }
+                return Ok(Server::text_response(
+                    500,
+                    &format!("Unable to write the extracted files, {} were written", extracted),
+                ));
+            }
+        }
+        match reader.await {
+            Ok(Ok(())) => {}
+            result => {
+                // the file that was being written when the archive broke off isn't complete
+                if let Some((_, temp, _)) = current.take() {
+                    let _ = self.storage.delete(&temp).await;
+                }
+                return Ok(match result {
+                    Ok(Err(e)) => Server::text_response(
+                        422,
+                        &format!("The archive is damaged ({}), {} files were extracted before that", e, extracted),
+                    ),
+                    _ => Server::text_response(500, "Unable to read the archive"),
+                });
+            }
+        }
+        if let Some((target, temp, file)) = current.take() {
+            if self.finish_extracted(&target, &temp, file).await.is_ok() {
+                extracted += 1;
+            }
+        }
+
+        println!("Extracted {} files of '{}' into '{}'", extracted, rel_path, dest);
+        let mut message = format!("Extracted {} files into /{}", extracted, dest);
+        if skipped > 0 {
+            message += &format!(", left out {} links or unsafe names", skipped);
+        }
+        Ok(Server::text_response(200, &message))
+    }
+
+    /// Completes an extracted file written to `temp` and gives it its name, the temp file is
+    /// removed when that fails
+    async fn finish_extracted(&self, target: &str, temp: &str, mut file: WriteStream) -> std::io::Result<()> {
+        let finished = match file.shutdown().await {
+            Ok(()) => self.storage.rename(temp, target).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = finished {
+            eprintln!("Unable to finish '{}': {}", target, e);
+            let _ = self.storage.delete(temp).await;
+            return Err(e);
+        }
+        self.changed(target, ChangeKind::Created).await;
+        Ok(())
+    }
+
+    /// The archive that the path of something that isn't in the storage points into, with its
+    /// kind and the path inside it (`""` for the archive itself). `None` when it doesn't go
+    /// through an archive file
+    async fn inside_archive(&self, rel_path: &str) -> Option<(String, ArchiveKind, String)> {
+        let mut end = 0;
+        for part in rel_path.split('/') {
+            end += part.len();
+            if let Some(kind) = ArchiveKind::of(part) {
+                let archive = &rel_path[..end];
+                if self.storage.stat(archive).await.is_ok_and(|metadata| !metadata.is_dir) {
+                    let inner = rel_path[end..].trim_start_matches('/').to_string();
+                    return Some((archive.to_string(), kind, inner));
+                }
+            }
+            end += 1;
+        }
+        None
+    }
+
+    /// Contents of the archive at `rel_path`, `Err` holds the response when it can't be opened
+    async fn load_archive(&self, rel_path: &str) -> Result<Vec<u8>, Box<Response<Full<Bytes>>>> {
+        match self.storage.stat(rel_path).await {
+            Ok(metadata) if metadata.size > unpack::MAX_ARCHIVE_BYTES => Err(Box::new(Server::text_response(
+                413,
+                &format!("Archives over {} bytes can't be opened on the server", unpack::MAX_ARCHIVE_BYTES),
+            ))),
+            Ok(_) => storage::read_all(self.storage.as_ref(), rel_path)
+                .await
+                .map_err(|_| Box::new(Server::text_response(404, "No such file"))),
+            Err(_) => Err(Box::new(Server::text_response(404, "No such file"))),
+        }
+    }
+
+    /// Listing of the folder `dir` that is inside an archive (`inner` inside `archive`), for
+    /// `handle_getfiles`. nothing in it can be changed
+    async fn archive_listing(&self, dir: String, archive: &str, kind: ArchiveKind, inner: &str) -> Response<Full<Bytes>> {
+        let data = match self.load_archive(archive).await {
+            Ok(data) => data,
+            Err(response) => return *response,
+        };
+        let members = match tokio::task::spawn_blocking(move || unpack::members(kind, &data)).await {
+            Ok(Ok((members, _))) => members,
+            Ok(Err(e)) => return Server::text_response(422, &format!("The archive can't be read: {}", e)),
+            Err(_) => return Server::text_response(500, "Unable to read the archive"),
+        };
+        if !inner.is_empty() && !members.iter().any(|member| member.is_dir && member.name == inner) {
+            return Server::text_response(404, "No such folder");
+        }
+        let mut files: Vec<FileEntry> = members
+            .into_iter()
+            .filter(|member| paths::parent(&member.name) == inner)
+            .map(|member| FileEntry {
+                name: member.name.rsplit('/').next().unwrap_or_default().to_string(),
+                is_dir: member.is_dir,
+                size: member.size,
+                modified: member.modified,
+                can_read: true,
+                can_write: false,
+                tags: Vec::new(),
+                note: None,
+                sha256: None,
+            })
+            .collect();
+        files.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+        println!("Listing '{}' inside '{}', sending {} entries", inner, archive, files.len());
+
+        let response = GetFilesResponse {
+            dir,
+            can_write: false,
+            files,
+        };
+        let response_body = serde_json::to_string(&response).unwrap();
+        hyper::Response::builder()
+            .status(200)
+            .body(Full::from(Bytes::from(response_body)))
+            .unwrap()
+    }
+
+    /// Contents of the file at `rel_path` inside an archive, for `handle_downloadfile`
+    async fn archive_member(&self, rel_path: &str) -> Result<Vec<u8>, Box<Response<Full<Bytes>>>> {
+        let no_file = || Box::new(Server::text_response(404, "No such file"));
+        let Some((archive, kind, inner)) = self.inside_archive(rel_path).await.filter(|(_, _, inner)| !inner.is_empty())
+        else {
+            return Err(no_file());
+        };
+        let data = self.load_archive(&archive).await?;
+        match tokio::task::spawn_blocking(move || unpack::read_member(kind, &data, &inner)).await {
+            Ok(Ok(Some(contents))) => Ok(contents),
+            Ok(Ok(None)) => Err(no_file()),
+            Ok(Err(e)) => Err(Box::new(Server::text_response(
+                422,
+                &format!("The file can't be taken out of the archive: {}", e),
+            ))),
+            Err(_) => Err(Box::new(Server::text_response(500, "Unable to read the archive"))),
+        }
+    }
+}
+
+// html listings
+impl Server {
+    /// Rows for the entries of the folder `dir` that pass `visible` (given the entry's path
+    /// below `rel_dir`), linked as `base` + that path
+    async fn dir_rows(
+        &self,
+        dir: &str,
+        base: &str,
+        rel_dir: &str,
+        visible: impl Fn(&str) -> bool,
+    ) -> Vec<html::Row> {
+        let Ok(entries) = self.list_dir(dir).await else {
+            return Vec::new();
+        };
+        entries
+            .into_iter()
+            .filter_map(|entry| {
+                let rel_path = paths::join(rel_dir, &entry.name);
+                if !visible(&rel_path) {
+                    return None;
+                }
+                let href = if entry.metadata.is_dir {
+                    Server::dir_href(base, &rel_path)
+                } else {
+                    format!("{}{}", base, html::encode_path(&rel_path))
+                };
+                Some(html::Row {
+                    name: entry.name,
+                    href,
+                    is_dir: entry.metadata.is_dir,
+                    size: entry.metadata.size,
+                    modified: entry.metadata.modified,
+                })
+            })
+            .collect()
+    }
+
+    /// Link to the folder `rel_path` under `base`, always ending in '/'
+    fn dir_href(base: &str, rel_path: &str) -> String {
+        if rel_path.is_empty() {
+            base.to_string()
+        } else {
+            format!("{}{}/", base, html::encode_path(rel_path))
+        }
+    }
+
+    /// The file at `rel_path` as a browser download, `None` if it can't be read
+    async fn file_response(&self, rel_path: &str) -> Option<Response<Full<Bytes>>> {
+        let contents = storage::read_all(self.storage.as_ref(), rel_path).await.ok()?;
+        let name = rel_path.rsplit('/').next()?;
+        let mime = mime_guess::from_path(rel_path).first_or_octet_stream();
+        Some(
+            hyper::Response::builder()
+                .status(200)
+                .header(hyper::header::CONTENT_TYPE, mime.as_ref())
+                .header(
+                    hyper::header::CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"{}\"", name.replace(['"', '\\'], "_")),
+                )
+                .body(Full::from(Bytes::from(contents)))
+                .unwrap(),
+        )
+    }
+}
+
+// active server stuff
+impl Server {
+    pub async fn handle_addfile(
+        &self,
+        req_body: hyper::body::Incoming,
+        req_headers: hyper::header::HeaderMap,
+        user: Option<&str>,
+    ) -> Result<Response<Full<Bytes>>, hyper::Error> {
+        let rel_path = match self.upload_path(&req_headers, user).await {
+            Ok(rel_path) => rel_path,
+            Err(response) => return Ok(*response),
+        };
+        let encoding = match Server::content_encoding(&req_headers) {
+            Ok(encoding) => encoding,
+            Err(response) => return Ok(*response),
+        };
+        let room = match self.upload_room(&rel_path).await {
+            Ok(room) => room,
+            Err(message) => return Ok(Server::quota_response(&message)),
+        };
+        let kind = self.upload_kind(&rel_path).await;
+        let temp_path = storage::upload_temp_path(&rel_path);
+        let Ok(file) = self.storage.write(&temp_path).await else {
+            return Ok(Server::text_response(404, "No such folder"));
+        };
+        if let Some(response) = self.receive_file(req_body, encoding, file, &temp_path, room).await {
+            return Ok(response);
+        }
+        if let Some(response) = self.finish_upload(&temp_path, &rel_path, user).await {
+            return Ok(response);
+        }
+        self.changed(&rel_path, kind).await;
+
+        let response_body = "";
+
+        Ok(hyper::Response::builder()
+            .status(200)
+            .body(Full::from(Bytes::from(response_body)))
+            .unwrap())
+    }
+
+    /// Like `/addfile` without a body - the `content_sha256` header names the content and
+    /// 200 means the server already had it, 404 that it has to be uploaded after all
+    pub async fn handle_linkfile(
+        &self,
+        req_headers: hyper::header::HeaderMap,
+        user: Option<&str>,
+    ) -> Result<Response<Full<Bytes>>, hyper::Error> {
+        let rel_path = match self.upload_path(&req_headers, user).await {
+            Ok(rel_path) => rel_path,
+            Err(response) => return Ok(*response),
+        };
+        let Some(sha256) = req_headers.get("content_sha256").and_then(|v| v.to_str().ok()) else {
+            return Ok(Server::text_response(400, "Missing content_sha256 header"));
+        };
+        let sha256 = sha256.to_ascii_lowercase();
+        if !self.storage.has_content(&sha256).await {
+            return Ok(Server::text_response(404, "Unknown content, upload it"));
+        }
+        let room = match self.upload_room(&rel_path).await {
+            Ok(room) => room,
+            Err(message) => return Ok(Server::quota_response(&message)),
+        };
+        let kind = self.upload_kind(&rel_path).await;
+        // linked under a temporary name first, like uploads, so a file that doesn't fit leaves
+        // the one that is there alone
+        let temp_path = storage::upload_temp_path(&rel_path);
+        match self.storage.link(&temp_path, &sha256).await {
+            Ok(true) => {
+                // the size is only known once linked
+                let size = self.storage.stat(&temp_path).await.map_or(0, |metadata| metadata.size);
+                if room.is_some_and(|room| size > room) {
+                    let _ = self.storage.delete(&temp_path).await;
+                    return Ok(Server::quota_response("The file doesn't fit in the quota"));
+                }
+                if let Some(response) = self.finish_upload(&temp_path, &rel_path, user).await {
+                    return Ok(response);
+                }
+                self.changed(&rel_path, kind).await;
+                println!("Linked '{}' to stored content {}", rel_path, sha256);
+                Ok(Server::text_response(200, "Already have it"))
+            }
+            Ok(false) => Ok(Server::text_response(404, "Unknown content, upload it")),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Ok(Server::text_response(404, "No such folder"))
+            }
+            Err(e) => {
+                eprintln!("Unable to link '{}': {}", rel_path, e);
+                Ok(Server::text_response(500, "Unable to store the file"))
+            }
+        }
+    }
+
+    /// Checked path of the file an upload names with its `file_name`, `file_type` and `dir`
+    /// headers - `Err` holds the response to send back when it can't be written
+    async fn upload_path(
+        &self,
+        req_headers: &hyper::header::HeaderMap,
+        user: Option<&str>,
+    ) -> Result<String, Box<Response<Full<Bytes>>>> {
+        let file_name_header = HeaderName::from_static("file_name").clone();
+        let file_type_header = HeaderName::from_static("file_type");
+
+        let file_name = req_headers.get(file_name_header).and_then(|v| v.to_str().ok());
+        let file_type = req_headers.get(file_type_header).and_then(|v| v.to_str().ok());
+        let (Some(file_name), Some(file_type)) = (file_name, file_type) else {
+            return Err(Box::new(Server::text_response(400, "Missing file_name or file_type header")));
+        };
+        let Some(dir) = Server::header_path(req_headers, "dir") else {
+            return Err(Box::new(Server::text_response(400, "Invalid dir")));
+        };
+
+        // an empty file_type is a file without an extension
+        let final_file_name = if file_type.is_empty() {
+            file_name.to_string()
+        } else {
+            format!("{}.{}", file_name, file_type)
+        };
+        let Some(rel_path) = paths::normalize(&paths::join(&dir, &final_file_name))
+            .filter(|rel| paths::parent(rel) == dir)
+        else {
+            return Err(Box::new(Server::text_response(400, "Invalid file name")));
+        };
+        if !self.acl.permissions(user, &rel_path).write {
+            return Err(Box::new(Server::text_response(403, "You can't upload here")));
+        }
+        if storage::is_dir(self.storage.as_ref(), &rel_path).await {
+            return Err(Box::new(Server::text_response(409, "A folder with that name already exists")));
+        }
+        Ok(rel_path)
+    }
+
+    pub async fn handle_downloadfile(
+        &self,
+        req_headers: hyper::header::HeaderMap,
+        user: Option<&str>,
+    ) -> Result<Response<Full<Bytes>>, hyper::Error> {
+        let Some(rel_path) = Server::header_path(&req_headers, "file") else {
+            return Ok(Server::text_response(400, "Invalid file"));
+        };
+        if !self.acl.permissions(user, &rel_path).read {
+            return Ok(Server::text_response(403, "You can't read this file"));
+        }
+
+        if storage::is_dir(self.storage.as_ref(), &rel_path).await {
+            return Ok(Server::text_response(400, "Folders can't be downloaded"));
+        }
+        // the client's preview only asks for the start of a file
+        if req_headers.contains_key(hyper::header::RANGE) {
+            return Ok(self.download_range(&rel_path, &req_headers).await);
+        }
+        let contents = match storage::read_all(self.storage.as_ref(), &rel_path).await {
+            Ok(contents) => contents,
+            // files inside zip and tar files
+            Err(_) => match self.archive_member(&rel_path).await {
+                Ok(contents) => contents,
+                Err(response) => return Ok(*response),
+            },
+        };
+        let response = hyper::Response::builder()
+            .status(200)
+            .header(hyper::header::VARY, "accept-encoding");
+        let accept = req_headers
+            .get(hyper::header::ACCEPT_ENCODING)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        let encoding = encoding::negotiate(accept)
+            .filter(|_| encoding::worth_compressing(&rel_path, contents.len() as u64));
+        if let Some(encoding) = encoding {
+            match compression::compress(encoding, &contents).await {
+                // text that doesn't shrink is sent as it is too
+                Ok(compressed) if compressed.len() < contents.len() => {
+                    println!(
+                        "Sending '{}' with {}, {} of {} bytes",
+                        rel_path,
+                        encoding,
+                        compressed.len(),
+                        contents.len()
+                    );
+                    return Ok(response
+                        .header(hyper::header::CONTENT_ENCODING, encoding.name())
+                        .body(Full::from(Bytes::from(compressed)))
+                        .unwrap());
+                }
+                Ok(_) => {}
+                Err(e) => eprintln!("Unable to compress '{}': {}", rel_path, e),
+            }
+        }
+        Ok(response.body(Full::from(Bytes::from(contents))).unwrap())
+    }
+
+    /// The part of the file at `rel_path` that the `Range` header asks for (one range, like
+    /// `bytes=0-16383`), only that much of it is read. sent as it is, without compressing it
+    async fn download_range(&self, rel_path: &str, req_headers: &hyper::header::HeaderMap) -> Response<Full<Bytes>> {
+        let unsatisfiable = || Server::text_response(416, "The requested range is not satisfiable");
+        let (size, start, contents) = match self.storage.stat(rel_path).await {
+            Ok(metadata) => {
+                let Ok(Some((start, end))) = s3::parse_range(req_headers, metadata.size) else {
+                    return unsatisfiable();
+                };
+                let Ok(mut file) = self.storage.read(rel_path).await else {
+                    return Server::text_response(404, "No such file");
+                };
+                let mut contents = Vec::new();
+                let read = async {
+                    tokio::io::copy(&mut (&mut file).take(start), &mut tokio::io::sink()).await?;
+                    file.take(end - start + 1).read_to_end(&mut contents).await
+                };
+                if let Err(e) = read.await {
+                    eprintln!("Unable to read '{}': {}", rel_path, e);
+                    return Server::text_response(500, "Unable to read the file");
+                }
+                (metadata.size, start, contents)
+            }
+            // files inside zip and tar files
+            Err(_) => match self.archive_member(rel_path).await {
+                Ok(member) => {
+                    let Ok(Some((start, end))) = s3::parse_range(req_headers, member.len() as u64) else {
+                        return unsatisfiable();
+                    };
+                    let contents = member[start as usize..=end as usize].to_vec();
+                    (member.len() as u64, start, contents)
+                }
+                Err(response) => return *response,
+            },
+        };
+        // the file may have shrunk since it was looked at
+        if contents.is_empty() {
+            return unsatisfiable();
+        }
+        println!("Sending {} bytes of '{}' from {}", contents.len(), rel_path, start);
+        hyper::Response::builder()
+            .status(206)
+            .header(hyper::header::ACCEPT_RANGES, "bytes")
+            .header(
+                hyper::header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, start + contents.len() as u64 - 1, size),
+            )
+            .body(Full::from(Bytes::from(contents)))
+            .unwrap()
+    }
+
+    /// Creates the folder named in the body (relative to the storage root)
+    pub async fn handle_addfolder(
+        &self,
+        req_bytes: Bytes,
+        user: Option<&str>,
+    ) -> Result<Response<Full<Bytes>>, hyper::Error> {
+        let Some(rel_path) = std::str::from_utf8(&req_bytes)
+            .ok()
+            .and_then(paths::normalize)
+            .filter(|rel| !rel.is_empty())
+        else {
+            return Ok(Server::text_response(400, "Invalid folder name"));
+        };
+        if !self.acl.permissions(user, &rel_path).write {
+            return Ok(Server::text_response(403, "You can't create folders here"));
+        }
+
+        match self.storage.create_dir(&rel_path).await {
+            Ok(_) => {
+                self.changed(&rel_path, ChangeKind::Created).await;
+                Ok(Server::text_response(200, ""))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                Ok(Server::text_response(409, "That name is already taken"))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                Ok(Server::text_response(403, "That name is reserved"))
+            }
+            Err(_) => Ok(Server::text_response(404, "No such parent folder")),
+        }
+    }
+
+    pub async fn handle_getfiles(
+        &self,
+        req_headers: hyper::header::HeaderMap,
+        user: Option<&str>,
+    ) -> Result<Response<Full<Bytes>>, hyper::Error> {
+        let Some(dir) = Server::header_path(&req_headers, "dir") else {
+            return Ok(Server::text_response(400, "Invalid dir"));
+        };
+        let dir_permissions = self.acl.permissions(user, &dir);
+        if !dir_permissions.read {
+            return Ok(Server::text_response(403, "You can't list this folder"));
+        }
+        let Ok(entries) = self.list_dir(&dir).await else {
+            // folders inside zip and tar files
+            return Ok(match self.inside_archive(&dir).await {
+                Some((archive, kind, inner)) => self.archive_listing(dir.clone(), &archive, kind, &inner).await,
+                None => Server::text_response(404, "No such folder"),
+            });
+        };
+
+        let mut files: Vec<FileEntry> = Vec::new();
+        for entry in entries {
+            // entries the user can't read aren't shown at all, nor are uploads in progress
+            let rel_path = paths::join(&dir, &entry.name);
+            let permissions = self.acl.permissions(user, &rel_path);
+            if !permissions.read || storage::is_upload_temp(&entry.name) {
+                continue;
+            }
+            let mut file = FileEntry {
+                name: entry.name,
+                is_dir: entry.metadata.is_dir,
+                size: entry.metadata.size,
+                modified: entry.metadata.modified,
+                can_read: permissions.read,
+                can_write: permissions.write,
+                tags: Vec::new(),
+                note: None,
+                sha256: None,
+            };
+            self.annotate(&rel_path, &mut file);
+            files.push(file);
+        }
+        // folders first, then by name
+        files.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+        println!("Recieved getfiles request for '{}', sending {} entries", dir, files.len());
+
+        let response = GetFilesResponse {
+            dir,
+            can_write: dir_permissions.write,
+            files,
+        };
+        let response_body = serde_json::to_string(&response).unwrap();
+        Ok(hyper::Response::builder()
+            .status(200)
+            .body(Full::from(Bytes::from(response_body)))
+            .unwrap())
+    }
+
+    /// Writes the body to `file` (opened at `temp_path`, see `storage::upload_temp_path`) as it
+    /// arrives, decompressing it when it was sent with an `encoding`. gives up (and removes the
+    /// temporary file) once it passes the upload limit or the `room` left by quotas, both count
+    /// the stored bytes - `Some` holds the 413, 507 or 400 for a body that was cut off or
+    /// doesn't decompress. the file the upload is for is only replaced by `finish_upload`
+    async fn receive_file(
+        &self,
+        req_body: hyper::body::Incoming,
+        encoding: Option<Encoding>,
+        mut file: WriteStream,
+        temp_path: &str,
+        room: Option<u64>,
+    ) -> Option<Response<Full<Bytes>>> {
+        let body = BodyDataStream::new(req_body.map_err(std::io::Error::other));
+        let mut reader = compression::decoder(encoding, StreamReader::new(body));
+        let mut buffer = vec![0; 64 * 1024];
+        let mut written: u64 = 0;
+        let rejected = loop {
+            let read = match reader.read(&mut buffer).await {
+                Ok(0) => break None,
+                Ok(read) => read,
+                Err(e) => {
+                    println!("Stopped an upload: {}", e);
+                    break Some(Server::text_response(400, "The upload was cut off or doesn't decompress"));
+                }
+            };
+            written += read as u64;
+            if let Some(max) = self.limits.max_upload_bytes {
+                if written > max {
+                    break Some(Server::text_response(
+                        413,
+                        &format!("Upload exceeds the {} byte limit", max),
+                    ));
+                }
+            }
+            if room.is_some_and(|room| written > room) {
+                println!("Stopped an upload, it doesn't fit in the quota");
+                break Some(Server::quota_response("The upload doesn't fit in the quota"));
+            }
+            if let Err(e) = file.write_all(&buffer[..read]).await {
+                eprintln!("Unable to write '{}': {}", temp_path, e);
+                break Some(Server::text_response(500, "Unable to store the file"));
+            }
+        };
+        let rejected = match rejected {
+            Some(response) => Some(response),
+            None => file.shutdown().await.err().map(|e| {
+                eprintln!("Unable to write '{}': {}", temp_path, e);
+                Server::text_response(500, "Unable to store the file")
+            }),
+        };
+        if rejected.is_some() {
+            drop(file);
+            let _ = self.storage.delete(temp_path).await;
+        }
+        rejected
+    }
+
+    /// Moves a complete upload from `temp_path` over `rel_path`, keeping a version of what it
+    /// replaces. `Some` holds the response when that didn't work
+    async fn finish_upload(&self, temp_path: &str, rel_path: &str, user: Option<&str>) -> Option<Response<Full<Bytes>>> {
+        self.save_version(rel_path, user).await;
+        if let Err(e) = self.storage.rename(temp_path, rel_path).await {
+            eprintln!("Unable to move the upload into '{}': {}", rel_path, e);
+            let _ = self.storage.delete(temp_path).await;
+            return Some(Server::text_response(500, "Unable to store the file"));
+        }
+        None
+    }
+
+    /// What an upload body was compressed with, 415 for an encoding we don't know
+    fn content_encoding(req_headers: &hyper::header::HeaderMap) -> Result<Option<Encoding>, Box<Response<Full<Bytes>>>> {
+        let Some(value) = req_headers.get(hyper::header::CONTENT_ENCODING) else {
+            return Ok(None);
+        };
+        match value.to_str().unwrap_or_default().trim() {
+            "" | "identity" => Ok(None),
+            name => Encoding::parse(name).map(Some).ok_or_else(|| {
+                Box::new(Server::text_response(
+                    415,
+                    &format!("Unsupported content encoding, use one of {}", encoding::SUPPORTED),
+                ))
+            }),
+        }
+    }
+
+    pub fn handle_std_request(&self) -> Result<Response<Full<Bytes>>, hyper::Error> {
+        Ok(Server::text_response(404, "Not Found"))
+    }
+
+    /// Normalized path from a request header, a missing header means the storage root
+    fn header_path(req_headers: &hyper::header::HeaderMap, name: &str) -> Option<String> {
+        match req_headers.get(name) {
+            Some(value) => paths::normalize(value.to_str().ok()?),
+            None => Some(String::new()),
+        }
+    }
+
+    fn xml_response(status: u16, xml: String) -> Response<Full<Bytes>> {
+        hyper::Response::builder()
+            .status(status)
+            .header(hyper::header::CONTENT_TYPE, "application/xml; charset=utf-8")
+            .body(Full::from(Bytes::from(xml)))
+            .unwrap()
+    }
+
+    fn html_response(status: u16, page: String) -> Response<Full<Bytes>> {
+        hyper::Response::builder()
+            .status(status)
+            .header(hyper::header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(Full::from(Bytes::from(page)))
+            .unwrap()
+    }
+
+    /// Plain text response, mostly for failed requests
+    fn text_response(status: u16, message: &str) -> Response<Full<Bytes>> {
+        hyper::Response::builder()
+            .status(status)
+            .body(Full::from(Bytes::from(message.to_string())))
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acl::SharedFolder;
+    use crate::storage::Memory;
+
+    async fn server(acl: Acl) -> Server {
+        let mut server = Server::new("test", 0).await;
+        server.set_storage(Box::new(Memory::new()));
+        server.set_webdav(true);
+        server.set_acl(acl).await.unwrap();
+        for (path, contents) in [
+            ("team/a.txt", "a"),
+            ("team/private/secret.txt", "s"),
+            ("releases/v1/app", "v1"),
+            ("releases/signed/app.sig", "sig"),
+            ("home/bob/notes.txt", "n"),
+        ] {
+            storage::create_dir_all(server.storage.as_ref(), paths::parent(path)).await.unwrap();
+            storage::write_all(server.storage.as_ref(), path, contents.as_bytes()).await.unwrap();
+        }
+        server
+    }
+
+    fn shared(path: &str, read: &[&str], write: &[&str]) -> SharedFolder {
+        SharedFolder {
+            path: path.to_string(),
+            read: read.iter().map(|s| s.to_string()).collect(),
+            write: write.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    /// bob can read and write `team` and `releases`, but only carol reads `team/private`
+    /// and only alice writes `releases/signed`
+    async fn shared_server() -> Server {
+        server(Acl {
+            shared: vec![
+                shared("team", &[], &["bob"]),
+                shared("team/private", &["carol"], &[]),
+                shared("releases", &["*"], &["bob", "alice"]),
+                shared("releases/signed", &["*"], &["alice"]),
+            ],
+            ..Acl::default()
+        })
+        .await
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> hyper::header::HeaderMap {
+        let mut headers = hyper::header::HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    async fn copy(server: &Server, from: &str, to: &str, extra: &[(&'static str, &str)], user: &str) -> u16 {
+        let destination = format!("http://localhost/dav/{}", to);
+        let mut pairs = vec![("destination", destination.as_str())];
+        pairs.extend_from_slice(extra);
+        let user = Some(user).filter(|user| !user.is_empty());
+        server.dav_copy_move(from, &headers(&pairs), false, &[], user).await.status().as_u16()
+    }
+
+    async fn moved(server: &Server, from: &str, to: &str, user: &str) -> u16 {
+        let destination = format!("/dav/{}", to);
+        let user = Some(user).filter(|user| !user.is_empty());
+        let headers = headers(&[("destination", &destination)]);
+        server.dav_copy_move(from, &headers, true, &[], user).await.status().as_u16()
+    }
+
+    async fn delete(server: &Server, path: &str, user: &str) -> u16 {
+        let user = Some(user).filter(|user| !user.is_empty());
+        server.dav_delete(path, &[], user).await.status().as_u16()
+    }
+
+    async fn exists(server: &Server, path: &str) -> bool {
+        storage::exists(server.storage.as_ref(), path).await
+    }
+
+    #[tokio::test]
+    async fn copy_and_move() {
+        let server = server(Acl::default()).await;
+        assert_eq!(copy(&server, "team", "team2", &[], "").await, 201);
+        assert_eq!(storage::read_all(server.storage.as_ref(), "team2/private/secret.txt").await.unwrap(), b"s");
+        // depth 0 only copies the folder itself
+        assert_eq!(copy(&server, "team", "team3", &[("depth", "0")], "").await, 201);
+        assert!(server.storage.list("team3").await.unwrap().is_empty());
+
+        assert_eq!(copy(&server, "team", "team2", &[("overwrite", "F")], "").await, 412);
+        assert_eq!(copy(&server, "team/a.txt", "team2/a.txt", &[], "").await, 204);
+        assert_eq!(copy(&server, "team", "team/inside", &[], "").await, 409);
+        assert_eq!(copy(&server, "missing", "elsewhere", &[], "").await, 404);
+        assert_eq!(copy(&server, "team", "no/such/folder", &[], "").await, 409);
+
+        assert_eq!(moved(&server, "team2", "team4", "").await, 201);
+        assert!(!exists(&server, "team2").await);
+        assert!(exists(&server, "team4/private/secret.txt").await);
+
+        assert_eq!(delete(&server, "team4", "").await, 204);
+        assert!(!exists(&server, "team4").await);
+        assert_eq!(delete(&server, "team4", "").await, 404);
+        assert_eq!(delete(&server, "", "").await, 403);
+    }
+
+    #[tokio::test]
+    async fn copy_needs_read_all_the_way_down() {
+        let server = shared_server().await;
+        // team/private is in team but bob can't read it
+        assert_eq!(copy(&server, "team", "home/bob/team", &[], "bob").await, 403);
+        assert!(!exists(&server, "home/bob/team").await);
+        assert_eq!(copy(&server, "team/private", "home/bob/private", &[], "bob").await, 403);
+        // what he can read is fine
+        assert_eq!(copy(&server, "team/a.txt", "home/bob/a.txt", &[], "bob").await, 201);
+        assert_eq!(copy(&server, "team", "home/bob/team", &[("depth", "0")], "bob").await, 201);
+    }
+
+    #[tokio::test]
+    async fn move_and_delete_need_write_all_the_way_down() {
+        let server = shared_server().await;
+        // releases/signed is in releases but bob can't write it
+        assert_eq!(moved(&server, "releases", "home/bob/releases", "bob").await, 403);
+        assert_eq!(delete(&server, "releases", "bob").await, 403);
+        assert!(exists(&server, "releases/signed/app.sig").await);
+        assert_eq!(delete(&server, "releases/signed/app.sig", "bob").await, 403);
+        // writing over a folder holding releases/signed removes it too
+        assert_eq!(copy(&server, "home/bob", "releases", &[], "bob").await, 403);
+        assert!(exists(&server, "releases/signed/app.sig").await);
+
+        assert_eq!(moved(&server, "releases/v1", "releases/v2", "bob").await, 201);
+        assert_eq!(delete(&server, "releases/v2", "bob").await, 204);
+        assert_eq!(delete(&server, "releases/signed", "alice").await, 204);
+        assert!(!exists(&server, "releases/signed").await);
+    }
+
+    fn quotas(home: quota::Limit, users: &[(&str, quota::Limit)], folders: &[(&str, u64)]) -> Quotas {
+        Quotas {
+            home: Some(home),
+            users: users.iter().map(|(user, limit)| (user.to_string(), *limit)).collect(),
+            folders: folders
+                .iter()
+                .map(|(path, max_bytes)| quota::FolderQuota {
+                    path: path.to_string(),
+                    max_bytes: Some(*max_bytes),
+                    max_files: None,
+                })
+                .collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn upload_room_is_the_least_left_above() {
+        let mut server = server(Acl::default()).await;
+        assert_eq!(server.upload_room("home/bob/new.txt").await, Ok(None));
+
+        let ten = quota::Limit {
+            max_bytes: Some(10),
+            max_files: None,
+        };
+        server.set_quotas(quotas(ten, &[], &[("team", 100), ("team/private", 3)]));
+        // home/bob holds notes.txt, 1 byte
+        assert_eq!(server.upload_room("home/bob/new.txt").await, Ok(Some(9)));
+        // a file that is replaced doesn't count
+        assert_eq!(server.upload_room("home/bob/notes.txt").await, Ok(Some(10)));
+        assert_eq!(server.upload_room("team/private/x").await, Ok(Some(2)));
+        assert_eq!(server.upload_room("team/x").await, Ok(Some(98)));
+        // home quotas don't reach into shared folders or other folders
+        assert_eq!(server.upload_room("releases/x").await, Ok(None));
+
+        let five = quota::Limit {
+            max_bytes: Some(5),
+            max_files: None,
+        };
+        server.set_quotas(quotas(ten, &[("bob", five)], &[]));
+        assert_eq!(server.upload_room("home/bob/new.txt").await, Ok(Some(4)));
+        assert_eq!(server.upload_room("home/carol/new.txt").await, Ok(Some(10)));
+
+        let one_file = quota::Limit {
+            max_bytes: None,
+            max_files: Some(1),
+        };
+        server.set_quotas(quotas(one_file, &[], &[]));
+        assert!(server.upload_room("home/bob/new.txt").await.is_err());
+        assert_eq!(server.upload_room("home/bob/notes.txt").await, Ok(None));
+    }
+
+    /// a stored zip of `entries`
+    fn zip_of(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        use std::io::Write;
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        let options = zip::write::SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        for (name, data) in entries {
+            zip.start_file(*name, options).unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    async fn extract(server: &Server, file: &str, dir: &str) -> u16 {
+        let headers = headers(&[("file", file), ("dir", dir)]);
+        server.handle_extract(headers, None).await.unwrap().status().as_u16()
+    }
+
+    #[tokio::test]
+    async fn damaged_archive_leaves_no_partial_file() {
+        let server = server(Acl::default()).await;
+        let big: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let mut archive = zip_of(&[("a.txt", b"aaa"), ("big.bin", &big)]);
+        // the checksum of big.bin only fails once all of it has been read
+        let middle = archive.windows(big.len()).position(|window| window == big.as_slice()).unwrap() + big.len() / 2;
+        archive[middle] ^= 0xff;
+        storage::write_all(server.storage.as_ref(), "team/bad.zip", &archive).await.unwrap();
+
+        assert_eq!(extract(&server, "team/bad.zip", "team/out").await, 404);
+        server.storage.create_dir("team/out").await.unwrap();
+        assert_eq!(extract(&server, "team/bad.zip", "team/out").await, 422);
+        let names: Vec<String> = server.storage.list("team/out").await.unwrap().into_iter().map(|entry| entry.name).collect();
+        assert_eq!(names, ["a.txt"]);
+    }
+
+    #[tokio::test]
+    async fn extracting_counts_against_quotas() {
+        let mut server = server(Acl::default()).await;
+        server.set_quotas(Quotas {
+            folders: vec![quota::FolderQuota {
+                path: "team".to_string(),
+                max_bytes: Some(100),
+                max_files: Some(3),
+            }],
+            ..Quotas::default()
+        });
+        // team holds 2 files already
+        let archive = zip_of(&[("x", b"x"), ("y", b"y")]);
+        storage::write_all(server.storage.as_ref(), "archive.zip", &archive).await.unwrap();
+        assert_eq!(extract(&server, "archive.zip", "team").await, 507);
+        assert!(!exists(&server, "team/x").await);
+        let big = zip_of(&[("big", &[0; 200])]);
+        storage::write_all(server.storage.as_ref(), "big.zip", &big).await.unwrap();
+        assert_eq!(extract(&server, "big.zip", "team").await, 507);
+
+        // a quota below the folder it is extracted to counts too
+        let deeper = zip_of(&[("private/x", b"x"), ("private/y", b"y")]);
+        storage::write_all(server.storage.as_ref(), "deeper.zip", &deeper).await.unwrap();
+        server.set_quotas(Quotas {
+            folders: vec![quota::FolderQuota {
+                path: "team/private".to_string(),
+                max_bytes: None,
+                max_files: Some(2),
+            }],
+            ..Quotas::default()
+        });
+        assert_eq!(extract(&server, "deeper.zip", "team").await, 507);
+        assert!(!exists(&server, "team/private/x").await);
+
+        assert_eq!(extract(&server, "archive.zip", "team").await, 200);
+        assert!(exists(&server, "team/y").await);
     }
 }
@@ -0,0 +1,236 @@
+/// share.rs - signed, expiring download links
+/// a link is `/s/<token>`, the token being `base64(claims).base64(hmac)` signed with a
+/// server secret - nothing is stored per link, so links survive restarts as long as the
+/// secret file does. the optional password is kept as an hmac too, so a leaked link
+/// doesn't leak anything that can be brute forced offline.
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// what a link grants, signed into the token
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShareClaims {
+    /// normalized path of the shared file or folder
+    pub path: String,
+    /// unix timestamp (seconds) the link stops working at
+    pub expires_at: u64,
+    /// user who made the link, what they can't read can't be reached through it either.
+    /// `None` when authentication was off
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub creator: Option<String>,
+    /// hmac of the password, when there is one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ShareError {
+    Malformed,
+    BadSignature,
+    Expired,
+}
+
+pub struct ShareSigner {
+    secret: Vec<u8>,
+}
+
+impl ShareSigner {
+    /// Loads the signing secret, creating a random one the first time
+    pub fn load_or_create(secret_file: &Path) -> io::Result<Self> {
+        let secret = match fs::read(secret_file) {
+            Ok(secret) if secret.len() >= 32 => secret,
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("share secret {:?} is shorter than 32 bytes", secret_file),
+                ))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let mut secret = vec![0u8; 32];
+                rand::thread_rng().fill_bytes(&mut secret);
+                fs::write(secret_file, &secret)?;
+                println!("Created share link secret at {:?}", secret_file);
+                secret
+            }
+            Err(e) => return Err(e),
+        };
+        Ok(Self { secret })
+    }
+
+    fn mac(&self, parts: &[&[u8]]) -> Vec<u8> {
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("hmac takes any key size");
+        for part in parts {
+            mac.update(part);
+        }
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    fn password_mac(&self, password: &str) -> String {
+        URL_SAFE_NO_PAD.encode(self.mac(&[b"share-password:", password.as_bytes()]))
+    }
+
+    pub fn create(
+        &self,
+        path: &str,
+        creator: Option<&str>,
+        expires_at: u64,
+        password: Option<&str>,
+    ) -> String {
+        let claims = ShareClaims {
+            path: path.to_string(),
+            expires_at,
+            creator: creator.map(str::to_string),
+            password: password.map(|p| self.password_mac(p)),
+        };
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).unwrap());
+        let signature = URL_SAFE_NO_PAD.encode(self.mac(&[b"share:", payload.as_bytes()]));
+        format!("{}.{}", payload, signature)
+    }
+
+    /// Checks signature and expiry, `now` is a unix timestamp
+    pub fn verify(&self, token: &str, now: u64) -> Result<ShareClaims, ShareError> {
+        let (payload, signature) = token.split_once('.').ok_or(ShareError::Malformed)?;
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| ShareError::Malformed)?;
+
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("hmac takes any key size");
+        mac.update(b"share:");
+        mac.update(payload.as_bytes());
+        mac.verify_slice(&signature)
+            .map_err(|_| ShareError::BadSignature)?;
+
+        let claims: ShareClaims = URL_SAFE_NO_PAD
+            .decode(payload)
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .ok_or(ShareError::Malformed)?;
+        if claims.expires_at <= now {
+            return Err(ShareError::Expired);
+        }
+        Ok(claims)
+    }
+
+    pub fn check_password(&self, claims: &ShareClaims, password: &str) -> bool {
+        match &claims.password {
+            Some(expected) => {
+                let mut mac =
+                    HmacSha256::new_from_slice(&self.secret).expect("hmac takes any key size");
+                mac.update(b"share-password:");
+                mac.update(password.as_bytes());
+                URL_SAFE_NO_PAD
+                    .decode(expected)
+                    .is_ok_and(|expected| mac.verify_slice(&expected).is_ok())
+            }
+            None => true,
+        }
+    }
+
+    /// Cookie value proving the password for `token` was entered
+    pub fn unlock_cookie(&self, token: &str) -> String {
+        URL_SAFE_NO_PAD.encode(self.mac(&[b"share-unlock:", token.as_bytes()]))
+    }
+
+    pub fn check_unlock_cookie(&self, token: &str, cookie: &str) -> bool {
+        let Ok(cookie) = URL_SAFE_NO_PAD.decode(cookie) else {
+            return false;
+        };
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("hmac takes any key size");
+        mac.update(b"share-unlock:");
+        mac.update(token.as_bytes());
+        mac.verify_slice(&cookie).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signer() -> ShareSigner {
+        ShareSigner { secret: vec![7; 32] }
+    }
+
+    #[test]
+    fn round_trip() {
+        let signer = signer();
+        let token = signer.create("music/album", Some("alice"), 100, None);
+        let claims = signer.verify(&token, 50).unwrap();
+        assert_eq!(claims.path, "music/album");
+        assert_eq!(claims.creator.as_deref(), Some("alice"));
+        assert_eq!(claims.expires_at, 100);
+        assert!(signer.check_password(&claims, "anything"));
+    }
+
+    #[test]
+    fn tampering_is_caught() {
+        let signer = signer();
+        let token = signer.create("music", Some("alice"), 100, None);
+        let (payload, signature) = token.split_once('.').unwrap();
+
+        // another path signed with nothing
+        let forged = URL_SAFE_NO_PAD.encode(br#"{"path":"","expires_at":100}"#);
+        assert_eq!(
+            signer.verify(&format!("{}.{}", forged, signature), 50).unwrap_err(),
+            ShareError::BadSignature
+        );
+        let mut bad_signature = URL_SAFE_NO_PAD.decode(signature).unwrap();
+        bad_signature[0] ^= 1;
+        let bad_signature = URL_SAFE_NO_PAD.encode(bad_signature);
+        assert_eq!(
+            signer.verify(&format!("{}.{}", payload, bad_signature), 50).unwrap_err(),
+            ShareError::BadSignature
+        );
+        // a different secret doesn't accept it either
+        let other = ShareSigner { secret: vec![8; 32] };
+        assert_eq!(other.verify(&token, 50).unwrap_err(), ShareError::BadSignature);
+
+        assert_eq!(signer.verify(payload, 50).unwrap_err(), ShareError::Malformed);
+        assert_eq!(
+            signer.verify(&format!("{}.not base64!", payload), 50).unwrap_err(),
+            ShareError::Malformed
+        );
+    }
+
+    #[test]
+    fn links_expire() {
+        let signer = signer();
+        let token = signer.create("music", None, 100, None);
+        assert!(signer.verify(&token, 99).is_ok());
+        assert_eq!(signer.verify(&token, 100).unwrap_err(), ShareError::Expired);
+        assert_eq!(signer.verify(&token, 1000).unwrap_err(), ShareError::Expired);
+    }
+
+    #[test]
+    fn passwords() {
+        let signer = signer();
+        let token = signer.create("music", None, 100, Some("hunter2"));
+        let claims = signer.verify(&token, 50).unwrap();
+        // only its hmac is in the token
+        assert!(!claims.password.as_deref().unwrap().contains("hunter2"));
+        assert!(signer.check_password(&claims, "hunter2"));
+        assert!(!signer.check_password(&claims, "hunter3"));
+        assert!(!signer.check_password(&claims, ""));
+    }
+
+    #[test]
+    fn unlock_cookies() {
+        let signer = signer();
+        let token = signer.create("music", None, 100, Some("hunter2"));
+        let other_token = signer.create("photos", None, 100, Some("hunter2"));
+        let cookie = signer.unlock_cookie(&token);
+        assert!(signer.check_unlock_cookie(&token, &cookie));
+        assert!(!signer.check_unlock_cookie(&other_token, &cookie));
+        assert!(!signer.check_unlock_cookie(&token, "garbage!"));
+        assert!(!signer.check_unlock_cookie(&token, ""));
+        let other = ShareSigner { secret: vec![8; 32] };
+        assert!(!other.check_unlock_cookie(&token, &cookie));
+    }
+}
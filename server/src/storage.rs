@@ -0,0 +1,192 @@
+/// storage.rs - where file contents actually live
+/// - `StorageBackend` is everything the endpoints need from a store, addressed by
+///   normalized paths (see paths.rs) with `""` as the root
+/// - `LocalDisk` keeps files under the storage directory, `Memory` keeps them in ram
+///   (for tests and throwaway servers), `Dedup` stores each distinct content once by hash
+/// - `HideDir` wraps another backend to keep a folder of server data out of sight
+/// - helpers that only need the trait (recursive copy, whole-file read/write) are below it
+use async_trait::async_trait;
+use sha2::{Digest, Sha256};
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::paths;
+
+mod dedup;
+mod hide;
+mod local;
+mod memory;
+
+pub use dedup::Dedup;
+pub use hide::HideDir;
+pub use local::LocalDisk;
+pub use memory::Memory;
+
+pub type ReadStream = Box<dyn AsyncRead + Send + Unpin>;
+pub type WriteStream = Box<dyn AsyncWrite + Send + Unpin>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub is_dir: bool,
+    /// 0 for folders
+    pub size: u64,
+    /// unix timestamp (seconds)
+    pub modified: u64,
+}
+
+pub struct DirEntry {
+    pub name: String,
+    pub metadata: Metadata,
+}
+
+/// A place to keep files. Errors use the usual `io::ErrorKind`s - `NotFound` for a missing
+/// path or parent folder, `AlreadyExists`, `IsADirectory`, `NotADirectory`
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// Entries of the folder `dir`, in no particular order
+    async fn list(&self, dir: &str) -> io::Result<Vec<DirEntry>>;
+
+    async fn stat(&self, path: &str) -> io::Result<Metadata>;
+
+    /// Contents of the file at `path`
+    async fn read(&self, path: &str) -> io::Result<ReadStream>;
+
+    /// Contents of the file at `path` from `offset` on, backends that can seek should
+    async fn read_from(&self, path: &str, offset: u64) -> io::Result<ReadStream> {
+        let mut reader = self.read(path).await?;
+        tokio::io::copy(&mut (&mut reader).take(offset), &mut tokio::io::sink()).await?;
+        Ok(reader)
+    }
+
+    /// Creates (or empties) the file at `path`, its folder must exist.
+    /// Call `shutdown` on the stream once everything is written
+    async fn write(&self, path: &str) -> io::Result<WriteStream>;
+
+    /// Creates one folder, its parent must exist
+    async fn create_dir(&self, path: &str) -> io::Result<()>;
+
+    /// Removes a file or a whole folder tree
+    async fn delete(&self, path: &str) -> io::Result<()>;
+
+    /// Moves a file or folder, replacing a file (or empty folder) at `to`
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+
+    /// Makes `path` a file with the content whose sha256 (lowercase hex) is given, without
+    /// uploading it again. `false` when that content isn't stored, which is always the case
+    /// for backends that don't store by hash
+    async fn link(&self, _path: &str, _sha256: &str) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    /// Whether `link` would succeed for this sha256
+    async fn has_content(&self, _sha256: &str) -> bool {
+        false
+    }
+}
+
+/// Whether anything (file or folder) is at `path`
+pub async fn exists(storage: &dyn StorageBackend, path: &str) -> bool {
+    storage.stat(path).await.is_ok()
+}
+
+pub async fn is_dir(storage: &dyn StorageBackend, path: &str) -> bool {
+    storage.stat(path).await.is_ok_and(|metadata| metadata.is_dir)
+}
+
+/// Creates `path` and any missing folders above it
+pub async fn create_dir_all(storage: &dyn StorageBackend, path: &str) -> io::Result<()> {
+    let mut dir = String::new();
+    for part in path.split('/').filter(|p| !p.is_empty()) {
+        dir = paths::join(&dir, part);
+        match storage.create_dir(&dir).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists && is_dir(storage, &dir).await => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// name uploads are written under until they are complete, they replace the file they are
+/// for only then
+const UPLOAD_PREFIX: &str = ".tuifs-upload-";
+
+/// A fresh temporary name next to `path` to write an upload for it to
+pub fn upload_temp_path(path: &str) -> String {
+    paths::join(paths::parent(path), &format!("{}{:016x}", UPLOAD_PREFIX, rand::random::<u64>()))
+}
+
+/// Whether `name` (the last part of a path) is an upload that isn't complete yet
+pub fn is_upload_temp(name: &str) -> bool {
+    name.starts_with(UPLOAD_PREFIX)
+}
+
+/// Copies a file or a whole folder tree, `recursive: false` copies a folder without its contents
+pub async fn copy_all(storage: &dyn StorageBackend, from: &str, to: &str, recursive: bool) -> io::Result<()> {
+    let mut pending = vec![(from.to_string(), to.to_string())];
+    while let Some((from, to)) = pending.pop() {
+        if !storage.stat(&from).await?.is_dir {
+            let mut reader = storage.read(&from).await?;
+            let mut writer = storage.write(&to).await?;
+            tokio::io::copy(&mut reader, &mut writer).await?;
+            writer.shutdown().await?;
+            continue;
+        }
+        storage.create_dir(&to).await?;
+        if recursive {
+            for entry in storage.list(&from).await? {
+                pending.push((paths::join(&from, &entry.name), paths::join(&to, &entry.name)));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Total size of the files at or below `path`
+pub async fn tree_size(storage: &dyn StorageBackend, path: &str) -> io::Result<u64> {
+    let metadata = storage.stat(path).await?;
+    if !metadata.is_dir {
+        return Ok(metadata.size);
+    }
+    let mut size = 0;
+    let mut pending = vec![path.to_string()];
+    while let Some(dir) = pending.pop() {
+        for entry in storage.list(&dir).await? {
+            if entry.metadata.is_dir {
+                pending.push(paths::join(&dir, &entry.name));
+            } else {
+                size += entry.metadata.size;
+            }
+        }
+    }
+    Ok(size)
+}
+
+/// The whole file at `path`
+pub async fn read_all(storage: &dyn StorageBackend, path: &str) -> io::Result<Vec<u8>> {
+    let mut contents = Vec::new();
+    storage.read(path).await?.read_to_end(&mut contents).await?;
+    Ok(contents)
+}
+
+/// sha256 of the file at `path` as lowercase hex
+pub async fn sha256(storage: &dyn StorageBackend, path: &str) -> io::Result<String> {
+    let mut reader = storage.read(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let read = reader.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Replaces the file at `path` with `contents`
+pub async fn write_all(storage: &dyn StorageBackend, path: &str, contents: &[u8]) -> io::Result<()> {
+    let mut writer = storage.write(path).await?;
+    writer.write_all(contents).await?;
+    writer.shutdown().await
+}
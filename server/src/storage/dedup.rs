@@ -0,0 +1,370 @@
+/// dedup.rs - content-addressed storage, every distinct file content is stored once
+/// - `blobs/ab/abcd...` holds contents by sha256, `names/` mirrors the folder tree with small
+///   json pointers in place of files
+/// - blobs are reference counted in memory (counted from the pointers on startup) and removed
+///   when the last name pointing at them goes
+/// - uploads are hashed while they are written to `tmp/`, a content we already have is dropped
+///   instead of stored twice, and `link` lets clients skip the upload entirely
+/// - pointer and refcount changes happen together under one lock, with plain (blocking) file
+///   calls since they are tiny
+use async_trait::async_trait;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::UNIX_EPOCH;
+use tokio::fs::File;
+use tokio::io::{AsyncSeekExt, AsyncWrite};
+
+use super::{DirEntry, Metadata, ReadStream, StorageBackend, WriteStream};
+use crate::paths;
+
+/// sha256 of nothing, the blob every freshly created file points at
+const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+/// What a name in `names/` contains
+#[derive(Serialize, Deserialize)]
+struct Pointer {
+    sha256: String,
+    size: u64,
+}
+
+struct Inner {
+    root: PathBuf,
+    /// number of names pointing at each blob
+    refs: Mutex<HashMap<String, u64>>,
+}
+
+pub struct Dedup {
+    inner: Arc<Inner>,
+}
+
+impl Dedup {
+    /// Opens (or sets up) the store in `root`, counting references and dropping blobs
+    /// nothing points at (left behind by a crash)
+    pub fn open(root: PathBuf) -> io::Result<Self> {
+        for dir in ["names", "blobs", "tmp"] {
+            fs::create_dir_all(root.join(dir))?;
+        }
+        // half written uploads from last time
+        for entry in fs::read_dir(root.join("tmp"))?.flatten() {
+            let _ = fs::remove_file(entry.path());
+        }
+
+        let mut refs = HashMap::new();
+        count_refs(&root.join("names"), &mut refs)?;
+        let mut removed = 0;
+        for prefix in fs::read_dir(root.join("blobs"))?.flatten() {
+            for blob in fs::read_dir(prefix.path())?.flatten() {
+                let hash = blob.file_name().to_string_lossy().into_owned();
+                if hash != EMPTY_SHA256 && !refs.contains_key(&hash) {
+                    fs::remove_file(blob.path())?;
+                    removed += 1;
+                }
+            }
+        }
+        let inner = Inner {
+            root,
+            refs: Mutex::new(refs),
+        };
+        let empty = inner.blob_path(EMPTY_SHA256);
+        if !empty.exists() {
+            fs::create_dir_all(empty.parent().unwrap())?;
+            fs::write(&empty, b"")?;
+        }
+        println!(
+            "Deduplicating storage: {} blobs in use, removed {} unused",
+            inner.refs.lock().unwrap().len(),
+            removed
+        );
+        Ok(Self { inner: Arc::new(inner) })
+    }
+
+    /// The blob holding the contents of the file at `path`
+    async fn blob(&self, path: &str) -> io::Result<File> {
+        let name_path = self.inner.name_path(path);
+        if tokio::fs::metadata(&name_path).await?.is_dir() {
+            return Err(io::ErrorKind::IsADirectory.into());
+        }
+        let pointer: Pointer = serde_json::from_slice(&tokio::fs::read(&name_path).await?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        File::open(self.inner.blob_path(&pointer.sha256)).await
+    }
+}
+
+/// Adds one reference per pointer below `dir`
+fn count_refs(dir: &Path, refs: &mut HashMap<String, u64>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)?.flatten() {
+        if entry.file_type()?.is_dir() {
+            count_refs(&entry.path(), refs)?;
+        } else if let Ok(pointer) = read_pointer(&entry.path()) {
+            *refs.entry(pointer.sha256).or_insert(0) += 1;
+        }
+    }
+    Ok(())
+}
+
+fn read_pointer(path: &Path) -> io::Result<Pointer> {
+    serde_json::from_slice(&fs::read(path)?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn is_sha256(hash: &str) -> bool {
+    hash.len() == 64 && hash.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+}
+
+impl Inner {
+    fn name_path(&self, rel_path: &str) -> PathBuf {
+        paths::resolve(&self.root.join("names"), rel_path)
+    }
+
+    fn blob_path(&self, sha256: &str) -> PathBuf {
+        self.root.join("blobs").join(&sha256[..2]).join(sha256)
+    }
+
+    /// Metadata of a name, files get their size from the pointer
+    fn metadata(&self, name_path: &Path, metadata: &fs::Metadata) -> io::Result<Metadata> {
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs());
+        if metadata.is_dir() {
+            return Ok(Metadata {
+                is_dir: true,
+                size: 0,
+                modified,
+            });
+        }
+        Ok(Metadata {
+            is_dir: false,
+            size: read_pointer(name_path)?.size,
+            modified,
+        })
+    }
+
+    /// Points `rel_path` at `sha256`, releasing whatever it pointed at before.
+    /// Its folder must exist and it can't be a folder itself
+    fn set_pointer(&self, refs: &mut HashMap<String, u64>, rel_path: &str, sha256: &str, size: u64) -> io::Result<()> {
+        let name_path = self.name_path(rel_path);
+        if !name_path.parent().is_some_and(Path::is_dir) {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        if name_path.is_dir() {
+            return Err(io::ErrorKind::IsADirectory.into());
+        }
+        let previous = read_pointer(&name_path).ok();
+        let pointer = Pointer {
+            sha256: sha256.to_string(),
+            size,
+        };
+        fs::write(&name_path, serde_json::to_vec(&pointer)?)?;
+        *refs.entry(sha256.to_string()).or_insert(0) += 1;
+        if let Some(previous) = previous {
+            self.release(refs, &previous.sha256);
+        }
+        Ok(())
+    }
+
+    /// Drops one reference to a blob, removing it once nothing points at it
+    fn release(&self, refs: &mut HashMap<String, u64>, sha256: &str) {
+        let Some(count) = refs.get_mut(sha256) else {
+            return;
+        };
+        *count -= 1;
+        if *count == 0 {
+            refs.remove(sha256);
+            if sha256 != EMPTY_SHA256 {
+                let _ = fs::remove_file(self.blob_path(sha256));
+            }
+        }
+    }
+
+    /// Releases every pointer below `path` (a file or folder in `names/`)
+    fn release_all(&self, refs: &mut HashMap<String, u64>, path: &Path) -> io::Result<()> {
+        if path.is_dir() {
+            for entry in fs::read_dir(path)?.flatten() {
+                self.release_all(refs, &entry.path())?;
+            }
+        } else if let Ok(pointer) = read_pointer(path) {
+            self.release(refs, &pointer.sha256);
+        }
+        Ok(())
+    }
+
+    /// Moves a finished upload into the blob store (unless the content is already there)
+    /// and points `rel_path` at it
+    fn commit(&self, temp_path: &Path, rel_path: &str, sha256: &str, size: u64) -> io::Result<()> {
+        let mut refs = self.refs.lock().unwrap();
+        let blob_path = self.blob_path(sha256);
+        if blob_path.exists() {
+            fs::remove_file(temp_path)?;
+        } else {
+            fs::create_dir_all(blob_path.parent().unwrap())?;
+            fs::rename(temp_path, &blob_path)?;
+        }
+        if let Err(e) = self.set_pointer(&mut refs, rel_path, sha256, size) {
+            // nothing points at a brand new blob yet
+            if !refs.contains_key(sha256) {
+                let _ = fs::remove_file(&blob_path);
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl StorageBackend for Dedup {
+    async fn list(&self, dir: &str) -> io::Result<Vec<DirEntry>> {
+        let mut read_dir = tokio::fs::read_dir(self.inner.name_path(dir)).await?;
+        let mut entries = Vec::new();
+        while let Some(entry) = read_dir.next_entry().await? {
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            let Ok(metadata) = entry.metadata().await else {
+                continue;
+            };
+            let Ok(metadata) = self.inner.metadata(&entry.path(), &metadata) else {
+                continue;
+            };
+            entries.push(DirEntry { name, metadata });
+        }
+        Ok(entries)
+    }
+
+    async fn stat(&self, path: &str) -> io::Result<Metadata> {
+        let name_path = self.inner.name_path(path);
+        let metadata = tokio::fs::metadata(&name_path).await?;
+        self.inner.metadata(&name_path, &metadata)
+    }
+
+    async fn read(&self, path: &str) -> io::Result<ReadStream> {
+        Ok(Box::new(self.blob(path).await?))
+    }
+
+    async fn read_from(&self, path: &str, offset: u64) -> io::Result<ReadStream> {
+        let mut blob = self.blob(path).await?;
+        blob.seek(io::SeekFrom::Start(offset)).await?;
+        Ok(Box::new(blob))
+    }
+
+    async fn write(&self, path: &str) -> io::Result<WriteStream> {
+        {
+            let mut refs = self.inner.refs.lock().unwrap();
+            self.inner.set_pointer(&mut refs, path, EMPTY_SHA256, 0)?;
+        }
+        let temp_name: String = rand::thread_rng()
+            .sample_iter(&rand::distributions::Alphanumeric)
+            .take(24)
+            .map(char::from)
+            .collect();
+        let temp_path = self.inner.root.join("tmp").join(temp_name);
+        let file = File::create(&temp_path).await?;
+        Ok(Box::new(DedupWriter {
+            inner: Arc::clone(&self.inner),
+            rel_path: path.to_string(),
+            temp_path,
+            file,
+            hasher: Sha256::new(),
+            size: 0,
+            committed: false,
+        }))
+    }
+
+    async fn create_dir(&self, path: &str) -> io::Result<()> {
+        tokio::fs::create_dir(self.inner.name_path(path)).await
+    }
+
+    async fn delete(&self, path: &str) -> io::Result<()> {
+        let name_path = self.inner.name_path(path);
+        let mut refs = self.inner.refs.lock().unwrap();
+        if name_path.is_dir() {
+            self.inner.release_all(&mut refs, &name_path)?;
+            fs::remove_dir_all(&name_path)
+        } else {
+            let pointer = read_pointer(&name_path)?;
+            fs::remove_file(&name_path)?;
+            self.inner.release(&mut refs, &pointer.sha256);
+            Ok(())
+        }
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let to_path = self.inner.name_path(to);
+        let mut refs = self.inner.refs.lock().unwrap();
+        // a file that gets replaced loses its reference
+        let replaced = read_pointer(&to_path).ok();
+        fs::rename(self.inner.name_path(from), &to_path)?;
+        if let Some(replaced) = replaced {
+            self.inner.release(&mut refs, &replaced.sha256);
+        }
+        Ok(())
+    }
+
+    async fn link(&self, path: &str, sha256: &str) -> io::Result<bool> {
+        if !is_sha256(sha256) {
+            return Ok(false);
+        }
+        let mut refs = self.inner.refs.lock().unwrap();
+        if !refs.contains_key(sha256) {
+            return Ok(false);
+        }
+        let size = fs::metadata(self.inner.blob_path(sha256))?.len();
+        self.inner.set_pointer(&mut refs, path, sha256, size)?;
+        Ok(true)
+    }
+
+    async fn has_content(&self, sha256: &str) -> bool {
+        self.inner.refs.lock().unwrap().contains_key(sha256)
+    }
+}
+
+/// Writes an upload to a temp file while hashing it, `shutdown` moves it into place
+struct DedupWriter {
+    inner: Arc<Inner>,
+    rel_path: String,
+    temp_path: PathBuf,
+    file: File,
+    hasher: Sha256,
+    size: u64,
+    committed: bool,
+}
+
+impl AsyncWrite for DedupWriter {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let written = ready!(Pin::new(&mut self.file).poll_write(cx, buf))?;
+        self.hasher.update(&buf[..written]);
+        self.size += written as u64;
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.file).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(Pin::new(&mut self.file).poll_shutdown(cx))?;
+        if !self.committed {
+            self.committed = true;
+            let sha256 = format!("{:x}", self.hasher.clone().finalize());
+            self.inner.commit(&self.temp_path, &self.rel_path, &sha256, self.size)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for DedupWriter {
+    fn drop(&mut self) {
+        // given up on before shutdown, the name keeps pointing at the empty blob
+        if !self.committed {
+            let _ = fs::remove_file(&self.temp_path);
+        }
+    }
+}
@@ -0,0 +1,100 @@
+/// hide.rs - another backend with one top level folder cut out of it, for things the server
+/// keeps beside the files (the trash). the folder isn't listed, can't be opened and its name
+/// can't be taken, while the backend itself still sees everything
+use async_trait::async_trait;
+use std::io;
+use std::sync::Arc;
+
+use super::{DirEntry, Metadata, ReadStream, StorageBackend, WriteStream};
+
+pub struct HideDir {
+    inner: Arc<dyn StorageBackend>,
+    name: &'static str,
+}
+
+impl HideDir {
+    pub fn new(inner: Arc<dyn StorageBackend>, name: &'static str) -> Self {
+        Self { inner, name }
+    }
+
+    fn is_hidden(&self, path: &str) -> bool {
+        path.split('/').next() == Some(self.name)
+    }
+
+    /// `NotFound` for paths inside the hidden folder
+    fn check(&self, path: &str) -> io::Result<()> {
+        if self.is_hidden(path) {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        Ok(())
+    }
+
+    /// Like `check`, for paths about to be created
+    fn check_new(&self, path: &str) -> io::Result<()> {
+        if self.is_hidden(path) {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "that name is reserved"));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl StorageBackend for HideDir {
+    async fn list(&self, dir: &str) -> io::Result<Vec<DirEntry>> {
+        self.check(dir)?;
+        let mut entries = self.inner.list(dir).await?;
+        if dir.is_empty() {
+            entries.retain(|entry| entry.name != self.name);
+        }
+        Ok(entries)
+    }
+
+    async fn stat(&self, path: &str) -> io::Result<Metadata> {
+        self.check(path)?;
+        self.inner.stat(path).await
+    }
+
+    async fn read(&self, path: &str) -> io::Result<ReadStream> {
+        self.check(path)?;
+        self.inner.read(path).await
+    }
+
+    async fn read_from(&self, path: &str, offset: u64) -> io::Result<ReadStream> {
+        self.check(path)?;
+        self.inner.read_from(path, offset).await
+    }
+
+    async fn write(&self, path: &str) -> io::Result<WriteStream> {
+        self.check_new(path)?;
+        self.inner.write(path).await
+    }
+
+    async fn create_dir(&self, path: &str) -> io::Result<()> {
+        self.check_new(path)?;
+        self.inner.create_dir(path).await
+    }
+
+    async fn delete(&self, path: &str) -> io::Result<()> {
+        // deleting the root would take the hidden folder with it
+        if path.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "can't delete the root"));
+        }
+        self.check(path)?;
+        self.inner.delete(path).await
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        self.check(from)?;
+        self.check_new(to)?;
+        self.inner.rename(from, to).await
+    }
+
+    async fn link(&self, path: &str, sha256: &str) -> io::Result<bool> {
+        self.check_new(path)?;
+        self.inner.link(path, sha256).await
+    }
+
+    async fn has_content(&self, sha256: &str) -> bool {
+        self.inner.has_content(sha256).await
+    }
+}
@@ -0,0 +1,105 @@
+/// local.rs - files kept in a folder on disk, the default backend
+use async_trait::async_trait;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+use tokio::fs::File;
+use tokio::io::AsyncSeekExt;
+
+use super::{DirEntry, Metadata, ReadStream, StorageBackend, WriteStream};
+use crate::paths;
+
+pub struct LocalDisk {
+    root: PathBuf,
+}
+
+impl LocalDisk {
+    /// Files go under `root`, which must already exist
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    fn path(&self, rel_path: &str) -> PathBuf {
+        paths::resolve(&self.root, rel_path)
+    }
+}
+
+impl From<&fs::Metadata> for Metadata {
+    fn from(metadata: &fs::Metadata) -> Self {
+        Self {
+            is_dir: metadata.is_dir(),
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            modified: metadata
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |d| d.as_secs()),
+        }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for LocalDisk {
+    async fn list(&self, dir: &str) -> io::Result<Vec<DirEntry>> {
+        let mut read_dir = tokio::fs::read_dir(self.path(dir)).await?;
+        let mut entries = Vec::new();
+        while let Some(entry) = read_dir.next_entry().await? {
+            // names that aren't utf-8 can't be addressed by clients anyway
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            let Ok(metadata) = entry.metadata().await else {
+                continue;
+            };
+            entries.push(DirEntry {
+                name,
+                metadata: Metadata::from(&metadata),
+            });
+        }
+        Ok(entries)
+    }
+
+    async fn stat(&self, path: &str) -> io::Result<Metadata> {
+        Ok(Metadata::from(&tokio::fs::metadata(self.path(path)).await?))
+    }
+
+    async fn read(&self, path: &str) -> io::Result<ReadStream> {
+        let file = File::open(self.path(path)).await?;
+        // opening a folder works on unix, reading it doesn't
+        if file.metadata().await?.is_dir() {
+            return Err(io::ErrorKind::IsADirectory.into());
+        }
+        Ok(Box::new(file))
+    }
+
+    async fn read_from(&self, path: &str, offset: u64) -> io::Result<ReadStream> {
+        let mut file = File::open(self.path(path)).await?;
+        if file.metadata().await?.is_dir() {
+            return Err(io::ErrorKind::IsADirectory.into());
+        }
+        file.seek(io::SeekFrom::Start(offset)).await?;
+        Ok(Box::new(file))
+    }
+
+    async fn write(&self, path: &str) -> io::Result<WriteStream> {
+        Ok(Box::new(File::create(self.path(path)).await?))
+    }
+
+    async fn create_dir(&self, path: &str) -> io::Result<()> {
+        tokio::fs::create_dir(self.path(path)).await
+    }
+
+    async fn delete(&self, path: &str) -> io::Result<()> {
+        let full_path = self.path(path);
+        if tokio::fs::metadata(&full_path).await?.is_dir() {
+            tokio::fs::remove_dir_all(full_path).await
+        } else {
+            tokio::fs::remove_file(full_path).await
+        }
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        tokio::fs::rename(self.path(from), self.path(to)).await
+    }
+}
@@ -0,0 +1,371 @@
+/// memory.rs - files kept in a map in ram, everything is gone when the server stops.
+/// Meant for tests and demos, it behaves like `LocalDisk` as far as the endpoints can tell
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWrite;
+
+use super::{DirEntry, Metadata, ReadStream, StorageBackend, WriteStream};
+use crate::paths;
+
+enum Entry {
+    Dir { modified: u64 },
+    File { data: Vec<u8>, modified: u64 },
+}
+
+impl Entry {
+    fn metadata(&self) -> Metadata {
+        match self {
+            Entry::Dir { modified } => Metadata {
+                is_dir: true,
+                size: 0,
+                modified: *modified,
+            },
+            Entry::File { data, modified } => Metadata {
+                is_dir: false,
+                size: data.len() as u64,
+                modified: *modified,
+            },
+        }
+    }
+}
+
+/// Every file and folder by normalized path, the root is `""`
+type Entries = BTreeMap<String, Entry>;
+
+pub struct Memory {
+    entries: Arc<Mutex<Entries>>,
+}
+
+impl Memory {
+    pub fn new() -> Self {
+        let mut entries = Entries::new();
+        entries.insert(String::new(), Entry::Dir { modified: unix_now() });
+        Self {
+            entries: Arc::new(Mutex::new(entries)),
+        }
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Whether `path` is `dir` or somewhere below it
+fn is_below(path: &str, dir: &str) -> bool {
+    dir.is_empty() || path == dir || path.starts_with(&format!("{}/", dir))
+}
+
+/// `NotFound` unless the parent folder of `path` exists
+fn check_parent(entries: &Entries, path: &str) -> io::Result<()> {
+    match entries.get(paths::parent(path)) {
+        Some(Entry::Dir { .. }) => Ok(()),
+        Some(Entry::File { .. }) => Err(io::ErrorKind::NotADirectory.into()),
+        None => Err(io::ErrorKind::NotFound.into()),
+    }
+}
+
+#[async_trait]
+impl StorageBackend for Memory {
+    async fn list(&self, dir: &str) -> io::Result<Vec<DirEntry>> {
+        let entries = self.entries.lock().unwrap();
+        match entries.get(dir) {
+            Some(Entry::Dir { .. }) => {}
+            Some(Entry::File { .. }) => return Err(io::ErrorKind::NotADirectory.into()),
+            None => return Err(io::ErrorKind::NotFound.into()),
+        }
+        Ok(entries
+            .iter()
+            .filter(|(path, _)| !path.is_empty() && paths::parent(path) == dir)
+            .map(|(path, entry)| DirEntry {
+                name: path.rsplit('/').next().unwrap_or_default().to_string(),
+                metadata: entry.metadata(),
+            })
+            .collect())
+    }
+
+    async fn stat(&self, path: &str) -> io::Result<Metadata> {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(path)
+            .map(Entry::metadata)
+            .ok_or_else(|| io::ErrorKind::NotFound.into())
+    }
+
+    async fn read(&self, path: &str) -> io::Result<ReadStream> {
+        match self.entries.lock().unwrap().get(path) {
+            Some(Entry::File { data, .. }) => Ok(Box::new(io::Cursor::new(data.clone()))),
+            Some(Entry::Dir { .. }) => Err(io::ErrorKind::IsADirectory.into()),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    async fn read_from(&self, path: &str, offset: u64) -> io::Result<ReadStream> {
+        match self.entries.lock().unwrap().get(path) {
+            Some(Entry::File { data, .. }) => {
+                let start = data.len().min(offset as usize);
+                Ok(Box::new(io::Cursor::new(data[start..].to_vec())))
+            }
+            Some(Entry::Dir { .. }) => Err(io::ErrorKind::IsADirectory.into()),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    async fn write(&self, path: &str) -> io::Result<WriteStream> {
+        let mut entries = self.entries.lock().unwrap();
+        check_parent(&entries, path)?;
+        if let Some(Entry::Dir { .. }) = entries.get(path) {
+            return Err(io::ErrorKind::IsADirectory.into());
+        }
+        entries.insert(
+            path.to_string(),
+            Entry::File {
+                data: Vec::new(),
+                modified: unix_now(),
+            },
+        );
+        Ok(Box::new(MemoryWriter {
+            entries: Arc::clone(&self.entries),
+            path: path.to_string(),
+        }))
+    }
+
+    async fn create_dir(&self, path: &str) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.contains_key(path) {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        check_parent(&entries, path)?;
+        entries.insert(path.to_string(), Entry::Dir { modified: unix_now() });
+        Ok(())
+    }
+
+    async fn delete(&self, path: &str) -> io::Result<()> {
+        if path.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "can't delete the root"));
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.contains_key(path) {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        entries.retain(|entry, _| !is_below(entry, path));
+        Ok(())
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        let Some(source) = entries.get(from).map(Entry::metadata) else {
+            return Err(io::ErrorKind::NotFound.into());
+        };
+        if from.is_empty() || is_below(to, from) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "can't move a folder into itself"));
+        }
+        check_parent(&entries, to)?;
+        match entries.get(to).map(Entry::metadata) {
+            Some(target) if target.is_dir && !source.is_dir => return Err(io::ErrorKind::IsADirectory.into()),
+            Some(target) if !target.is_dir && source.is_dir => return Err(io::ErrorKind::NotADirectory.into()),
+            Some(target) if target.is_dir && entries.keys().any(|path| path != to && is_below(path, to)) => {
+                return Err(io::ErrorKind::DirectoryNotEmpty.into())
+            }
+            _ => {}
+        }
+
+        entries.remove(to);
+        let moved: Vec<String> = entries.keys().filter(|path| is_below(path, from)).cloned().collect();
+        for path in moved {
+            if let Some(entry) = entries.remove(&path) {
+                entries.insert(format!("{}{}", to, &path[from.len()..]), entry);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Appends straight into the stored file, so there is nothing to flush
+struct MemoryWriter {
+    entries: Arc<Mutex<Entries>>,
+    path: String,
+}
+
+impl AsyncWrite for MemoryWriter {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get_mut(&self.path) {
+            Some(Entry::File { data, modified }) => {
+                data.extend_from_slice(buf);
+                *modified = unix_now();
+                Poll::Ready(Ok(buf.len()))
+            }
+            // deleted or replaced by a folder while we were writing
+            _ => Poll::Ready(Err(io::ErrorKind::NotFound.into())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{self, copy_all, create_dir_all, read_all, tree_size, write_all};
+
+    async fn names(memory: &Memory, dir: &str) -> Vec<String> {
+        let mut names: Vec<String> = memory.list(dir).await.unwrap().into_iter().map(|entry| entry.name).collect();
+        names.sort();
+        names
+    }
+
+    fn kind<T>(result: io::Result<T>) -> io::ErrorKind {
+        result.err().expect("should have failed").kind()
+    }
+
+    #[tokio::test]
+    async fn write_and_read() {
+        let memory = Memory::new();
+        write_all(&memory, "a.txt", b"hello").await.unwrap();
+        assert_eq!(read_all(&memory, "a.txt").await.unwrap(), b"hello");
+        assert_eq!(memory.stat("a.txt").await.unwrap().size, 5);
+        // writing again replaces the contents
+        write_all(&memory, "a.txt", b"hi").await.unwrap();
+        assert_eq!(read_all(&memory, "a.txt").await.unwrap(), b"hi");
+        assert_eq!(names(&memory, "").await, ["a.txt"]);
+
+        assert_eq!(kind(memory.read("missing").await), io::ErrorKind::NotFound);
+        assert_eq!(kind(memory.read("").await), io::ErrorKind::IsADirectory);
+        assert_eq!(kind(memory.write("no/such/dir.txt").await), io::ErrorKind::NotFound);
+        assert_eq!(kind(memory.write("a.txt/inside").await), io::ErrorKind::NotADirectory);
+        assert_eq!(kind(memory.list("a.txt").await), io::ErrorKind::NotADirectory);
+    }
+
+    #[tokio::test]
+    async fn read_from_an_offset() {
+        let memory = Memory::new();
+        write_all(&memory, "a.txt", b"hello").await.unwrap();
+        let store = &memory;
+        let rest = |offset| async move {
+            use tokio::io::AsyncReadExt;
+            let mut contents = Vec::new();
+            store.read_from("a.txt", offset).await.unwrap().read_to_end(&mut contents).await.unwrap();
+            contents
+        };
+        assert_eq!(rest(0).await, b"hello");
+        assert_eq!(rest(3).await, b"lo");
+        assert_eq!(rest(9).await, b"");
+        assert_eq!(kind(memory.read_from("missing", 1).await), io::ErrorKind::NotFound);
+        memory.create_dir("dir").await.unwrap();
+        assert_eq!(kind(memory.read_from("dir", 0).await), io::ErrorKind::IsADirectory);
+    }
+
+    #[tokio::test]
+    async fn folders() {
+        let memory = Memory::new();
+        create_dir_all(&memory, "a/b/c").await.unwrap();
+        create_dir_all(&memory, "a/b").await.unwrap();
+        assert!(storage::is_dir(&memory, "a/b/c").await);
+        assert_eq!(kind(memory.create_dir("a").await), io::ErrorKind::AlreadyExists);
+        assert_eq!(kind(memory.create_dir("x/y").await), io::ErrorKind::NotFound);
+        write_all(&memory, "a/b/c.txt", b"").await.unwrap();
+        assert_eq!(kind(memory.write("a/b").await), io::ErrorKind::IsADirectory);
+        // only direct children are listed
+        assert_eq!(names(&memory, "a").await, ["b"]);
+        assert_eq!(names(&memory, "a/b").await, ["c", "c.txt"]);
+    }
+
+    #[tokio::test]
+    async fn delete() {
+        let memory = Memory::new();
+        create_dir_all(&memory, "a/b").await.unwrap();
+        write_all(&memory, "a/b/file", b"x").await.unwrap();
+        write_all(&memory, "ab", b"x").await.unwrap();
+        memory.delete("a").await.unwrap();
+        assert!(!storage::exists(&memory, "a/b/file").await);
+        // a sibling sharing the prefix stays
+        assert_eq!(names(&memory, "").await, ["ab"]);
+        assert_eq!(kind(memory.delete("a").await), io::ErrorKind::NotFound);
+        assert_eq!(kind(memory.delete("").await), io::ErrorKind::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn rename() {
+        let memory = Memory::new();
+        create_dir_all(&memory, "a/b").await.unwrap();
+        write_all(&memory, "a/b/file", b"contents").await.unwrap();
+        write_all(&memory, "other", b"old").await.unwrap();
+
+        memory.rename("a", "moved").await.unwrap();
+        assert!(!storage::exists(&memory, "a").await);
+        assert_eq!(read_all(&memory, "moved/b/file").await.unwrap(), b"contents");
+        // a file replaces a file
+        memory.rename("moved/b/file", "other").await.unwrap();
+        assert_eq!(read_all(&memory, "other").await.unwrap(), b"contents");
+        assert!(!storage::exists(&memory, "moved/b/file").await);
+
+        assert_eq!(kind(memory.rename("missing", "x").await), io::ErrorKind::NotFound);
+        assert_eq!(kind(memory.rename("moved", "moved/b/in").await), io::ErrorKind::InvalidInput);
+        assert_eq!(kind(memory.rename("other", "moved").await), io::ErrorKind::IsADirectory);
+        assert_eq!(kind(memory.rename("moved", "other").await), io::ErrorKind::NotADirectory);
+        assert_eq!(kind(memory.rename("other", "no/such").await), io::ErrorKind::NotFound);
+        create_dir_all(&memory, "full/inside").await.unwrap();
+        assert_eq!(kind(memory.rename("moved/b", "full").await), io::ErrorKind::DirectoryNotEmpty);
+        // an empty folder is replaced
+        memory.rename("moved/b", "full/inside").await.unwrap();
+        assert!(storage::is_dir(&memory, "full/inside").await);
+    }
+
+    #[tokio::test]
+    async fn copy_trees() {
+        let memory = Memory::new();
+        create_dir_all(&memory, "src/sub").await.unwrap();
+        write_all(&memory, "src/a", b"aaa").await.unwrap();
+        write_all(&memory, "src/sub/b", b"bb").await.unwrap();
+
+        copy_all(&memory, "src", "copy", true).await.unwrap();
+        assert_eq!(read_all(&memory, "copy/a").await.unwrap(), b"aaa");
+        assert_eq!(read_all(&memory, "copy/sub/b").await.unwrap(), b"bb");
+        assert_eq!(read_all(&memory, "src/sub/b").await.unwrap(), b"bb");
+
+        copy_all(&memory, "src", "shallow", false).await.unwrap();
+        assert!(storage::is_dir(&memory, "shallow").await);
+        assert!(names(&memory, "shallow").await.is_empty());
+
+        copy_all(&memory, "src/a", "single", true).await.unwrap();
+        assert_eq!(read_all(&memory, "single").await.unwrap(), b"aaa");
+        assert_eq!(kind(copy_all(&memory, "src", "copy", true).await), io::ErrorKind::AlreadyExists);
+        assert_eq!(kind(copy_all(&memory, "missing", "x", true).await), io::ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn tree_sizes() {
+        let memory = Memory::new();
+        create_dir_all(&memory, "a/b/c").await.unwrap();
+        write_all(&memory, "a/one", b"1").await.unwrap();
+        write_all(&memory, "a/b/two", b"22").await.unwrap();
+        write_all(&memory, "a/b/c/three", b"333").await.unwrap();
+        write_all(&memory, "outside", b"4444").await.unwrap();
+
+        assert_eq!(tree_size(&memory, "a").await.unwrap(), 6);
+        assert_eq!(tree_size(&memory, "a/b").await.unwrap(), 5);
+        assert_eq!(tree_size(&memory, "a/b/c/three").await.unwrap(), 3);
+        assert_eq!(tree_size(&memory, "").await.unwrap(), 10);
+        assert_eq!(kind(tree_size(&memory, "missing").await), io::ErrorKind::NotFound);
+    }
+}
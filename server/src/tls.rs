@@ -0,0 +1,73 @@
+/// tls.rs - loading (or generating) the server certificate and building the TLS acceptor
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::sync::Arc;
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::TlsConfig;
+
+/// Builds the acceptor for `config`, generating a self-signed pair first if asked to
+pub fn load_acceptor(config: &TlsConfig) -> io::Result<TlsAcceptor> {
+    if config.self_signed && !config.cert.exists() && !config.key.exists() {
+        generate_self_signed(config)?;
+    }
+
+    let certs: Vec<CertificateDer<'static>> = CertificateDer::pem_file_iter(&config.cert)
+        .and_then(|certs| certs.collect())
+        .map_err(|e| invalid_data(format!("unable to read certificate {:?}: {}", config.cert, e)))?;
+    if certs.is_empty() {
+        return Err(invalid_data(format!("no certificates found in {:?}", config.cert)));
+    }
+    let key = PrivateKeyDer::from_pem_file(&config.key)
+        .map_err(|e| invalid_data(format!("unable to read private key {:?}: {}", config.key, e)))?;
+
+    println!("TLS certificate fingerprint (sha256): {}", fingerprint(&certs[0]));
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut server_config = rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| invalid_data(e.to_string()))?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| invalid_data(format!("certificate and key don't match: {}", e)))?;
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// Writes a fresh self-signed certificate and key to the configured paths
+fn generate_self_signed(config: &TlsConfig) -> io::Result<()> {
+    let generated = rcgen::generate_simple_self_signed(config.hostnames.clone())
+        .map_err(|e| io::Error::other(format!("unable to generate certificate: {}", e)))?;
+
+    for path in [&config.cert, &config.key] {
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+    }
+    fs::write(&config.cert, generated.cert.pem())?;
+    fs::write(&config.key, generated.signing_key.serialize_pem())?;
+    println!(
+        "Generated self-signed certificate for {:?} at {:?} (key {:?})",
+        config.hostnames, config.cert, config.key
+    );
+    Ok(())
+}
+
+/// SHA-256 of the DER certificate as colon separated hex, the format clients pin
+pub fn fingerprint(cert: &CertificateDer<'_>) -> String {
+    Sha256::digest(cert.as_ref())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,155 @@
+/// trash.rs - deleted files and folders, kept for a while so they can be restored
+/// - items are moved (not copied) into `TRASH_DIR` at the top of the storage, which the server
+///   hides with `HideDir`. each gets a folder `<deleted at>-<random>` holding the item itself
+///   and `info.json` saying where it came from
+/// - restoring moves it back, purging deletes it for good. anything past the retention is
+///   purged by `purge_expired`, which the server runs every hour
+use serde::{Deserialize, Serialize};
+use shared::TrashItem;
+use std::io;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::paths;
+use crate::quota::{self, Usage};
+use crate::storage::{self, StorageBackend};
+
+pub const TRASH_DIR: &str = ".tuifs-trash";
+const INFO: &str = "info.json";
+const ITEM: &str = "item";
+
+/// `info.json` of one item
+#[derive(Serialize, Deserialize)]
+struct Info {
+    path: String,
+    is_dir: bool,
+    size: u64,
+    deleted_at: u64,
+    deleted_by: Option<String>,
+}
+
+pub struct Trash {
+    /// the whole storage, trash included
+    store: Arc<dyn StorageBackend>,
+    retention_secs: u64,
+}
+
+impl Trash {
+    pub fn new(store: Arc<dyn StorageBackend>, retention_days: u64) -> Self {
+        Self {
+            store,
+            retention_secs: retention_days * 24 * 60 * 60,
+        }
+    }
+
+    /// Moves the file or folder at `path` into the trash
+    pub async fn delete(&self, path: &str, user: Option<&str>) -> io::Result<TrashItem> {
+        let metadata = self.store.stat(path).await?;
+        let info = Info {
+            path: path.to_string(),
+            is_dir: metadata.is_dir,
+            size: storage::tree_size(self.store.as_ref(), path).await?,
+            deleted_at: unix_now(),
+            deleted_by: user.map(str::to_string),
+        };
+        let id = format!("{}-{:08x}", info.deleted_at, rand::random::<u32>());
+        let dir = paths::join(TRASH_DIR, &id);
+        storage::create_dir_all(self.store.as_ref(), &dir).await?;
+        let contents = serde_json::to_vec(&info).map_err(io::Error::other)?;
+        storage::write_all(self.store.as_ref(), &paths::join(&dir, INFO), &contents).await?;
+        if let Err(e) = self.store.rename(path, &paths::join(&dir, ITEM)).await {
+            let _ = self.store.delete(&dir).await;
+            return Err(e);
+        }
+        Ok(self.item_from(id, info))
+    }
+
+    /// Everything in the trash, newest first
+    pub async fn list(&self) -> io::Result<Vec<TrashItem>> {
+        let entries = match self.store.list(TRASH_DIR).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut items = Vec::new();
+        for entry in entries {
+            match self.item(&entry.name).await {
+                Ok(item) => items.push(item),
+                Err(e) => eprintln!("Skipping unreadable trash item '{}': {}", entry.name, e),
+            }
+        }
+        items.sort_by(|a, b| b.deleted_at.cmp(&a.deleted_at).then_with(|| b.id.cmp(&a.id)));
+        Ok(items)
+    }
+
+    /// One item, `NotFound` for ids that aren't in the trash
+    pub async fn item(&self, id: &str) -> io::Result<TrashItem> {
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_hexdigit() || c == '-') {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        let contents = storage::read_all(self.store.as_ref(), &paths::join(&item_dir(id), INFO)).await?;
+        let info: Info = serde_json::from_slice(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(self.item_from(id.to_string(), info))
+    }
+
+    /// What an item would add to the storage when restored
+    pub async fn usage(&self, id: &str) -> io::Result<Usage> {
+        self.item(id).await?;
+        quota::usage_of(self.store.as_ref(), &paths::join(&item_dir(id), ITEM)).await
+    }
+
+    /// Moves an item back where it was, recreating missing parent folders.
+    /// `AlreadyExists` when something new took its place in the meantime
+    pub async fn restore(&self, id: &str) -> io::Result<TrashItem> {
+        let item = self.item(id).await?;
+        if storage::exists(self.store.as_ref(), &item.path).await {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        storage::create_dir_all(self.store.as_ref(), paths::parent(&item.path)).await?;
+        let dir = item_dir(id);
+        self.store.rename(&paths::join(&dir, ITEM), &item.path).await?;
+        self.store.delete(&dir).await?;
+        Ok(item)
+    }
+
+    /// Deletes an item for good
+    pub async fn purge(&self, id: &str) -> io::Result<()> {
+        self.item(id).await?;
+        self.store.delete(&item_dir(id)).await
+    }
+
+    /// Purges everything past the retention, returns how many items went
+    pub async fn purge_expired(&self) -> io::Result<usize> {
+        let now = unix_now();
+        let mut purged = 0;
+        for item in self.list().await? {
+            if item.purge_at <= now {
+                self.store.delete(&item_dir(&item.id)).await?;
+                purged += 1;
+            }
+        }
+        Ok(purged)
+    }
+
+    fn item_from(&self, id: String, info: Info) -> TrashItem {
+        TrashItem {
+            id,
+            path: info.path,
+            is_dir: info.is_dir,
+            size: info.size,
+            deleted_at: info.deleted_at,
+            deleted_by: info.deleted_by,
+            purge_at: info.deleted_at + self.retention_secs,
+        }
+    }
+}
+
+fn item_dir(id: &str) -> String {
+    paths::join(TRASH_DIR, id)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
@@ -0,0 +1,267 @@
+/// unpack.rs - looking inside the zip, tar and tar.gz files in the storage
+/// - `members` lists what an archive holds, `read_member` gets one file out of it and `extract`
+///   sends files out in chunks for the server to write to the storage as they come
+/// - the archive is in memory (up to `MAX_ARCHIVE_BYTES`), the work itself blocks so the server
+///   runs it off the async threads
+/// - member names are cleaned like request paths: absolute names, `..`, backslashes and control
+///   characters leave a member out, and so do links and special files. nothing in an archive
+///   can end up outside the folder it is extracted to
+use flate2::read::GzDecoder;
+use shared::ArchiveKind;
+use std::collections::{BTreeMap, HashSet};
+use std::io::{self, Cursor, Read};
+use tokio::sync::mpsc;
+
+use crate::paths;
+
+/// archives bigger than this aren't opened
+pub const MAX_ARCHIVE_BYTES: u64 = 1 << 30;
+const CHUNK: usize = 64 * 1024;
+
+/// One file or folder in an archive
+#[derive(Debug, Clone)]
+pub struct Member {
+    /// cleaned path inside the archive, folders without a trailing slash
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    /// unix timestamp (seconds)
+    pub modified: u64,
+}
+
+/// A piece of what `extract` sends
+pub enum Piece {
+    /// the next file starts, its contents follow
+    File(String),
+    Data(Vec<u8>),
+}
+
+/// What an archive holds by name, folders before what is in them. folders only implied by the
+/// paths of files are added too, the second value counts the entries that were left out
+pub fn members(kind: ArchiveKind, data: &[u8]) -> io::Result<(Vec<Member>, usize)> {
+    let mut found: BTreeMap<String, Member> = BTreeMap::new();
+    let mut skipped = 0;
+    each_member(kind, data, |member, _| {
+        let Some(member) = member else {
+            skipped += 1;
+            return Ok(true);
+        };
+        let mut parent = paths::parent(&member.name);
+        while !parent.is_empty() {
+            found.entry(parent.to_string()).or_insert_with(|| Member {
+                name: parent.to_string(),
+                is_dir: true,
+                size: 0,
+                modified: member.modified,
+            });
+            parent = paths::parent(parent);
+        }
+        found.insert(member.name.clone(), member);
+        Ok(true)
+    })?;
+    Ok((found.into_values().collect(), skipped))
+}
+
+/// Contents of the file called `name` in the archive, `None` when it has no such file
+pub fn read_member(kind: ArchiveKind, data: &[u8], name: &str) -> io::Result<Option<Vec<u8>>> {
+    let mut contents = None;
+    each_member(kind, data, |member, reader| match member {
+        Some(member) if !member.is_dir && member.name == name => {
+            // the size comes from the archive, don't trust it with memory
+            if member.size > MAX_ARCHIVE_BYTES {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "the file is too big to take out"));
+            }
+            let mut buffer = Vec::with_capacity(member.size as usize);
+            reader.take(member.size).read_to_end(&mut buffer)?;
+            contents = Some(buffer);
+            Ok(false)
+        }
+        _ => Ok(true),
+    })?;
+    Ok(contents)
+}
+
+/// Sends the files named in `wanted` out of the archive, each as a `Piece::File` followed by
+/// its contents. stops early when the receiver goes away
+pub fn extract(kind: ArchiveKind, data: &[u8], wanted: &HashSet<String>, sender: mpsc::Sender<Piece>) -> io::Result<()> {
+    each_member(kind, data, |member, reader| {
+        let Some(member) = member.filter(|member| !member.is_dir && wanted.contains(&member.name)) else {
+            return Ok(true);
+        };
+        if sender.blocking_send(Piece::File(member.name.clone())).is_err() {
+            return Ok(false);
+        }
+        let mut reader = reader.take(member.size);
+        loop {
+            let mut chunk = vec![0; CHUNK];
+            let read = reader.read(&mut chunk)?;
+            if read == 0 {
+                // reading on to the end is what checks a zip member's checksum
+                if reader.into_inner().read(&mut [0])? != 0 {
+                    return Err(invalid(format!("'{}' is longer than the archive says", member.name)));
+                }
+                return Ok(true);
+            }
+            chunk.truncate(read);
+            if sender.blocking_send(Piece::Data(chunk)).is_err() {
+                return Ok(false);
+            }
+        }
+    })
+}
+
+/// Calls `visit` with each entry of the archive (`None` for one that is left out) and a reader
+/// of its contents, until it returns false
+fn each_member<F>(kind: ArchiveKind, data: &[u8], visit: F) -> io::Result<()>
+where
+    F: FnMut(Option<Member>, &mut dyn Read) -> io::Result<bool>,
+{
+    match kind {
+        ArchiveKind::Zip => zip_members(data, visit),
+        ArchiveKind::Tar => tar_members(data, visit),
+        ArchiveKind::TarGz => tar_members(GzDecoder::new(data), visit),
+    }
+}
+
+fn zip_members<F>(data: &[u8], mut visit: F) -> io::Result<()>
+where
+    F: FnMut(Option<Member>, &mut dyn Read) -> io::Result<bool>,
+{
+    let mut archive = zip::ZipArchive::new(Cursor::new(data)).map_err(invalid)?;
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i).map_err(invalid)?;
+        let name = file.name().ok().and_then(|name| clean_name(&name));
+        // "./" and the like
+        if name.as_deref() == Some("") {
+            continue;
+        }
+        let member = name.filter(|_| !file.is_symlink()).map(|name| Member {
+            name,
+            is_dir: file.is_dir(),
+            size: if file.is_dir() { 0 } else { file.size() },
+            modified: file.last_modified().map_or(0, zip_time),
+        });
+        if !visit(member, &mut file)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn tar_members<R, F>(reader: R, mut visit: F) -> io::Result<()>
+where
+    R: Read,
+    F: FnMut(Option<Member>, &mut dyn Read) -> io::Result<bool>,
+{
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let kind = entry.header().entry_type();
+        let name = entry.path().ok().and_then(|path| path.to_str().and_then(clean_name));
+        if name.as_deref() == Some("") {
+            continue;
+        }
+        // links and devices are left out
+        let is_dir = kind.is_dir();
+        let member = name.filter(|_| is_dir || kind.is_file()).map(|name| Member {
+            name,
+            is_dir,
+            size: if is_dir { 0 } else { entry.size() },
+            modified: entry.header().mtime().unwrap_or(0),
+        });
+        if !visit(member, &mut entry)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Member name as a path below the folder it is extracted to, `None` when it could leave it.
+/// `./` parts are dropped, the archive's own root becomes `""`
+fn clean_name(raw: &str) -> Option<String> {
+    let first = raw.split('/').next().unwrap_or_default();
+    // absolute names and windows drives, `c:name` is relative to the drive's current folder
+    let drive = first.as_bytes().get(1) == Some(&b':') && first.as_bytes()[0].is_ascii_alphabetic();
+    if raw.starts_with('/') || first.ends_with(':') || drive {
+        return None;
+    }
+    let parts: Vec<&str> = raw.split('/').filter(|part| *part != ".").collect();
+    paths::normalize(&parts.join("/"))
+}
+
+fn zip_time(time: zip::DateTime) -> u64 {
+    chrono::NaiveDate::from_ymd_opt(time.year() as i32, time.month() as u32, time.day() as u32)
+        .and_then(|date| date.and_hms_opt(time.hour() as u32, time.minute() as u32, time.second() as u32))
+        .map_or(0, |time| time.and_utc().timestamp().max(0) as u64)
+}
+
+fn invalid(e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clean_names() {
+        assert_eq!(clean_name("a/b.txt").as_deref(), Some("a/b.txt"));
+        assert_eq!(clean_name("dir/").as_deref(), Some("dir"));
+        assert_eq!(clean_name("./a/./b").as_deref(), Some("a/b"));
+        assert_eq!(clean_name("./").as_deref(), Some(""));
+        assert_eq!(clean_name(".").as_deref(), Some(""));
+        assert_eq!(clean_name("a//b").as_deref(), Some("a/b"));
+        // a colon further in is only part of a name
+        assert_eq!(clean_name("a/b:c").as_deref(), Some("a/b:c"));
+    }
+
+    #[test]
+    fn names_that_could_leave_the_folder() {
+        for name in [
+            "../a",
+            "a/../../b",
+            "a/..",
+            "./../a",
+            "/etc/passwd",
+            "//server/share",
+            "C:",
+            "C:/Windows/system.ini",
+            "c:file",
+            "C:\\Windows",
+            "..\\a",
+            "a\\b",
+            "a\nb",
+            "a\rb",
+            "a\0b",
+            "bell\u{7}",
+        ] {
+            assert_eq!(clean_name(name), None, "{:?}", name);
+        }
+    }
+
+    fn tar_of(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, data) in entries {
+            let mut header = tar::Header::new_gnu();
+            // set_path refuses `..`, the raw name field doesn't
+            header.as_gnu_mut().unwrap().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append(&header, *data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn tar_members_are_cleaned() {
+        let archive = tar_of(&[("./docs/a.txt", b"aaa"), ("../evil", b"x"), ("/abs", b"x"), ("docs/b", b"bb")]);
+        let (members, skipped) = members(ArchiveKind::Tar, &archive).unwrap();
+        let names: Vec<&str> = members.iter().map(|member| member.name.as_str()).collect();
+        assert_eq!(names, ["docs", "docs/a.txt", "docs/b"]);
+        assert_eq!(skipped, 2);
+        assert!(members[0].is_dir);
+        assert_eq!(read_member(ArchiveKind::Tar, &archive, "docs/b").unwrap(), Some(b"bb".to_vec()));
+        assert_eq!(read_member(ArchiveKind::Tar, &archive, "../evil").unwrap(), None);
+    }
+}
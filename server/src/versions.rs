@@ -0,0 +1,184 @@
+/// versions.rs - previous contents of files, saved right before an upload replaces them
+/// - versions live in a store of their own (same kind of backend as the files), one folder
+///   per file named by the sha256 of its path, holding `history.json` and the old contents
+///   by version id
+/// - the history also remembers who uploaded the current contents, so each version knows
+///   who it came from
+/// - a count per file and/or a maximum age decide what is kept, checked whenever a version
+///   is added
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use shared::FileVersion;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+use crate::config::VersionsConfig;
+use crate::paths;
+use crate::storage::{self, StorageBackend};
+
+const HISTORY: &str = "history.json";
+
+/// `history.json` of one file
+#[derive(Serialize, Deserialize, Default)]
+struct History {
+    /// the file these are versions of
+    path: String,
+    /// who uploaded the current contents
+    uploader: Option<String>,
+    next_id: u64,
+    /// oldest first
+    versions: Vec<FileVersion>,
+}
+
+pub struct Versions {
+    store: Box<dyn StorageBackend>,
+    keep: Option<usize>,
+    max_age_secs: Option<u64>,
+    /// histories are read, changed and written back while holding this
+    lock: Mutex<()>,
+}
+
+impl Versions {
+    pub fn new(store: Box<dyn StorageBackend>, config: &VersionsConfig) -> Self {
+        Self {
+            store,
+            keep: config.keep(),
+            max_age_secs: config.max_age_days.map(|days| days * 24 * 60 * 60),
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Keeps the current contents of `path` (when it is a file) as a version, call it right
+    /// before replacing them. `uploader` is who the new contents come from
+    pub async fn save(&self, storage: &dyn StorageBackend, path: &str, uploader: Option<&str>) -> io::Result<()> {
+        let _guard = self.lock.lock().await;
+        let mut history = self.history(path).await?;
+        self.push_current(storage, &mut history).await?;
+        history.uploader = uploader.map(str::to_string);
+        self.prune(&mut history).await;
+        self.write_history(&history).await
+    }
+
+    /// Versions of `path`, newest first
+    pub async fn list(&self, path: &str) -> io::Result<Vec<FileVersion>> {
+        let _guard = self.lock.lock().await;
+        let now = unix_now();
+        let mut versions = self.history(path).await?.versions;
+        versions.retain(|version| !self.expired(version, now));
+        versions.reverse();
+        Ok(versions)
+    }
+
+    /// Puts version `id` of `path` back, the contents it replaces become a version themselves.
+    /// `None` when there is no such version, otherwise the restored version
+    pub async fn restore(&self, storage: &dyn StorageBackend, path: &str, id: u64) -> io::Result<Option<FileVersion>> {
+        let _guard = self.lock.lock().await;
+        let mut history = self.history(path).await?;
+        let now = unix_now();
+        let Some(version) = history
+            .versions
+            .iter()
+            .find(|version| version.id == id && !self.expired(version, now))
+            .cloned()
+        else {
+            return Ok(None);
+        };
+
+        self.push_current(storage, &mut history).await?;
+        let mut reader = self.store.read(&version_path(path, id)).await?;
+        let mut writer = storage.write(path).await?;
+        tokio::io::copy(&mut reader, &mut writer).await?;
+        writer.shutdown().await?;
+
+        history.uploader = version.uploader.clone();
+        self.prune(&mut history).await;
+        self.write_history(&history).await?;
+        Ok(Some(version))
+    }
+
+    async fn history(&self, path: &str) -> io::Result<History> {
+        match storage::read_all(self.store.as_ref(), &paths::join(&history_dir(path), HISTORY)).await {
+            Ok(contents) => serde_json::from_slice(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(History {
+                path: path.to_string(),
+                ..Default::default()
+            }),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn write_history(&self, history: &History) -> io::Result<()> {
+        let dir = history_dir(&history.path);
+        storage::create_dir_all(self.store.as_ref(), &dir).await?;
+        let contents = serde_json::to_vec(history).map_err(io::Error::other)?;
+        storage::write_all(self.store.as_ref(), &paths::join(&dir, HISTORY), &contents).await
+    }
+
+    /// Copies the file at `history.path` into the store as its newest version
+    async fn push_current(&self, storage: &dyn StorageBackend, history: &mut History) -> io::Result<()> {
+        let metadata = match storage.stat(&history.path).await {
+            Ok(metadata) if !metadata.is_dir => metadata,
+            // nothing to keep
+            _ => return Ok(()),
+        };
+        storage::create_dir_all(self.store.as_ref(), &history_dir(&history.path)).await?;
+        let id = history.next_id;
+        let mut reader = storage.read(&history.path).await?;
+        let mut writer = self.store.write(&version_path(&history.path, id)).await?;
+        tokio::io::copy(&mut reader, &mut writer).await?;
+        writer.shutdown().await?;
+
+        history.next_id += 1;
+        history.versions.push(FileVersion {
+            id,
+            size: metadata.size,
+            modified: metadata.modified,
+            replaced: unix_now(),
+            uploader: history.uploader.take(),
+        });
+        Ok(())
+    }
+
+    /// Drops versions past the count or age limits
+    async fn prune(&self, history: &mut History) {
+        let now = unix_now();
+        let too_many = history
+            .versions
+            .len()
+            .saturating_sub(self.keep.unwrap_or(usize::MAX));
+        let mut kept = Vec::new();
+        for (i, version) in history.versions.drain(..).enumerate() {
+            if i >= too_many && !self.expired(&version, now) {
+                kept.push(version);
+            } else if let Err(e) = self.store.delete(&version_path(&history.path, version.id)).await {
+                eprintln!("Unable to remove version {} of '{}': {}", version.id, history.path, e);
+            }
+        }
+        history.versions = kept;
+    }
+
+    fn expired(&self, version: &FileVersion, now: u64) -> bool {
+        self.max_age_secs
+            .is_some_and(|max_age| now.saturating_sub(version.replaced) > max_age)
+    }
+}
+
+/// Folder in the store holding the versions of `path`
+fn history_dir(path: &str) -> String {
+    Sha256::digest(path.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn version_path(path: &str, id: u64) -> String {
+    paths::join(&history_dir(path), &id.to_string())
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
@@ -0,0 +1,467 @@
+/// webdav.rs - the bits of WebDAV (RFC 4918) that aren't plain file handling
+/// - parsing PROPFIND/LOCK bodies and the Depth, Destination, Overwrite, If and Timeout headers
+/// - building multistatus and lockdiscovery xml
+/// - the lock table, kept in memory like login tokens - a restart drops every lock
+/// - the handlers themselves live with the other endpoints in server.rs
+use hyper::header::HeaderMap;
+use percent_encoding::percent_decode_str;
+use rand::Rng;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use crate::html;
+use crate::paths;
+use crate::storage::Metadata;
+
+/// Url prefix the WebDAV tree is mounted at
+pub const PREFIX: &str = "/dav";
+
+/// Longest lock we hand out, `Timeout: Infinite` gets this
+const MAX_LOCK_SECS: u64 = 24 * 60 * 60;
+const DEFAULT_LOCK_SECS: u64 = 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Depth {
+    Zero,
+    One,
+    Infinity,
+}
+
+impl Depth {
+    /// The `Depth` header, `default` when missing, `None` when invalid
+    pub fn from_headers(headers: &HeaderMap, default: Depth) -> Option<Depth> {
+        match headers.get("depth").map(|v| v.to_str().map(str::trim)) {
+            None => Some(default),
+            Some(Ok("0")) => Some(Depth::Zero),
+            Some(Ok("1")) => Some(Depth::One),
+            Some(Ok(value)) if value.eq_ignore_ascii_case("infinity") => Some(Depth::Infinity),
+            Some(_) => None,
+        }
+    }
+}
+
+/// Whether `Overwrite: F` was sent (the default is to overwrite)
+pub fn overwrite(headers: &HeaderMap) -> bool {
+    headers
+        .get("overwrite")
+        .and_then(|v| v.to_str().ok())
+        .is_none_or(|v| !v.trim().eq_ignore_ascii_case("f"))
+}
+
+/// Normalized storage path from a request path under `PREFIX`
+pub fn request_path(uri_path: &str) -> Option<String> {
+    let rest = uri_path.strip_prefix(PREFIX)?;
+    if !rest.is_empty() && !rest.starts_with('/') {
+        return None;
+    }
+    paths::normalize(&percent_decode_str(rest).decode_utf8().ok()?)
+}
+
+/// Normalized storage path from the `Destination` header, which may be a full url
+pub fn destination(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get("destination")?.to_str().ok()?;
+    let path = match value.split_once("://") {
+        Some((_, rest)) => &rest[rest.find('/')?..],
+        None => value,
+    };
+    request_path(path.split(['?', '#']).next()?)
+}
+
+/// Lock tokens named in the `If` header
+pub fn if_tokens(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all("if")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split('<').skip(1))
+        .filter_map(|part| part.split_once('>'))
+        .map(|(token, _)| token.to_string())
+        .filter(|token| token.starts_with("opaquelocktoken:"))
+        .collect()
+}
+
+/// Requested lock length from `Timeout: Second-n` / `Infinite`
+pub fn timeout(headers: &HeaderMap) -> Duration {
+    let secs = headers
+        .get("timeout")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(',').next())
+        .map(str::trim)
+        .and_then(|v| match v {
+            "Infinite" => Some(MAX_LOCK_SECS),
+            _ => v.strip_prefix("Second-")?.parse().ok(),
+        })
+        .unwrap_or(DEFAULT_LOCK_SECS);
+    Duration::from_secs(secs.clamp(1, MAX_LOCK_SECS))
+}
+
+/// Link to `rel_path` in the WebDAV tree, collections end in '/'
+pub fn href(rel_path: &str, is_dir: bool) -> String {
+    match (rel_path.is_empty(), is_dir) {
+        (true, _) => format!("{}/", PREFIX),
+        (false, true) => format!("{}/{}/", PREFIX, html::encode_path(rel_path)),
+        (false, false) => format!("{}/{}", PREFIX, html::encode_path(rel_path)),
+    }
+}
+
+/// What a PROPFIND asked for
+pub enum PropRequest {
+    /// every live property (also used for an empty body)
+    All,
+    /// just the property names
+    Names,
+    /// these `(namespace, name)` pairs
+    Props(Vec<(String, String)>),
+}
+
+/// Parses a PROPFIND body, `None` if it isn't a valid propfind document
+pub fn parse_propfind(body: &[u8]) -> Option<PropRequest> {
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Some(PropRequest::All);
+    }
+    let text = std::str::from_utf8(body).ok()?;
+    let doc = roxmltree::Document::parse(text).ok()?;
+    let root = doc.root_element();
+    if !is_dav(root, "propfind") {
+        return None;
+    }
+    let child = root.children().find(|n| n.is_element())?;
+    if is_dav(child, "allprop") {
+        Some(PropRequest::All)
+    } else if is_dav(child, "propname") {
+        Some(PropRequest::Names)
+    } else if is_dav(child, "prop") {
+        let props = child
+            .children()
+            .filter(|n| n.is_element())
+            .map(|n| {
+                let name = n.tag_name();
+                (name.namespace().unwrap_or_default().to_string(), name.name().to_string())
+            })
+            .collect();
+        Some(PropRequest::Props(props))
+    } else {
+        None
+    }
+}
+
+/// Owner xml from a LOCK body, `Some(None)` for a lockinfo without owner,
+/// `None` if the body isn't a lockinfo at all
+pub fn parse_lockinfo(body: &[u8]) -> Option<Option<String>> {
+    let text = std::str::from_utf8(body).ok()?;
+    let doc = roxmltree::Document::parse(text).ok()?;
+    let root = doc.root_element();
+    if !is_dav(root, "lockinfo") {
+        return None;
+    }
+    let owner = root.children().find(|n| is_dav(*n, "owner")).map(|owner| {
+        match owner.children().find(|n| is_dav(*n, "href")) {
+            Some(href) => format!("<D:href>{}</D:href>", html::escape(href.text().unwrap_or_default())),
+            None => html::escape(owner.text().unwrap_or_default().trim()),
+        }
+    });
+    Some(owner)
+}
+
+fn is_dav(node: roxmltree::Node, name: &str) -> bool {
+    node.is_element() && node.tag_name().namespace() == Some("DAV:") && node.tag_name().name() == name
+}
+
+/// One resource in a PROPFIND answer
+pub struct PropEntry {
+    pub rel_path: String,
+    pub is_dir: bool,
+    pub size: u64,
+    /// unix timestamp (seconds)
+    pub modified: u64,
+    /// `<D:activelock>` of a lock on it, if any
+    pub lock: Option<String>,
+}
+
+impl PropEntry {
+    pub fn from_metadata(rel_path: String, metadata: &Metadata, lock: Option<String>) -> Self {
+        Self {
+            rel_path,
+            is_dir: metadata.is_dir,
+            size: metadata.size,
+            modified: metadata.modified,
+            lock,
+        }
+    }
+
+    /// Value of the live DAV: property `name`, `None` if this resource doesn't have it
+    fn prop(&self, name: &str) -> Option<String> {
+        let value = match name {
+            "displayname" => {
+                let name = self.rel_path.rsplit('/').next().unwrap_or_default();
+                html::escape(name)
+            }
+            "resourcetype" if self.is_dir => "<D:collection/>".to_string(),
+            "resourcetype" => String::new(),
+            "getcontentlength" if !self.is_dir => self.size.to_string(),
+            "getcontenttype" if !self.is_dir => mime_guess::from_path(&self.rel_path)
+                .first_or_octet_stream()
+                .to_string(),
+            "getlastmodified" => http_date(self.modified),
+            "getetag" => etag(self.size, self.modified),
+            "supportedlock" => "<D:lockentry><D:lockscope><D:exclusive/></D:lockscope>\
+                                <D:locktype><D:write/></D:locktype></D:lockentry>"
+                .to_string(),
+            "lockdiscovery" => self.lock.clone().unwrap_or_default(),
+            _ => return None,
+        };
+        Some(value)
+    }
+}
+
+const LIVE_PROPS: [&str; 8] = [
+    "displayname",
+    "resourcetype",
+    "getcontentlength",
+    "getcontenttype",
+    "getlastmodified",
+    "getetag",
+    "supportedlock",
+    "lockdiscovery",
+];
+
+/// 207 Multi-Status body for a PROPFIND
+pub fn multistatus(entries: &[PropEntry], request: &PropRequest) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n");
+    for entry in entries {
+        let mut found = String::new();
+        let mut missing = String::new();
+        match request {
+            PropRequest::All => {
+                for name in LIVE_PROPS {
+                    if let Some(value) = entry.prop(name) {
+                        found.push_str(&element(name, &value));
+                    }
+                }
+            }
+            PropRequest::Names => {
+                for name in LIVE_PROPS {
+                    if entry.prop(name).is_some() {
+                        found.push_str(&format!("<D:{}/>", name));
+                    }
+                }
+            }
+            PropRequest::Props(props) => {
+                for (namespace, name) in props {
+                    match entry.prop(name).filter(|_| namespace == "DAV:") {
+                        Some(value) => found.push_str(&element(name, &value)),
+                        None if namespace == "DAV:" => missing.push_str(&format!("<D:{}/>", name)),
+                        None => missing.push_str(&format!(
+                            "<X:{} xmlns:X=\"{}\"/>",
+                            name,
+                            html::escape(namespace)
+                        )),
+                    }
+                }
+            }
+        }
+        xml.push_str(&format!(
+            "<D:response><D:href>{}</D:href>",
+            html::escape(&href(&entry.rel_path, entry.is_dir))
+        ));
+        if !found.is_empty() {
+            xml.push_str(&propstat(&found, "200 OK"));
+        }
+        if !missing.is_empty() {
+            xml.push_str(&propstat(&missing, "404 Not Found"));
+        }
+        xml.push_str("</D:response>\n");
+    }
+    xml.push_str("</D:multistatus>\n");
+    xml
+}
+
+fn element(name: &str, value: &str) -> String {
+    if value.is_empty() {
+        format!("<D:{}/>", name)
+    } else {
+        format!("<D:{name}>{value}</D:{name}>")
+    }
+}
+
+fn propstat(props: &str, status: &str) -> String {
+    format!(
+        "<D:propstat><D:prop>{}</D:prop><D:status>HTTP/1.1 {}</D:status></D:propstat>",
+        props, status
+    )
+}
+
+/// Body of a successful LOCK
+pub fn lock_response(activelock: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <D:prop xmlns:D=\"DAV:\"><D:lockdiscovery>{}</D:lockdiscovery></D:prop>\n",
+        activelock
+    )
+}
+
+/// Body for a 403 PROPFIND with `Depth: infinity`
+pub fn finite_depth_error() -> String {
+    "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+     <D:error xmlns:D=\"DAV:\"><D:propfind-finite-depth/></D:error>\n"
+        .to_string()
+}
+
+/// RFC 7231 date, as used by `getlastmodified` and `Last-Modified`
+pub fn http_date(unix: u64) -> String {
+    chrono::DateTime::from_timestamp(unix as i64, 0)
+        .map(|t| t.format("%a, %d %b %Y %H:%M:%S GMT").to_string())
+        .unwrap_or_default()
+}
+
+pub fn etag(size: u64, modified: u64) -> String {
+    format!("\"{:x}-{:x}\"", modified, size)
+}
+
+struct Lock {
+    path: String,
+    infinite: bool,
+    owner: Option<String>,
+    timeout: Duration,
+    expires: SystemTime,
+}
+
+impl Lock {
+    /// Whether this lock is in the way of changing `path`
+    fn covers(&self, path: &str) -> bool {
+        self.path == path
+            || (self.infinite && is_inside(path, &self.path))
+            || is_inside(&self.path, path)
+    }
+
+    fn activelock(&self, token: &str) -> String {
+        format!(
+            "<D:activelock><D:locktype><D:write/></D:locktype>\
+             <D:lockscope><D:exclusive/></D:lockscope><D:depth>{}</D:depth>{}\
+             <D:timeout>Second-{}</D:timeout>\
+             <D:locktoken><D:href>{}</D:href></D:locktoken>\
+             <D:lockroot><D:href>{}</D:href></D:lockroot></D:activelock>",
+            if self.infinite { "infinity" } else { "0" },
+            self.owner
+                .as_ref()
+                .map(|owner| format!("<D:owner>{}</D:owner>", owner))
+                .unwrap_or_default(),
+            self.timeout.as_secs(),
+            token,
+            html::escape(&href(&self.path, false))
+        )
+    }
+}
+
+/// `path` is somewhere below `dir`
+fn is_inside(path: &str, dir: &str) -> bool {
+    (dir.is_empty() && !path.is_empty()) || path.starts_with(&format!("{}/", dir))
+}
+
+/// Exclusive write locks by token
+#[derive(Default)]
+pub struct Locks {
+    locks: Mutex<HashMap<String, Lock>>,
+}
+
+impl Locks {
+    /// Takes a new lock, returning its token and `<D:activelock>`,
+    /// `None` if another lock is in the way
+    pub fn lock(
+        &self,
+        path: &str,
+        infinite: bool,
+        owner: Option<String>,
+        timeout: Duration,
+    ) -> Option<(String, String)> {
+        let mut locks = self.locks.lock().unwrap();
+        let now = SystemTime::now();
+        locks.retain(|_, lock| lock.expires > now);
+        let conflict = locks.values().any(|lock| {
+            lock.covers(path) || (infinite && is_inside(&lock.path, path))
+        });
+        if conflict {
+            return None;
+        }
+        let lock = Lock {
+            path: path.to_string(),
+            infinite,
+            owner,
+            timeout,
+            expires: now + timeout,
+        };
+        let token = new_token();
+        let activelock = lock.activelock(&token);
+        locks.insert(token.clone(), lock);
+        Some((token, activelock))
+    }
+
+    /// Extends one of `tokens` that locks `path`, returning its `<D:activelock>`
+    pub fn refresh(&self, path: &str, tokens: &[String], timeout: Duration) -> Option<String> {
+        let mut locks = self.locks.lock().unwrap();
+        let now = SystemTime::now();
+        let (token, lock) = locks
+            .iter_mut()
+            .find(|(token, lock)| tokens.contains(token) && lock.expires > now && lock.covers(path))?;
+        lock.timeout = timeout;
+        lock.expires = now + timeout;
+        Some(lock.activelock(token))
+    }
+
+    /// Drops the lock `token` if it applies to `path`
+    pub fn unlock(&self, path: &str, token: &str) -> bool {
+        let mut locks = self.locks.lock().unwrap();
+        match locks.get(token) {
+            Some(lock) if lock.covers(path) => {
+                locks.remove(token);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether `path` may be changed by someone holding `tokens`
+    pub fn can_write(&self, path: &str, tokens: &[String]) -> bool {
+        let locks = self.locks.lock().unwrap();
+        let now = SystemTime::now();
+        locks
+            .iter()
+            .filter(|(_, lock)| lock.expires > now && lock.covers(path))
+            .all(|(token, _)| tokens.contains(token))
+    }
+
+    /// `<D:activelock>` of the lock on exactly `path` (or an infinite one above it)
+    pub fn discovery(&self, path: &str) -> Option<String> {
+        let locks = self.locks.lock().unwrap();
+        let now = SystemTime::now();
+        locks
+            .iter()
+            .find(|(_, lock)| {
+                lock.expires > now
+                    && (lock.path == path || (lock.infinite && is_inside(path, &lock.path)))
+            })
+            .map(|(token, lock)| lock.activelock(token))
+    }
+
+    /// Forgets every lock at or below `path`, after it was deleted or moved away
+    pub fn remove_below(&self, path: &str) {
+        self.locks
+            .lock()
+            .unwrap()
+            .retain(|_, lock| lock.path != path && !is_inside(&lock.path, path));
+    }
+}
+
+fn new_token() -> String {
+    let bytes: [u8; 16] = rand::thread_rng().gen();
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "opaquelocktoken:{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
@@ -0,0 +1,599 @@
+/// delta.rs - sending only the changed parts of a file the other side has an older copy of
+/// - the side with the old copy sends its `signature`: a cheap rolling checksum and a strong
+///   hash of every block. the side with the new contents finds those blocks in it (at any
+///   offset, like rsync) and sends a `delta` of block copies and the bytes in between, which
+///   `patch` turns back into the new contents
+/// - both are plain byte strings so they can be request and response bodies as they are:
+///   signature = block size (u32) then per block weak checksum (u32) and strong hash (16 bytes),
+///   delta = ops, `0` block index (u32) and count (u32) or `1` length (u32) and that many bytes.
+///   numbers are big endian
+/// - contents are read and written a chunk at a time, neither side holds a whole file
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const STRONG_LEN: usize = 16;
+const COPY: u8 = 0;
+const DATA: u8 = 1;
+/// how much is read or written at a time
+const CHUNK: usize = 64 * 1024;
+
+/// Block size for a file of `len` bytes, about the square root like rsync so big files don't
+/// get huge signatures
+pub fn block_size(len: u64) -> u32 {
+    ((len as f64).sqrt() as u32).clamp(2048, 128 * 1024) & !7
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    pub block_size: u32,
+    /// weak checksum and strong hash of each full block, a shorter last block is left out
+    pub blocks: Vec<(u32, [u8; STRONG_LEN])>,
+}
+
+impl Signature {
+    /// Signature of the `len` bytes `reader` has, read a block at a time
+    pub async fn read(mut reader: impl AsyncRead + Unpin, len: u64) -> io::Result<Self> {
+        let block_size = block_size(len);
+        let mut block = vec![0; block_size as usize];
+        let mut blocks = Vec::new();
+        while fill(&mut reader, &mut block).await? == block.len() {
+            blocks.push((weak(&block), strong(&block)));
+        }
+        Ok(Signature { block_size, blocks })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(4 + self.blocks.len() * (4 + STRONG_LEN));
+        out.extend_from_slice(&self.block_size.to_be_bytes());
+        for (weak, strong) in &self.blocks {
+            out.extend_from_slice(&weak.to_be_bytes());
+            out.extend_from_slice(strong);
+        }
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let block_size = u32::from_be_bytes(bytes.get(..4)?.try_into().ok()?);
+        let rest = &bytes[4..];
+        if block_size == 0 || !rest.len().is_multiple_of(4 + STRONG_LEN) {
+            return None;
+        }
+        let blocks = rest
+            .chunks_exact(4 + STRONG_LEN)
+            .map(|block| {
+                let weak = u32::from_be_bytes(block[..4].try_into().unwrap());
+                (weak, block[4..].try_into().unwrap())
+            })
+            .collect();
+        Some(Signature { block_size, blocks })
+    }
+}
+
+/// Writes to `out` what the contents `reader` has need from the old copy described by
+/// `signature`, returns how many bytes of them went as they are
+pub async fn delta(
+    signature: &Signature,
+    mut reader: impl AsyncRead + Unpin,
+    mut out: impl AsyncWrite + Unpin,
+) -> io::Result<u64> {
+    let size = signature.block_size as usize;
+    let mut by_weak: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (i, (weak, _)) in signature.blocks.iter().enumerate() {
+        by_weak.entry(*weak).or_default().push(i);
+    }
+
+    let mut ops = Vec::new();
+    let mut literal = 0;
+    // contents read so far, sent up to `start`. the window is at `pos`
+    let mut buffer = Vec::new();
+    let (mut start, mut pos) = (0, 0);
+    let mut ended = false;
+    // consecutive matching blocks become one copy
+    let mut run: Option<(usize, usize)> = None;
+    let mut rolling: Option<Rolling> = None;
+    loop {
+        // the window and the byte after it have to be read to roll on
+        if !ended && buffer.len() <= pos + size {
+            buffer.drain(..start);
+            pos -= start;
+            start = 0;
+            let want = CHUNK.max(size);
+            let have = buffer.len();
+            buffer.resize(have + want, 0);
+            let read = fill(&mut reader, &mut buffer[have..]).await?;
+            buffer.truncate(have + read);
+            ended = read < want;
+        }
+        if rolling.is_none() && pos + size <= buffer.len() {
+            rolling = Some(Rolling::new(&buffer[pos..pos + size]));
+        }
+        let Some(window) = rolling.as_mut() else {
+            break;
+        };
+        let found = by_weak.get(&window.value()).and_then(|candidates| {
+            let hash = strong(&buffer[pos..pos + size]);
+            // the block following the current run first, it keeps the run going
+            let next = run.map(|(first, count)| first + count);
+            candidates
+                .iter()
+                .copied()
+                .filter(|&i| signature.blocks[i].1 == hash)
+                .min_by_key(|&i| Some(i) != next)
+        });
+        match found {
+            Some(index) => {
+                if start < pos {
+                    flush_run(&mut ops, &mut run);
+                    literal += push_data(&mut ops, &buffer[start..pos]);
+                }
+                run = match run {
+                    Some((first, count)) if first + count == index => Some((first, count + 1)),
+                    _ => {
+                        flush_run(&mut ops, &mut run);
+                        Some((index, 1))
+                    }
+                };
+                pos += size;
+                start = pos;
+                rolling = None;
+            }
+            None if pos + size < buffer.len() => {
+                window.roll(buffer[pos], buffer[pos + size]);
+                pos += 1;
+                // what didn't match goes out a chunk at a time
+                if pos - start >= CHUNK {
+                    flush_run(&mut ops, &mut run);
+                    literal += push_data(&mut ops, &buffer[start..pos]);
+                    start = pos;
+                }
+            }
+            None if ended => break,
+            None => {}
+        }
+        if ops.len() >= CHUNK {
+            out.write_all(&ops).await?;
+            ops.clear();
+        }
+    }
+    if start < buffer.len() {
+        flush_run(&mut ops, &mut run);
+        literal += push_data(&mut ops, &buffer[start..]);
+    }
+    flush_run(&mut ops, &mut run);
+    out.write_all(&ops).await?;
+    out.flush().await?;
+    Ok(literal)
+}
+
+/// What `patch` wrote
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Patched {
+    pub len: u64,
+    /// bytes that came in the delta, the rest was copied
+    pub literal_bytes: u64,
+    /// lowercase hex, like `sha256_hex`
+    pub sha256: String,
+}
+
+/// Writes the new contents to `out` from the old copy and a `delta` made against its
+/// signature. `open_base(offset)` reads the `base_len` bytes long old copy from `offset` on,
+/// it is only opened again when the delta doesn't copy blocks in order. fails with
+/// `InvalidData` when the delta doesn't fit the old copy or the contents would get longer
+/// than `max_len`
+pub async fn patch<F, Fut, R>(
+    base_len: u64,
+    mut open_base: F,
+    mut delta: impl AsyncRead + Unpin,
+    mut out: impl AsyncWrite + Unpin,
+    max_len: u64,
+) -> io::Result<Patched>
+where
+    F: FnMut(u64) -> Fut,
+    Fut: Future<Output = io::Result<R>>,
+    R: AsyncRead + Unpin,
+{
+    let size = block_size(base_len) as u64;
+    let mut copier = Copier {
+        buffer: vec![0; CHUNK],
+        hasher: Sha256::new(),
+        len: 0,
+    };
+    let mut literal_bytes = 0;
+    // the old copy as read last, and where it is at
+    let mut base: Option<(R, u64)> = None;
+    loop {
+        let mut op = [0];
+        if delta.read(&mut op).await? == 0 {
+            break;
+        }
+        match op[0] {
+            COPY => {
+                let start = number(&mut delta).await? * size;
+                let len = number(&mut delta).await? * size;
+                // every op adds something, so a delta can't go on without getting longer
+                if len == 0 || start + len > base_len || len > max_len - copier.len {
+                    return Err(invalid_delta());
+                }
+                let mut reader = match base.take() {
+                    Some((reader, at)) if at == start => reader,
+                    _ => open_base(start).await?,
+                };
+                copier.copy(&mut reader, len, &mut out).await?;
+                base = Some((reader, start + len));
+            }
+            DATA => {
+                let len = number(&mut delta).await?;
+                if len == 0 || len > max_len - copier.len {
+                    return Err(invalid_delta());
+                }
+                copier.copy(&mut delta, len, &mut out).await?;
+                literal_bytes += len;
+            }
+            _ => return Err(invalid_delta()),
+        }
+    }
+    out.flush().await?;
+    Ok(Patched {
+        len: copier.len,
+        literal_bytes,
+        sha256: hex(&copier.hasher.finalize()),
+    })
+}
+
+/// sha256 of whole contents as lowercase hex, a patched file is checked against it
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex(&Sha256::digest(data))
+}
+
+/// Copies and hashes what `patch` writes
+struct Copier {
+    buffer: Vec<u8>,
+    hasher: Sha256,
+    len: u64,
+}
+
+impl Copier {
+    /// Exactly `len` bytes from `from` to `out`, the delta doesn't fit when there are fewer
+    async fn copy(
+        &mut self,
+        from: &mut (impl AsyncRead + Unpin),
+        len: u64,
+        out: &mut (impl AsyncWrite + Unpin),
+    ) -> io::Result<()> {
+        let mut left = len;
+        while left > 0 {
+            let want = left.min(self.buffer.len() as u64) as usize;
+            let read = from.read(&mut self.buffer[..want]).await?;
+            if read == 0 {
+                return Err(invalid_delta());
+            }
+            self.hasher.update(&self.buffer[..read]);
+            out.write_all(&self.buffer[..read]).await?;
+            left -= read as u64;
+        }
+        self.len += len;
+        Ok(())
+    }
+}
+
+async fn number(delta: &mut (impl AsyncRead + Unpin)) -> io::Result<u64> {
+    match delta.read_u32().await {
+        Ok(number) => Ok(number as u64),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Err(invalid_delta()),
+        Err(e) => Err(e),
+    }
+}
+
+fn invalid_delta() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "the delta doesn't fit the old copy or makes the file too big",
+    )
+}
+
+/// Reads until `buffer` is full or the contents end, how much was read
+async fn fill(reader: &mut (impl AsyncRead + Unpin), buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]).await? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+    Ok(filled)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn flush_run(out: &mut Vec<u8>, run: &mut Option<(usize, usize)>) {
+    if let Some((first, count)) = run.take() {
+        out.push(COPY);
+        out.extend_from_slice(&(first as u32).to_be_bytes());
+        out.extend_from_slice(&(count as u32).to_be_bytes());
+    }
+}
+
+/// Adds `data` as it is, how many bytes that was. it is never more than a few chunks, far
+/// below what the u32 length holds
+fn push_data(out: &mut Vec<u8>, data: &[u8]) -> u64 {
+    out.push(DATA);
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(data);
+    data.len() as u64
+}
+
+fn strong(block: &[u8]) -> [u8; STRONG_LEN] {
+    Sha256::digest(block)[..STRONG_LEN].try_into().unwrap()
+}
+
+fn weak(block: &[u8]) -> u32 {
+    Rolling::new(block).value()
+}
+
+/// rsync's rolling checksum over a window that can slide one byte at a time
+struct Rolling {
+    a: u16,
+    b: u16,
+    len: u16,
+}
+
+impl Rolling {
+    fn new(block: &[u8]) -> Self {
+        let (mut a, mut b) = (0u16, 0u16);
+        for (i, &byte) in block.iter().enumerate() {
+            a = a.wrapping_add(byte as u16);
+            b = b.wrapping_add(((block.len() - i) as u16).wrapping_mul(byte as u16));
+        }
+        Rolling { a, b, len: block.len() as u16 }
+    }
+
+    /// Drops `out` from the front of the window and adds `next` at the end
+    fn roll(&mut self, out: u8, next: u8) {
+        self.a = self.a.wrapping_sub(out as u16).wrapping_add(next as u16);
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(out as u16)).wrapping_add(self.a);
+    }
+
+    fn value(&self) -> u32 {
+        self.a as u32 | (self.b as u32) << 16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::ReadBuf;
+
+    /// contents that don't repeat, so every block is found only where it came from
+    fn contents(len: usize) -> Vec<u8> {
+        let mut state = 0x2545f491u32;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+
+    /// hands out a few bytes per read, like a slow network
+    struct Trickle<'a>(&'a [u8]);
+
+    impl AsyncRead for Trickle<'_> {
+        fn poll_read(mut self: Pin<&mut Self>, _: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+            let len = self.0.len().min(buf.remaining()).min(1000);
+            buf.put_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    async fn signature_of(data: &[u8]) -> Signature {
+        Signature::read(Trickle(data), data.len() as u64).await.unwrap()
+    }
+
+    async fn delta_of(signature: &Signature, data: &[u8]) -> (Vec<u8>, u64) {
+        let mut out = Vec::new();
+        let literal = delta(signature, Trickle(data), &mut out).await.unwrap();
+        (out, literal)
+    }
+
+    async fn patched(base: &[u8], delta: &[u8], max_len: u64) -> Option<Vec<u8>> {
+        let mut out = Vec::new();
+        let open = |offset: u64| async move { Ok(Trickle(&base[offset as usize..])) };
+        match patch(base.len() as u64, open, Trickle(delta), &mut out, max_len).await {
+            Ok(patched) => {
+                assert_eq!(patched.len, out.len() as u64);
+                assert_eq!(patched.sha256, sha256_hex(&out));
+                Some(out)
+            }
+            Err(e) => {
+                assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+                None
+            }
+        }
+    }
+
+    /// patches `new` onto `old` through an encoded signature, returns the bytes sent
+    async fn round_trip(old: &[u8], new: &[u8]) -> u64 {
+        let signature = Signature::decode(&signature_of(old).await.encode()).unwrap();
+        let (delta, literal) = delta_of(&signature, new).await;
+        assert_eq!(patched(old, &delta, u64::MAX).await.as_deref(), Some(new));
+        literal
+    }
+
+    #[tokio::test]
+    async fn signature_encoding() {
+        let data = contents(10_000);
+        let signature = signature_of(&data).await;
+        assert_eq!(signature.block_size, 2048);
+        // the 1808 bytes past the last full block are left out
+        assert_eq!(signature.blocks.len(), 4);
+        let encoded = signature.encode();
+        assert_eq!(encoded.len(), 4 + 4 * 20);
+        assert_eq!(Signature::decode(&encoded), Some(signature));
+
+        assert_eq!(Signature::decode(&[]), None);
+        assert_eq!(Signature::decode(&[0, 0, 0, 0]), None);
+        assert_eq!(Signature::decode(&encoded[..encoded.len() - 1]), None);
+        assert_eq!(Signature::decode(&[0, 0, 8, 0]).unwrap().blocks, []);
+    }
+
+    #[test]
+    fn block_sizes() {
+        assert_eq!(block_size(0), 2048);
+        assert_eq!(block_size(100 << 20), 10240);
+        assert_eq!(block_size(1 << 40), 128 * 1024);
+        assert_eq!(block_size(12_345_678) % 8, 0);
+    }
+
+    #[tokio::test]
+    async fn unchanged() {
+        let data = contents(50_000);
+        assert_eq!(round_trip(&data, &data).await, 50_000 % 2048);
+        let (delta, _) = delta_of(&signature_of(&data).await, &data).await;
+        // full blocks in a row are one copy
+        assert_eq!(delta[0], COPY);
+        assert_eq!(&delta[1..9], [0, 0, 0, 0, 0, 0, 0, 24]);
+    }
+
+    #[tokio::test]
+    async fn insertion() {
+        let old = contents(30_000);
+        let mut new = old.clone();
+        new.splice(5_000..5_000, b"inserted in the middle".iter().copied());
+        new.splice(0..0, b"at the start".iter().copied());
+        let sent = round_trip(&old, &new).await;
+        // what was inserted and the blocks it broke
+        assert!(sent < 2 * 2048 + 34 + 30_000 % 2048, "{}", sent);
+    }
+
+    #[tokio::test]
+    async fn deletion() {
+        let old = contents(30_000);
+        let mut new = old.clone();
+        new.drain(10_000..13_000);
+        let sent = round_trip(&old, &new).await;
+        assert!(sent < 2 * 2048 + 30_000 % 2048, "{}", sent);
+        assert_eq!(round_trip(&old, &[]).await, 0);
+        assert_eq!(round_trip(&[], &old).await, 30_000);
+    }
+
+    #[tokio::test]
+    async fn bigger_than_a_chunk() {
+        // blocks and changes across the chunks the contents are read in
+        let old = contents(5 * CHUNK + 123);
+        let mut new = old.clone();
+        new.splice(CHUNK - 10..CHUNK - 10, b"across a chunk boundary".iter().copied());
+        new.drain(3 * CHUNK..3 * CHUNK + 5000);
+        new.extend_from_slice(&contents(2 * CHUNK)[..CHUNK + 77]);
+        let sent = round_trip(&old, &new).await;
+        assert!(sent < 4 * 2048 + 23 + CHUNK as u64 + 77 + 123, "{}", sent);
+
+        // nothing in common, it all goes as data in chunks
+        let other: Vec<u8> = old.iter().map(|b| !b).collect();
+        assert_eq!(round_trip(&old, &other).await, other.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn partial_last_block() {
+        let old = contents(4_100);
+        // the 4 bytes after the second block always go as data
+        let mut new = old.clone();
+        assert_eq!(round_trip(&old, &new).await, 4);
+        new.truncate(4_097);
+        assert_eq!(round_trip(&old, &new).await, 1);
+        new.extend_from_slice(b"appended");
+        assert_eq!(round_trip(&old, &new).await, 9);
+        // shorter than a block, nothing can be copied
+        assert_eq!(round_trip(&old, &old[..2_000]).await, 2_000);
+    }
+
+    #[tokio::test]
+    async fn blocks_out_of_order() {
+        let old = contents(3 * 2048);
+        let mut new = old[2 * 2048..].to_vec();
+        new.extend_from_slice(&old[..2 * 2048]);
+        new.extend_from_slice(&old[..2048]);
+        assert_eq!(round_trip(&old, &new).await, 0);
+
+        // the old copy is only opened again when the copies jump around
+        let (delta, _) = delta_of(&signature_of(&old).await, &new).await;
+        let mut opened = Vec::new();
+        let open = |offset: u64| {
+            opened.push(offset);
+            let old = &old;
+            async move { Ok(&old[offset as usize..]) }
+        };
+        patch(old.len() as u64, open, &delta[..], tokio::io::sink(), u64::MAX).await.unwrap();
+        assert_eq!(opened, [2 * 2048, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn malformed_deltas() {
+        let base = contents(3 * 2048);
+        let copy = |first: u32, count: u32| [&[COPY][..], &first.to_be_bytes(), &count.to_be_bytes()].concat();
+        let data = |len: u32, bytes: &[u8]| [&[DATA][..], &len.to_be_bytes(), bytes].concat();
+
+        assert_eq!(patched(&base, &[], u64::MAX).await, Some(Vec::new()));
+        assert_eq!(patched(&base, &copy(1, 2), u64::MAX).await.as_deref(), Some(&base[2048..]));
+        assert_eq!(patched(&base, &data(3, b"abc"), u64::MAX).await.as_deref(), Some(&b"abc"[..]));
+        // past the end of the base, past the end of the delta, cut off and unknown ops
+        assert_eq!(patched(&base, &copy(2, 2), u64::MAX).await, None);
+        assert_eq!(patched(&base, &copy(u32::MAX, u32::MAX), u64::MAX).await, None);
+        assert_eq!(patched(&base, &data(4, b"abc"), u64::MAX).await, None);
+        assert_eq!(patched(&base, &copy(0, 1)[..5], u64::MAX).await, None);
+        assert_eq!(patched(&base, &[DATA, 0, 0], u64::MAX).await, None);
+        assert_eq!(patched(&base, &[7], u64::MAX).await, None);
+        assert_eq!(patched(&base, &[data(1, b"a"), vec![9]].concat(), u64::MAX).await, None);
+        // ops that add nothing could go on forever
+        assert_eq!(patched(&base, &copy(0, 0), u64::MAX).await, None);
+        assert_eq!(patched(&base, &data(0, b""), u64::MAX).await, None);
+    }
+
+    #[tokio::test]
+    async fn patch_size_limit() {
+        let base = contents(3 * 2048);
+        let (delta, _) = delta_of(&signature_of(&base).await, &[&base[..], b"more"].concat()).await;
+        assert_eq!(patched(&base, &delta, 3 * 2048 + 4).await.map(|out| out.len()), Some(3 * 2048 + 4));
+        assert_eq!(patched(&base, &delta, 3 * 2048 + 3).await, None);
+        assert_eq!(patched(&base, &delta, 2048).await, None);
+        // copying the same block over and over can't get past the limit either
+        let repeated: Vec<u8> = std::iter::repeat_n([COPY, 0, 0, 0, 0, 0, 0, 0, 1], 1000).flatten().collect();
+        assert_eq!(patched(&base, &repeated, u64::MAX).await.map(|out| out.len()), Some(1000 * 2048));
+        assert_eq!(patched(&base, &repeated, 10 * 2048).await, None);
+    }
+
+    #[tokio::test]
+    async fn literal_byte_counts() {
+        let old = contents(5_000);
+        let new = [b"abc", &old[..4096], b"de"].concat();
+        let (delta, literal) = delta_of(&signature_of(&old).await, &new).await;
+        assert_eq!(literal, 5);
+        let open = |offset: u64| {
+            let old = &old;
+            async move { Ok(&old[offset as usize..]) }
+        };
+        let patched = patch(old.len() as u64, open, &delta[..], tokio::io::sink(), u64::MAX).await.unwrap();
+        assert_eq!(patched.literal_bytes, 5);
+        assert_eq!(patched.len, new.len() as u64);
+    }
+
+    #[test]
+    fn rolling_checksum() {
+        let data = contents(5_000);
+        let mut window = Rolling::new(&data[..2048]);
+        for start in 0..1000 {
+            assert_eq!(window.value(), weak(&data[start..start + 2048]));
+            window.roll(data[start], data[start + 2048]);
+        }
+        assert_eq!(sha256_hex(b""), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+    }
+}
@@ -0,0 +1,92 @@
+/// encoding.rs - compressing file contents on the way over
+/// - a client that can take compressed downloads says so with `Accept-Encoding`, the server
+///   answers with `Content-Encoding` when it compressed the body
+/// - the server lists what uploads may be compressed with in the `Accept-Encoding` header of
+///   its responses, a client only compresses uploads once it has seen that
+/// - contents that are already compressed (images, video, archives, ...) and small files are
+///   sent as they are, compressing them again only costs time
+use std::fmt;
+
+/// what we can compress with, in the order we prefer them
+pub const SUPPORTED: &str = "zstd, gzip";
+
+/// files smaller than this aren't worth compressing
+pub const MIN_BYTES: u64 = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Zstd,
+    Gzip,
+}
+
+impl Encoding {
+    /// Name in `Content-Encoding` and `Accept-Encoding` headers
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "zstd" => Some(Encoding::Zstd),
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// The encoding to use for a peer that sent `accept` (an `Accept-Encoding` value), zstd
+/// before gzip whatever order they are listed in. `None` when it takes neither
+pub fn negotiate(accept: &str) -> Option<Encoding> {
+    let accepted: Vec<Encoding> = accept
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let encoding = Encoding::parse(parts.next()?)?;
+            // "gzip;q=0" means no gzip
+            let refused = parts.any(|param| {
+                param
+                    .trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.trim().parse::<f32>().ok())
+                    .is_some_and(|q| q <= 0.0)
+            });
+            (!refused).then_some(encoding)
+        })
+        .collect();
+    [Encoding::Zstd, Encoding::Gzip].into_iter().find(|encoding| accepted.contains(encoding))
+}
+
+/// Whether a file called `name` is compressed already, going by its extension
+pub fn already_compressed(name: &str) -> bool {
+    const COMPRESSED: &[&str] = &[
+        // archives
+        "zip", "gz", "tgz", "bz2", "xz", "txz", "zst", "lz4", "7z", "rar", "jar", "apk", "deb", "rpm",
+        // images
+        "jpg", "jpeg", "png", "gif", "webp", "avif", "heic", "heif", "jxl",
+        // audio and video
+        "mp3", "m4a", "aac", "ogg", "oga", "opus", "flac", "mp4", "m4v", "mkv", "mov", "webm", "avi",
+        // documents that are zip files inside
+        "docx", "xlsx", "pptx", "odt", "ods", "odp", "epub", "pdf",
+        // fonts
+        "woff", "woff2",
+    ];
+    let name = name.rsplit('/').next().unwrap_or(name);
+    match name.rsplit_once('.') {
+        Some((_, extension)) => COMPRESSED.contains(&extension.to_ascii_lowercase().as_str()),
+        None => false,
+    }
+}
+
+/// Whether `len` bytes of a file called `name` are worth compressing
+pub fn worth_compressing(name: &str, len: u64) -> bool {
+    len >= MIN_BYTES && !already_compressed(name)
+}
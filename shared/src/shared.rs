@@ -1,3 +1,305 @@
+/// shared.rs - request/response bodies used by both the client and the server
+use serde::{Deserialize, Serialize};
+
+pub mod delta;
+pub mod encoding;
+
+/// one file or folder in a listing
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FileEntry {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    /// unix timestamp (seconds) of the last modification
+    pub modified: u64,
+    pub can_read: bool,
+    pub can_write: bool,
+    /// labels like "release", sorted - always empty without the server's metadata index
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub note: Option<String>,
+    /// sha256 of the contents as lowercase hex, when the server's metadata index has it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+/// reply to `/getfiles`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct GetFilesResponse {
-    pub files: Vec<String>,
+    /// folder that was listed, relative to the storage root ("" is the root)
+    pub dir: String,
+    /// whether the user may upload into / create folders in `dir`
+    pub can_write: bool,
+    pub files: Vec<FileEntry>,
+}
+
+/// body of `POST /login`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoginRequest {
+    pub username: String,
+    pub password: String,
+}
+
+/// reply to a successful login, `token` goes in `Authorization: Bearer <token>`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoginResponse {
+    pub token: String,
+    /// unix timestamp (seconds) the token stops working at
+    pub expires_at: u64,
+}
+
+/// body of `POST /share`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShareRequest {
+    /// file or folder to share, relative to the storage root
+    pub path: String,
+    pub expires_in_secs: u64,
+    /// visitors have to enter this before downloading
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+/// reply to `POST /share`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShareResponse {
+    /// link that can be opened in any browser
+    pub url: String,
+    /// unix timestamp (seconds) the link stops working at
+    pub expires_at: u64,
+}
+
+/// one previous version of a file
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FileVersion {
+    /// what `/restoreversion` takes in its `version` header
+    pub id: u64,
+    pub size: u64,
+    /// unix timestamp (seconds) these contents were written
+    pub modified: u64,
+    /// unix timestamp (seconds) they were replaced by a newer upload
+    pub replaced: u64,
+    /// who uploaded them, `None` when unknown or authentication is off
+    pub uploader: Option<String>,
+}
+
+/// reply to `/versions`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct VersionsResponse {
+    /// the file, relative to the storage root
+    pub path: String,
+    /// newest first
+    pub versions: Vec<FileVersion>,
+}
+
+/// something deleted that can still be restored
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TrashItem {
+    /// what `/restoretrash` and `/purgetrash` take in their `id` header
+    pub id: String,
+    /// where it was, relative to the storage root
+    pub path: String,
+    pub is_dir: bool,
+    /// for folders, everything inside them
+    pub size: u64,
+    /// unix timestamp (seconds) of the delete
+    pub deleted_at: u64,
+    /// `None` when authentication is off
+    pub deleted_by: Option<String>,
+    /// unix timestamp (seconds) it gets purged at
+    pub purge_at: u64,
+}
+
+/// reply to `/trash`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TrashResponse {
+    /// newest first
+    pub items: Vec<TrashItem>,
+}
+
+/// a quota and how much of it is used
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct QuotaUsage {
+    /// folder the quota covers, relative to the storage root
+    pub path: String,
+    pub used_bytes: u64,
+    /// `None` when only the file count is limited
+    pub max_bytes: Option<u64>,
+    pub used_files: u64,
+    /// `None` when only the size is limited
+    pub max_files: Option<u64>,
+}
+
+/// reply to `/usage`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UsageResponse {
+    /// folder that was asked about
+    pub dir: String,
+    /// quotas uploads into `dir` have to fit in, outermost first
+    pub quotas: Vec<QuotaUsage>,
+}
+
+/// how `SearchRequest::pattern` is matched against file and folder names
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchMode {
+    /// the name contains the pattern, ignoring case
+    #[default]
+    Substring,
+    /// `*`, `?` and `[...]` over the whole name, ignoring case
+    Glob,
+    Regex,
+}
+
+/// body of `POST /search`, every filter that is set has to match
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SearchRequest {
+    /// folder searched recursively, relative to the storage root ("" is the root)
+    #[serde(default)]
+    pub dir: String,
+    /// an empty pattern matches every name
+    #[serde(default)]
+    pub pattern: String,
+    #[serde(default)]
+    pub mode: SearchMode,
+    /// size filters in bytes, folders never match them
+    #[serde(default)]
+    pub min_size: Option<u64>,
+    #[serde(default)]
+    pub max_size: Option<u64>,
+    /// unix timestamps (seconds) the last modification has to be within
+    #[serde(default)]
+    pub modified_after: Option<u64>,
+    #[serde(default)]
+    pub modified_before: Option<u64>,
+    /// entries need every one of these tags
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// one match of a search
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SearchResult {
+    /// relative to the storage root
+    pub path: String,
+    pub entry: FileEntry,
+}
+
+/// reply to `POST /search`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SearchResponse {
+    pub dir: String,
+    pub results: Vec<SearchResult>,
+    /// more matched than the server sends back
+    pub truncated: bool,
+}
+
+/// body of `POST /textsearch`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TextSearchRequest {
+    /// folder searched recursively, relative to the storage root ("" is the root)
+    #[serde(default)]
+    pub dir: String,
+    /// words that all have to be in a file, case doesn't matter
+    pub query: String,
+}
+
+/// a line of a file with searched words in it
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TextSnippet {
+    /// 1 based
+    pub line: u64,
+    pub text: String,
+    /// byte ranges of `text` that matched a searched word
+    pub highlights: Vec<(usize, usize)>,
+}
+
+/// one file found by a full-text search
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TextMatch {
+    /// relative to the storage root
+    pub path: String,
+    pub entry: FileEntry,
+    pub snippets: Vec<TextSnippet>,
+}
+
+/// reply to `POST /textsearch`, best matches first
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TextSearchResponse {
+    pub dir: String,
+    pub results: Vec<TextMatch>,
+    /// more files matched than the server sends back
+    pub truncated: bool,
+}
+
+/// body of `POST /tags`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TagsRequest {
+    /// file or folder, relative to the storage root
+    pub path: String,
+    #[serde(default)]
+    pub add: Vec<String>,
+    #[serde(default)]
+    pub remove: Vec<String>,
+}
+
+/// body of `POST /note`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct NoteRequest {
+    /// file or folder, relative to the storage root
+    pub path: String,
+    /// an empty note removes it
+    pub note: String,
+}
+
+/// reply to `POST /tags` and `POST /note`, what the path has now
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LabelsResponse {
+    pub path: String,
+    pub tags: Vec<String>,
+    pub note: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Created,
+    Modified,
+    Deleted,
+    /// `path` moved to `to`
+    Renamed,
+}
+
+/// one change streamed by `GET /events`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangeEvent {
+    pub kind: ChangeKind,
+    /// relative to the storage root
+    pub path: String,
+    /// where it went, for `Renamed`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+}
+
+/// archives the server shows as folders, going by the file name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+impl ArchiveKind {
+    pub fn of(name: &str) -> Option<Self> {
+        let name = name.to_ascii_lowercase();
+        if name.ends_with(".zip") {
+            Some(ArchiveKind::Zip)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(ArchiveKind::TarGz)
+        } else if name.ends_with(".tar") {
+            Some(ArchiveKind::Tar)
+        } else {
+            None
+        }
+    }
 }